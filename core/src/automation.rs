@@ -0,0 +1,305 @@
+//! Automation lanes, for changing track parameters over time.
+//!
+//! A [`Curve`] is a list of [`Breakpoint`]s, each giving a value at a sample
+//! position. Between breakpoints the value moves linearly or exponentially,
+//! and before the first/after the last breakpoint it holds steady. A
+//! [`Lane`] connects a curve to one [`Target`] parameter, and an
+//! [`Automation`] holds all of the lanes for one track.
+//!
+//! Positions are in samples, relative to the start of the track (the same as
+//! `Track::cur_samp`). Use [`Curve::push_tick`] to place breakpoints in
+//! musical time instead.
+
+use heapless::Vec;
+
+use crate::{
+    clock::Timebase,
+    tones::{Channel, Operator},
+};
+
+/// How the value moves from the previous breakpoint to this one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shape {
+    Linear,
+    /// Exponential curves are good for anything we hear logarithmically,
+    /// like gain or frequency. If either end is zero, or the ends have
+    /// different signs, this falls back to linear.
+    Exponential,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Breakpoint {
+    pub sample: u32,
+    pub value: f32,
+    pub shape: Shape,
+}
+
+/// The parameter driven by a lane, and the units of its values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    /// `0.0` (silent) to `1.0` (full volume)
+    Gain,
+    /// `-1.0` (left) to `1.0` (right)
+    Pan,
+    /// Low-pass filter cutoff, in Hz
+    Cutoff,
+    /// Frequency of the operator's LFO, in Hz
+    LfoRate,
+    /// `0.0` (no effect) to `1.0` (full effect)
+    OperatorDepth,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AutomationError {
+    /// Breakpoints must be added in increasing sample order
+    OutOfOrder,
+    /// There is no room for more breakpoints or lanes
+    Full,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Curve<const N: usize> {
+    points: Vec<Breakpoint, N>,
+}
+
+impl<const N: usize> Curve<N> {
+    pub fn new() -> Self {
+        Self { points: Vec::new() }
+    }
+
+    pub fn push(&mut self, point: Breakpoint) -> Result<(), AutomationError> {
+        if let Some(last) = self.points.last() {
+            if point.sample < last.sample {
+                return Err(AutomationError::OutOfOrder);
+            }
+        }
+        self.points.push(point).map_err(|_| AutomationError::Full)
+    }
+
+    /// Add a breakpoint at a position given in ticks
+    pub fn push_tick<T: Timebase>(
+        &mut self,
+        timebase: &T,
+        tick: u32,
+        value: f32,
+        shape: Shape,
+    ) -> Result<(), AutomationError> {
+        self.push(Breakpoint {
+            sample: timebase.tick_to_sample(tick),
+            value,
+            shape,
+        })
+    }
+
+    pub fn clear(&mut self) {
+        self.points.clear();
+    }
+
+    pub fn points(&self) -> &[Breakpoint] {
+        &self.points
+    }
+
+    /// The value of the curve at the given sample, or `None` if the curve
+    /// has no breakpoints.
+    pub fn value_at(&self, sample: u32) -> Option<f32> {
+        let first = self.points.first()?;
+        if sample <= first.sample {
+            return Some(first.value);
+        }
+
+        let idx = self.points.iter().position(|p| p.sample > sample);
+        let Some(idx) = idx else {
+            // Past the last breakpoint
+            return self.points.last().map(|p| p.value);
+        };
+
+        let prev = &self.points[idx - 1];
+        let next = &self.points[idx];
+        let span = (next.sample - prev.sample) as f32;
+        let frac = ((sample - prev.sample) as f32) / span;
+
+        let exp_ok = (prev.value * next.value) > 0.0;
+        Some(match next.shape {
+            Shape::Exponential if exp_ok => {
+                prev.value * libm::powf(next.value / prev.value, frac)
+            }
+            _ => prev.value + (next.value - prev.value) * frac,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Lane<const N: usize> {
+    pub target: Target,
+    pub curve: Curve<N>,
+}
+
+/// All of the automation lanes for a single track.
+#[derive(Debug, Clone, Default)]
+pub struct Automation<const LANES: usize, const POINTS: usize> {
+    lanes: Vec<Lane<POINTS>, LANES>,
+}
+
+impl<const LANES: usize, const POINTS: usize> Automation<LANES, POINTS> {
+    pub fn new() -> Self {
+        Self { lanes: Vec::new() }
+    }
+
+    /// Get the curve for a target, adding an empty lane if there isn't
+    /// one already.
+    pub fn lane_mut(&mut self, target: Target) -> Result<&mut Curve<POINTS>, AutomationError> {
+        let idx = match self.lanes.iter().position(|l| l.target == target) {
+            Some(idx) => idx,
+            None => {
+                self.lanes
+                    .push(Lane {
+                        target,
+                        curve: Curve::new(),
+                    })
+                    .map_err(|_| AutomationError::Full)?;
+                self.lanes.len() - 1
+            }
+        };
+        Ok(&mut self.lanes[idx].curve)
+    }
+
+    pub fn lanes(&self) -> &[Lane<POINTS>] {
+        &self.lanes
+    }
+
+    pub fn clear(&mut self) {
+        self.lanes.clear();
+    }
+
+    /// Update the channel and operator to the values at the given sample.
+    pub fn apply(&self, sample: u32, sample_rate: u32, channel: &mut Channel, operator: &mut Operator) {
+        for lane in self.lanes.iter() {
+            let Some(val) = lane.curve.value_at(sample) else {
+                continue;
+            };
+            match lane.target {
+                Target::Gain => channel.set_gain(val),
+                Target::Pan => channel.set_pan(val),
+                Target::Cutoff => channel.set_cutoff(val, sample_rate),
+                Target::LfoRate => {
+                    if let Some(lfo) = operator.lfo_mut() {
+                        lfo.set_freq(val, sample_rate);
+                    }
+                }
+                Target::OperatorDepth => {
+                    operator.depth = (val.clamp(0.0, 1.0) * 255.0) as u8;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::Clock,
+        scale::{Note, Pitch},
+        tones::{Mix, OperatorKind, ToneKind},
+        Sample, StereoSample, Track,
+    };
+
+    fn bp(sample: u32, value: f32, shape: Shape) -> Breakpoint {
+        Breakpoint { sample, value, shape }
+    }
+
+    #[test]
+    fn linear_and_exponential() {
+        let mut curve: Curve<4> = Curve::new();
+        assert_eq!(curve.value_at(0), None);
+
+        curve.push(bp(100, 0.0, Shape::Linear)).unwrap();
+        curve.push(bp(200, 1.0, Shape::Linear)).unwrap();
+        curve.push(bp(300, 100.0, Shape::Exponential)).unwrap();
+        assert_eq!(curve.push(bp(299, 1.0, Shape::Linear)), Err(AutomationError::OutOfOrder));
+
+        assert_eq!(curve.value_at(0), Some(0.0));
+        assert_eq!(curve.value_at(150), Some(0.5));
+        assert_eq!(curve.value_at(200), Some(1.0));
+        let mid = curve.value_at(250).unwrap();
+        assert!((mid - 10.0).abs() < 0.001, "{mid}");
+        assert_eq!(curve.value_at(1000), Some(100.0));
+    }
+
+    #[test]
+    fn exponential_through_zero_is_linear() {
+        let mut curve: Curve<2> = Curve::new();
+        curve.push(bp(0, 0.0, Shape::Linear)).unwrap();
+        curve.push(bp(10, 1.0, Shape::Exponential)).unwrap();
+        assert_eq!(curve.value_at(5), Some(0.5));
+    }
+
+    #[test]
+    fn tick_positions() {
+        let clock = Clock::new(48000, 120).unwrap();
+        let mut auto: Automation<2, 4> = Automation::new();
+        let gain = auto.lane_mut(Target::Gain).unwrap();
+        gain.push_tick(&clock, 0, 0.0, Shape::Linear).unwrap();
+        gain.push_tick(&clock, 192, 1.0, Shape::Linear).unwrap();
+        assert_eq!(gain.points()[1].sample, 24000);
+
+        // Asking again gives the same lane
+        auto.lane_mut(Target::Gain).unwrap();
+        assert_eq!(auto.lanes().len(), 1);
+    }
+
+    #[test]
+    fn fades_track() {
+        let mut track: Track<4> = Track::new(8000);
+        let note = Note { pitch: Pitch::A, octave: 4 };
+        track.add_note(ToneKind::Square, note, 0, 8000).unwrap();
+        let mut op = Operator::new(OperatorKind::None);
+
+        // Fade in over the first half second
+        let mut auto: Automation<1, 2> = Automation::new();
+        let gain = auto.lane_mut(Target::Gain).unwrap();
+        gain.push(bp(0, 0.0, Shape::Linear)).unwrap();
+        gain.push(bp(4000, 1.0, Shape::Linear)).unwrap();
+
+        let mut peaks = [0i16; 10];
+        for peak in peaks.iter_mut() {
+            let empty = Sample { word: 0 };
+            let mut samples = [StereoSample { left: empty, right: empty }; 512];
+            track.fill_stereo_samples_automated(&mut samples, Mix::Div1, &mut op, &auto);
+            *peak = samples
+                .iter()
+                .map(|s| unsafe { s.left.word }.saturating_abs())
+                .max()
+                .unwrap();
+        }
+
+        // The note starts in the second block, and each block is louder
+        // until the fade is done.
+        assert_eq!(peaks[0], 0);
+        assert!(peaks[1] < peaks[2]);
+        assert!(peaks[2] < peaks[3]);
+        assert!(peaks[3] < peaks[7]);
+        assert!(peaks[7] < peaks[8]);
+        assert_eq!(peaks[8], peaks[9]);
+        assert_eq!(track.channel.gain(), 1.0);
+    }
+
+    #[test]
+    fn operator_targets() {
+        let mut auto: Automation<2, 2> = Automation::new();
+        auto.lane_mut(Target::OperatorDepth)
+            .unwrap()
+            .push(bp(0, 1.0, Shape::Linear))
+            .unwrap();
+        auto.lane_mut(Target::Pan)
+            .unwrap()
+            .push(bp(0, -1.0, Shape::Linear))
+            .unwrap();
+
+        let mut op = Operator::new(OperatorKind::None);
+        let mut chan = Channel::default();
+        auto.apply(0, 44100, &mut chan, &mut op);
+        assert_eq!(op.depth, 255);
+        assert_eq!(chan.pan(), -1.0);
+    }
+}
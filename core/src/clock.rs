@@ -0,0 +1,171 @@
+//! Musical time for scheduling notes.
+//!
+//! A [`Track`](crate::Track) only knows about sample indices. The [`Clock`]
+//! owns the tempo and sample rate, and converts positions measured in
+//! PPQN ticks into sample positions.
+//!
+//! Every conversion is made from an *absolute* tick position, rather than by
+//! adding up per-note sample lengths, so rounding errors never accumulate: a
+//! note scheduled an hour into a session lands within one sample of where it
+//! belongs.
+
+/// Pulses (ticks) per quarter note. This matches the resolution used by
+/// the `thursday` encoding.
+pub const PPQN: u32 = 192;
+
+/// Anything that can place ticks on the sample timeline.
+///
+/// This is implemented by the fixed tempo [`Clock`], as well as by
+/// [`TempoMap`](crate::tempo::TempoMap) for tempos that change over time.
+pub trait Timebase {
+    /// The sample position of the given absolute tick, rounded down.
+    fn tick_to_sample(&self, tick: u32) -> u32;
+
+    /// The last tick that starts at or before the given sample position.
+    fn sample_to_tick(&self, sample: u32) -> u32;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Clock {
+    sample_rate: u32,
+    bpm: u32,
+    ppqn: u32,
+}
+
+impl Clock {
+    /// Create a new clock running at [`PPQN`] ticks per quarter note.
+    ///
+    /// Returns `None` if the sample rate or bpm are zero.
+    pub fn new(sample_rate: u32, bpm: u32) -> Option<Self> {
+        Self::with_ppqn(sample_rate, bpm, PPQN)
+    }
+
+    /// Create a new clock with a custom tick resolution.
+    ///
+    /// Returns `None` if any of the values are zero.
+    pub fn with_ppqn(sample_rate: u32, bpm: u32, ppqn: u32) -> Option<Self> {
+        if sample_rate == 0 || bpm == 0 || ppqn == 0 {
+            return None;
+        }
+        Some(Self {
+            sample_rate,
+            bpm,
+            ppqn,
+        })
+    }
+
+    #[inline]
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    #[inline]
+    pub fn bpm(&self) -> u32 {
+        self.bpm
+    }
+
+    #[inline]
+    pub fn ppqn(&self) -> u32 {
+        self.ppqn
+    }
+
+    /// Change the tempo. A bpm of zero is ignored.
+    ///
+    /// NOTE: this re-times *every* tick, including ones that have already
+    /// been scheduled. Set the tempo before scheduling a phrase.
+    pub fn set_bpm(&mut self, bpm: u32) {
+        if bpm != 0 {
+            self.bpm = bpm;
+        }
+    }
+
+    /// The sample position of the given absolute tick, rounded down.
+    #[inline]
+    pub fn tick_to_sample(&self, tick: u32) -> u32 {
+        let num = (tick as u64) * (self.sample_rate as u64) * 60;
+        let den = (self.bpm as u64) * (self.ppqn as u64);
+        (num / den) as u32
+    }
+
+    /// The last tick that starts at or before the given sample position.
+    #[inline]
+    pub fn sample_to_tick(&self, sample: u32) -> u32 {
+        // Tick `t` starts at or before `sample` when `t * spt < sample + 1`,
+        // where `spt` is the (fractional) samples per tick.
+        let num = ((sample as u64) + 1) * (self.bpm as u64) * (self.ppqn as u64);
+        let den = (self.sample_rate as u64) * 60;
+        (num.div_ceil(den) - 1) as u32
+    }
+
+    /// The number of samples between two absolute ticks.
+    ///
+    /// Both ends are converted separately, so back-to-back spans always
+    /// line up exactly.
+    #[inline]
+    pub fn span_to_samples(&self, start_tick: u32, len_ticks: u32) -> u32 {
+        let start = self.tick_to_sample(start_tick);
+        let end = self.tick_to_sample(start_tick.saturating_add(len_ticks));
+        end - start
+    }
+}
+
+impl Timebase for Clock {
+    #[inline]
+    fn tick_to_sample(&self, tick: u32) -> u32 {
+        Clock::tick_to_sample(self, tick)
+    }
+
+    #[inline]
+    fn sample_to_tick(&self, sample: u32) -> u32 {
+        Clock::sample_to_tick(self, sample)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quarter_notes() {
+        let clock = Clock::new(44100, 120).unwrap();
+        assert_eq!(clock.tick_to_sample(0), 0);
+        assert_eq!(clock.tick_to_sample(PPQN), 22050);
+        assert_eq!(clock.tick_to_sample(PPQN * 4), 88200);
+        assert_eq!(clock.sample_to_tick(22050), PPQN);
+    }
+
+    #[test]
+    fn no_drift() {
+        // 44100 * 60 / (97 * 192) is not an integer number of samples per
+        // tick, so summing per-tick lengths would drift.
+        let clock = Clock::new(44100, 97).unwrap();
+        let mut pos = 0;
+        let mut tick = 0;
+        for _ in 0..100_000 {
+            let len = clock.span_to_samples(tick, PPQN / 3);
+            pos += len;
+            tick += PPQN / 3;
+        }
+        assert_eq!(pos, clock.tick_to_sample(tick));
+
+        // An hour at 97 bpm is 5820 quarter notes
+        let exact = (5820u64 * 44100 * 60) / 97;
+        assert_eq!(clock.tick_to_sample(5820 * PPQN) as u64, exact);
+    }
+
+    #[test]
+    fn round_trip() {
+        let clock = Clock::new(48000, 133).unwrap();
+        for tick in (0..(PPQN * 256)).step_by(7) {
+            let samp = clock.tick_to_sample(tick);
+            assert_eq!(clock.sample_to_tick(samp), tick);
+        }
+    }
+
+    #[test]
+    fn rejects_zero() {
+        assert!(Clock::new(0, 120).is_none());
+        assert!(Clock::new(44100, 0).is_none());
+        assert!(Clock::with_ppqn(44100, 120, 0).is_none());
+    }
+}
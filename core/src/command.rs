@@ -0,0 +1,202 @@
+//! Commands for controlling a [`Track`] from another context.
+//!
+//! The audio callback usually owns the track, so anything else (a UI
+//! thread, another core, an interrupt) can't call `Track::add_note`
+//! directly. Instead, split a [`CommandQueue`] into a [`Producer`] for the
+//! controlling side and a [`Consumer`] for the audio side, and have the
+//! audio side call [`Track::drain_commands`] before filling each block.
+//!
+//! The queue is a lock-free single-producer/single-consumer ring buffer,
+//! and never allocates. Note that a `CommandQueue<N>` holds at most
+//! `N - 1` commands.
+
+use heapless::spsc;
+
+use crate::{
+    scale,
+    tones::{Operator, ToneKind},
+    NoteError, Track,
+};
+
+pub type CommandQueue<const N: usize> = spsc::Queue<Command, N>;
+pub type Producer<'a, const N: usize> = spsc::Producer<'a, Command, N>;
+pub type Consumer<'a, const N: usize> = spsc::Consumer<'a, Command, N>;
+
+pub enum Command {
+    /// Queue a note, with the start and end given in samples
+    AddNote {
+        kind: ToneKind,
+        freq: f32,
+        start: u32,
+        end: u32,
+    },
+    /// Stop the current note, and drop any queued notes
+    Stop,
+    /// Set the track gain, from `0.0` (silent) to `1.0` (full volume)
+    SetGain(f32),
+    /// Replace the operator used when filling samples
+    SetOperator(Operator),
+    /// Reset the track back to the start, as with `Track::reset`
+    Reset,
+}
+
+impl Command {
+    pub fn add_note(kind: ToneKind, note: scale::Note, start: u32, end: u32) -> Self {
+        Command::AddNote {
+            kind,
+            freq: note.freq_f32(),
+            start,
+            end,
+        }
+    }
+}
+
+impl<const DEPTH: usize> Track<DEPTH> {
+    /// Apply a single command to the track.
+    ///
+    /// Returns an error if the command was an `AddNote` that the track
+    /// could not accept (see `Track::add_note_freq`).
+    pub fn apply_command(&mut self, cmd: Command, operator: &mut Operator) -> Result<(), NoteError> {
+        match cmd {
+            Command::AddNote { kind, freq, start, end } => {
+                return self.add_note_freq(kind, freq, start, end);
+            }
+            Command::Stop => {
+                self.note_q.clear();
+                self.current = None;
+            }
+            Command::SetGain(gain) => self.channel.set_gain(gain),
+            Command::SetOperator(op) => *operator = op,
+            Command::Reset => self.reset(),
+        }
+        Ok(())
+    }
+
+    /// Apply every command waiting in the queue. This should be called
+    /// between blocks, before filling samples.
+    ///
+    /// Returns the number of commands that could not be applied.
+    pub fn drain_commands<const N: usize>(
+        &mut self,
+        consumer: &mut Consumer<'_, N>,
+        operator: &mut Operator,
+    ) -> usize {
+        let mut failed = 0;
+        while let Some(cmd) = consumer.dequeue() {
+            if self.apply_command(cmd, operator).is_err() {
+                failed += 1;
+            }
+        }
+        failed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::{
+        scale::{Note, Pitch},
+        tones::{Mix, OperatorKind},
+        Sample, StereoSample,
+    };
+
+    const A4: Note = Note { pitch: Pitch::A, octave: 4 };
+
+    fn silence() -> [StereoSample; 64] {
+        let empty = Sample { word: 0 };
+        [StereoSample { left: empty, right: empty }; 64]
+    }
+
+    #[test]
+    fn apply_commands() {
+        let mut track: Track<4> = Track::new(8000);
+        let mut op = Operator::new(OperatorKind::None);
+
+        track.apply_command(Command::add_note(ToneKind::Sine, A4, 0, 100), &mut op).unwrap();
+        track.apply_command(Command::add_note(ToneKind::Sine, A4, 200, 300), &mut op).unwrap();
+        assert_eq!(
+            track.apply_command(Command::add_note(ToneKind::Sine, A4, 250, 400), &mut op),
+            Err(NoteError::OutOfOrder)
+        );
+        assert_eq!(
+            track.apply_command(Command::add_note(ToneKind::Sine, A4, 500, 500), &mut op),
+            Err(NoteError::Empty)
+        );
+        assert_eq!(track.note_q.len(), 2);
+
+        track.apply_command(Command::SetGain(0.5), &mut op).unwrap();
+        assert_eq!(track.channel.gain(), 0.5);
+
+        track.apply_command(Command::Stop, &mut op).unwrap();
+        assert!(track.is_done());
+
+        op.depth = 0;
+        let lfo = Operator::new(OperatorKind::None);
+        track.apply_command(Command::SetOperator(lfo), &mut op).unwrap();
+        assert_eq!(op.depth, Operator::DEFAULT_DEPTH);
+
+        track.cur_samp = 1234;
+        track.apply_command(Command::Reset, &mut op).unwrap();
+        assert_eq!(track.cur_samp, 0);
+    }
+
+    #[test]
+    fn two_threads() {
+        const NOTES: u32 = 100;
+
+        let mut queue: CommandQueue<8> = CommandQueue::new();
+        let (mut prod, mut cons) = queue.split();
+        let mut track: Track<4> = Track::new(8000);
+        let mut op = Operator::new(OperatorKind::None);
+        let mut sounding = 0;
+
+        std::thread::scope(|s| {
+            s.spawn(move || {
+                for i in 0..NOTES {
+                    let mut cmd = Command::add_note(ToneKind::Square, A4, i * 64, (i + 1) * 64);
+                    // Spin until the audio side makes room
+                    while let Err(back) = prod.enqueue(cmd) {
+                        cmd = back;
+                        std::thread::yield_now();
+                    }
+                }
+                while prod.enqueue(Command::SetGain(0.25)).is_err() {
+                    std::thread::yield_now();
+                }
+            });
+
+            // The "audio callback": only take commands when there is room
+            // for them in the track, as the producer is free-running.
+            // Filling samples moves the track along by a block each time,
+            // so wait for the producer rather than letting time run on
+            // with nothing queued.
+            let mut applied = 0;
+            while applied < NOTES + 1 || !track.is_done() {
+                while track.note_q.len() < 2 {
+                    let Some(cmd) = cons.dequeue() else {
+                        break;
+                    };
+                    track.apply_command(cmd, &mut op).unwrap();
+                    applied += 1;
+                }
+                if track.is_done() {
+                    std::thread::yield_now();
+                    continue;
+                }
+                let mut samples = silence();
+                track.fill_stereo_samples(&mut samples, Mix::Div1, &mut op);
+                if samples.iter().any(|s| unsafe { s.left.word } != 0) {
+                    sounding += 1;
+                }
+                std::thread::yield_now();
+            }
+        });
+
+        assert!(cons.dequeue().is_none());
+        assert_eq!(track.channel.gain(), 0.25);
+        // Each note is exactly one block long
+        assert_eq!(sounding, NOTES);
+    }
+}
@@ -1,16 +1,26 @@
 #![no_std]
 
 use heapless::Deque;
-use tones::{Tone, Mix, ToneKind, Operator};
+use clock::Timebase;
+use automation::Automation;
+use tones::{Channel, Tone, Mix, ToneKind, Operator};
 
 pub mod tones;
 pub mod scale;
+pub mod clock;
+pub mod tempo;
+pub mod automation;
+pub mod command;
+pub mod midi;
+pub mod sync;
+pub mod wav;
 
 pub struct Track<const DEPTH: usize> {
     pub note_q: Deque<Note, DEPTH>,
     pub current: Option<Note>,
     pub cur_samp: u32,
     pub sample_rate: u32,
+    pub channel: Channel,
 }
 
 impl<const DEPTH: usize> Track<DEPTH> {
@@ -21,6 +31,7 @@ impl<const DEPTH: usize> Track<DEPTH> {
             current: None,
             cur_samp: 0,
             sample_rate,
+            channel: Channel::default(),
         }
     }
 
@@ -28,6 +39,7 @@ impl<const DEPTH: usize> Track<DEPTH> {
         self.note_q.clear();
         self.current = None;
         self.cur_samp = 0;
+        self.channel.reset();
     }
 
     #[inline]
@@ -38,9 +50,9 @@ impl<const DEPTH: usize> Track<DEPTH> {
                 true
             } else {
                 if (self.cur_samp + samp_len) > note.samp_end {
-                    note.wave.fill_last_stereo_samples(samples, mix, operator);
+                    note.wave.fill_last_stereo_samples(samples, mix, operator, &mut self.channel);
                 } else {
-                    note.wave.fill_stereo_samples(samples, mix, operator);
+                    note.wave.fill_stereo_samples(samples, mix, operator, &mut self.channel);
                     self.current = Some(note);
                 }
                 false
@@ -52,7 +64,7 @@ impl<const DEPTH: usize> Track<DEPTH> {
         if need_new {
             while let Some(mut note) = self.note_q.pop_front() {
                 if note.samp_start < self.cur_samp {
-                    note.wave.fill_first_stereo_samples(samples, mix, operator);
+                    note.wave.fill_first_stereo_samples(samples, mix, operator, &mut self.channel);
                     self.current = Some(note);
                     break;
                 } else {
@@ -107,14 +119,59 @@ impl<const DEPTH: usize> Track<DEPTH> {
         self.cur_samp += samp_len;
     }
 
+    /// Like `fill_stereo_samples`, but first updating the channel and
+    /// operator from the automation lanes.
+    ///
+    /// Automation is evaluated once per call (at "control rate"), at the
+    /// position of the first sample, so smaller blocks give smoother changes.
+    #[inline]
+    pub fn fill_stereo_samples_automated<const LANES: usize, const POINTS: usize>(
+        &mut self,
+        samples: &mut [StereoSample],
+        mix: Mix,
+        operator: &mut Operator,
+        automation: &Automation<LANES, POINTS>,
+    ) {
+        automation.apply(self.cur_samp, self.sample_rate, &mut self.channel, operator);
+        self.fill_stereo_samples(samples, mix, operator);
+    }
+
     pub fn add_note(
         &mut self,
         kind: ToneKind,
         note: scale::Note,
         start: u32,
         end: u32
-    ) -> Result<(), ()> {
+    ) -> Result<(), NoteError> {
+        let freq = note.freq_f32();
+        self.add_note_freq(kind, freq, start, end)
+    }
+
+    /// Add a note, with the start and length given in ticks rather than samples.
+    ///
+    /// The clock should use the same sample rate as this track.
+    pub fn add_note_ticks<T: Timebase>(
+        &mut self,
+        clock: &T,
+        kind: ToneKind,
+        note: scale::Note,
+        start_tick: u32,
+        len_ticks: u32,
+    ) -> Result<(), NoteError> {
         let freq = note.freq_f32();
+        self.add_note_freq_ticks(clock, kind, freq, start_tick, len_ticks)
+    }
+
+    pub fn add_note_freq_ticks<T: Timebase>(
+        &mut self,
+        clock: &T,
+        kind: ToneKind,
+        freq: f32,
+        start_tick: u32,
+        len_ticks: u32,
+    ) -> Result<(), NoteError> {
+        let start = clock.tick_to_sample(start_tick);
+        let end = clock.tick_to_sample(start_tick.saturating_add(len_ticks));
         self.add_note_freq(kind, freq, start, end)
     }
 
@@ -124,20 +181,20 @@ impl<const DEPTH: usize> Track<DEPTH> {
         freq: f32,
         start: u32,
         end: u32
-    ) -> Result<(), ()> {
+    ) -> Result<(), NoteError> {
         if end <= start {
-            return Err(());
+            return Err(NoteError::Empty);
         }
 
         if self.note_q.is_full() {
-            return Err(());
+            return Err(NoteError::QueueFull);
         }
 
         if let Some(note) = self.note_q.pop_back() {
             let out_of_order = start < note.samp_end;
             self.note_q.push_back(note).ok();
             if out_of_order {
-                return Err(());
+                return Err(NoteError::OutOfOrder);
             }
         }
 
@@ -151,7 +208,7 @@ impl<const DEPTH: usize> Track<DEPTH> {
             wave: tone,
             samp_start: start,
             samp_end: end,
-        }).map_err(drop)
+        }).map_err(|_| NoteError::QueueFull)
     }
 
     pub fn is_done(&self) -> bool {
@@ -165,6 +222,17 @@ pub struct Note {
     pub samp_end: u32,
 }
 
+/// Why a note could not be added to a [`Track`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoteError {
+    /// The note ends before (or as) it starts
+    Empty,
+    /// There is no room left in the queue
+    QueueFull,
+    /// The note starts before the last queued note ends
+    OutOfOrder,
+}
+
 
 #[derive(Copy, Clone)]
 #[repr(C)]
@@ -179,3 +247,11 @@ pub union Sample {
     pub bytes: [u8; 2],
     pub word: i16,
 }
+
+impl Sample {
+    #[inline]
+    pub fn to_i16(self) -> i16 {
+        // SAFETY: Both fields are plain bytes, so either is always valid
+        unsafe { self.word }
+    }
+}
@@ -0,0 +1,534 @@
+//! Playing minijam from a MIDI 1.0 byte stream, such as a keyboard connected
+//! over a UART.
+//!
+//! A [`Parser`] turns bytes into [`Message`]s, one byte at a time. It
+//! handles running status, realtime messages arriving in the middle of
+//! other messages, and skips over SysEx.
+//!
+//! A [`MidiPlayer`] plays those messages on a set of tracks. Each track
+//! plays one note at a time, so the tracks are shared out between the
+//! notes being held, stealing the oldest one when they are all busy.
+//! The player only ever queues notes that start "now", so the tracks should
+//! not be used for anything else at the same time.
+//!
+//! | Message                 | Action                                       |
+//! |-------------------------|----------------------------------------------|
+//! | Note on/off             | Start/release a note on a free track         |
+//! | Program change          | `0` sine, `1` square, `2` saw (others ignored) |
+//! | Pitch bend              | Bend every sounding note on the channel      |
+//! | CC 7 (volume)           | Set the gain of the channel                  |
+//! | CC 100/101 and 6 (RPN 0)| Set the pitch bend range, in semitones       |
+//! | CC 120/123              | Release every note on the channel            |
+//! | CC 121                  | Reset bend, volume and bend range            |
+
+use crate::{tones::ToneKind, Track};
+
+/// A MIDI channel, `0..=15`
+pub type MidiChannel = u8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Message {
+    NoteOff { channel: MidiChannel, key: u8, velocity: u8 },
+    /// A velocity of zero is a note off, which is left to the receiver
+    NoteOn { channel: MidiChannel, key: u8, velocity: u8 },
+    KeyPressure { channel: MidiChannel, key: u8, pressure: u8 },
+    ControlChange { channel: MidiChannel, controller: u8, value: u8 },
+    ProgramChange { channel: MidiChannel, program: u8 },
+    ChannelPressure { channel: MidiChannel, pressure: u8 },
+    /// From `-8192` (all the way down) to `8191` (all the way up)
+    PitchBend { channel: MidiChannel, bend: i16 },
+    /// Where to continue from, in MIDI beats (16th notes) from the start
+    SongPosition(u16),
+    /// 24 of these are sent every quarter note
+    TimingClock,
+    Start,
+    Continue,
+    Stop,
+    ActiveSensing,
+    SystemReset,
+}
+
+/// A streaming MIDI byte parser.
+///
+/// Other system common messages (song select, tune request and MTC quarter
+/// frames) are parsed, but not returned.
+#[derive(Debug, Default)]
+pub struct Parser {
+    // The status byte of the message being received, if any
+    status: Option<u8>,
+    data: [u8; 2],
+    len: usize,
+    in_sysex: bool,
+}
+
+impl Parser {
+    pub const fn new() -> Self {
+        Self {
+            status: None,
+            data: [0; 2],
+            len: 0,
+            in_sysex: false,
+        }
+    }
+
+    /// Feed in a byte, returning a message if it completed one
+    pub fn push(&mut self, byte: u8) -> Option<Message> {
+        if byte >= 0xF8 {
+            // Realtime messages can arrive at any time, even in the middle
+            // of another message, and don't affect it
+            return match byte {
+                0xF8 => Some(Message::TimingClock),
+                0xFA => Some(Message::Start),
+                0xFB => Some(Message::Continue),
+                0xFC => Some(Message::Stop),
+                0xFE => Some(Message::ActiveSensing),
+                0xFF => Some(Message::SystemReset),
+                _ => None,
+            };
+        }
+
+        if byte & 0x80 != 0 {
+            // Any status byte ends a SysEx, and running status
+            self.in_sysex = byte == 0xF0;
+            self.status = match byte {
+                // SysEx, End of SysEx, and tune request have no data
+                0xF0 | 0xF7 | 0xF6 => None,
+                _ => Some(byte),
+            };
+            self.len = 0;
+            return None;
+        }
+
+        if self.in_sysex {
+            return None;
+        }
+        let status = self.status?;
+
+        self.data[self.len] = byte;
+        self.len += 1;
+        if self.len < data_len(status) {
+            return None;
+        }
+        self.len = 0;
+        if status >= 0xF0 {
+            // No running status for system common messages
+            self.status = None;
+        }
+
+        let channel = status & 0x0F;
+        let [d0, d1] = self.data;
+        if status == 0xF2 {
+            return Some(Message::SongPosition(((d1 as u16) << 7) | (d0 as u16)));
+        }
+        match status & 0xF0 {
+            0x80 => Some(Message::NoteOff { channel, key: d0, velocity: d1 }),
+            0x90 => Some(Message::NoteOn { channel, key: d0, velocity: d1 }),
+            0xA0 => Some(Message::KeyPressure { channel, key: d0, pressure: d1 }),
+            0xB0 => Some(Message::ControlChange { channel, controller: d0, value: d1 }),
+            0xC0 => Some(Message::ProgramChange { channel, program: d0 }),
+            0xD0 => Some(Message::ChannelPressure { channel, pressure: d0 }),
+            0xE0 => {
+                let bend = (((d1 as i16) << 7) | (d0 as i16)) - 0x2000;
+                Some(Message::PitchBend { channel, bend })
+            }
+            _ => None,
+        }
+    }
+}
+
+// How many data bytes follow a status byte
+fn data_len(status: u8) -> usize {
+    match status {
+        0xC0..=0xDF | 0xF1 | 0xF3 => 1,
+        _ => 2,
+    }
+}
+
+/// The frequency of a MIDI key, bent by the given number of semitones
+pub fn key_freq(key: u8, bend_semitones: f32) -> f32 {
+    let semis = (key as f32 - 69.0) + bend_semitones;
+    440.0 * libm::exp2f(semis / 12.0)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ChannelState {
+    kind: ToneKind,
+    bend: i16,
+    /// In semitones
+    bend_range: u8,
+    volume: u8,
+    // The registered parameter selected with CC 101 and 100
+    rpn: (u8, u8),
+}
+
+impl ChannelState {
+    const DEFAULT: Self = Self {
+        kind: ToneKind::Square,
+        bend: 0,
+        bend_range: 2,
+        volume: 100,
+        rpn: (0x7F, 0x7F),
+    };
+
+    fn bend_semitones(&self) -> f32 {
+        (self.bend as f32 / 8192.0) * self.bend_range as f32
+    }
+
+    fn gain(&self, velocity: u8) -> f32 {
+        (self.volume as f32 / 127.0) * (velocity as f32 / 127.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Voice {
+    // The channel and key being held, if any
+    held: Option<(MidiChannel, u8)>,
+    velocity: u8,
+    freq: f32,
+    // When the voice was last started, for choosing which to reuse
+    age: u32,
+}
+
+/// Plays incoming MIDI messages on a set of tracks
+pub struct MidiPlayer<const TRACKS: usize> {
+    parser: Parser,
+    channels: [ChannelState; 16],
+    voices: [Voice; TRACKS],
+    listen: Option<MidiChannel>,
+    started: u32,
+}
+
+impl<const TRACKS: usize> Default for MidiPlayer<TRACKS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const TRACKS: usize> MidiPlayer<TRACKS> {
+    /// A player listening to every channel
+    pub const fn new() -> Self {
+        Self {
+            parser: Parser::new(),
+            channels: [ChannelState::DEFAULT; 16],
+            voices: [Voice {
+                held: None,
+                velocity: 0,
+                freq: 0.0,
+                age: 0,
+            }; TRACKS],
+            listen: None,
+            started: 0,
+        }
+    }
+
+    /// Only play one channel, or every channel if `None`
+    pub fn set_listen(&mut self, channel: Option<MidiChannel>) {
+        self.listen = channel;
+    }
+
+    /// Feed in bytes, playing any messages they complete
+    pub fn feed<const DEPTH: usize>(&mut self, bytes: &[u8], tracks: &mut [Track<DEPTH>; TRACKS]) {
+        for byte in bytes {
+            self.push(*byte, tracks);
+        }
+    }
+
+    /// Feed in a byte, playing the message it completes (if any).
+    ///
+    /// The message is also returned, so realtime messages like
+    /// `TimingClock` can be handled elsewhere.
+    pub fn push<const DEPTH: usize>(&mut self, byte: u8, tracks: &mut [Track<DEPTH>; TRACKS]) -> Option<Message> {
+        let msg = self.parser.push(byte)?;
+        self.handle(msg, tracks);
+        Some(msg)
+    }
+
+    /// Play a message
+    pub fn handle<const DEPTH: usize>(&mut self, msg: Message, tracks: &mut [Track<DEPTH>; TRACKS]) {
+        match msg {
+            Message::NoteOn { channel, key, velocity } if velocity != 0 && self.listening(channel) => {
+                self.note_on(channel, key, velocity, tracks);
+            }
+            Message::NoteOn { channel, key, velocity: 0 } | Message::NoteOff { channel, key, .. } => {
+                self.release(tracks, |held| held == (channel, key));
+            }
+            Message::ProgramChange { channel, program } => {
+                let kind = match program {
+                    0 => ToneKind::Sine,
+                    1 => ToneKind::Square,
+                    2 => ToneKind::Saw,
+                    _ => return,
+                };
+                self.channels[channel as usize].kind = kind;
+            }
+            Message::PitchBend { channel, bend } => {
+                self.channels[channel as usize].bend = bend;
+                self.retune(channel, tracks);
+            }
+            Message::ControlChange { channel, controller, value } => {
+                self.control_change(channel, controller, value, tracks);
+            }
+            Message::SystemReset => {
+                self.release(tracks, |_| true);
+                self.channels = [ChannelState::DEFAULT; 16];
+            }
+            _ => {}
+        }
+    }
+
+    fn listening(&self, channel: MidiChannel) -> bool {
+        self.listen.map(|ch| ch == channel).unwrap_or(true)
+    }
+
+    fn note_on<const DEPTH: usize>(
+        &mut self,
+        channel: MidiChannel,
+        key: u8,
+        velocity: u8,
+        tracks: &mut [Track<DEPTH>; TRACKS],
+    ) {
+        // A free voice if there is one, otherwise the oldest. Prefer one
+        // that last played this key, so repeated notes stay put.
+        let Some(idx) = (0..TRACKS).min_by_key(|i| {
+            let voice = &self.voices[*i];
+            (voice.held.is_some(), voice.held.map(|h| h.1) != Some(key), voice.age)
+        }) else {
+            return;
+        };
+
+        let state = &self.channels[channel as usize];
+        let freq = key_freq(key, state.bend_semitones());
+        let track = &mut tracks[idx];
+        stop(track);
+        track.channel.set_gain(state.gain(velocity));
+        let now = track.cur_samp;
+        if track.add_note_freq(state.kind, freq, now, u32::MAX).is_err() {
+            return;
+        }
+
+        self.started = self.started.wrapping_add(1);
+        self.voices[idx] = Voice {
+            held: Some((channel, key)),
+            velocity,
+            freq,
+            age: self.started,
+        };
+    }
+
+    fn release<const DEPTH: usize, F: Fn((MidiChannel, u8)) -> bool>(
+        &mut self,
+        tracks: &mut [Track<DEPTH>; TRACKS],
+        matches: F,
+    ) {
+        for (voice, track) in self.voices.iter_mut().zip(tracks.iter_mut()) {
+            if voice.held.map(&matches).unwrap_or(false) {
+                voice.held = None;
+                let now = track.cur_samp;
+                if let Some(note) = track.current.as_mut() {
+                    note.samp_end = note.samp_end.min(now);
+                }
+                // Anything that hasn't started yet never will
+                track.note_q.clear();
+            }
+        }
+    }
+
+    fn retune<const DEPTH: usize>(&mut self, channel: MidiChannel, tracks: &mut [Track<DEPTH>; TRACKS]) {
+        let bend = self.channels[channel as usize].bend_semitones();
+        for (voice, track) in self.voices.iter_mut().zip(tracks.iter_mut()) {
+            let Some((ch, key)) = voice.held else {
+                continue;
+            };
+            if ch != channel {
+                continue;
+            }
+            voice.freq = key_freq(key, bend);
+            let sample_rate = track.sample_rate;
+            let current = track.current.iter_mut();
+            for note in current.chain(track.note_q.iter_mut()) {
+                note.wave.set_freq(voice.freq, sample_rate);
+            }
+        }
+    }
+
+    fn control_change<const DEPTH: usize>(
+        &mut self,
+        channel: MidiChannel,
+        controller: u8,
+        value: u8,
+        tracks: &mut [Track<DEPTH>; TRACKS],
+    ) {
+        let state = &mut self.channels[channel as usize];
+        match controller {
+            7 => {
+                state.volume = value;
+                let state = *state;
+                for (voice, track) in self.voices.iter().zip(tracks.iter_mut()) {
+                    if voice.held.map(|h| h.0) == Some(channel) {
+                        track.channel.set_gain(state.gain(voice.velocity));
+                    }
+                }
+            }
+            101 => state.rpn.0 = value,
+            100 => state.rpn.1 = value,
+            6 if state.rpn == (0, 0) => {
+                state.bend_range = value;
+                self.retune(channel, tracks);
+            }
+            120 | 123 => self.release(tracks, |held| held.0 == channel),
+            121 => {
+                *state = ChannelState {
+                    kind: state.kind,
+                    ..ChannelState::DEFAULT
+                };
+                self.retune(channel, tracks);
+            }
+            _ => {}
+        }
+    }
+}
+
+// Cut off whatever the track was playing
+fn stop<const DEPTH: usize>(track: &mut Track<DEPTH>) {
+    track.current = None;
+    track.note_q.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        tones::{Mix, Operator, OperatorKind},
+        Sample, StereoSample,
+    };
+
+    fn parse(bytes: &[u8]) -> heapless::Vec<Message, 16> {
+        let mut parser = Parser::new();
+        bytes.iter().filter_map(|b| parser.push(*b)).collect()
+    }
+
+    #[test]
+    fn running_status() {
+        let msgs = parse(&[0x93, 60, 100, 62, 90, 60, 0, 0xC1, 5, 7, 0xE0, 0x00, 0x40, 0x7F, 0x7F]);
+        assert_eq!(
+            msgs.as_slice(),
+            &[
+                Message::NoteOn { channel: 3, key: 60, velocity: 100 },
+                Message::NoteOn { channel: 3, key: 62, velocity: 90 },
+                Message::NoteOn { channel: 3, key: 60, velocity: 0 },
+                Message::ProgramChange { channel: 1, program: 5 },
+                Message::ProgramChange { channel: 1, program: 7 },
+                Message::PitchBend { channel: 0, bend: 0 },
+                Message::PitchBend { channel: 0, bend: 8191 },
+            ]
+        );
+    }
+
+    #[test]
+    fn realtime_and_sysex() {
+        let msgs = parse(&[
+            // Clock in the middle of a note on
+            0x90, 0xF8, 60, 0xFA, 100,
+            // A SysEx with a clock inside, which doesn't end it
+            0xF0, 0x7E, 0xF8, 0x7F, 0x09, 0x01, 0xF7,
+            // Running status is cancelled by the SysEx
+            61, 100,
+            // Song position, then undefined bytes which are skipped
+            0xF2, 0x10, 0x20, 0xF9, 0xFD,
+            0xB2, 7, 64, 0xFC,
+        ]);
+        assert_eq!(
+            msgs.as_slice(),
+            &[
+                Message::TimingClock,
+                Message::Start,
+                Message::NoteOn { channel: 0, key: 60, velocity: 100 },
+                Message::TimingClock,
+                Message::SongPosition(0x1010),
+                Message::ControlChange { channel: 2, controller: 7, value: 64 },
+                Message::Stop,
+            ]
+        );
+    }
+
+    #[test]
+    fn key_frequencies() {
+        assert!((key_freq(69, 0.0) - 440.0).abs() < 0.01);
+        assert!((key_freq(60, 0.0) - 261.63).abs() < 0.01);
+        assert!((key_freq(57, 12.0) - 440.0).abs() < 0.01);
+    }
+
+    fn silence() -> [StereoSample; 64] {
+        let empty = Sample { word: 0 };
+        [StereoSample { left: empty, right: empty }; 64]
+    }
+
+    #[test]
+    fn play_notes() {
+        let mut tracks: [Track<4>; 2] = [Track::new(8000), Track::new(8000)];
+        let mut player: MidiPlayer<2> = MidiPlayer::new();
+
+        player.feed(&[0x90, 60, 127, 64, 127], &mut tracks);
+        assert_eq!(player.voices[0].held, Some((0, 60)));
+        assert_eq!(player.voices[1].held, Some((0, 64)));
+        assert!(tracks.iter().all(|t| t.note_q.len() == 1));
+
+        // Render a bit, so the notes are sounding
+        let mut op = Operator::new(OperatorKind::None);
+        for track in tracks.iter_mut() {
+            for _ in 0..2 {
+                track.fill_stereo_samples(&mut silence(), Mix::Div4, &mut op);
+            }
+            assert!(track.current.is_some());
+        }
+
+        // A third note steals the oldest voice
+        player.feed(&[67, 127], &mut tracks);
+        assert_eq!(player.voices[0].held, Some((0, 67)));
+        assert!(tracks[0].current.is_none());
+
+        // Release the second note, with running status and zero velocity
+        player.feed(&[64, 0], &mut tracks);
+        assert_eq!(player.voices[1].held, None);
+        assert_eq!(tracks[1].current.as_ref().unwrap().samp_end, 128);
+        tracks[1].fill_stereo_samples(&mut silence(), Mix::Div4, &mut op);
+        tracks[1].fill_stereo_samples(&mut silence(), Mix::Div4, &mut op);
+        assert!(tracks[1].is_done());
+
+        // Other channels are ignored when listening to one
+        player.set_listen(Some(0));
+        player.feed(&[0x91, 50, 100], &mut tracks);
+        assert_eq!(player.voices[1].held, None);
+    }
+
+    #[test]
+    fn bend_and_controllers() {
+        let mut tracks: [Track<4>; 1] = [Track::new(8000)];
+        let mut player: MidiPlayer<1> = MidiPlayer::new();
+
+        // Volume, then a note with half velocity
+        player.feed(&[0xB0, 7, 127, 0x90, 69, 64], &mut tracks);
+        assert!((tracks[0].channel.gain() - (64.0 / 127.0)).abs() < 0.001);
+
+        // Bend all the way up, with the default range of two semitones
+        player.feed(&[0xE0, 0x7F, 0x7F], &mut tracks);
+        assert!((player.voices[0].freq - key_freq(71, 0.0)).abs() < 0.1);
+
+        // Set the range to 12 semitones with RPN 0
+        player.feed(&[0xB0, 101, 0, 100, 0, 6, 12, 0xE0, 0x00, 0x00], &mut tracks);
+        assert!((player.voices[0].freq - 220.0).abs() < 0.01);
+
+        // Reset controllers
+        player.feed(&[0xB0, 121, 0], &mut tracks);
+        assert!((player.voices[0].freq - 440.0).abs() < 0.01);
+
+        // Program change picks the tone for the next note
+        player.feed(&[0xC0, 2, 0x90, 60, 100], &mut tracks);
+        assert_eq!(player.channels[0].kind, ToneKind::Saw);
+
+        // All notes off
+        player.feed(&[0xB0, 123, 0], &mut tracks);
+        assert_eq!(player.voices[0].held, None);
+        assert!(tracks[0].is_done());
+    }
+}
@@ -3,6 +3,7 @@ use core::ops::Add;
 pub const PITCHES_PER_OCTAVE: u32 = 12;
 
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Eq, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Pitch {
     C,
     CSharp,
@@ -122,6 +123,7 @@ impl Note {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Semitones(pub u32);
 
 #[cfg(test)]
@@ -0,0 +1,393 @@
+//! Locking to (and driving) other gear with MIDI clock.
+//!
+//! MIDI clock runs at 24 pulses per quarter note, so each clock is
+//! [`TICKS_PER_CLOCK`] of our [`PPQN`] ticks. Song position pointers count
+//! MIDI beats, which are 16th notes (six clocks).
+//!
+//! A [`ClockFollower`] is fed the incoming transport messages, along with
+//! the sample position each one arrived at. It measures the tempo from the
+//! time between clocks, and implements [`Timebase`], so notes can be
+//! scheduled on the follower just like on a [`Clock`](crate::clock::Clock).
+//!
+//! A [`ClockGenerator`] goes the other way, working out when to send each
+//! clock for any `Timebase`, such as a [`TempoMap`](crate::tempo::TempoMap).
+
+use crate::{
+    clock::{Timebase, PPQN},
+    midi::Message,
+};
+
+/// Ticks per MIDI clock
+pub const TICKS_PER_CLOCK: u32 = PPQN / 24;
+
+/// Ticks per MIDI beat, as used by song position pointers
+pub const TICKS_PER_MIDI_BEAT: u32 = TICKS_PER_CLOCK * 6;
+
+// How many clock intervals to average the tempo over (one quarter note)
+const AVERAGE: usize = 24;
+
+pub const START: u8 = 0xFA;
+pub const CONTINUE: u8 = 0xFB;
+pub const STOP: u8 = 0xFC;
+pub const TIMING_CLOCK: u8 = 0xF8;
+
+/// A change to the transport, from a [`ClockFollower`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncEvent {
+    /// Playing from the start
+    Start,
+    /// Playing from the current position
+    Continue,
+    Stop,
+    /// Moved to a new position (in ticks) while stopped
+    Locate(u32),
+}
+
+pub struct ClockFollower {
+    sample_rate: u32,
+    playing: bool,
+    // The tick the next clock lands on, when playing
+    next_tick: u32,
+    // The tick and sample of the last clock while playing
+    anchor: (u32, u32),
+    last_clock: Option<u32>,
+    // Clocks after the first that arrived at the same sample as it
+    stacked: u32,
+    intervals: [u32; AVERAGE],
+    count: usize,
+    idx: usize,
+    // Samples per clock, until we've heard two clocks
+    default_interval: u32,
+}
+
+impl ClockFollower {
+    /// Create a follower that assumes `bpm` until it has measured the
+    /// incoming clock.
+    ///
+    /// Returns `None` if the sample rate or bpm are zero.
+    pub fn new(sample_rate: u32, bpm: u32) -> Option<Self> {
+        if sample_rate == 0 || bpm == 0 {
+            return None;
+        }
+        Some(Self {
+            sample_rate,
+            playing: false,
+            next_tick: 0,
+            anchor: (0, 0),
+            last_clock: None,
+            stacked: 0,
+            intervals: [0; AVERAGE],
+            count: 0,
+            idx: 0,
+            default_interval: ((sample_rate * 60) / (bpm * 24)).max(1),
+        })
+    }
+
+    /// Handle a message that arrived at the given sample position. Messages
+    /// other than clock, start, stop, continue and song position are ignored.
+    pub fn handle(&mut self, msg: &Message, sample: u32) -> Option<SyncEvent> {
+        match *msg {
+            Message::TimingClock => {
+                match self.last_clock {
+                    // Clocks read from a buffer once per block all land on
+                    // the same sample, so share out the time between them
+                    // once the next one arrives
+                    Some(last) if last == sample => self.stacked += 1,
+                    Some(last) => {
+                        let clocks = self.stacked + 1;
+                        let interval = (sample.wrapping_sub(last) / clocks).max(1);
+                        for _ in 0..clocks.min(AVERAGE as u32) {
+                            self.intervals[self.idx] = interval;
+                            self.idx = (self.idx + 1) % AVERAGE;
+                        }
+                        self.count = (self.count + clocks as usize).min(AVERAGE);
+                        self.stacked = 0;
+                        self.last_clock = Some(sample);
+                    }
+                    None => self.last_clock = Some(sample),
+                }
+                if self.playing {
+                    self.anchor = (self.next_tick, sample);
+                    self.next_tick += TICKS_PER_CLOCK;
+                }
+                None
+            }
+            Message::Start => {
+                self.next_tick = 0;
+                self.play(sample);
+                Some(SyncEvent::Start)
+            }
+            Message::Continue => {
+                self.play(sample);
+                Some(SyncEvent::Continue)
+            }
+            Message::Stop => {
+                self.playing = false;
+                Some(SyncEvent::Stop)
+            }
+            Message::SongPosition(beats) if !self.playing => {
+                self.next_tick = u32::from(beats) * TICKS_PER_MIDI_BEAT;
+                Some(SyncEvent::Locate(self.next_tick))
+            }
+            _ => None,
+        }
+    }
+
+    // The first clock after a start or continue is the current position
+    fn play(&mut self, sample: u32) {
+        self.playing = true;
+        let interval = self.interval_sum() / (self.interval_count() as u64);
+        self.anchor = (self.next_tick, sample.wrapping_add(interval as u32));
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    /// The measured tempo, once at least two clocks have arrived
+    pub fn bpm(&self) -> Option<f32> {
+        if self.count == 0 {
+            return None;
+        }
+        let per_clock = self.interval_sum() as f32 / self.count as f32;
+        Some((self.sample_rate as f32 * 60.0) / (per_clock * 24.0))
+    }
+
+    /// The position at the given sample, in ticks. This never runs past the
+    /// next clock, so it holds still if the clock stops arriving.
+    pub fn tick_at(&self, sample: u32) -> u32 {
+        if !self.playing {
+            return self.next_tick;
+        }
+        self.sample_to_tick(sample).min(self.next_tick.saturating_sub(1).max(self.anchor.0))
+    }
+
+    fn interval_sum(&self) -> u64 {
+        match self.count {
+            0 => u64::from(self.default_interval),
+            n => self.intervals[..n].iter().map(|i| u64::from(*i)).sum::<u64>().max(1),
+        }
+    }
+
+    fn interval_count(&self) -> usize {
+        self.count.max(1)
+    }
+}
+
+impl Timebase for ClockFollower {
+    /// Extrapolated from the last clock, at the measured tempo
+    fn tick_to_sample(&self, tick: u32) -> u32 {
+        let (a_tick, a_samp) = self.anchor;
+        let den = (self.interval_count() as u64) * u64::from(TICKS_PER_CLOCK);
+        let sum = self.interval_sum();
+        if tick >= a_tick {
+            a_samp.wrapping_add(((u64::from(tick - a_tick) * sum) / den) as u32)
+        } else {
+            a_samp.wrapping_sub((u64::from(a_tick - tick) * sum).div_ceil(den) as u32)
+        }
+    }
+
+    fn sample_to_tick(&self, sample: u32) -> u32 {
+        let (a_tick, a_samp) = self.anchor;
+        let num = (self.interval_count() as u64) * u64::from(TICKS_PER_CLOCK);
+        let sum = self.interval_sum();
+        // Treat anything within half the range as being behind the anchor
+        let delta = sample.wrapping_sub(a_samp) as i32;
+        if delta >= 0 {
+            a_tick.saturating_add(((delta as u64 * num) / sum) as u32)
+        } else {
+            a_tick.saturating_sub((u64::from(delta.unsigned_abs()) * num).div_ceil(sum) as u32)
+        }
+    }
+}
+
+/// Works out when to send MIDI clock, following a timebase.
+///
+/// Clocks are only sent while playing.
+#[derive(Debug, Default)]
+pub struct ClockGenerator {
+    playing: bool,
+    next_tick: u32,
+}
+
+impl ClockGenerator {
+    pub const fn new() -> Self {
+        Self {
+            playing: false,
+            next_tick: 0,
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    /// Play from the start. Returns the byte to send.
+    pub fn start(&mut self) -> u8 {
+        self.playing = true;
+        self.next_tick = 0;
+        START
+    }
+
+    /// Play from the current position. Returns the byte to send.
+    pub fn resume(&mut self) -> u8 {
+        self.playing = true;
+        CONTINUE
+    }
+
+    /// Returns the byte to send.
+    pub fn stop(&mut self) -> u8 {
+        self.playing = false;
+        STOP
+    }
+
+    /// Move to a tick, rounded down to a MIDI beat (a 16th note), while
+    /// stopped. Returns the song position pointer to send.
+    pub fn locate(&mut self, tick: u32) -> Option<[u8; 3]> {
+        if self.playing {
+            return None;
+        }
+        let beats = (tick / TICKS_PER_MIDI_BEAT).min(0x3FFF);
+        self.next_tick = beats * TICKS_PER_MIDI_BEAT;
+        Some([0xF2, (beats & 0x7F) as u8, (beats >> 7) as u8])
+    }
+
+    /// The sample position of the next clock to send, if it is before
+    /// `before`. Call this until it returns `None` each block, sending
+    /// a `TIMING_CLOCK` byte for each.
+    pub fn next_clock<T: Timebase>(&mut self, timebase: &T, before: u32) -> Option<u32> {
+        if !self.playing {
+            return None;
+        }
+        let at = timebase.tick_to_sample(self.next_tick);
+        if at >= before {
+            return None;
+        }
+        self.next_tick += TICKS_PER_CLOCK;
+        Some(at)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::Clock, tempo::TempoMap};
+
+    #[test]
+    fn follow() {
+        // 120bpm at 48kHz is 1000 samples per clock
+        let mut sync = ClockFollower::new(48000, 100).unwrap();
+        assert_eq!(sync.bpm(), None);
+
+        for i in 0..10 {
+            assert_eq!(sync.handle(&Message::TimingClock, i * 1000), None);
+        }
+        assert_eq!(sync.bpm(), Some(120.0));
+        assert_eq!(sync.tick_at(9500), 0);
+
+        // The first clock after start is tick 0
+        assert_eq!(sync.handle(&Message::Start, 9990), Some(SyncEvent::Start));
+        assert_eq!(sync.tick_to_sample(0), 10990);
+        sync.handle(&Message::TimingClock, 10000);
+        sync.handle(&Message::TimingClock, 11000);
+        assert_eq!(sync.tick_at(11000), TICKS_PER_CLOCK);
+        assert_eq!(sync.tick_at(11500), TICKS_PER_CLOCK + 4);
+        assert_eq!(sync.tick_to_sample(PPQN), 11000 + 23 * 1000);
+
+        // Don't run ahead of a missing clock
+        assert_eq!(sync.tick_at(20000), 2 * TICKS_PER_CLOCK - 1);
+
+        // Speed up to 800 samples per clock
+        for i in 1..=24 {
+            sync.handle(&Message::TimingClock, 11000 + i * 800);
+        }
+        assert_eq!(sync.bpm(), Some(150.0));
+        assert_eq!(sync.tick_at(11000 + 24 * 800), 25 * TICKS_PER_CLOCK);
+
+        // Stop, locate, and continue
+        assert_eq!(sync.handle(&Message::Stop, 40000), Some(SyncEvent::Stop));
+        assert!(!sync.is_playing());
+        let locate = sync.handle(&Message::SongPosition(8), 40100);
+        assert_eq!(locate, Some(SyncEvent::Locate(8 * 48)));
+        assert_eq!(sync.tick_at(45000), 8 * 48);
+        sync.handle(&Message::Continue, 41000);
+        sync.handle(&Message::TimingClock, 41800);
+        assert_eq!(sync.tick_at(41800), 8 * 48);
+
+        // Position can't be changed while playing
+        assert_eq!(sync.handle(&Message::SongPosition(0), 42000), None);
+    }
+
+    #[test]
+    fn clocks_in_one_block() {
+        // 120bpm at 48kHz, with the clocks read once every 4000 samples
+        let mut sync = ClockFollower::new(48000, 100).unwrap();
+        sync.handle(&Message::Start, 0);
+        for block in 0..20 {
+            for _ in 0..4 {
+                sync.handle(&Message::TimingClock, block * 4000);
+            }
+            assert!(sync.tick_at(block * 4000 + 100) <= (block + 1) * 4 * TICKS_PER_CLOCK);
+        }
+        assert_eq!(sync.bpm(), Some(120.0));
+        assert_eq!(sync.tick_at(19 * 4000), 79 * TICKS_PER_CLOCK);
+
+        // Before a second block, there's nothing to measure
+        let mut sync = ClockFollower::new(48000, 100).unwrap();
+        for _ in 0..4 {
+            sync.handle(&Message::TimingClock, 500);
+        }
+        assert_eq!(sync.bpm(), None);
+        sync.handle(&Message::Start, 500);
+        sync.handle(&Message::TimingClock, 500);
+        sync.handle(&Message::TimingClock, 500);
+        assert_eq!(sync.tick_at(500), TICKS_PER_CLOCK);
+    }
+
+    #[test]
+    fn generate() {
+        let clock = Clock::new(48000, 120).unwrap();
+        let mut gen = ClockGenerator::new();
+        assert_eq!(gen.next_clock(&clock, 48000), None);
+
+        assert_eq!(gen.start(), START);
+        let mut sent = 0;
+        let mut block = 0;
+        while block < 48000 {
+            block += 64;
+            while let Some(at) = gen.next_clock(&clock, block) {
+                assert_eq!(at, sent * 1000);
+                assert!(at >= block - 64);
+                sent += 1;
+            }
+        }
+        // One second at 120bpm is two quarter notes
+        assert_eq!(sent, 48);
+
+        assert_eq!(gen.locate(0), None);
+        assert_eq!(gen.stop(), STOP);
+        assert_eq!(gen.locate(PPQN * 4 + 5), Some([0xF2, 16, 0]));
+        assert_eq!(gen.locate(TICKS_PER_MIDI_BEAT * 200), Some([0xF2, 200 - 128, 1]));
+        assert_eq!(gen.resume(), CONTINUE);
+        assert_eq!(gen.next_clock(&clock, u32::MAX), Some(clock.tick_to_sample(200 * 48)));
+    }
+
+    #[test]
+    fn follow_generated() {
+        // A ramp from 90 to 150bpm, sent as clock and followed
+        let mut map: TempoMap<4> = TempoMap::new(44100, 90).unwrap();
+        map.ramp_to(PPQN * 8, 150).unwrap();
+        let mut gen = ClockGenerator::new();
+        let mut sync = ClockFollower::new(44100, 120).unwrap();
+
+        sync.handle(&Message::Start, 0);
+        gen.start();
+        while let Some(at) = gen.next_clock(&map, map.tick_to_sample(PPQN * 12)) {
+            sync.handle(&Message::TimingClock, at);
+        }
+        let bpm = sync.bpm().unwrap();
+        assert!((bpm - 150.0).abs() < 0.1, "{bpm}");
+        let end = map.tick_to_sample(PPQN * 12) - 1;
+        assert_eq!(sync.tick_at(end), PPQN * 12 - 1);
+    }
+}
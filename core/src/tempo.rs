@@ -0,0 +1,357 @@
+//! Tempo maps, for phrases that change speed part way through.
+//!
+//! A [`TempoMap`] is a list of [`TempoEvent`]s, sorted by tick. Each event
+//! either switches to a new tempo immediately ([`TempoKind::Step`]), or is
+//! the *end* of a linear accelerando/ritardando that starts at the previous
+//! event ([`TempoKind::Ramp`]).
+//!
+//! As with [`Clock`](crate::clock::Clock), every sample position is worked
+//! out from the start of the segment that contains it, so there is no
+//! rounding drift between notes. Ramps are integrated exactly: with the tempo
+//! changing linearly over a segment of `L` ticks from `b0` to `b1` bpm, the
+//! time taken to reach tick `x` is
+//!
+//! ```text
+//! (60 / ppqn) * (L / (b1 - b0)) * ln(bpm(x) / b0)
+//! ```
+
+use heapless::Vec;
+
+use crate::clock::{Timebase, PPQN};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TempoKind {
+    /// Jump to the new tempo at this tick.
+    Step,
+    /// Change linearly from the previous event's tempo, arriving at the
+    /// new tempo at this tick.
+    Ramp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TempoEvent {
+    pub tick: u32,
+    pub bpm: u32,
+    pub kind: TempoKind,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TempoError {
+    /// A bpm or sample rate of zero was given
+    InvalidTempo,
+    /// Events must be added in strictly increasing tick order
+    OutOfOrder,
+    /// There is no room left for more events
+    MapFull,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    event: TempoEvent,
+    // The (fractional) sample position this event lands on.
+    start: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct TempoMap<const N: usize> {
+    sample_rate: u32,
+    ppqn: u32,
+    entries: Vec<Entry, N>,
+}
+
+impl<const N: usize> TempoMap<N> {
+    /// Create a new tempo map starting at the given bpm, running at
+    /// [`PPQN`] ticks per quarter note.
+    pub fn new(sample_rate: u32, bpm: u32) -> Result<Self, TempoError> {
+        Self::with_ppqn(sample_rate, bpm, PPQN)
+    }
+
+    pub fn with_ppqn(sample_rate: u32, bpm: u32, ppqn: u32) -> Result<Self, TempoError> {
+        if sample_rate == 0 || bpm == 0 || ppqn == 0 {
+            return Err(TempoError::InvalidTempo);
+        }
+        let mut entries = Vec::new();
+        entries
+            .push(Entry {
+                event: TempoEvent {
+                    tick: 0,
+                    bpm,
+                    kind: TempoKind::Step,
+                },
+                start: 0.0,
+            })
+            .map_err(|_| TempoError::MapFull)?;
+        Ok(Self {
+            sample_rate,
+            ppqn,
+            entries,
+        })
+    }
+
+    /// Create a tempo map from a list of events.
+    ///
+    /// If the first event is not at tick zero, the map starts at that
+    /// event's tempo. If there are several at tick zero, as MIDI files can
+    /// have, the last one is used.
+    pub fn from_events(sample_rate: u32, events: &[TempoEvent]) -> Result<Self, TempoError> {
+        let first = events.first().ok_or(TempoError::InvalidTempo)?;
+        let start = events
+            .iter()
+            .take_while(|ev| ev.tick == 0)
+            .last()
+            .unwrap_or(first);
+        let mut map = Self::new(sample_rate, start.bpm)?;
+        for ev in events.iter().skip_while(|ev| ev.tick == 0) {
+            map.push(*ev)?;
+        }
+        Ok(map)
+    }
+
+    /// Jump to a new tempo at the given tick.
+    pub fn set_tempo(&mut self, tick: u32, bpm: u32) -> Result<(), TempoError> {
+        self.push(TempoEvent {
+            tick,
+            bpm,
+            kind: TempoKind::Step,
+        })
+    }
+
+    /// Ramp linearly from the current tempo, reaching `bpm` at `tick`.
+    pub fn ramp_to(&mut self, tick: u32, bpm: u32) -> Result<(), TempoError> {
+        self.push(TempoEvent {
+            tick,
+            bpm,
+            kind: TempoKind::Ramp,
+        })
+    }
+
+    pub fn push(&mut self, event: TempoEvent) -> Result<(), TempoError> {
+        if event.bpm == 0 {
+            return Err(TempoError::InvalidTempo);
+        }
+        if self.entries.is_full() {
+            return Err(TempoError::MapFull);
+        }
+
+        // NOTE: `new` always pushes an initial entry, so there is always a last one
+        let last = self.entries[self.entries.len() - 1];
+        if event.tick <= last.event.tick {
+            return Err(TempoError::OutOfOrder);
+        }
+
+        let start = last.start + self.offset(&last, Some(&event), event.tick);
+        self.entries
+            .push(Entry { event, start })
+            .map_err(|_| TempoError::MapFull)
+    }
+
+    pub fn events(&self) -> impl Iterator<Item = &TempoEvent> {
+        self.entries.iter().map(|e| &e.event)
+    }
+
+    #[inline]
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    #[inline]
+    pub fn ppqn(&self) -> u32 {
+        self.ppqn
+    }
+
+    /// The tempo at the given tick, in (possibly fractional) bpm.
+    pub fn bpm_at(&self, tick: u32) -> f32 {
+        let (cur, next) = self.segment(tick);
+        match next {
+            Some(next) if next.event.kind == TempoKind::Ramp => {
+                let b0 = cur.event.bpm as f64;
+                let b1 = next.event.bpm as f64;
+                let len = (next.event.tick - cur.event.tick) as f64;
+                let x = (tick - cur.event.tick) as f64;
+                (b0 + (b1 - b0) * (x / len)) as f32
+            }
+            _ => cur.event.bpm as f32,
+        }
+    }
+
+    /// The sample position of the given absolute tick, rounded down.
+    pub fn tick_to_sample(&self, tick: u32) -> u32 {
+        let (cur, next) = self.segment(tick);
+        let pos = cur.start + self.offset(cur, next.map(|n| &n.event), tick);
+        // NOTE: float-to-int casts saturate, rather than wrapping
+        libm::floor(pos) as u32
+    }
+
+    /// The last tick that starts at or before the given sample position.
+    pub fn sample_to_tick(&self, sample: u32) -> u32 {
+        // `tick_to_sample` never decreases, so just search for it
+        let mut lo = 0u32;
+        let mut hi = u32::MAX;
+        while lo < hi {
+            let mid = lo + ((hi - lo) / 2) + 1;
+            if self.tick_to_sample(mid) <= sample {
+                lo = mid;
+            } else {
+                hi = mid - 1;
+            }
+        }
+        lo
+    }
+
+    // Find the entry that contains the given tick, and the one after it (if any)
+    fn segment(&self, tick: u32) -> (&Entry, Option<&Entry>) {
+        let idx = self
+            .entries
+            .iter()
+            .rposition(|e| e.event.tick <= tick)
+            .unwrap_or(0);
+        (&self.entries[idx], self.entries.get(idx + 1))
+    }
+
+    // The number of samples from the start of `cur` until `tick`. `next` is
+    // the event that ends the segment, which decides whether it is a ramp.
+    fn offset(&self, cur: &Entry, next: Option<&TempoEvent>, tick: u32) -> f64 {
+        let b0 = cur.event.bpm;
+        let x = (tick - cur.event.tick) as u64;
+
+        match next {
+            Some(next) if next.kind == TempoKind::Ramp && next.bpm != b0 => {
+                let b0 = b0 as f64;
+                let b1 = next.bpm as f64;
+                let len = (next.tick - cur.event.tick) as f64;
+                let bx = b0 + (b1 - b0) * ((x as f64) / len);
+                let secs = (60.0 / (self.ppqn as f64)) * (len / (b1 - b0)) * libm::log(bx / b0);
+                secs * (self.sample_rate as f64)
+            }
+            _ => {
+                // Keep the integer part exact, so constant tempos agree
+                // exactly with `Clock`
+                let num = x * (self.sample_rate as u64) * 60;
+                let den = (b0 as u64) * (self.ppqn as u64);
+                ((num / den) as f64) + (((num % den) as f64) / (den as f64))
+            }
+        }
+    }
+}
+
+impl<const N: usize> Timebase for TempoMap<N> {
+    #[inline]
+    fn tick_to_sample(&self, tick: u32) -> u32 {
+        TempoMap::tick_to_sample(self, tick)
+    }
+
+    #[inline]
+    fn sample_to_tick(&self, sample: u32) -> u32 {
+        TempoMap::sample_to_tick(self, sample)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::Clock;
+
+    #[test]
+    fn constant_matches_clock() {
+        let clock = Clock::new(44100, 97).unwrap();
+        let map: TempoMap<4> = TempoMap::new(44100, 97).unwrap();
+        for tick in (0..(PPQN * 1024)).step_by(13) {
+            assert_eq!(clock.tick_to_sample(tick), map.tick_to_sample(tick));
+        }
+    }
+
+    #[test]
+    fn step_change() {
+        let mut map: TempoMap<4> = TempoMap::new(48000, 120).unwrap();
+        map.set_tempo(PPQN * 4, 60).unwrap();
+
+        // Four beats at 120bpm is two seconds...
+        assert_eq!(map.tick_to_sample(PPQN * 4), 96000);
+        // ...and then each beat takes a second
+        assert_eq!(map.tick_to_sample(PPQN * 5), 144000);
+        assert_eq!(map.sample_to_tick(144000), PPQN * 5);
+        assert_eq!(map.bpm_at(PPQN * 4 - 1), 120.0);
+        assert_eq!(map.bpm_at(PPQN * 4), 60.0);
+    }
+
+    #[test]
+    fn ramp() {
+        let mut map: TempoMap<4> = TempoMap::new(48000, 60).unwrap();
+        map.ramp_to(PPQN * 8, 180).unwrap();
+        map.set_tempo(PPQN * 9, 120).unwrap();
+
+        assert_eq!(map.bpm_at(PPQN * 4), 120.0);
+
+        // Integrate the ramp by hand, one tick at a time, using the tempo in
+        // the middle of each tick
+        let mut secs = 0.0f64;
+        for t in 0..(PPQN * 8) {
+            let bpm = 60.0 + 120.0 * ((t as f64 + 0.5) / (PPQN * 8) as f64);
+            secs += 60.0 / (bpm * PPQN as f64);
+        }
+        let exp = (secs * 48000.0) as i64;
+        let act = map.tick_to_sample(PPQN * 8) as i64;
+        assert!((exp - act).abs() <= 1, "{exp} != {act}");
+
+        // After the ramp, one beat at 180 bpm, then 120 bpm
+        let end = map.tick_to_sample(PPQN * 8);
+        assert_eq!(map.tick_to_sample(PPQN * 9) - end, 16000);
+        assert_eq!(map.tick_to_sample(PPQN * 10) - end, 16000 + 24000);
+    }
+
+    #[test]
+    fn monotonic_and_invertible() {
+        let mut map: TempoMap<8> = TempoMap::new(44100, 90).unwrap();
+        map.ramp_to(PPQN * 3, 150).unwrap();
+        map.ramp_to(PPQN * 7, 70).unwrap();
+        map.set_tempo(PPQN * 8, 200).unwrap();
+
+        let mut last = 0;
+        for tick in 0..(PPQN * 12) {
+            let samp = map.tick_to_sample(tick);
+            assert!(samp >= last);
+            assert_eq!(map.sample_to_tick(samp), tick);
+            last = samp;
+        }
+    }
+
+    #[test]
+    fn from_events() {
+        let ev = |tick, bpm| TempoEvent {
+            tick,
+            bpm,
+            kind: TempoKind::Step,
+        };
+
+        // The last of several events at tick zero wins
+        let map: TempoMap<4> = TempoMap::from_events(48000, &[ev(0, 120), ev(0, 140)]).unwrap();
+        assert_eq!(map.bpm_at(0), 140.0);
+        assert_eq!(map.events().count(), 1);
+
+        // A map that doesn't start at zero starts at its first tempo
+        let map: TempoMap<4> = TempoMap::from_events(48000, &[ev(PPQN, 60), ev(PPQN * 2, 90)]).unwrap();
+        assert_eq!(map.bpm_at(0), 60.0);
+        assert_eq!(map.bpm_at(PPQN * 2), 90.0);
+        assert_eq!(map.tick_to_sample(PPQN * 2), 96000);
+
+        assert!(matches!(
+            TempoMap::<4>::from_events(48000, &[]),
+            Err(TempoError::InvalidTempo)
+        ));
+        assert!(matches!(
+            TempoMap::<4>::from_events(48000, &[ev(0, 120), ev(PPQN, 100), ev(0, 90)]),
+            Err(TempoError::OutOfOrder)
+        ));
+    }
+
+    #[test]
+    fn errors() {
+        let mut map: TempoMap<2> = TempoMap::new(44100, 120).unwrap();
+        assert_eq!(map.set_tempo(0, 100), Err(TempoError::OutOfOrder));
+        assert_eq!(map.set_tempo(10, 0), Err(TempoError::InvalidTempo));
+        map.set_tempo(10, 100).unwrap();
+        assert_eq!(map.set_tempo(20, 100), Err(TempoError::MapFull));
+    }
+}
@@ -23,13 +23,15 @@ pub const SINE_TABLE: [i16; 256] = [
     -9512, -8739, -7962, -7179, -6393, -5602, -4808, -4011, -3212, -2410, -1608, -804,
 ];
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ToneKind {
     Sine,
     Square,
     Saw,
 }
 
+#[derive(Clone)]
 pub struct Tone {
     kind: ToneKind,
     cur_offset: i32,
@@ -44,34 +46,59 @@ pub enum Mix {
     Div8,
 }
 
+#[derive(Clone)]
 pub enum OperatorKind {
     AmplitudeLfo(Tone),
     FrequencyLfo(Tone),
     None,
 }
 
+#[derive(Clone)]
 pub struct Operator {
     pub kind: OperatorKind,
+    /// How strongly the operator affects the sound. `0` has no effect,
+    /// [`Operator::DEFAULT_DEPTH`] swings the volume between 50%-100%, and
+    /// `255` swings it (almost) all the way to silence.
+    pub depth: u8,
 }
 
-/// Shifts volume between 50%-100%
+/// Shifts volume between (100% - depth/2)..=100%
 #[inline]
-fn volume_shift(samp: i16, vol: i16) -> i16 {
+fn volume_shift(samp: i16, vol: i16, depth: u8) -> i16 {
     let samp = samp as i32;
     let vol = vol as i32; // i16::MIN..=i16::MAX
     let vol = vol.wrapping_add((u16::MAX / 2).into()); // 0..=(i16::MAX * 2)
-    let vol = (vol >> 7) + 512; // 512..=1024
+    let dip = 512 - (vol >> 7); // 0..=512
+    let vol = 1024 - ((dip * (depth as i32)) >> 7); // 512..=1024 at the default depth
     let samp = samp.wrapping_mul(vol);
     let samp = samp >> 10;
     samp as i16
 }
 
 impl Operator {
+    pub const DEFAULT_DEPTH: u8 = 128;
+
+    pub fn new(kind: OperatorKind) -> Self {
+        Self {
+            kind,
+            depth: Self::DEFAULT_DEPTH,
+        }
+    }
+
+    /// The oscillator driving this operator, if any
+    pub fn lfo_mut(&mut self) -> Option<&mut Tone> {
+        match &mut self.kind {
+            OperatorKind::AmplitudeLfo(op) => Some(op),
+            OperatorKind::FrequencyLfo(op) => Some(op),
+            OperatorKind::None => None,
+        }
+    }
+
     fn operate(&mut self, samp: i16) -> i16 {
         match &mut self.kind {
             OperatorKind::AmplitudeLfo(op) => {
                 let ops = op.next_sample();
-                volume_shift(samp, ops)
+                volume_shift(samp, ops, self.depth)
             },
             OperatorKind::FrequencyLfo(_op) => todo!(),
             OperatorKind::None => samp,
@@ -79,6 +106,98 @@ impl Operator {
     }
 }
 
+/// Per-track gain, panning and low-pass filtering, applied to every
+/// sample after the operator.
+///
+/// The defaults (full gain, centered, filter open) leave the sound
+/// untouched, and skip all of the extra processing.
+#[derive(Debug, Clone)]
+pub struct Channel {
+    // 0..=UNITY_GAIN
+    gain: u16,
+    // -127 (left)..=127 (right). Balance law: centered is full volume on both sides.
+    pan: i8,
+    // One-pole low-pass coefficient, `u16::MAX` means the filter is open
+    alpha: u16,
+    state: i32,
+}
+
+impl Default for Channel {
+    fn default() -> Self {
+        Self {
+            gain: Self::UNITY_GAIN,
+            pan: 0,
+            alpha: u16::MAX,
+            state: 0,
+        }
+    }
+}
+
+impl Channel {
+    pub const UNITY_GAIN: u16 = 256;
+
+    /// Set the gain, from `0.0` (silent) to `1.0` (unchanged).
+    pub fn set_gain(&mut self, gain: f32) {
+        let gain = gain.clamp(0.0, 1.0) * (Self::UNITY_GAIN as f32);
+        self.gain = gain as u16;
+    }
+
+    /// Set the pan, from `-1.0` (left) to `1.0` (right).
+    pub fn set_pan(&mut self, pan: f32) {
+        self.pan = (pan.clamp(-1.0, 1.0) * 127.0) as i8;
+    }
+
+    /// Set the low-pass filter cutoff frequency. Frequencies at or above
+    /// the nyquist frequency open the filter completely.
+    pub fn set_cutoff(&mut self, freq: f32, sample_rate: u32) {
+        let nyquist = (sample_rate / 2) as f32;
+        if freq >= nyquist {
+            self.alpha = u16::MAX;
+            return;
+        }
+        let freq = freq.max(0.0);
+        let rc = (2.0 * core::f32::consts::PI * freq) / (sample_rate as f32);
+        let alpha = 1.0 - libm::expf(-rc);
+        self.alpha = (alpha * (u16::MAX as f32)) as u16;
+    }
+
+    pub fn gain(&self) -> f32 {
+        (self.gain as f32) / (Self::UNITY_GAIN as f32)
+    }
+
+    pub fn pan(&self) -> f32 {
+        (self.pan as f32) / 127.0
+    }
+
+    /// Clear the filter history, without changing any settings
+    pub fn reset(&mut self) {
+        self.state = 0;
+    }
+
+    #[inline]
+    fn process(&mut self, samp: i16) -> (i16, i16) {
+        if self.alpha == u16::MAX && self.gain == Self::UNITY_GAIN && self.pan == 0 {
+            return (samp, samp);
+        }
+
+        let mut samp = samp as i32;
+
+        if self.alpha != u16::MAX {
+            let delta = (samp - self.state) as i64;
+            self.state += ((delta * (self.alpha as i64)) >> 16) as i32;
+            samp = self.state;
+        }
+
+        samp = (samp * (self.gain as i32)) >> 8;
+
+        let pan = self.pan as i32;
+        let left_gain = if pan > 0 { 256 - (pan * 2) } else { 256 };
+        let right_gain = if pan < 0 { 256 + (pan * 2) } else { 256 };
+
+        (((samp * left_gain) >> 8) as i16, ((samp * right_gain) >> 8) as i16)
+    }
+}
+
 // TODO: Add some kind of volume shift for higher frequencies?
 
 impl Mix {
@@ -95,38 +214,45 @@ impl Mix {
 
 impl Tone {
     pub fn new_sine(freq: f32, sample_rate: u32) -> Self {
-        let samp_per_cyc: f32 = (sample_rate as f32) / freq;
-        let fincr = (SINE_TABLE.len() as f32) / samp_per_cyc;
-        let incr = (((1 << 24) as f32) * fincr) as i32;
-
         Self {
             kind: ToneKind::Sine,
             cur_offset: 0,
-            incr,
+            incr: Self::incr_for(ToneKind::Sine, freq, sample_rate),
         }
     }
 
     pub fn new_square(freq: f32, sample_rate: u32) -> Self {
-        let samp_per_cyc: f32 = (sample_rate as f32) / freq;
-        let fincr = (u32::MAX as f32) / samp_per_cyc;
-        let incr = fincr as i32;
-
         Self {
             kind: ToneKind::Square,
             cur_offset: 0,
-            incr,
+            incr: Self::incr_for(ToneKind::Square, freq, sample_rate),
         }
     }
 
     pub fn new_saw(freq: f32, sample_rate: u32) -> Self {
-        let samp_per_cyc: f32 = (sample_rate as f32) / freq;
-        let fincr = (u32::MAX as f32) / samp_per_cyc;
-        let incr = fincr as i32;
-
         Self {
             kind: ToneKind::Saw,
             cur_offset: 0,
-            incr,
+            incr: Self::incr_for(ToneKind::Saw, freq, sample_rate),
+        }
+    }
+
+    /// Change the frequency, without resetting the phase of the wave
+    pub fn set_freq(&mut self, freq: f32, sample_rate: u32) {
+        self.incr = Self::incr_for(self.kind, freq, sample_rate);
+    }
+
+    fn incr_for(kind: ToneKind, freq: f32, sample_rate: u32) -> i32 {
+        let samp_per_cyc: f32 = (sample_rate as f32) / freq;
+        match kind {
+            ToneKind::Sine => {
+                let fincr = (SINE_TABLE.len() as f32) / samp_per_cyc;
+                (((1 << 24) as f32) * fincr) as i32
+            }
+            ToneKind::Square | ToneKind::Saw => {
+                let fincr = (u32::MAX as f32) / samp_per_cyc;
+                fincr as i32
+            }
         }
     }
 
@@ -146,7 +272,7 @@ impl Tone {
     }
 
     #[inline]
-    pub fn fill_first_stereo_samples(&mut self, samples: &mut [StereoSample], mix: Mix, operator: &mut Operator) {
+    pub fn fill_first_stereo_samples(&mut self, samples: &mut [StereoSample], mix: Mix, operator: &mut Operator, channel: &mut Channel) {
         let next_sample = self.next_sample_func();
         let shift = mix.to_shift();
 
@@ -161,10 +287,11 @@ impl Tone {
                 let rsamp = rsamp.wrapping_mul(ct); // multiply by 1..=32;
                 let rsamp = rsamp >> 5; // divide by 32
                 let samp = rsamp as i16;
+                let (left, right) = channel.process(samp);
 
                 unsafe {
-                    s.left.word = s.left.word.wrapping_add(samp);
-                    s.right.word = s.right.word.wrapping_add(samp);
+                    s.left.word = s.left.word.wrapping_add(left);
+                    s.right.word = s.right.word.wrapping_add(right);
                 }
             });
             ct += 1;
@@ -172,7 +299,7 @@ impl Tone {
     }
 
     #[inline]
-    pub fn fill_last_stereo_samples(&mut self, samples: &mut [StereoSample], mix: Mix, operator: &mut Operator) {
+    pub fn fill_last_stereo_samples(&mut self, samples: &mut [StereoSample], mix: Mix, operator: &mut Operator, channel: &mut Channel) {
         let next_sample = self.next_sample_func();
         let shift = mix.to_shift();
 
@@ -187,10 +314,11 @@ impl Tone {
                 let rsamp = rsamp.wrapping_mul(ct); // multiply by 1..=32;
                 let rsamp = rsamp >> 5; // divide by 32
                 let samp = rsamp as i16;
+                let (left, right) = channel.process(samp);
 
                 unsafe {
-                    s.left.word = s.left.word.wrapping_add(samp);
-                    s.right.word = s.right.word.wrapping_add(samp);
+                    s.left.word = s.left.word.wrapping_add(left);
+                    s.right.word = s.right.word.wrapping_add(right);
                 }
             });
             ct -= 1;
@@ -198,15 +326,16 @@ impl Tone {
     }
 
     #[inline]
-    pub fn fill_stereo_samples(&mut self, samples: &mut [StereoSample], mix: Mix, operator: &mut Operator) {
+    pub fn fill_stereo_samples(&mut self, samples: &mut [StereoSample], mix: Mix, operator: &mut Operator, channel: &mut Channel) {
         let next_sample = self.next_sample_func();
         let shift = mix.to_shift();
 
         samples.iter_mut().for_each(|s| {
             let samp = operator.operate(next_sample(self)) >> shift;
+            let (left, right) = channel.process(samp);
             unsafe {
-                s.left.word = s.left.word.wrapping_add(samp);
-                s.right.word = s.right.word.wrapping_add(samp);
+                s.left.word = s.left.word.wrapping_add(left);
+                s.right.word = s.right.word.wrapping_add(right);
             }
         });
     }
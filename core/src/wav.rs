@@ -0,0 +1,325 @@
+//! Streaming 16-bit PCM WAV output.
+//!
+//! A [`WavWriter`] writes the header up front, then takes rendered blocks
+//! as they come, so a render of any length only needs one block of memory.
+//! The RIFF and data chunk sizes aren't known until the end, so they are
+//! patched in by [`WavWriter::finish`].
+//!
+//! Output goes to a [`WavSink`]. [`SliceSink`] writes into a byte buffer,
+//! and with the `std` feature, [`IoSink`] writes to anything that is
+//! `std::io::Write + std::io::Seek`, like a `File`.
+
+use crate::StereoSample;
+
+// Bytes from the start of the file to the two size fields
+const RIFF_SIZE_POS: u32 = 4;
+const DATA_SIZE_POS: u32 = 40;
+
+/// Size of the header, before the first sample
+pub const HEADER_LEN: u32 = 44;
+
+/// Somewhere to write a WAV file to.
+pub trait WavSink {
+    type Error;
+
+    /// Write bytes after everything written so far
+    fn write_all(&mut self, bytes: &[u8]) -> Result<(), Self::Error>;
+
+    /// Overwrite bytes that were already written, starting `pos` bytes
+    /// from the start of the file. Later writes still go on the end.
+    fn patch(&mut self, pos: u32, bytes: &[u8]) -> Result<(), Self::Error>;
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum WavError<E> {
+    Sink(E),
+    /// The data chunk would be bigger than a WAV file can describe (4GiB)
+    TooLong,
+    /// Stereo samples were written to a file that isn't stereo
+    WrongChannels,
+    /// Zero channels or a zero sample rate, or too many of either
+    BadFormat,
+}
+
+impl<E> From<E> for WavError<E> {
+    fn from(e: E) -> Self {
+        WavError::Sink(e)
+    }
+}
+
+pub struct WavWriter<S: WavSink> {
+    sink: S,
+    channels: u16,
+    data_len: u32,
+}
+
+impl<S: WavSink> WavWriter<S> {
+    /// Start a 16-bit file, writing the header.
+    pub fn new(mut sink: S, sample_rate: u32, channels: u16) -> Result<Self, WavError<S::Error>> {
+        if sample_rate == 0 || channels == 0 {
+            return Err(WavError::BadFormat);
+        }
+        let block_align = channels.checked_mul(2).ok_or(WavError::BadFormat)?;
+        let byte_rate = sample_rate
+            .checked_mul(u32::from(block_align))
+            .ok_or(WavError::BadFormat)?;
+
+        let mut header = [0u8; HEADER_LEN as usize];
+        header[0..4].copy_from_slice(b"RIFF");
+        // RIFF size, patched on finish
+        header[8..12].copy_from_slice(b"WAVE");
+        header[12..16].copy_from_slice(b"fmt ");
+        header[16..20].copy_from_slice(&16u32.to_le_bytes());
+        // PCM
+        header[20..22].copy_from_slice(&1u16.to_le_bytes());
+        header[22..24].copy_from_slice(&channels.to_le_bytes());
+        header[24..28].copy_from_slice(&sample_rate.to_le_bytes());
+        header[28..32].copy_from_slice(&byte_rate.to_le_bytes());
+        header[32..34].copy_from_slice(&block_align.to_le_bytes());
+        header[34..36].copy_from_slice(&16u16.to_le_bytes());
+        header[36..40].copy_from_slice(b"data");
+        // Data size, patched on finish
+        sink.write_all(&header)?;
+
+        Ok(Self {
+            sink,
+            channels,
+            data_len: 0,
+        })
+    }
+
+    /// Write interleaved samples, one per channel per frame.
+    pub fn write_samples(&mut self, samples: &[i16]) -> Result<(), WavError<S::Error>> {
+        self.write_iter(samples.len(), samples.iter().copied())
+    }
+
+    /// Write a block of stereo samples. The file must have two channels.
+    pub fn write_stereo(&mut self, samples: &[StereoSample]) -> Result<(), WavError<S::Error>> {
+        if self.channels != 2 {
+            return Err(WavError::WrongChannels);
+        }
+        let words = samples
+            .iter()
+            .flat_map(|s| [s.left.to_i16(), s.right.to_i16()]);
+        self.write_iter(samples.len() * 2, words)
+    }
+
+    fn write_iter<I>(&mut self, count: usize, words: I) -> Result<(), WavError<S::Error>>
+    where
+        I: Iterator<Item = i16>,
+    {
+        // Keep the RIFF size (data plus the rest of the header) in range
+        let len = u32::try_from(count * 2).map_err(|_| WavError::TooLong)?;
+        self.data_len
+            .checked_add(len)
+            .filter(|l| *l <= u32::MAX - (HEADER_LEN - 8))
+            .ok_or(WavError::TooLong)?;
+
+        // Convert to little endian a chunk at a time. The length is counted
+        // as each chunk is written, so the header still matches the data
+        // if the sink fails part way through.
+        let mut buf = [0u8; 256];
+        let mut used = 0;
+        for word in words {
+            buf[used..used + 2].copy_from_slice(&word.to_le_bytes());
+            used += 2;
+            if used == buf.len() {
+                self.sink.write_all(&buf)?;
+                self.data_len += used as u32;
+                used = 0;
+            }
+        }
+        if used != 0 {
+            self.sink.write_all(&buf[..used])?;
+            self.data_len += used as u32;
+        }
+        Ok(())
+    }
+
+    /// Samples written so far, per channel
+    pub fn frames(&self) -> u32 {
+        self.data_len / (u32::from(self.channels) * 2)
+    }
+
+    /// Fill in the sizes in the header, returning the sink.
+    pub fn finish(mut self) -> Result<S, WavError<S::Error>> {
+        let riff_len = self.data_len + (HEADER_LEN - 8);
+        self.sink.patch(RIFF_SIZE_POS, &riff_len.to_le_bytes())?;
+        self.sink.patch(DATA_SIZE_POS, &self.data_len.to_le_bytes())?;
+        Ok(self.sink)
+    }
+}
+
+/// Writes into a byte slice.
+pub struct SliceSink<'a> {
+    buf: &'a mut [u8],
+    used: usize,
+}
+
+/// The slice given to a `SliceSink` is full
+#[derive(Debug, PartialEq, Eq)]
+pub struct SliceFull;
+
+impl<'a> SliceSink<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, used: 0 }
+    }
+
+    /// Everything written so far
+    pub fn written(&self) -> &[u8] {
+        &self.buf[..self.used]
+    }
+}
+
+impl<'a> WavSink for SliceSink<'a> {
+    type Error = SliceFull;
+
+    fn write_all(&mut self, bytes: &[u8]) -> Result<(), SliceFull> {
+        let dest = self
+            .buf
+            .get_mut(self.used..self.used + bytes.len())
+            .ok_or(SliceFull)?;
+        dest.copy_from_slice(bytes);
+        self.used += bytes.len();
+        Ok(())
+    }
+
+    fn patch(&mut self, pos: u32, bytes: &[u8]) -> Result<(), SliceFull> {
+        let pos = pos as usize;
+        let dest = self.buf[..self.used]
+            .get_mut(pos..pos + bytes.len())
+            .ok_or(SliceFull)?;
+        dest.copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+pub use io_sink::IoSink;
+
+#[cfg(feature = "std")]
+mod io_sink {
+    extern crate std;
+    use std::io::{self, Seek, SeekFrom, Write};
+
+    use super::WavSink;
+
+    /// Writes to a seekable `std::io` writer, like a `File`. Wrap the writer
+    /// in a `BufWriter` to avoid lots of small writes.
+    pub struct IoSink<W: Write + Seek>(pub W);
+
+    impl<W: Write + Seek> WavSink for IoSink<W> {
+        type Error = io::Error;
+
+        fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+            self.0.write_all(bytes)
+        }
+
+        fn patch(&mut self, pos: u32, bytes: &[u8]) -> io::Result<()> {
+            let end = self.0.stream_position()?;
+            self.0.seek(SeekFrom::Start(pos.into()))?;
+            self.0.write_all(bytes)?;
+            self.0.seek(SeekFrom::Start(end))?;
+            self.0.flush()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Sample;
+
+    fn stereo(left: i16, right: i16) -> StereoSample {
+        StereoSample {
+            left: Sample { word: left },
+            right: Sample { word: right },
+        }
+    }
+
+    #[test]
+    fn header_and_data() {
+        let mut buf = [0u8; 1024];
+        let mut wav = WavWriter::new(SliceSink::new(&mut buf), 44100, 2).unwrap();
+        wav.write_stereo(&[stereo(1, -1), stereo(0x1234, i16::MIN)]).unwrap();
+        wav.write_samples(&[7, 8]).unwrap();
+        assert_eq!(wav.frames(), 3);
+        let sink = wav.finish().unwrap();
+
+        let out = sink.written();
+        assert_eq!(out.len(), 44 + 12);
+        assert_eq!(&out[0..4], b"RIFF");
+        assert_eq!(&out[4..8], &(36u32 + 12).to_le_bytes());
+        assert_eq!(&out[8..16], b"WAVEfmt ");
+        assert_eq!(&out[22..24], &2u16.to_le_bytes());
+        assert_eq!(&out[24..28], &44100u32.to_le_bytes());
+        assert_eq!(&out[28..32], &(44100u32 * 4).to_le_bytes());
+        assert_eq!(&out[32..34], &4u16.to_le_bytes());
+        assert_eq!(&out[34..36], &16u16.to_le_bytes());
+        assert_eq!(&out[36..40], b"data");
+        assert_eq!(&out[40..44], &12u32.to_le_bytes());
+        assert_eq!(
+            &out[44..],
+            &[1, 0, 0xFF, 0xFF, 0x34, 0x12, 0x00, 0x80, 7, 0, 8, 0]
+        );
+    }
+
+    #[test]
+    fn large_blocks() {
+        // More than one chunk of conversion in a block
+        let mut buf = [0u8; 44 + 2000];
+        let mut wav = WavWriter::new(SliceSink::new(&mut buf), 8000, 1).unwrap();
+        let block = [0x0102i16; 1000];
+        wav.write_samples(&block).unwrap();
+        assert_eq!(wav.write_samples(&[0]), Err(WavError::Sink(SliceFull)));
+        let sink = wav.finish().unwrap();
+        assert_eq!(sink.written().len(), 44 + 2000);
+        assert!(sink.written()[44..].chunks(2).all(|c| c == [2, 1]));
+    }
+
+    #[test]
+    fn bad_format() {
+        let mut buf = [0u8; 64];
+        assert!(matches!(
+            WavWriter::new(SliceSink::new(&mut buf), 0, 2),
+            Err(WavError::BadFormat)
+        ));
+        assert!(matches!(
+            WavWriter::new(SliceSink::new(&mut buf), 8000, u16::MAX),
+            Err(WavError::BadFormat)
+        ));
+        let mut wav = WavWriter::new(SliceSink::new(&mut buf), 8000, 1).unwrap();
+        assert_eq!(wav.write_stereo(&[stereo(0, 0)]), Err(WavError::WrongChannels));
+    }
+
+    #[test]
+    fn failed_write() {
+        // Room for the first chunk of a block, but not the rest
+        let mut buf = [0u8; 44 + 300];
+        let mut wav = WavWriter::new(SliceSink::new(&mut buf), 8000, 1).unwrap();
+        assert_eq!(wav.write_samples(&[5; 200]), Err(WavError::Sink(SliceFull)));
+        assert_eq!(wav.frames(), 128);
+
+        let sink = wav.finish().unwrap();
+        let out = sink.written();
+        assert_eq!(out.len(), 44 + 256);
+        assert_eq!(&out[4..8], &(36u32 + 256).to_le_bytes());
+        assert_eq!(&out[40..44], &256u32.to_le_bytes());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn io_sink() {
+        extern crate std;
+        use std::{io::Cursor, vec::Vec};
+
+        let mut wav = WavWriter::new(IoSink(Cursor::new(Vec::new())), 48000, 2).unwrap();
+        for _ in 0..10 {
+            wav.write_stereo(&[stereo(3, 4); 100]).unwrap();
+        }
+        let out = wav.finish().unwrap().0.into_inner();
+        assert_eq!(out.len(), 44 + 4000);
+        assert_eq!(&out[40..44], &4000u32.to_le_bytes());
+        assert_eq!(&out[out.len() - 4..], &[3, 0, 4, 0]);
+    }
+}
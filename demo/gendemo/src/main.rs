@@ -1,188 +1,372 @@
-use std::{thread::sleep, time::Duration};
-
-use minijam::scale::{Pitch, MAJOR_PENTATONIC_INTERVALS};
-use thursday::{phrdat::{PhraseDataParameters, PhraseDataBuilder}, Length};
-use rand::thread_rng;
-
-fn main() {
-    let mut rng = thread_rng();
-    let params = PhraseDataParameters::default();
-    let mut builder = PhraseDataBuilder::default();
-    // for _ in 0..10 {
-    //     builder.fill(&mut rng, &params);
-    //     println!("{:#?}", builder);
-    //     sleep(Duration::from_millis(2000));
-    // }
-    builder.fill(&mut rng, &params);
-    println!("{:#?}", builder);
-
-    let mut bbs = vec![];
-    for (i, lead) in builder.lead_voices.iter().enumerate() {
-        let mut bb = BarBuf::new();
-        let mut lend = 0;
-        assert_ne!(0, lead.rhythm.len());
-        for note in lead.rhythm.iter() {
-            let start = note.ppqn_start();
-            if start > lend {
-                let delta = start - lend;
-                bb.push_rest_simple(Length::PPQNCount(delta)).unwrap();
-                lend += delta;
+// Generate phrases with `thursday::phrdat`, then listen to, export or look
+// at them. Run with no arguments for usage.
+//
+// The same seed and parameters always give the same phrases.
+
+use std::{
+    env,
+    fmt,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    process::ExitCode,
+};
+
+use mididemo::export::{write_midi, ExportError, MidiMeta, MidiVoice};
+use minijam::{
+    scale::{Note, MAJOR_PENTATONIC_INTERVALS},
+    tones::{Mix, ToneKind},
+    wav::{IoSink, WavError, WavWriter},
+};
+use thursday::{
+    bars::{BarBuf, BarError},
+    phrdat::{EncRhythm, PhraseDataBuilder, PhraseDataHeader, PhraseError, ValidatedParameters},
+    phrid::{PhraseGenerator, PhraseId, PhraseIdError},
+    render::{RenderVoice, Renderer},
+    Length, PlayNote, PPQN_MAX,
+};
+
+mod params;
+
+use params::{parse_params, ParamError};
+
+// Room for the tempo changes of a generated phrase: its starting tempo, and
+// the tempo it steps or ramps to
+const TEMPO_EVENTS: usize = 4;
+
+const USAGE: &str = "\
+usage: gendemo <command> [options]
+
+commands:
+  generate    print a summary of each phrase
+  render      render the last phrase to a WAV file
+  export      export the last phrase to a MIDI file
+  inspect     print the notes of the last phrase
+
+options:
+  --seed <n>           seed for the generator (default 0)
+  --phrases <n>        number of phrases to generate (default 1)
+  --id <id>            the phrase ID printed by `generate`, in place of
+                       --seed and --phrases
+  --params <file>      TOML or JSON parameter file (default: built in
+                       parameters)
+  --out <file>         output file (default phrase.wav or phrase.mid)
+  --sample-rate <hz>   sample rate to render at (default 44100)";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Command {
+    Generate,
+    Render,
+    Export,
+    Inspect,
+}
+
+struct Options {
+    command: Command,
+    seed: u64,
+    phrases: u32,
+    id: Option<PhraseId>,
+    params: Option<String>,
+    out: Option<String>,
+    sample_rate: u32,
+}
+
+enum CliError {
+    Usage(String),
+    Params(String, ParamError),
+    PhraseId(PhraseIdError),
+    Phrase(PhraseError),
+    Io(String, io::Error),
+    Bar(BarError),
+    Export(ExportError),
+    Render,
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CliError::Usage(msg) => write!(f, "{msg}\n\n{USAGE}"),
+            CliError::Params(path, err) => write!(f, "{path}: {err}"),
+            CliError::PhraseId(PhraseIdError::BadFormat) => write!(f, "bad phrase ID"),
+            CliError::PhraseId(PhraseIdError::ParamsMismatch { .. }) => {
+                write!(f, "the phrase ID was made with different parameters")
             }
-            bb.push_note_simple(
-                Length::PPQNCount(note.ppqn_len()),
-                ((Pitch::C as u8) + MAJOR_PENTATONIC_INTERVALS[i].0 as u8).into(),
-                5
-            ).unwrap();
-            lend += note.ppqn_len();
+            CliError::PhraseId(PhraseIdError::Phrase(err)) | CliError::Phrase(err) => {
+                write!(f, "couldn't generate the phrase: {err}")
+            }
+            CliError::Io(path, err) => write!(f, "{path}: {err}"),
+            CliError::Bar(err) => write!(f, "couldn't encode the phrase: {err:?}"),
+            CliError::Export(err) => write!(f, "couldn't export the phrase: {err}"),
+            CliError::Render => write!(f, "couldn't render the phrase"),
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    match parse_args(&args).and_then(|opts| run(&opts)) {
+        Ok(()) => ExitCode::SUCCESS,
+        // Whatever we were piped to (like `head`) has seen all it wants
+        Err(CliError::Io(_, err)) if err.kind() == io::ErrorKind::BrokenPipe => ExitCode::SUCCESS,
+        Err(err @ CliError::Usage(_)) => {
+            eprintln!("error: {err}");
+            ExitCode::from(2)
+        }
+        Err(err) => {
+            eprintln!("error: {err}");
+            ExitCode::FAILURE
         }
-        bbs.push(bb)
     }
-    for (i, chorus) in builder.chorus_voices.iter().enumerate() {
-        let mut bb = BarBuf::new();
-        let mut lend = 0;
-        assert_ne!(0, chorus.rhythm.len());
-        for note in chorus.rhythm.iter() {
-            let start = note.ppqn_start();
-            if start > lend {
-                let delta = start - lend;
-                bb.push_rest_simple(Length::PPQNCount(delta)).unwrap();
-                lend += delta;
+}
+
+fn parse_args(args: &[String]) -> Result<Options, CliError> {
+    let usage = |msg: String| CliError::Usage(msg);
+    let mut args = args.iter();
+
+    let command = match args.next().map(String::as_str) {
+        Some("generate") => Command::Generate,
+        Some("render") => Command::Render,
+        Some("export") => Command::Export,
+        Some("inspect") => Command::Inspect,
+        Some(other) => return Err(usage(format!("unknown command `{other}`"))),
+        None => return Err(usage("no command given".into())),
+    };
+
+    let mut opts = Options {
+        command,
+        seed: 0,
+        phrases: 1,
+        id: None,
+        params: None,
+        out: None,
+        sample_rate: 44100,
+    };
+
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| usage(format!("`{flag}` needs a value")))?;
+        let number = || usage(format!("`{flag}` needs a number, not `{value}`"));
+        match flag.as_str() {
+            "--seed" => opts.seed = value.parse().map_err(|_| number())?,
+            "--phrases" => opts.phrases = value.parse().map_err(|_| number())?,
+            "--sample-rate" => opts.sample_rate = value.parse().map_err(|_| number())?,
+            "--id" => {
+                let id = value.parse().map_err(|_| usage(format!("bad phrase ID `{value}`")))?;
+                opts.id = Some(id);
             }
-            bb.push_note_simple(
-                Length::PPQNCount(note.ppqn_len()),
-                ((Pitch::C as u8) + MAJOR_PENTATONIC_INTERVALS[i].0 as u8).into(),
-                3
-            ).unwrap();
-            lend += note.ppqn_len();
+            "--params" => opts.params = Some(value.clone()),
+            "--out" => opts.out = Some(value.clone()),
+            _ => return Err(usage(format!("unknown option `{flag}`"))),
         }
-        bbs.push(bb)
     }
 
-    let btm = bbs.iter().map(|bar| (bar, None)).collect::<Vec<_>>();
-    println!("{}", btm.len());
-    println!("{}, {}", builder.lead_voices.len(), builder.chorus_voices.len());
-    bars_to_midi(&btm, "rythm.mid", builder.build_header().bpm as u32).unwrap();
+    if opts.id.is_some_and(|id| id.step == 0) {
+        return Err(usage("a phrase ID's step must be at least 1".into()));
+    }
+    if opts.phrases == 0 {
+        return Err(usage("`--phrases` must be at least 1".into()));
+    }
+    if opts.sample_rate == 0 {
+        return Err(usage("`--sample-rate` must be more than 0".into()));
+    }
+    Ok(opts)
+}
 
+fn stdout_err(err: io::Error) -> CliError {
+    CliError::Io("stdout".into(), err)
 }
 
-use std::error::Error;
+fn run(opts: &Options) -> Result<(), CliError> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
 
-use midly::{Header, Smf, Format, TrackEvent, TrackEventKind, MetaMessage, MidiMessage};
-use thursday::{bars::BarBuf, PPQN};
+    let params = match &opts.params {
+        Some(path) => {
+            let src = fs::read_to_string(path).map_err(|e| CliError::Io(path.clone(), e))?;
+            parse_params(path, &src).map_err(|e| CliError::Params(path.clone(), e))?
+        }
+        None => ValidatedParameters::default(),
+    };
 
-pub fn bar_to_midi(
-    bbuf: &BarBuf,
-    path: &str,
-    bpm: u32,
-    midi_instrument: Option<u8>,
-) -> Result<(), Box<dyn Error>> {
-    bars_to_midi(&[(bbuf, midi_instrument)], path, bpm)?;
-    Ok(())
-}
+    // Each phrase carries on from the last
+    let gen = match opts.id {
+        Some(id) => {
+            let gen = PhraseGenerator::from_id(id, params).map_err(CliError::PhraseId)?;
+            if opts.command == Command::Generate {
+                print_summary(&mut out, &gen)?;
+            }
+            gen
+        }
+        None => {
+            let mut gen = PhraseGenerator::new(opts.seed, params);
+            for _ in 0..opts.phrases {
+                gen.next_phrase().map_err(CliError::Phrase)?;
+                if opts.command == Command::Generate {
+                    print_summary(&mut out, &gen)?;
+                }
+            }
+            gen
+        }
+    };
 
-pub fn bars_to_midi(
-    bbufs: &[(&BarBuf, Option<u8>)],
-    path: &str,
-    bpm: u32,
-) -> Result<(), Box<dyn Error>> {
-    let mut smf = Smf::new(Header::new(
-        Format::Parallel,
-        midly::Timing::Metrical(PPQN.into()),
-    ));
-    let mut track_0: Vec<TrackEvent> = vec![];
-
-    // Fixed metadata
-    track_0.push(TrackEvent {
-        delta: 0u32.into(),
-        kind: TrackEventKind::Meta(MetaMessage::TrackName(b"thursday")),
-    });
-    track_0.push(TrackEvent {
-        delta: 0u32.into(),
-        kind: TrackEventKind::Meta(MetaMessage::Tempo(((1_000_000u32 * 60) / bpm).into())),
-    });
-    track_0.push(TrackEvent {
-        delta: 0u32.into(),
-        kind: TrackEventKind::Meta(MetaMessage::TimeSignature(4, 2, 24, 8)),
-    });
-    track_0.push(TrackEvent {
-        delta: 0u32.into(),
-        kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
-    });
-
-    smf.tracks.push(track_0);
-
-    for (i, (bbuf, instr)) in bbufs.iter().enumerate() {
-        let ch = (i as u8).into();
-        let mut track_n: Vec<TrackEvent> = vec![];
-
-        track_n.push(TrackEvent {
-            delta: 0u32.into(),
-            kind: TrackEventKind::Midi {
-                channel: ch,
-                message: MidiMessage::Controller {
-                    controller: 0u8.into(),
-                    value: 121u8.into(),
-                },
-            },
-        });
-        track_n.push(TrackEvent {
-            delta: 0u32.into(),
-            kind: TrackEventKind::Midi {
-                channel: ch,
-                message: MidiMessage::Controller {
-                    controller: 32u8.into(),
-                    value: 0u8.into(),
-                },
-            },
-        });
-        track_n.push(TrackEvent {
-            delta: 0u32.into(),
-            kind: TrackEventKind::Midi {
-                channel: ch,
-                message: MidiMessage::ProgramChange {
-                    // Default to piano
-                    program: instr.unwrap_or(1).into(),
-                },
-            },
-        });
-
-
-        let mut idx = 0u16;
-        for note in bbuf.notes() {
-            track_n.push(TrackEvent {
-                delta: ((note.ppqn_start() - idx) as u32).into(),
-                kind: TrackEventKind::Midi {
-                    channel: ch,
-                    message: MidiMessage::NoteOn {
-                        key: note.pitch_tone_offset().0.into(),
-                        vel: 80u8.into(),
-                    },
-                },
-            });
-            track_n.push(TrackEvent {
-                delta: (note.ppqn_len() as u32).into(),
-                kind: TrackEventKind::Midi {
-                    channel: ch,
-                    message: MidiMessage::NoteOff {
-                        key: note.pitch_tone_offset().0.into(),
-                        vel: 64u8.into(),
-                    },
-                },
-            });
-            idx = note.ppqn_start() + note.ppqn_len();
+    let builder = gen.builder();
+    let header = builder.build_header().map_err(CliError::Phrase)?;
+    let (leads, chorus) = phrase_bars(builder, &header).map_err(CliError::Bar)?;
+
+    match opts.command {
+        Command::Generate => Ok(()),
+        Command::Inspect => inspect(&mut out, &header, &leads, &chorus).map_err(stdout_err),
+        Command::Render => {
+            let path = opts.out.clone().unwrap_or_else(|| "phrase.wav".into());
+            render(&path, opts.sample_rate, &header, &leads, &chorus)
         }
+        Command::Export => {
+            let path = opts.out.clone().unwrap_or_else(|| "phrase.mid".into());
+            export(&path, &header, &leads, &chorus)
+        }
+    }
+}
+
+fn print_summary(out: &mut impl Write, gen: &PhraseGenerator) -> Result<(), CliError> {
+    let builder = gen.builder();
+    let header = builder.build_header().map_err(CliError::Phrase)?;
+    let sig = header.time_signature();
+    let bpm = match header.end_bpm() {
+        end if end == header.bpm => format!("{}bpm", header.bpm),
+        end => format!("{}-{}bpm", header.bpm, end),
+    };
+    writeln!(
+        out,
+        "{}: {}, {}/{}, {:?} {:?}, {} measures, {} lead and {} chorus voices",
+        gen.id(),
+        bpm,
+        sig.numerator(),
+        sig.denominator(),
+        header.key(),
+        header.key_kind(),
+        header.num_measures(),
+        builder.lead_voices.len(),
+        builder.chorus_voices.len(),
+    )
+    .map_err(stdout_err)
+}
 
-        track_n.push(TrackEvent {
-            delta: 0u32.into(),
-            kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
-        });
+// One `BarBuf` per voice, each the length of the whole phrase. Every voice
+// plays a single pitch, a step up the pentatonic scale from the last.
+fn phrase_bars(
+    builder: &PhraseDataBuilder,
+    header: &PhraseDataHeader,
+) -> Result<(Vec<BarBuf>, Vec<BarBuf>), BarError> {
+    let bars = |rhythms: Vec<&[EncRhythm]>, octave: u8| {
+        rhythms
+            .into_iter()
+            .enumerate()
+            .map(|(i, rhythm)| {
+                let root = Note {
+                    pitch: header.key(),
+                    octave,
+                };
+                let note = root + MAJOR_PENTATONIC_INTERVALS[i % MAJOR_PENTATONIC_INTERVALS.len()];
+                voice_bar(rhythm, note, header.phrase_ppqn())
+            })
+            .collect::<Result<Vec<_>, _>>()
+    };
 
-        smf.tracks.push(track_n);
+    let leads = bars(builder.lead_voices.iter().map(|v| v.rhythm.as_slice()).collect(), 5)?;
+    let chorus = bars(builder.chorus_voices.iter().map(|v| v.rhythm.as_slice()).collect(), 3)?;
+    Ok((leads, chorus))
+}
+
+fn voice_bar(rhythm: &[EncRhythm], note: Note, len: u32) -> Result<BarBuf, BarError> {
+    let mut bar = BarBuf::new();
+    for hit in rhythm.iter() {
+        let rest = hit.ppqn_start().saturating_sub(bar.ppqn_len());
+        if rest != 0 {
+            bar.push_rest_simple(Length::PPQNCount(rest))?;
+        }
+        bar.push_note_simple(Length::PPQNCount(hit.ppqn_len()), note.pitch, note.octave)?;
+    }
+    // A `BarBuf` can't hold more than `PPQN_MAX`, so very long phrases are
+    // cut short
+    let rest = len.min(PPQN_MAX.into()).saturating_sub(bar.ppqn_len().into());
+    if rest != 0 {
+        bar.push_rest_simple(Length::PPQNCount(rest as u16))?;
     }
+    Ok(bar)
+}
 
-    println!("!!! {} !!!", smf.tracks.len());
+fn inspect(out: &mut impl Write, header: &PhraseDataHeader, leads: &[BarBuf], chorus: &[BarBuf]) -> io::Result<()> {
+    writeln!(out, "{:#?}", header)?;
+    let voices = leads
+        .iter()
+        .map(|b| ("lead", b))
+        .chain(chorus.iter().map(|b| ("chorus", b)));
+    for (i, (kind, bar)) in voices.enumerate() {
+        writeln!(out)?;
+        writeln!(out, "voice {i} ({kind}), {} bytes", bar.bytes().len())?;
+        writeln!(out, " start | len  |  freq")?;
+        writeln!(out, " ppqn  | ppqn |   Hz")?;
+        writeln!(out, "-------|------|--------")?;
+        for note in bar.notes() {
+            writeln!(
+                out,
+                " {:04}  | {:04} | {:04.2}",
+                note.ppqn_start(),
+                note.ppqn_len(),
+                PlayNote::from_enc(&note, 0).frequency(),
+            )?;
+        }
+    }
+    Ok(())
+}
 
-    smf.save(path)?;
+fn render(
+    path: &str,
+    sample_rate: u32,
+    header: &PhraseDataHeader,
+    leads: &[BarBuf],
+    chorus: &[BarBuf],
+) -> Result<(), CliError> {
+    // Play the tempo changes, as `export` does
+    let tempo = header
+        .tempo_map::<TEMPO_EVENTS>(sample_rate)
+        .map_err(|_| CliError::Render)?;
+    let mut renderer: Renderer<'_, 8, _> = Renderer::with_timebase(sample_rate, tempo).ok_or(CliError::Render)?;
+    let voices = leads
+        .iter()
+        .map(|b| (ToneKind::Square, b))
+        .chain(chorus.iter().map(|b| (ToneKind::Sine, b)));
+    for (kind, bar) in voices {
+        let mut voice = RenderVoice::new(core::slice::from_ref(bar), kind);
+        voice.mix = Mix::Div8;
+        renderer.add_voice(voice);
+    }
 
+    let io_err = |e| CliError::Io(path.to_string(), e);
+    let wav_err = |e| match e {
+        WavError::Sink(e) => io_err(e),
+        _ => CliError::Render,
+    };
+    let file = File::create(path).map_err(io_err)?;
+    let mut wav = WavWriter::new(IoSink(BufWriter::new(file)), sample_rate, 2).map_err(wav_err)?;
+    renderer.write_wav(&mut wav).map_err(wav_err)?;
+    wav.finish().map_err(wav_err)?;
     Ok(())
 }
+
+fn export(
+    path: &str,
+    header: &PhraseDataHeader,
+    leads: &[BarBuf],
+    chorus: &[BarBuf],
+) -> Result<(), CliError> {
+    let voices = leads
+        .iter()
+        .chain(chorus.iter())
+        .enumerate()
+        .map(|(i, bar)| MidiVoice::new(core::slice::from_ref(bar), i as u8))
+        .collect::<Vec<_>>();
+    let file = File::create(path).map_err(|e| CliError::Io(path.to_string(), e))?;
+    write_midi(&MidiMeta::from_header("thursday", header), &voices, BufWriter::new(file))
+        .map_err(CliError::Export)
+}
@@ -0,0 +1,52 @@
+// Parameter files, in TOML or (with a `.json` extension) JSON. The sections
+// and names are the fields of `PhraseDataParameters`:
+//
+// ```toml
+// [bpm]
+// min = 90
+// max = 140
+// change_probability = 0.2
+//
+// [voices]
+// total_voices_max = 6
+// ```
+//
+// Anything not in the file keeps its default, except that the `euc` and
+// `resolution` tables of `lead_voices` and `chorus_voices` have to be given
+// whole. The parameters are checked before any phrases are generated.
+
+use std::{fmt, path::Path};
+
+use thursday::phrdat::{ParameterError, PhraseDataParameters, ValidatedParameters};
+
+#[derive(Debug)]
+pub enum ParamError {
+    Toml(toml::de::Error),
+    Json(serde_json::Error),
+    Invalid(ParameterError),
+}
+
+impl fmt::Display for ParamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParamError::Toml(err) => write!(f, "{}", err.to_string().trim_end()),
+            ParamError::Json(err) => write!(f, "{err}"),
+            ParamError::Invalid(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for ParamError {}
+
+/// Parse and check a parameter file. `path` is only used to pick the format.
+pub fn parse_params(path: &str, src: &str) -> Result<ValidatedParameters, ParamError> {
+    let is_json = Path::new(path)
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+    let params: PhraseDataParameters = if is_json {
+        serde_json::from_str(src).map_err(ParamError::Json)?
+    } else {
+        toml::from_str(src).map_err(ParamError::Toml)?
+    };
+    ValidatedParameters::new(params).map_err(ParamError::Invalid)
+}
@@ -1,50 +1,35 @@
-use mididemo::bar_to_midi;
-use minijam::scale::Pitch;
-use thursday::{Length, bars::BarBuf};
+use std::fs::File;
 
-fn main() {
-    // Input notation
-    let mary = [
-        (Length::Quarter, Some((Pitch::E, 4))), // Ma
-        (Length::Quarter, Some((Pitch::D, 4))), // ry
-        (Length::Quarter, Some((Pitch::C, 4))), // had
-        (Length::Quarter, Some((Pitch::D, 4))), // a
-        (Length::Quarter, Some((Pitch::E, 4))), // lit
-        (Length::Quarter, Some((Pitch::E, 4))), // tle
-        (Length::Quarter, Some((Pitch::E, 4))), // lamb
-        (Length::Quarter, None), //
-        (Length::Quarter, Some((Pitch::D, 4))), // lit
-        (Length::Quarter, Some((Pitch::D, 4))), // tle
-        (Length::Quarter, Some((Pitch::D, 4))), // lamb
-        (Length::Quarter, None), //
-        (Length::Quarter, Some((Pitch::E, 4))), // lit
-        (Length::Quarter, Some((Pitch::E, 4))), // tle
-        (Length::Quarter, Some((Pitch::E, 4))), // lamb
-        (Length::Quarter, None), //
-        (Length::Quarter, Some((Pitch::E, 4))), // Ma
-        (Length::Quarter, Some((Pitch::D, 4))), // ry
-        (Length::Quarter, Some((Pitch::C, 4))), // had
-        (Length::Quarter, Some((Pitch::D, 4))), // a
-        (Length::Quarter, Some((Pitch::E, 4))), // lit
-        (Length::Quarter, Some((Pitch::E, 4))), // tle
-        (Length::Quarter, Some((Pitch::E, 4))), // lamb
-        (Length::Quarter, Some((Pitch::E, 4))), // its
-        (Length::Quarter, Some((Pitch::D, 4))), // fleece
-        (Length::Quarter, Some((Pitch::D, 4))), // was
-        (Length::Quarter, Some((Pitch::E, 4))), // white
-        (Length::Quarter, Some((Pitch::D, 4))), // as
-        (Length::Half, Some((Pitch::C, 4))), // snow
-    ];
+use mididemo::export::{write_midi, MidiMeta, MidiVoice};
+use minijam::tempo::{TempoEvent, TempoKind};
+use thursday::script::Script;
+
+const MARY: &str = "
+tempo 150
+voice lead square program 1
 
-    // Load into a Bar Buffer
-    let mut bbuf = BarBuf::new();
-    for (len, note) in mary {
-        match note {
-            Some((pitch, oct)) => bbuf.push_note_simple(len, pitch, oct).unwrap(),
-            None => bbuf.push_rest_simple(len).unwrap(),
-        }
-    }
+lead {
+    e4 d c d | e e e r  # Mary had a little lamb
+    d d d r  | e e e r  # little lamb, little lamb
+    e d c d  | e e e e  # Mary had a little lamb, its
+    d d e d  | c/2      # fleece was white as snow
+}
+";
+
+fn main() {
+    let script = Script::parse(MARY).unwrap();
+    let lead = script.voice("lead").unwrap();
 
     // Write to midi file
-    bar_to_midi(&bbuf, "mary.mid", 150, Some(1)).unwrap();
+    let tempo = [TempoEvent {
+        tick: 0,
+        bpm: script.tempo,
+        kind: TempoKind::Step,
+    }];
+    let voice = MidiVoice {
+        program: lead.program,
+        ..MidiVoice::new(core::slice::from_ref(&lead.bar), 0)
+    };
+    let file = File::create("mary.mid").unwrap();
+    write_midi(&MidiMeta::new("mary", &tempo), &[voice], file).unwrap();
 }
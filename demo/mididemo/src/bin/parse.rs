@@ -1,14 +1,33 @@
-use midly::Smf;
+use mididemo::import::import_midi;
 
 fn main() {
-    let smf = Smf::parse(include_bytes!("./../../MIDI_sample.mid")).unwrap();
+    let path = std::env::args().nth(1).unwrap_or_else(|| "MIDI_sample.mid".into());
+    let import = import_midi(&path).unwrap();
 
-    println!("{:?}", smf.header);
-    for (i, track) in smf.tracks.iter().enumerate() {
-        println!("track {} has {} events", i, track.len());
-        if i <= 1 {
-            for ev in track.iter() {
-                println!("{:?}", ev);
+    for ev in import.tempo.iter() {
+        println!("tempo {}bpm at tick {}", ev.bpm, ev.tick);
+    }
+    for sig in import.time_signatures.iter() {
+        println!("time {}/{} at tick {}", sig.numerator, sig.denominator, sig.tick);
+    }
+    for trk in import.tracks.iter() {
+        println!(
+            "track {} channel {} (program {:?}): {} notes in {} bars",
+            trk.track,
+            trk.channel,
+            trk.program,
+            trk.velocities.len(),
+            trk.bars.len(),
+        );
+        for (i, bar) in trk.bars.iter().enumerate() {
+            for note in bar.notes() {
+                println!(
+                    "  {}: start {} len {} tone {}",
+                    i,
+                    note.ppqn_start(),
+                    note.ppqn_len(),
+                    note.pitch_tone_offset().0
+                );
             }
         }
     }
@@ -0,0 +1,668 @@
+use std::{error::Error, fmt, io};
+
+use midly::{
+    Format, Header, MetaMessage, MidiMessage, PitchBend, Smf, Timing, TrackEvent, TrackEventKind,
+};
+use minijam::{
+    scale::Pitch,
+    tempo::{TempoError, TempoEvent, TempoKind, TempoMap},
+};
+use thursday::{
+    bars::BarBuf,
+    phrdat::{KeyKind, PhraseDataHeader},
+    PPQN, PPQN_16TH,
+};
+
+/// The most tempo changes that can be exported in one file
+const MAX_TEMPO_EVENTS: usize = 64;
+
+/// The velocity of notes with no velocity given
+pub const DEFAULT_VELOCITY: u8 = 80;
+
+/// The pitch bend range most synths start with, in semitones
+pub const DEFAULT_BEND_RANGE: u8 = 2;
+
+// Controllers used to set a registered parameter (RPN)
+const CC_DATA_ENTRY: u8 = 6;
+const CC_DATA_ENTRY_LSB: u8 = 38;
+const CC_RPN_LSB: u8 = 100;
+const CC_RPN_MSB: u8 = 101;
+
+const RPN_BEND_RANGE: (u8, u8) = (0, 0);
+const RPN_MPE_CONFIG: (u8, u8) = (0, 6);
+
+/// Everything about a song that isn't a note
+pub struct MidiMeta<'a> {
+    pub name: &'a str,
+    /// Tempo changes, which must start at tick 0. Ramps are written as a
+    /// series of steps, one every 16th note, each sized so that the ramp
+    /// takes exactly as long as it does when rendered.
+    pub tempo: &'a [TempoEvent],
+    /// Numerator and denominator, like `(6, 8)`. The denominator must be a
+    /// power of two.
+    pub time_signature: (u8, u8),
+    pub key_signature: Option<(Pitch, KeyKind)>,
+    /// How far a full pitch bend goes, in semitones. Notes with an offset
+    /// towards the next semitone are bent by this much at most.
+    pub bend_range: u8,
+    /// Spread notes over an MPE lower zone with this many member channels
+    /// (`1..=15`), so each sounding note can have its own bend. The voices'
+    /// own channels are ignored, and the first program given is sent on
+    /// the master channel (channel 0) for the whole zone.
+    pub mpe_members: Option<u8>,
+}
+
+impl<'a> MidiMeta<'a> {
+    /// A single tempo in 4/4, with no key signature
+    pub fn new(name: &'a str, tempo: &'a [TempoEvent]) -> Self {
+        Self {
+            name,
+            tempo,
+            time_signature: (4, 4),
+            key_signature: None,
+            bend_range: DEFAULT_BEND_RANGE,
+            mpe_members: None,
+        }
+    }
+
+    /// Take the tempo, time signature and key from a phrase
+    pub fn from_header(name: &'a str, header: &'a PhraseDataHeader) -> Self {
+        let sig = header.time_signature();
+        Self {
+            name,
+            tempo: header.tempo_events(),
+            time_signature: (sig.numerator(), sig.denominator()),
+            key_signature: Some((header.key(), header.key_kind().clone())),
+            bend_range: DEFAULT_BEND_RANGE,
+            mpe_members: None,
+        }
+    }
+}
+
+/// One voice, written as its own MIDI track
+pub struct MidiVoice<'a> {
+    /// Played one after another, each starting where the last one ends
+    pub bars: &'a [BarBuf],
+    /// `0..=15`
+    pub channel: u8,
+    /// A General MIDI program, `0..=127` (`0` is the grand piano). No
+    /// program change is sent if this is `None`.
+    pub program: Option<u8>,
+    /// The velocity of each note, in order. If empty, every note gets
+    /// `DEFAULT_VELOCITY`.
+    pub velocities: &'a [u8],
+}
+
+impl<'a> MidiVoice<'a> {
+    pub fn new(bars: &'a [BarBuf], channel: u8) -> Self {
+        Self {
+            bars,
+            channel,
+            program: None,
+            velocities: &[],
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ExportError {
+    Io(io::Error),
+    Tempo(TempoError),
+    /// The tempo at this tick is too slow to write: a MIDI tempo is at most
+    /// 2^24 - 1 microseconds per quarter note, or about 3.6 bpm
+    TooSlow(u32),
+    BadTimeSignature,
+    /// The voice at this index has a channel over 15
+    BadChannel(usize),
+    /// The voice at this index has a program over 127
+    BadProgram(usize),
+    /// The voice at this index has a velocity of 0 or over 127, or doesn't
+    /// have one velocity for each note
+    BadVelocities(usize),
+    /// A bend range of zero, or MPE members outside `1..=15`
+    BadBendSetup,
+    /// The voice at this index plays a note over another voice on the same
+    /// channel, and they need different bends
+    BendConflict(usize),
+    /// Every MPE member channel was busy at this tick
+    NoFreeChannel(u32),
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExportError::Io(err) => write!(f, "{err}"),
+            ExportError::Tempo(err) => write!(f, "invalid tempo map: {err:?}"),
+            ExportError::TooSlow(tick) => write!(f, "tempo at tick {tick} is too slow for MIDI"),
+            ExportError::BadTimeSignature => write!(f, "invalid time signature"),
+            ExportError::BadChannel(i) => write!(f, "voice {i} has a channel over 15"),
+            ExportError::BadProgram(i) => write!(f, "voice {i} has a program over 127"),
+            ExportError::BadVelocities(i) => write!(f, "voice {i} has invalid velocities"),
+            ExportError::BadBendSetup => write!(f, "invalid pitch bend setup"),
+            ExportError::BendConflict(i) => {
+                write!(f, "voice {i} needs a different bend from a note on the same channel")
+            }
+            ExportError::NoFreeChannel(tick) => {
+                write!(f, "no free MPE channel at tick {tick}")
+            }
+        }
+    }
+}
+
+impl Error for ExportError {}
+
+impl From<io::Error> for ExportError {
+    fn from(err: io::Error) -> Self {
+        ExportError::Io(err)
+    }
+}
+
+/// Write a format 1 MIDI file, with the metadata in the first track and one
+/// track for each voice.
+pub fn write_midi<W: io::Write>(
+    meta: &MidiMeta<'_>,
+    voices: &[MidiVoice<'_>],
+    out: W,
+) -> Result<(), ExportError> {
+    let (num, denom) = meta.time_signature;
+    if num == 0 || !denom.is_power_of_two() {
+        return Err(ExportError::BadTimeSignature);
+    }
+    if meta.bend_range == 0 || !matches!(meta.mpe_members, None | Some(1..=15)) {
+        return Err(ExportError::BadBendSetup);
+    }
+
+    let mut smf = Smf::new(Header::new(Format::Parallel, Timing::Metrical(PPQN.into())));
+    let mut track_0: Vec<TrackEvent> = vec![];
+
+    track_0.push(meta_event(0, MetaMessage::TrackName(meta.name.as_bytes())));
+    track_0.push(meta_event(
+        0,
+        MetaMessage::TimeSignature(num, denom.trailing_zeros() as u8, 96 / denom.min(96), 8),
+    ));
+    if let Some((key, kind)) = &meta.key_signature {
+        let (sharps, minor) = key_signature(*key, kind);
+        track_0.push(meta_event(0, MetaMessage::KeySignature(sharps, minor)));
+    }
+    let mut idx = 0u32;
+    for (tick, us_per_qn) in tempo_steps(meta.tempo)? {
+        track_0.push(meta_event(tick - idx, MetaMessage::Tempo(us_per_qn.into())));
+        idx = tick;
+    }
+    track_0.push(meta_event(0, MetaMessage::EndOfTrack));
+
+    for (i, voice) in voices.iter().enumerate() {
+        if voice.channel > 15 {
+            return Err(ExportError::BadChannel(i));
+        }
+        if voice.program.is_some_and(|p| p > 127) {
+            return Err(ExportError::BadProgram(i));
+        }
+        let count = voice.bars.iter().map(|b| b.notes().count()).sum::<usize>();
+        let bad_vel = voice.velocities.iter().any(|v| *v == 0 || *v > 127);
+        if bad_vel || !(voice.velocities.is_empty() || voice.velocities.len() == count) {
+            return Err(ExportError::BadVelocities(i));
+        }
+    }
+
+    let notes = place_notes(meta, voices)?;
+
+    // Events for each voice, as (tick, order, event), where notes end
+    // before bends are changed, and bends change before notes start
+    let mut events: Vec<Vec<(u32, u8, TrackEventKind)>> = vec![vec![]; voices.len()];
+    let midi = |channel: u8, message| TrackEventKind::Midi {
+        channel: channel.into(),
+        message,
+    };
+    let rpn = |channel: u8, (msb, lsb): (u8, u8), value: u8| {
+        [
+            (CC_RPN_MSB, msb),
+            (CC_RPN_LSB, lsb),
+            (CC_DATA_ENTRY, value),
+            (CC_DATA_ENTRY_LSB, 0),
+            // Deselect, so stray data entry doesn't change anything
+            (CC_RPN_MSB, 0x7F),
+            (CC_RPN_LSB, 0x7F),
+        ]
+        .map(|(controller, value)| {
+            midi(
+                channel,
+                MidiMessage::Controller {
+                    controller: controller.into(),
+                    value: value.into(),
+                },
+            )
+        })
+    };
+
+    // Channel setup
+    let bent = |ch: u8| notes.iter().any(|n| n.channel == ch && n.bend != 0);
+    let mut setup: Vec<(usize, TrackEventKind)> = vec![];
+    match meta.mpe_members {
+        Some(members) => {
+            // Setup goes in the first voice's track, if there is one
+            setup.extend(rpn(0, RPN_MPE_CONFIG, members).map(|ev| (0, ev)));
+            if let Some(program) = voices.iter().find_map(|v| v.program) {
+                setup.push((0, midi(0, MidiMessage::ProgramChange { program: program.into() })));
+            }
+            for ch in 1..=members {
+                setup.extend(rpn(ch, RPN_BEND_RANGE, meta.bend_range).map(|ev| (0, ev)));
+            }
+        }
+        None => {
+            let mut done = [false; 16];
+            for (i, voice) in voices.iter().enumerate() {
+                if let Some(program) = voice.program {
+                    let msg = MidiMessage::ProgramChange { program: program.into() };
+                    setup.push((i, midi(voice.channel, msg)));
+                }
+                let ch = usize::from(voice.channel);
+                if !done[ch] && bent(voice.channel) {
+                    setup.extend(rpn(voice.channel, RPN_BEND_RANGE, meta.bend_range).map(|ev| (i, ev)));
+                    done[ch] = true;
+                }
+            }
+        }
+    }
+    for (i, ev) in setup {
+        if let Some(evs) = events.get_mut(i) {
+            evs.push((0, 0, ev));
+        }
+    }
+
+    for note in notes.iter() {
+        let evs = &mut events[note.voice];
+        let key = note.key.into();
+        if let Some(bend) = note.bend_change {
+            let bend = PitchBend::from_int(bend);
+            evs.push((note.start, 2, midi(note.channel, MidiMessage::PitchBend { bend })));
+        }
+        let vel = note.velocity.into();
+        evs.push((note.start, 3, midi(note.channel, MidiMessage::NoteOn { key, vel })));
+        let vel = 64.into();
+        evs.push((note.end, 1, midi(note.channel, MidiMessage::NoteOff { key, vel })));
+    }
+
+    smf.tracks.push(track_0);
+    for mut evs in events {
+        evs.sort_by_key(|(tick, order, _)| (*tick, *order));
+        let mut track_n: Vec<TrackEvent> = vec![];
+        let mut idx = 0u32;
+        for (tick, _, kind) in evs {
+            track_n.push(TrackEvent {
+                delta: (tick - idx).into(),
+                kind,
+            });
+            idx = tick;
+        }
+        track_n.push(meta_event(0, MetaMessage::EndOfTrack));
+        smf.tracks.push(track_n);
+    }
+
+    smf.write_std(out)?;
+    Ok(())
+}
+
+struct Placed {
+    voice: usize,
+    start: u32,
+    end: u32,
+    key: u8,
+    velocity: u8,
+    // As a MIDI bend value, with 0 as no bend
+    bend: i16,
+    channel: u8,
+    // The bend to send before the note starts, if the channel needs it
+    bend_change: Option<i16>,
+}
+
+#[derive(Clone, Copy, Default)]
+struct ChannelUse {
+    bend: i16,
+    // Until when the channel is busy, and when it was last used
+    busy_until: u32,
+    last_start: u32,
+}
+
+// Lay out every note from every voice, and pick a channel for each
+fn place_notes(meta: &MidiMeta<'_>, voices: &[MidiVoice<'_>]) -> Result<Vec<Placed>, ExportError> {
+    let mut notes = vec![];
+    for (i, voice) in voices.iter().enumerate() {
+        let mut vels = voice.velocities.iter().copied();
+        let mut bar_start = 0u32;
+        for bar in voice.bars.iter() {
+            for note in bar.notes() {
+                let (key, offset) = note.pitch_tone_offset();
+                let start = bar_start + u32::from(note.ppqn_start());
+                notes.push(Placed {
+                    voice: i,
+                    start,
+                    end: start + u32::from(note.ppqn_len()),
+                    key,
+                    velocity: vels.next().unwrap_or(DEFAULT_VELOCITY),
+                    bend: bend_value(offset, meta.bend_range),
+                    channel: voice.channel,
+                    bend_change: None,
+                });
+            }
+            bar_start += u32::from(bar.ppqn_len());
+        }
+    }
+    notes.sort_by_key(|n| (n.start, n.voice));
+
+    let mut chans = [ChannelUse::default(); 16];
+    for note in notes.iter_mut() {
+        let ch = match meta.mpe_members {
+            None => {
+                let chan = &chans[usize::from(note.channel)];
+                if chan.busy_until > note.start && chan.bend != note.bend {
+                    return Err(ExportError::BendConflict(note.voice));
+                }
+                note.channel
+            }
+            Some(members) => {
+                // A free channel, preferring one already bent the right
+                // way, then the one that has been free the longest
+                (1..=members)
+                    .filter(|ch| chans[usize::from(*ch)].busy_until <= note.start)
+                    .min_by_key(|ch| {
+                        let chan = &chans[usize::from(*ch)];
+                        (chan.bend != note.bend, chan.last_start)
+                    })
+                    .ok_or(ExportError::NoFreeChannel(note.start))?
+            }
+        };
+
+        let chan = &mut chans[usize::from(ch)];
+        if chan.bend != note.bend {
+            note.bend_change = Some(note.bend);
+            chan.bend = note.bend;
+        }
+        chan.busy_until = chan.busy_until.max(note.end);
+        chan.last_start = note.start;
+        note.channel = ch;
+    }
+
+    Ok(notes)
+}
+
+/// The bend for an offset towards the next semitone, in 1/256ths
+fn bend_value(offset: u8, range: u8) -> i16 {
+    let full = 256 * i32::from(range);
+    ((i32::from(offset) * 0x2000 + (full / 2)) / full) as i16
+}
+
+fn meta_event(delta: u32, msg: MetaMessage<'_>) -> TrackEvent<'_> {
+    TrackEvent {
+        delta: delta.into(),
+        kind: TrackEventKind::Meta(msg),
+    }
+}
+
+/// Sharps (positive) or flats (negative), and whether the key is minor
+fn key_signature(key: Pitch, kind: &KeyKind) -> (i8, bool) {
+    let (tonic, minor) = match kind {
+        KeyKind::Major => (key as i8, false),
+        // Same signature as the relative major
+        KeyKind::Minor => ((key as i8 + 3) % 12, true),
+    };
+    // Walk the circle of fifths, preferring flats past six sharps
+    let fifths = (tonic * 7) % 12;
+    (if fifths > 6 { fifths - 12 } else { fifths }, minor)
+}
+
+/// Flatten a tempo map into `(tick, microseconds per quarter note)` steps.
+fn tempo_steps(tempo: &[TempoEvent]) -> Result<Vec<(u32, u32)>, ExportError> {
+    // Use a "sample rate" of 1MHz, so sample positions are in microseconds
+    let map = TempoMap::<MAX_TEMPO_EVENTS>::from_events(1_000_000, tempo)
+        .map_err(ExportError::Tempo)?;
+    let events = map.events().copied().collect::<Vec<_>>();
+
+    let mut steps = vec![];
+    let mut push = |tick, us_per_qn: u64| match u32::try_from(us_per_qn) {
+        Ok(us_per_qn) if us_per_qn <= 0xFF_FFFF => {
+            steps.push((tick, us_per_qn));
+            Ok(())
+        }
+        _ => Err(ExportError::TooSlow(tick)),
+    };
+    for (i, ev) in events.iter().enumerate() {
+        match events.get(i + 1) {
+            Some(next) if next.kind == TempoKind::Ramp => {
+                let step = u32::from(PPQN_16TH);
+                let mut tick = ev.tick;
+                while tick < next.tick {
+                    let end = (tick + step).min(next.tick);
+                    let us = map.tick_to_sample(end) - map.tick_to_sample(tick);
+                    let us_per_qn = (u64::from(us) * u64::from(PPQN)) / u64::from(end - tick);
+                    push(tick, us_per_qn)?;
+                    tick = end;
+                }
+            }
+            _ => push(ev.tick, (1_000_000u64 * 60) / u64::from(ev.bpm))?,
+        }
+    }
+
+    Ok(steps)
+}
+
+#[cfg(test)]
+mod test {
+    use midly::{Smf, TrackEventKind};
+    use thursday::{
+        bars::BarBuf,
+        phrdat::ValidatedParameters,
+        phrid::PhraseGenerator,
+        Length,
+    };
+
+    use super::*;
+    use crate::import::import_smf;
+
+    const TEMPO: [TempoEvent; 1] = [TempoEvent {
+        tick: 0,
+        bpm: 120,
+        kind: TempoKind::Step,
+    }];
+
+    // A quarter note of middle C for each offset, one after another
+    fn offsets_bar(offsets: &[u8]) -> BarBuf {
+        let mut bar = BarBuf::new();
+        for offset in offsets {
+            bar.push_note_tone(Length::Quarter, 60, *offset).unwrap();
+        }
+        bar
+    }
+
+    fn write(meta: &MidiMeta<'_>, voices: &[MidiVoice<'_>]) -> Result<Vec<u8>, ExportError> {
+        let mut out = vec![];
+        write_midi(meta, voices, &mut out)?;
+        Ok(out)
+    }
+
+    // Every controller change in a track, as (channel, controller, value)
+    fn controllers(smf: &Smf<'_>, track: usize) -> Vec<(u8, u8, u8)> {
+        smf.tracks[track]
+            .iter()
+            .filter_map(|ev| match ev.kind {
+                TrackEventKind::Midi {
+                    channel,
+                    message: MidiMessage::Controller { controller, value },
+                } => Some((channel.as_int(), controller.as_int(), value.as_int())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Select a registered parameter, set it, and deselect it again
+    fn rpn_bytes(channel: u8, (msb, lsb): (u8, u8), value: u8) -> Vec<(u8, u8, u8)> {
+        vec![
+            (channel, 101, msb),
+            (channel, 100, lsb),
+            (channel, 6, value),
+            (channel, 38, 0),
+            (channel, 101, 0x7F),
+            (channel, 100, 0x7F),
+        ]
+    }
+
+    #[test]
+    fn bend_round_trip() {
+        let offsets = [0, 64, 128, 200, 255];
+        let bars = [offsets_bar(&offsets)];
+
+        for mpe in [None, Some(4)] {
+            let mut meta = MidiMeta::new("bends", &TEMPO);
+            meta.mpe_members = mpe;
+            let data = write(&meta, &[MidiVoice::new(&bars, 0)]).unwrap();
+            let import = import_smf(&Smf::parse(&data).unwrap()).unwrap();
+
+            // With MPE, the notes are spread over the member channels
+            let mut found = import
+                .tracks
+                .iter()
+                .flat_map(|t| t.bars[0].notes())
+                .map(|n| (n.ppqn_start(), n.pitch_tone_offset()))
+                .collect::<Vec<_>>();
+            found.sort();
+            let found = found.iter().map(|(_, (tone, offset))| (*tone, *offset)).collect::<Vec<_>>();
+            assert_eq!(found, offsets.map(|o| (60, o)), "mpe: {mpe:?}");
+        }
+    }
+
+    #[test]
+    fn bend_setup() {
+        let bars = [offsets_bar(&[0, 128])];
+        let mut meta = MidiMeta::new("setup", &TEMPO);
+        meta.bend_range = 12;
+
+        // The bend range is set on the voice's channel before any notes
+        let data = write(&meta, &[MidiVoice::new(&bars, 3)]).unwrap();
+        let smf = Smf::parse(&data).unwrap();
+        assert_eq!(controllers(&smf, 1), rpn_bytes(3, RPN_BEND_RANGE, 12));
+
+        // Voices with no offsets don't need it
+        let plain = [offsets_bar(&[0, 0])];
+        let data = write(&meta, &[MidiVoice::new(&plain, 3)]).unwrap();
+        assert_eq!(controllers(&Smf::parse(&data).unwrap(), 1), []);
+
+        // MPE configures the zone, then the range of every member channel
+        meta.mpe_members = Some(2);
+        let data = write(&meta, &[MidiVoice::new(&bars, 3)]).unwrap();
+        let smf = Smf::parse(&data).unwrap();
+        let mut expected = rpn_bytes(0, RPN_MPE_CONFIG, 2);
+        expected.extend(rpn_bytes(1, RPN_BEND_RANGE, 12));
+        expected.extend(rpn_bytes(2, RPN_BEND_RANGE, 12));
+        assert_eq!(controllers(&smf, 1), expected);
+    }
+
+    #[test]
+    fn bend_errors() {
+        // Two voices sharing a channel, playing together with different bends
+        let low = [offsets_bar(&[0])];
+        let high = [offsets_bar(&[128])];
+        let voices = [MidiVoice::new(&low, 0), MidiVoice::new(&high, 0)];
+        let meta = MidiMeta::new("conflict", &TEMPO);
+        assert!(matches!(write(&meta, &voices), Err(ExportError::BendConflict(1))));
+
+        // The same bend is fine
+        let voices = [MidiVoice::new(&low, 0), MidiVoice::new(&low, 0)];
+        assert!(write(&meta, &voices).is_ok());
+
+        // With one MPE member, there's only room for one note at a time
+        let mut meta = MidiMeta::new("busy", &TEMPO);
+        meta.mpe_members = Some(1);
+        let voices = [MidiVoice::new(&low, 0), MidiVoice::new(&high, 0)];
+        assert!(matches!(write(&meta, &voices), Err(ExportError::NoFreeChannel(0))));
+
+        meta.mpe_members = Some(16);
+        assert!(matches!(write(&meta, &voices), Err(ExportError::BadBendSetup)));
+        meta.mpe_members = None;
+        meta.bend_range = 0;
+        assert!(matches!(write(&meta, &voices), Err(ExportError::BadBendSetup)));
+    }
+
+    #[test]
+    fn key_signatures() {
+        let major = |key| key_signature(key, &KeyKind::Major);
+        let minor = |key| key_signature(key, &KeyKind::Minor);
+
+        assert_eq!(major(Pitch::C), (0, false));
+        assert_eq!(major(Pitch::G), (1, false));
+        assert_eq!(major(Pitch::FSharp), (6, false));
+        assert_eq!(major(Pitch::F), (-1, false));
+        assert_eq!(major(Pitch::ASharp), (-2, false));
+        // C# major is written as Db major
+        assert_eq!(major(Pitch::CSharp), (-5, false));
+
+        // Minor keys share the signature of their relative major
+        assert_eq!(minor(Pitch::A), (0, true));
+        assert_eq!(minor(Pitch::E), (1, true));
+        assert_eq!(minor(Pitch::D), (-1, true));
+        assert_eq!(minor(Pitch::G), (-2, true));
+        assert_eq!(minor(Pitch::C), (-3, true));
+    }
+
+    #[test]
+    fn from_header() {
+        let mut gen = PhraseGenerator::new(4, ValidatedParameters::default());
+        gen.next_phrase().unwrap();
+        let header = gen.builder().build_header().unwrap();
+        let meta = MidiMeta::from_header("phrase", &header);
+
+        let sig = header.time_signature();
+        assert_eq!(meta.time_signature, (sig.numerator(), sig.denominator()));
+        assert_eq!(meta.tempo, header.tempo_events());
+        let (key, kind) = meta.key_signature.as_ref().unwrap();
+        assert_eq!(*key, header.key());
+        assert_eq!(
+            matches!(kind, KeyKind::Minor),
+            matches!(header.key_kind(), KeyKind::Minor)
+        );
+
+        // The key signature makes it into the file
+        let data = write(&meta, &[]).unwrap();
+        let smf = Smf::parse(&data).unwrap();
+        let written = smf.tracks[0].iter().find_map(|ev| match ev.kind {
+            TrackEventKind::Meta(MetaMessage::KeySignature(sharps, minor)) => Some((sharps, minor)),
+            _ => None,
+        });
+        assert_eq!(written, Some(key_signature(*key, kind)));
+    }
+
+    #[test]
+    fn validation() {
+        let bars = [offsets_bar(&[0, 0])];
+        let mut meta = MidiMeta::new("bad", &TEMPO);
+        for sig in [(0, 4), (3, 0), (3, 5)] {
+            meta.time_signature = sig;
+            let res = write(&meta, &[MidiVoice::new(&bars, 0)]);
+            assert!(matches!(res, Err(ExportError::BadTimeSignature)), "{sig:?}");
+        }
+
+        let meta = MidiMeta::new("bad", &TEMPO);
+        let ok = MidiVoice::new(&bars, 0);
+        for velocities in [&[100][..], &[100, 0], &[100, 128], &[100, 100, 100]] {
+            let voice = MidiVoice {
+                velocities,
+                ..MidiVoice::new(&bars, 1)
+            };
+            let res = write(&meta, &[MidiVoice::new(&bars, 0), voice]);
+            assert!(matches!(res, Err(ExportError::BadVelocities(1))), "{velocities:?}");
+        }
+        let voice = MidiVoice {
+            velocities: &[1, 127],
+            ..ok
+        };
+        assert!(write(&meta, &[voice]).is_ok());
+
+        // Tempos that don't fit in 24 bits. A ramp gets too slow partway.
+        let slowest = TempoEvent { bpm: 4, ..TEMPO[0] };
+        assert!(write(&MidiMeta::new("slow", &[slowest]), &[]).is_ok());
+        for (kind, at) in [(TempoKind::Step, PPQN), (TempoKind::Ramp, PPQN_16TH)] {
+            let tempo = [slowest, TempoEvent { tick: PPQN as u32, bpm: 1, kind }];
+            let res = write(&MidiMeta::new("slow", &tempo), &[]);
+            assert!(matches!(res, Err(ExportError::TooSlow(tick)) if tick == u32::from(at)), "{kind:?}");
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use std::error::Error;
+
+use midly::{MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+use thursday::{
+    groove::{Groove, GROOVE_STEPS},
+    PPQN, PPQN_16TH,
+};
+
+/// Extract a groove template from the notes in a MIDI file.
+///
+/// Every note is matched to its nearest 16th note step. The timing offset
+/// of each step is the average distance of its notes from the grid, and the
+/// velocity offset is how much louder or quieter than average they are.
+///
+/// A groove is one measure of 4/4, so the file must be in 4/4 throughout.
+/// Files with any other time signature are rejected, rather than having
+/// their measures wrapped onto the wrong steps. Files without a time
+/// signature are taken to be in 4/4, as MIDI files are by default.
+pub fn groove_from_midi(path: &str) -> Result<Groove, Box<dyn Error>> {
+    let data = std::fs::read(path)?;
+    let smf = Smf::parse(&data)?;
+    groove_from_smf(&smf)
+}
+
+/// Extract a groove template from a parsed MIDI file. See [`groove_from_midi`].
+pub fn groove_from_smf(smf: &Smf) -> Result<Groove, Box<dyn Error>> {
+    let tpq = match smf.header.timing {
+        Timing::Metrical(tpq) => u64::from(tpq.as_int()),
+        Timing::Timecode(..) => return Err("timecode MIDI files have no beat grid".into()),
+    };
+
+    let step_len = i64::from(PPQN_16TH);
+    let mut dev_sum = [0i64; GROOVE_STEPS];
+    let mut vel_sum = [0i64; GROOVE_STEPS];
+    let mut count = [0i64; GROOVE_STEPS];
+
+    for track in smf.tracks.iter() {
+        let mut tick = 0u64;
+        for ev in track.iter() {
+            tick += u64::from(ev.delta.as_int());
+            if let TrackEventKind::Meta(MetaMessage::TimeSignature(num, denom_pow, _, _)) = ev.kind {
+                if (num, denom_pow) != (4, 2) {
+                    let denom = 2u32.saturating_pow(denom_pow.into());
+                    return Err(format!("grooves are one measure of 4/4, not {num}/{denom}").into());
+                }
+                continue;
+            }
+            let TrackEventKind::Midi { message: MidiMessage::NoteOn { vel, .. }, .. } = ev.kind else {
+                continue;
+            };
+            if vel == 0 {
+                // A note on with zero velocity is a note off
+                continue;
+            }
+
+            let pos = ((tick * u64::from(PPQN)) / tpq) as i64;
+            let grid = (pos + (step_len / 2)) / step_len;
+            let step = (grid as usize) % GROOVE_STEPS;
+            dev_sum[step] += pos - (grid * step_len);
+            vel_sum[step] += i64::from(vel.as_int());
+            count[step] += 1;
+        }
+    }
+
+    let total: i64 = count.iter().sum();
+    if total == 0 {
+        return Err("no notes to take a groove from".into());
+    }
+    let avg_vel = vel_sum.iter().sum::<i64>() / total;
+
+    let mut timing = [0i8; GROOVE_STEPS];
+    let mut velocity = [0i8; GROOVE_STEPS];
+    for step in 0..GROOVE_STEPS {
+        if count[step] == 0 {
+            continue;
+        }
+        // NOTE: Keeping each offset within half a step means neighbours can
+        // never differ by more than a step, which `Groove::new` requires.
+        let lim = step_len / 2;
+        timing[step] = (dev_sum[step] / count[step]).clamp(-lim, lim) as i8;
+        velocity[step] = ((vel_sum[step] / count[step]) - avg_vel).clamp(-127, 127) as i8;
+    }
+
+    Groove::new(timing, velocity).ok_or_else(|| "groove would reorder notes".into())
+}
+
+#[cfg(test)]
+mod test {
+    use midly::{Format, Fps, Header, TrackEvent};
+
+    use super::*;
+
+    // One track of note ons, at 96 ticks per quarter note
+    fn smf(events: &[(u32, TrackEventKind<'static>)]) -> Smf<'static> {
+        let timing = Timing::Metrical(midly::num::u15::new(96));
+        let mut smf = Smf::new(Header::new(Format::SingleTrack, timing));
+        let mut last = 0;
+        let track = events
+            .iter()
+            .map(|(tick, kind)| {
+                let delta = tick - last;
+                last = *tick;
+                TrackEvent {
+                    delta: delta.into(),
+                    kind: *kind,
+                }
+            })
+            .collect();
+        smf.tracks.push(track);
+        smf
+    }
+
+    fn on(vel: u8) -> TrackEventKind<'static> {
+        TrackEventKind::Midi {
+            channel: 0.into(),
+            message: MidiMessage::NoteOn {
+                key: 60.into(),
+                vel: vel.into(),
+            },
+        }
+    }
+
+    fn time_signature(num: u8, denom_pow: u8) -> TrackEventKind<'static> {
+        TrackEventKind::Meta(MetaMessage::TimeSignature(num, denom_pow, 24, 8))
+    }
+
+    #[test]
+    fn swung_eighths() {
+        // Two measures of 8ths, with the off-beats a 48th note late and the
+        // on-beats accented
+        let mut events = vec![(0, time_signature(4, 2))];
+        for i in 0..16 {
+            let (late, vel) = if i % 2 == 0 { (0, 110) } else { (8, 70) };
+            events.push((i * 48 + late, on(vel)));
+        }
+        let groove = groove_from_smf(&smf(&events)).unwrap();
+
+        let late = (PPQN / 12) as i8;
+        for step in 0..GROOVE_STEPS {
+            let (timing, velocity) = match step % 4 {
+                0 => (0, 20),
+                2 => (late, -20),
+                _ => (0, 0),
+            };
+            assert_eq!(groove.timing()[step], timing, "{step}");
+            assert_eq!(groove.velocity()[step], velocity, "{step}");
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let waltz = smf(&[(0, time_signature(3, 2)), (0, on(100))]);
+        let err = groove_from_smf(&waltz).unwrap_err();
+        assert_eq!(err.to_string(), "grooves are one measure of 4/4, not 3/4");
+
+        // A change part way through is rejected too
+        let changes = smf(&[(0, time_signature(4, 2)), (0, on(100)), (384, time_signature(7, 3))]);
+        assert!(groove_from_smf(&changes).is_err());
+
+        assert!(groove_from_smf(&smf(&[(0, time_signature(4, 2))])).is_err());
+
+        let mut timecode = smf(&[(0, on(100))]);
+        timecode.header.timing = Timing::Timecode(Fps::Fps25, 40);
+        assert!(groove_from_smf(&timecode).is_err());
+    }
+}
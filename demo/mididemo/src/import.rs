@@ -0,0 +1,444 @@
+use std::{collections::BTreeMap, error::Error, fmt};
+
+use midly::{Format, MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+use minijam::tempo::{TempoEvent, TempoKind};
+use thursday::{
+    bars::{BarBuf, BarError},
+    Length, PPQN, PPQN_MAX,
+};
+
+/// Tones are numbered the same way as MIDI keys, matching `export::write_midi`.
+/// A note's pitch bend (as of when it starts) becomes its offset towards the
+/// next semitone, using the bend range set with RPN 0 (2 semitones if not).
+pub struct ImportedTrack {
+    /// The track in the file, starting at 0
+    pub track: usize,
+    pub channel: u8,
+    /// The first program change seen on this channel, if any
+    pub program: Option<u8>,
+    /// One `BarBuf` for every `PPQN_MAX` ticks (64 quarter notes). All but
+    /// the last are padded with a rest to the full length, so bar `n`
+    /// always starts at tick `n * PPQN_MAX`.
+    pub bars: Vec<BarBuf>,
+    /// The velocity of each note, in order
+    pub velocities: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeSignature {
+    pub tick: u32,
+    pub numerator: u8,
+    pub denominator: u8,
+}
+
+pub struct Import {
+    /// Tempo changes, rescaled to `thursday::PPQN`. There is always one at
+    /// tick 0, which is 120bpm if the file doesn't say otherwise.
+    pub tempo: Vec<TempoEvent>,
+    /// Time signature changes. There is always one at tick 0, which is 4/4
+    /// if the file doesn't say otherwise.
+    pub time_signatures: Vec<TimeSignature>,
+    /// One entry for each channel used by each track
+    pub tracks: Vec<ImportedTrack>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ImportError {
+    /// Timecode (SMPTE) files have no beat grid to rescale
+    TimecodeTiming,
+    /// Format 2 files hold independent patterns rather than one song
+    SequentialFormat,
+    /// A note started before the last one on the same channel ended. Only
+    /// one note can sound at a time in a `BarBuf`.
+    Overlap { track: usize, channel: u8, tick: u32 },
+    /// The notes of a channel couldn't be stored in `BarBuf`s
+    Bar { track: usize, channel: u8, err: BarError },
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportError::TimecodeTiming => write!(f, "timecode MIDI files have no beat grid"),
+            ImportError::SequentialFormat => write!(f, "format 2 MIDI files are not supported"),
+            ImportError::Overlap { track, channel, tick } => write!(
+                f,
+                "overlapping notes on track {track}, channel {channel} at tick {tick}"
+            ),
+            ImportError::Bar { track, channel, err } => {
+                write!(f, "track {track}, channel {channel}: {err:?}")
+            }
+        }
+    }
+}
+
+impl Error for ImportError {}
+
+// The bend range a channel has until it is changed, in semitones
+const DEFAULT_BEND_RANGE: u8 = 2;
+
+// A finished note: start and end in ticks, tone, offset and velocity
+type Span = (u32, u32, u8, u8, u8);
+
+// The state of a channel, which is shared by every track
+struct Controls {
+    bend: i16,
+    bend_range: u8,
+    // The registered parameter selected with controllers 101 and 100
+    rpn: (u8, u8),
+}
+
+impl Default for Controls {
+    fn default() -> Self {
+        Self {
+            bend: 0,
+            bend_range: DEFAULT_BEND_RANGE,
+            rpn: (0x7F, 0x7F),
+        }
+    }
+}
+
+impl Controls {
+    // The tone and offset of a key, with the current bend applied
+    fn bent(&self, key: u8) -> (u8, u8) {
+        let bend = i32::from(self.bend) * 256 * i32::from(self.bend_range);
+        let bend = (bend + 0x1000).div_euclid(0x2000);
+        let pitch = (i32::from(key) * 256 + bend).clamp(0, (0x7F * 256) + 0xFF);
+        ((pitch / 256) as u8, (pitch % 256) as u8)
+    }
+}
+
+// The notes of one channel in one track
+#[derive(Default)]
+struct Voice {
+    program: Option<u8>,
+    // Start tick, key, tone, offset and velocity of the sounding note
+    active: Option<(u32, u8, u8, u8, u8)>,
+    notes: Vec<Span>,
+}
+
+/// Read a Standard MIDI File into `BarBuf`s.
+pub fn import_midi(path: &str) -> Result<Import, Box<dyn Error>> {
+    let data = std::fs::read(path)?;
+    let smf = Smf::parse(&data)?;
+    Ok(import_smf(&smf)?)
+}
+
+pub fn import_smf(smf: &Smf) -> Result<Import, ImportError> {
+    let tpq = match smf.header.timing {
+        Timing::Metrical(tpq) => u64::from(tpq.as_int()),
+        Timing::Timecode(..) => return Err(ImportError::TimecodeTiming),
+    };
+    if smf.header.format == Format::Sequential {
+        return Err(ImportError::SequentialFormat);
+    }
+
+    // Rescale from the file's ticks to ours, rounding to the nearest tick
+    let rescale = |tick: u64| ((tick * u64::from(PPQN) + (tpq / 2)) / tpq) as u32;
+
+    // Controllers and bends on a channel affect every track, so go through
+    // the events of all the tracks in time order
+    let mut events = vec![];
+    let mut track_ends = vec![];
+    for (track, evs) in smf.tracks.iter().enumerate() {
+        let mut tick = 0u64;
+        for ev in evs.iter() {
+            tick += u64::from(ev.delta.as_int());
+            events.push((tick, track, ev.kind));
+        }
+        track_ends.push(rescale(tick));
+    }
+    events.sort_by_key(|(tick, track, _)| (*tick, *track));
+
+    let mut tempo = vec![];
+    let mut time_signatures = vec![];
+    let mut controls: [Controls; 16] = Default::default();
+    let mut voices: BTreeMap<(usize, u8), Voice> = BTreeMap::new();
+
+    for (tick, track, kind) in events {
+        let now = rescale(tick);
+
+        match kind {
+            TrackEventKind::Meta(MetaMessage::Tempo(us_per_qn)) => {
+                let us_per_qn = u64::from(us_per_qn.as_int()).max(1);
+                tempo.push(TempoEvent {
+                    tick: now,
+                    bpm: ((60_000_000 + (us_per_qn / 2)) / us_per_qn) as u32,
+                    kind: TempoKind::Step,
+                });
+            }
+            TrackEventKind::Meta(MetaMessage::TimeSignature(num, denom_pow, _, _)) => {
+                time_signatures.push(TimeSignature {
+                    tick: now,
+                    numerator: num,
+                    denominator: 1u8.checked_shl(denom_pow.into()).unwrap_or(0),
+                });
+            }
+            TrackEventKind::Midi { channel, message } => {
+                let ch = channel.as_int();
+                let ctl = &mut controls[usize::from(ch)];
+                let (key, vel) = match message {
+                    MidiMessage::ProgramChange { program } => {
+                        let voice = voices.entry((track, ch)).or_default();
+                        voice.program.get_or_insert(program.as_int());
+                        continue;
+                    }
+                    MidiMessage::PitchBend { bend } => {
+                        ctl.bend = bend.as_int();
+                        continue;
+                    }
+                    MidiMessage::Controller { controller, value } => {
+                        match (controller.as_int(), value.as_int()) {
+                            (101, msb) => ctl.rpn.0 = msb,
+                            (100, lsb) => ctl.rpn.1 = lsb,
+                            // Bend range, in semitones
+                            (6, range) if ctl.rpn == (0, 0) => ctl.bend_range = range,
+                            _ => {}
+                        }
+                        continue;
+                    }
+                    MidiMessage::NoteOn { key, vel } => (key.as_int(), vel.as_int()),
+                    MidiMessage::NoteOff { key, .. } => (key.as_int(), 0),
+                    _ => continue,
+                };
+
+                let voice = voices.entry((track, ch)).or_default();
+                if vel != 0 {
+                    if voice.active.is_some() {
+                        return Err(ImportError::Overlap {
+                            track,
+                            channel: ch,
+                            tick: now,
+                        });
+                    }
+                    let (tone, offset) = ctl.bent(key);
+                    voice.active = Some((now, key, tone, offset, vel));
+                } else if let Some((start, _, tone, offset, vel)) =
+                    voice.active.filter(|a| a.1 == key)
+                {
+                    voice.notes.push((start, now, tone, offset, vel));
+                    voice.active = None;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut tracks = vec![];
+    for ((track, channel), mut voice) in voices {
+        // End anything still sounding at the end of the track
+        if let Some((start, _, tone, offset, vel)) = voice.active.take() {
+            voice.notes.push((start, track_ends[track], tone, offset, vel));
+        }
+        if voice.notes.is_empty() {
+            continue;
+        }
+        // Every key (bent or not) is a valid tone, and every note is split
+        // to fit, so this only fails if a `BarBuf` runs out of room
+        let (bars, velocities) =
+            segment(&voice.notes).map_err(|err| ImportError::Bar { track, channel, err })?;
+        tracks.push(ImportedTrack {
+            track,
+            channel,
+            program: voice.program,
+            bars,
+            velocities,
+        });
+    }
+
+    // Later events win if two land on the same tick
+    tempo.sort_by_key(|e| e.tick);
+    tempo.reverse();
+    tempo.dedup_by_key(|e| e.tick);
+    tempo.reverse();
+    if tempo.first().map(|e| e.tick) != Some(0) {
+        tempo.insert(
+            0,
+            TempoEvent {
+                tick: 0,
+                bpm: 120,
+                kind: TempoKind::Step,
+            },
+        );
+    }
+
+    time_signatures.sort_by_key(|t| t.tick);
+    time_signatures.reverse();
+    time_signatures.dedup_by_key(|t| t.tick);
+    time_signatures.reverse();
+    if time_signatures.first().map(|t| t.tick) != Some(0) {
+        time_signatures.insert(
+            0,
+            TimeSignature {
+                tick: 0,
+                numerator: 4,
+                denominator: 4,
+            },
+        );
+    }
+
+    Ok(Import {
+        tempo,
+        time_signatures,
+        tracks,
+    })
+}
+
+// Lay out notes over as many `BarBuf`s as needed. Notes that cross from
+// one to the next are split in two.
+fn segment(notes: &[Span]) -> Result<(Vec<BarBuf>, Vec<u8>), BarError> {
+    let max = u32::from(PPQN_MAX);
+    let mut bars: Vec<BarBuf> = vec![];
+    let mut velocities = vec![];
+
+    for &(start, end, tone, offset, vel) in notes {
+        // Very short notes may round down to nothing
+        let mut start = start;
+        let end = end.max(start + 1);
+
+        while start < end {
+            let seg = (start / max) as usize;
+            while bars.len() <= seg {
+                if let Some(last) = bars.last_mut() {
+                    pad(last, max)?;
+                }
+                bars.push(BarBuf::new());
+            }
+            let bar = &mut bars[seg];
+            let bar_start = (seg as u32) * max;
+            let piece_end = end.min(bar_start + max);
+
+            pad(bar, start - bar_start)?;
+            let len = Length::PPQNCount((piece_end - start) as u16);
+            bar.push_note_tone(len, tone, offset)?;
+            velocities.push(vel);
+            start = piece_end;
+        }
+    }
+
+    Ok((bars, velocities))
+}
+
+// Rest until `tick`, if we aren't there already
+fn pad(bar: &mut BarBuf, tick: u32) -> Result<(), BarError> {
+    let len = tick.saturating_sub(bar.ppqn_len().into());
+    if len != 0 {
+        bar.push_rest_simple(Length::PPQNCount(len as u16))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use midly::{Fps, Header, TrackEvent};
+
+    use super::*;
+
+    fn smf(format: Format, timing: Timing, events: &[(u32, TrackEventKind<'static>)]) -> Smf<'static> {
+        let mut smf = Smf::new(Header::new(format, timing));
+        let mut last = 0;
+        let track = events
+            .iter()
+            .map(|(tick, kind)| {
+                let delta = tick - last;
+                last = *tick;
+                TrackEvent {
+                    delta: delta.into(),
+                    kind: *kind,
+                }
+            })
+            .collect();
+        smf.tracks.push(track);
+        smf
+    }
+
+    fn on(key: u8, vel: u8) -> TrackEventKind<'static> {
+        TrackEventKind::Midi {
+            channel: 0.into(),
+            message: MidiMessage::NoteOn {
+                key: key.into(),
+                vel: vel.into(),
+            },
+        }
+    }
+
+    fn off(key: u8) -> TrackEventKind<'static> {
+        on(key, 0)
+    }
+
+    // (start, length, tone) of each note in a bar
+    fn notes(bar: &BarBuf) -> Vec<(u16, u16, u8)> {
+        bar.notes()
+            .map(|n| (n.ppqn_start(), n.ppqn_len(), n.pitch_tone_offset().0))
+            .collect()
+    }
+
+    const METRICAL_96: Timing = Timing::Metrical(midly::num::u15::new(96));
+
+    #[test]
+    fn rescale() {
+        // 96 ticks per quarter note in the file, `PPQN` in ours
+        let file = smf(
+            Format::SingleTrack,
+            METRICAL_96,
+            &[(0, on(60, 100)), (96, off(60)), (144, on(62, 90)), (192, off(62))],
+        );
+        let import = import_smf(&file).unwrap();
+        assert_eq!(import.tempo[0].bpm, 120);
+        assert_eq!(import.time_signatures[0].numerator, 4);
+
+        let track = &import.tracks[0];
+        assert_eq!(track.bars.len(), 1);
+        assert_eq!(track.velocities, [100, 90]);
+        let q = PPQN;
+        assert_eq!(notes(&track.bars[0]), [(0, q, 60), (q + q / 2, q / 2, 62)]);
+    }
+
+    #[test]
+    fn split_at_max() {
+        // One note 65 quarter notes long, then another in the second bar
+        let file = smf(
+            Format::SingleTrack,
+            METRICAL_96,
+            &[(0, on(48, 100)), (65 * 96, off(48)), (66 * 96, on(50, 80)), (67 * 96, off(50))],
+        );
+        let import = import_smf(&file).unwrap();
+        let track = &import.tracks[0];
+        let q = PPQN;
+
+        assert_eq!(track.bars.len(), 2);
+        assert_eq!(track.bars[0].ppqn_len(), PPQN_MAX);
+        assert_eq!(notes(&track.bars[0]), [(0, PPQN_MAX, 48)]);
+        // The rest of the long note, a rest, then the next note
+        assert_eq!(notes(&track.bars[1]).first(), Some(&(0, q, 48)));
+        assert_eq!(notes(&track.bars[1]).last(), Some(&(2 * q, q, 50)));
+        // The split note keeps its velocity in both halves
+        assert_eq!(track.velocities, [100, 100, 80]);
+    }
+
+    #[test]
+    fn errors() {
+        let overlap = smf(
+            Format::SingleTrack,
+            METRICAL_96,
+            &[(0, on(60, 100)), (48, on(64, 100)), (96, off(60))],
+        );
+        assert_eq!(
+            import_smf(&overlap).err(),
+            Some(ImportError::Overlap {
+                track: 0,
+                channel: 0,
+                tick: u32::from(PPQN / 2)
+            })
+        );
+
+        let sequential = smf(Format::Sequential, METRICAL_96, &[(0, on(60, 100)), (96, off(60))]);
+        assert_eq!(import_smf(&sequential).err(), Some(ImportError::SequentialFormat));
+
+        let timecode = smf(
+            Format::SingleTrack,
+            Timing::Timecode(Fps::Fps25, 40),
+            &[(0, on(60, 100)), (96, off(60))],
+        );
+        assert_eq!(import_smf(&timecode).err(), Some(ImportError::TimecodeTiming));
+    }
+}
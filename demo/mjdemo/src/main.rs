@@ -5,6 +5,8 @@ use minijam::{
         NATURAL_MINOR_INTERVALS,
     },
     tones::{ToneKind, Operator, OperatorKind, Tone},
+    clock::{Clock, PPQN},
+    wav::{IoSink, WavWriter},
     Sample, StereoSample, Track,
 };
 // use userspace::common::porcelain::{
@@ -12,8 +14,9 @@ use minijam::{
 //     time,
 //     system,
 // };
-use rand::{thread_rng, RngCore};
-use wav::{BitDepth, Header, WAV_FORMAT_PCM};
+use rand::{thread_rng, RngCore, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use std::{fs::File, io::BufWriter};
 
 // type MjVec<T, const N: usize> = heapless::Vec<T, N>;
 type MjVec<T, const N: usize> = Vec<T>;
@@ -38,12 +41,12 @@ pub enum Length {
 }
 
 impl Length {
-    fn qlen_to_notes(&self, qlen: u32) -> u32 {
+    fn to_ticks(&self) -> u32 {
         match self {
-            Length::Eighth => qlen / 2,
-            Length::Quarter => qlen,
-            Length::Half => qlen * 2,
-            Length::Whole => qlen * 4,
+            Length::Eighth => PPQN / 2,
+            Length::Quarter => PPQN,
+            Length::Half => PPQN * 2,
+            Length::Whole => PPQN * 4,
         }
     }
 }
@@ -59,7 +62,7 @@ impl<const N: usize> MetaTrack<N> {
             chance: 0,
             length,
             notes,
-            operator: Operator { kind: OperatorKind::None },
+            operator: Operator::new(OperatorKind::None),
         };
 
         me.gen_voice(rng);
@@ -80,9 +83,9 @@ impl<const N: usize> MetaTrack<N> {
     pub fn gen_operator<R: RngCore>(&mut self, rng: &mut R) {
         let wobble = rng.next_u32() % 64;
         if wobble < 32 {
-            self.operator = Operator { kind: OperatorKind::AmplitudeLfo(Tone::new_sine(wobble as f32, 44100)) };
+            self.operator = Operator::new(OperatorKind::AmplitudeLfo(Tone::new_sine(wobble as f32, 44100)));
         } else {
-            self.operator = Operator { kind: OperatorKind::None };
+            self.operator = Operator::new(OperatorKind::None);
         }
     }
 
@@ -137,8 +140,8 @@ impl<const N: usize> MetaTrack<N> {
         scale: &[Semitones],
         key: Pitch,
     ) {
-        let full_length = (self.track.sample_rate * 60) / bpm;
-        let full_length = self.length.qlen_to_notes(full_length);
+        let clock = Clock::new(self.track.sample_rate, bpm).unwrap();
+        let full_length = self.length.to_ticks();
         let note_length = (full_length * 9) / 10;
         let mut cur = 0;
 
@@ -148,7 +151,7 @@ impl<const N: usize> MetaTrack<N> {
         for n in self.refrain.iter() {
             if let Some(note) = n {
                 self.track
-                    .add_note(self.voice, *note, cur, cur + note_length)
+                    .add_note_ticks(&clock, self.voice, *note, cur, note_length)
                     .unwrap();
             }
             cur += full_length;
@@ -173,7 +176,7 @@ impl<const N: usize> MetaTrack<N> {
 
                 // TODO: Extend last one? Add a rest?
                 self.track
-                    .add_note(self.voice, note, cur, cur + note_length)
+                    .add_note_ticks(&clock, self.voice, note, cur, note_length)
                     .unwrap();
             }
             cur += full_length;
@@ -205,15 +208,15 @@ impl<const N: usize, const M: usize> MetaChorus<N, M> {
 
     pub fn fill_tracks(&mut self, bpm: u32) {
         self.tracks.iter_mut().for_each(|t| {
-            let full_length = (t.track.sample_rate * 60) / bpm;
-            let full_length = t.length.qlen_to_notes(full_length);
+            let clock = Clock::new(t.track.sample_rate, bpm).unwrap();
+            let full_length = t.length.to_ticks();
             let note_length = (full_length * 9) / 10;
             let mut cur = 0;
 
             for n in t.refrain.iter() {
                 if let Some(note) = n {
                     t.track
-                        .add_note(t.voice, *note, cur, cur + note_length)
+                        .add_note_ticks(&clock, t.voice, *note, cur, note_length)
                         .unwrap();
                 }
                 cur += full_length;
@@ -445,7 +448,13 @@ impl<R: RngCore, const N: usize, const M: usize> Conductor<R, N, M> {
 }
 
 pub fn main() {
-    let mut rng = thread_rng();
+    // Pass the seed from a previous run to hear the same jam again
+    let seed = match std::env::args().nth(1) {
+        Some(arg) => arg.parse().expect("the seed should be a number"),
+        None => thread_rng().next_u64(),
+    };
+    println!("seed: {seed}");
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
 
     let mut conductor: Conductor<_, 128, 3> = Conductor {
         lead_1: MetaTrack::new(44100, Length::Quarter, 32, &mut rng),
@@ -465,7 +474,8 @@ pub fn main() {
         is_major: true,
     };
 
-    let mut all_samples: Vec<StereoSample> = Vec::with_capacity(44100 * 180);
+    let out_file = BufWriter::new(File::create("target/jam.wav").unwrap());
+    let mut wav = WavWriter::new(IoSink(out_file), 44100, 2).unwrap();
 
     conductor.pick_scale();
     conductor.chorus.set_min_chances(0x6000_0000);
@@ -510,27 +520,15 @@ pub fn main() {
                 .track
                 .fill_stereo_samples(&mut samples, minijam::tones::Mix::Div8, &mut conductor.chorus.tracks[2].operator);
 
-            all_samples.extend_from_slice(&samples);
+            wav.write_stereo(&samples).unwrap();
         }
 
-        if all_samples.len() >= (44100 * 180) {
+        if wav.frames() >= (44100 * 180) {
             break;
         }
     }
 
-    use std::fs::File;
-    use std::path::Path;
-
-    let header = Header::new(WAV_FORMAT_PCM, 2, 44100, 16);
-
-    let mut new_data: Vec<i16> = Vec::new();
-    for samp in all_samples.iter() {
-        new_data.push(unsafe { samp.left.word });
-        new_data.push(unsafe { samp.right.word });
-    }
-
-    let mut out_file = File::create(Path::new("target/jam.wav")).unwrap();
-    wav::write(header, &BitDepth::Sixteen(new_data), &mut out_file).unwrap();
+    wav.finish().unwrap();
 }
 
 fn fill_chord2<R: RngCore, const N: usize>(
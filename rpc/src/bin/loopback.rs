@@ -0,0 +1,42 @@
+//! Serve a stand-in device, for testing hosts.
+//!
+//! With no arguments, this talks over stdin/stdout. Given a path (like one
+//! end of a pseudo-terminal pair, made with
+//! `socat -d -d pty,raw,echo=0 pty,raw,echo=0`), it talks over that instead.
+
+use std::{
+    fs::OpenOptions,
+    io::{stdin, stdout, Read, Write},
+};
+
+use minijam_rpc::{device::Device, loopback::serve};
+
+struct Stdio;
+
+impl Read for Stdio {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        stdin().read(buf)
+    }
+}
+
+impl Write for Stdio {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        stdout().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        stdout().flush()
+    }
+}
+
+fn main() -> std::io::Result<()> {
+    let mut device: Device<8, 64> = Device::new(44100, 120).unwrap();
+
+    match std::env::args().nth(1) {
+        Some(path) => {
+            let port = OpenOptions::new().read(true).write(true).open(path)?;
+            serve(&mut device, port)
+        }
+        None => serve(&mut device, Stdio),
+    }
+}
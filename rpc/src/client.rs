@@ -0,0 +1,206 @@
+//! The host side: sends requests over any byte stream, and waits for the
+//! matching response.
+
+use std::io::{self, Read, Write};
+
+use minijam::NoteError;
+use thursday::EncNote;
+
+use crate::{
+    frame::{self, Decoder, FrameError, MAX_FRAME, MAX_PAYLOAD},
+    proto::{ErrorCode, ProtoError, Rejected, Request, Response, Transport},
+};
+
+// Version, sequence number, opcode, track and bar tick
+const QUEUE_NOTES_HEADER: usize = 3 + 1 + 4;
+
+/// How many of the notes given to [`Client::queue_notes`] were queued
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Queued {
+    pub accepted: usize,
+    /// The index of the first note the track rejected, and why
+    pub rejected: Option<(usize, NoteError)>,
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    Io(io::Error),
+    Frame(FrameError),
+    Proto(ProtoError),
+    /// The device could not carry out the request
+    Device(ErrorCode),
+    /// The device replied with the wrong kind of response
+    Unexpected(Response),
+}
+
+impl From<io::Error> for ClientError {
+    fn from(err: io::Error) -> Self {
+        ClientError::Io(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub sample: u32,
+    pub tick: u32,
+    pub playing: bool,
+}
+
+pub struct Client<S: Read + Write> {
+    stream: S,
+    seq: u8,
+    decoder: Decoder,
+    rx: [u8; 64],
+    rx_start: usize,
+    rx_end: usize,
+}
+
+impl<S: Read + Write> Client<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            seq: 0,
+            decoder: Decoder::new(),
+            rx: [0; 64],
+            rx_start: 0,
+            rx_end: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    fn next_byte(&mut self) -> Result<u8, ClientError> {
+        if self.rx_start == self.rx_end {
+            let used = self.stream.read(&mut self.rx)?;
+            if used == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            self.rx_start = 0;
+            self.rx_end = used;
+        }
+        let byte = self.rx[self.rx_start];
+        self.rx_start += 1;
+        Ok(byte)
+    }
+
+    /// Send a request, and wait for its response.
+    ///
+    /// Responses to other requests (and corrupted frames) are skipped.
+    pub fn call(&mut self, req: &Request<'_>) -> Result<Response, ClientError> {
+        self.seq = self.seq.wrapping_add(1);
+        let mut payload = [0u8; MAX_PAYLOAD];
+        let used = req.encode(self.seq, &mut payload).map_err(ClientError::Proto)?;
+        let mut out = [0u8; MAX_FRAME];
+        let used = frame::encode(&payload[..used], &mut out).map_err(ClientError::Frame)?;
+        self.stream.write_all(&out[..used])?;
+        self.stream.flush()?;
+
+        loop {
+            let byte = self.next_byte()?;
+            let Some(Ok(payload)) = self.decoder.push(byte) else {
+                continue;
+            };
+            match Response::decode(payload) {
+                Ok((seq, resp)) if seq == self.seq => {
+                    return match resp {
+                        Response::Error(code) => Err(ClientError::Device(code)),
+                        resp => Ok(resp),
+                    };
+                }
+                Ok(_) => continue,
+                Err(e) => return Err(ClientError::Proto(e)),
+            }
+        }
+    }
+
+    fn call_ok(&mut self, req: &Request<'_>) -> Result<(), ClientError> {
+        match self.call(req)? {
+            Response::Ok => Ok(()),
+            other => Err(ClientError::Unexpected(other)),
+        }
+    }
+
+    /// Create a track, returning its id
+    pub fn create_track(&mut self, kind: minijam::tones::ToneKind) -> Result<u8, ClientError> {
+        match self.call(&Request::CreateTrack { kind })? {
+            Response::TrackCreated { track } => Ok(track),
+            other => Err(ClientError::Unexpected(other)),
+        }
+    }
+
+    /// Queue the notes of a bar starting at `bar_tick`, splitting them over
+    /// as many requests as needed. Returns how many notes were accepted, and
+    /// the first that was not.
+    pub fn queue_notes(&mut self, track: u8, bar_tick: u32, notes: &[EncNote]) -> Result<Queued, ClientError> {
+        let mut buf = [0u8; MAX_PAYLOAD - QUEUE_NOTES_HEADER];
+        let mut used = 0;
+        // The index of the first note in `buf`
+        let mut first = 0;
+        let mut queued = Queued { accepted: 0, rejected: None };
+
+        for (i, note) in notes.iter().enumerate() {
+            let fits = note.write_to_slice(&mut buf[used..]).map(|rem| rem.len());
+            let remain = match fits {
+                Ok(remain) => remain,
+                Err(_) => {
+                    // Full, send what we have so far
+                    self.send_notes(track, bar_tick, &buf[..used], first, &mut queued)?;
+                    first = i;
+                    note.write_to_slice(&mut buf)
+                        .map_err(|_| ClientError::Proto(ProtoError::BufferTooSmall))?
+                        .len()
+                }
+            };
+            used = buf.len() - remain;
+        }
+        if used != 0 {
+            self.send_notes(track, bar_tick, &buf[..used], first, &mut queued)?;
+        }
+        Ok(queued)
+    }
+
+    fn send_notes(
+        &mut self,
+        track: u8,
+        bar_tick: u32,
+        notes: &[u8],
+        first: usize,
+        queued: &mut Queued,
+    ) -> Result<(), ClientError> {
+        match self.call(&Request::QueueNotes { track, bar_tick, notes })? {
+            Response::Queued { count, rejected } => {
+                queued.accepted += usize::from(count);
+                if let (None, Some(Rejected { index, reason })) = (queued.rejected, rejected) {
+                    queued.rejected = Some((first + usize::from(index), reason));
+                }
+                Ok(())
+            }
+            other => Err(ClientError::Unexpected(other)),
+        }
+    }
+
+    pub fn set_tempo(&mut self, tick: u32, bpm: u16) -> Result<(), ClientError> {
+        self.call_ok(&Request::SetTempo { tick, bpm })
+    }
+
+    pub fn play(&mut self) -> Result<(), ClientError> {
+        self.call_ok(&Request::Transport(Transport::Play))
+    }
+
+    pub fn pause(&mut self) -> Result<(), ClientError> {
+        self.call_ok(&Request::Transport(Transport::Pause))
+    }
+
+    pub fn stop(&mut self) -> Result<(), ClientError> {
+        self.call_ok(&Request::Transport(Transport::Stop))
+    }
+
+    pub fn position(&mut self) -> Result<Position, ClientError> {
+        match self.call(&Request::QueryPosition)? {
+            Response::Position { sample, tick, playing } => Ok(Position { sample, tick, playing }),
+            other => Err(ClientError::Unexpected(other)),
+        }
+    }
+}
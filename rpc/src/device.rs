@@ -0,0 +1,319 @@
+//! The device side: carries out requests on a set of `minijam` tracks.
+
+use heapless::Vec;
+use minijam::{
+    tempo::TempoMap,
+    tones::{Mix, Operator, OperatorKind, ToneKind},
+    StereoSample, Track,
+};
+use thursday::PlayNote;
+
+use crate::{
+    frame::{self, FrameError, MAX_FRAME, MAX_PAYLOAD},
+    proto::{self, ErrorCode, Rejected, Request, Response, Transport},
+};
+
+/// The most tempo changes the device will remember
+pub const MAX_TEMPO_CHANGES: usize = 32;
+
+struct DeviceTrack<const DEPTH: usize> {
+    track: Track<DEPTH>,
+    kind: ToneKind,
+    operator: Operator,
+}
+
+/// A sequencer with up to `TRACKS` tracks, each holding up to `DEPTH`
+/// queued notes.
+pub struct Device<const TRACKS: usize, const DEPTH: usize> {
+    sample_rate: u32,
+    tracks: Vec<DeviceTrack<DEPTH>, TRACKS>,
+    tempo: TempoMap<MAX_TEMPO_CHANGES>,
+    sample: u32,
+    playing: bool,
+}
+
+impl<const TRACKS: usize, const DEPTH: usize> Device<TRACKS, DEPTH> {
+    /// Returns `None` if the sample rate or bpm is zero
+    pub fn new(sample_rate: u32, bpm: u32) -> Option<Self> {
+        Some(Self {
+            sample_rate,
+            tracks: Vec::new(),
+            tempo: TempoMap::new(sample_rate, bpm).ok()?,
+            sample: 0,
+            playing: false,
+        })
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    /// The current position, in samples
+    pub fn sample(&self) -> u32 {
+        self.sample
+    }
+
+    /// The current position, in ticks
+    pub fn tick(&self) -> u32 {
+        self.tempo.sample_to_tick(self.sample)
+    }
+
+    pub fn tempo(&self) -> &TempoMap<MAX_TEMPO_CHANGES> {
+        &self.tempo
+    }
+
+    /// Carry out a single request
+    pub fn handle(&mut self, req: Request<'_>) -> Response {
+        match req {
+            Request::CreateTrack { kind } => {
+                let mut track = Track::new(self.sample_rate);
+                track.cur_samp = self.sample;
+                let dtrack = DeviceTrack {
+                    track,
+                    kind,
+                    operator: Operator::new(OperatorKind::None),
+                };
+                if self.tracks.push(dtrack).is_err() {
+                    return Response::Error(ErrorCode::TooManyTracks);
+                }
+                Response::TrackCreated {
+                    track: (self.tracks.len() - 1) as u8,
+                }
+            }
+            Request::QueueNotes { track, bar_tick, notes } => {
+                let Some(dtrack) = self.tracks.get_mut(track as usize) else {
+                    return Response::Error(ErrorCode::NoSuchTrack);
+                };
+                // Check every note decodes before queueing any, so a bad
+                // request changes nothing
+                if proto::notes(notes).any(|note| note.is_err()) {
+                    return Response::Error(ErrorCode::Malformed);
+                }
+
+                let mut count = 0;
+                let mut rejected = None;
+                for (index, note) in proto::notes(notes).flatten().enumerate() {
+                    let play = PlayNote::from_enc(&note, 0);
+                    let res = dtrack.track.add_note_freq_ticks(
+                        &self.tempo,
+                        dtrack.kind,
+                        play.frequency(),
+                        bar_tick.saturating_add(play.start.into()),
+                        play.length.into(),
+                    );
+                    match res {
+                        Ok(()) => count += 1,
+                        Err(reason) => {
+                            rejected = rejected.or(Some(Rejected {
+                                index: index as u16,
+                                reason,
+                            }));
+                        }
+                    }
+                }
+                Response::Queued { count, rejected }
+            }
+            Request::SetTempo { tick, bpm } => {
+                let bpm = bpm as u32;
+                let res = if tick == 0 && self.tempo.events().count() == 1 {
+                    // Changing the starting tempo
+                    TempoMap::new(self.sample_rate, bpm).map(|map| self.tempo = map)
+                } else {
+                    self.tempo.set_tempo(tick, bpm)
+                };
+                match res {
+                    Ok(()) => Response::Ok,
+                    Err(_) => Response::Error(ErrorCode::BadTempo),
+                }
+            }
+            Request::Transport(Transport::Play) => {
+                self.playing = true;
+                Response::Ok
+            }
+            Request::Transport(Transport::Pause) => {
+                self.playing = false;
+                Response::Ok
+            }
+            Request::Transport(Transport::Stop) => {
+                self.playing = false;
+                self.sample = 0;
+                self.tracks.iter_mut().for_each(|t| t.track.reset());
+                Response::Ok
+            }
+            Request::QueryPosition => Response::Position {
+                sample: self.sample,
+                tick: self.tick(),
+                playing: self.playing,
+            },
+        }
+    }
+
+    /// Handle one frame's payload, writing a framed response into `out`.
+    ///
+    /// Returns the number of bytes of `out` used.
+    pub fn handle_payload(&mut self, payload: &[u8], out: &mut [u8]) -> Result<usize, FrameError> {
+        let (seq, resp) = match Request::decode(payload) {
+            Ok((seq, req)) => (seq, self.handle(req)),
+            // Do our best to echo the sequence number of a bad request
+            Err(e) => (payload.get(1).copied().unwrap_or(0), Response::Error(e.into())),
+        };
+
+        let mut buf = [0u8; MAX_PAYLOAD];
+        let used = resp
+            .encode(seq, &mut buf)
+            .map_err(|_| FrameError::BufferTooSmall)?;
+        frame::encode(&buf[..used], out)
+    }
+
+    /// Feed bytes received from the host, calling `reply` with each framed
+    /// response. Frames that fail their checksum are dropped, as we can't
+    /// trust their sequence number.
+    pub fn feed<F: FnMut(&[u8])>(&mut self, decoder: &mut frame::Decoder, bytes: &[u8], mut reply: F) {
+        let mut out = [0u8; MAX_FRAME];
+        for &byte in bytes {
+            let Some(Ok(payload)) = decoder.push(byte) else {
+                continue;
+            };
+            if let Ok(used) = self.handle_payload(payload, &mut out) {
+                reply(&out[..used]);
+            }
+        }
+    }
+
+    /// Render the next block of audio from all tracks, if playing.
+    ///
+    /// When paused, the samples are left untouched, and the position does
+    /// not move.
+    pub fn fill_stereo_samples(&mut self, samples: &mut [StereoSample], mix: Mix) {
+        if !self.playing {
+            return;
+        }
+        for dtrack in self.tracks.iter_mut() {
+            dtrack.track.fill_stereo_samples(samples, mix, &mut dtrack.operator);
+        }
+        self.sample += samples.len() as u32;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use minijam::{scale::Pitch, NoteError, Sample};
+    use thursday::{EncNote, Length, PPQN_WHOLE};
+
+    fn encode_notes(notes: &[EncNote], buf: &mut [u8]) -> usize {
+        let total = buf.len();
+        let remain = notes
+            .iter()
+            .try_fold(buf, |buf, n| n.write_to_slice(buf))
+            .unwrap()
+            .len();
+        total - remain
+    }
+
+    #[test]
+    fn plays_queued_notes() {
+        let mut dev: Device<2, 8> = Device::new(8000, 120).unwrap();
+        assert_eq!(
+            dev.handle(Request::CreateTrack { kind: ToneKind::Square }),
+            Response::TrackCreated { track: 0 }
+        );
+        assert_eq!(
+            dev.handle(Request::QueueNotes { track: 1, bar_tick: 0, notes: &[] }),
+            Response::Error(ErrorCode::NoSuchTrack)
+        );
+
+        let notes = [
+            EncNote::new_simple(Pitch::A, 4, 0, Length::Quarter).unwrap(),
+            EncNote::new_simple(Pitch::C, 5, 192, Length::Quarter).unwrap(),
+        ];
+        let mut buf = [0u8; 32];
+        let used = encode_notes(&notes, &mut buf);
+        let req = Request::QueueNotes {
+            track: 0,
+            bar_tick: PPQN_WHOLE as u32,
+            notes: &buf[..used],
+        };
+        assert_eq!(dev.handle(req), Response::Queued { count: 2, rejected: None });
+        // Queueing them again would overlap
+        assert_eq!(
+            dev.handle(req),
+            Response::Queued {
+                count: 0,
+                rejected: Some(Rejected { index: 0, reason: NoteError::OutOfOrder }),
+            }
+        );
+
+        // Silent while paused
+        let empty = Sample { word: 0 };
+        let mut samples = [StereoSample { left: empty, right: empty }; 4000];
+        dev.fill_stereo_samples(&mut samples, Mix::Div1);
+        assert_eq!(dev.sample(), 0);
+
+        dev.handle(Request::Transport(Transport::Play));
+        let mut loud = false;
+        for _ in 0..6 {
+            samples.iter_mut().for_each(|s| s.left.word = 0);
+            dev.fill_stereo_samples(&mut samples, Mix::Div1);
+            loud |= samples.iter().any(|s| unsafe { s.left.word } != 0);
+        }
+        assert!(loud);
+        assert_eq!(
+            dev.handle(Request::QueryPosition),
+            Response::Position { sample: 24000, tick: 1152, playing: true }
+        );
+
+        dev.handle(Request::Transport(Transport::Stop));
+        assert_eq!(dev.sample(), 0);
+        assert!(!dev.is_playing());
+    }
+
+    #[test]
+    fn malformed_notes_queue_nothing() {
+        let mut dev: Device<1, 8> = Device::new(8000, 120).unwrap();
+        dev.handle(Request::CreateTrack { kind: ToneKind::Square });
+
+        let notes = [
+            EncNote::new_simple(Pitch::A, 4, 0, Length::Quarter).unwrap(),
+            EncNote::new_simple(Pitch::C, 5, 192, Length::Quarter).unwrap(),
+        ];
+        let mut buf = [0u8; 32];
+        let used = encode_notes(&notes, &mut buf);
+        // The second note is cut short
+        let req = Request::QueueNotes { track: 0, bar_tick: 0, notes: &buf[..used - 1] };
+        assert_eq!(dev.handle(req), Response::Error(ErrorCode::Malformed));
+
+        // The first note was not queued, so nothing overlaps
+        let req = Request::QueueNotes { track: 0, bar_tick: 0, notes: &buf[..used] };
+        assert_eq!(dev.handle(req), Response::Queued { count: 2, rejected: None });
+    }
+
+    #[test]
+    fn tempo_changes() {
+        let mut dev: Device<1, 1> = Device::new(8000, 120).unwrap();
+        assert_eq!(dev.handle(Request::SetTempo { tick: 0, bpm: 60 }), Response::Ok);
+        assert_eq!(dev.tempo().tick_to_sample(192), 8000);
+        assert_eq!(dev.handle(Request::SetTempo { tick: 192, bpm: 120 }), Response::Ok);
+        assert_eq!(dev.tempo().tick_to_sample(384), 12000);
+        assert_eq!(
+            dev.handle(Request::SetTempo { tick: 100, bpm: 90 }),
+            Response::Error(ErrorCode::BadTempo)
+        );
+        assert_eq!(
+            dev.handle(Request::SetTempo { tick: 500, bpm: 0 }),
+            Response::Error(ErrorCode::BadTempo)
+        );
+    }
+
+    #[test]
+    fn bad_payloads() {
+        let mut dev: Device<1, 1> = Device::new(8000, 120).unwrap();
+        let mut out = [0u8; MAX_FRAME];
+        let used = dev.handle_payload(&[9, 42, 1], &mut out).unwrap();
+        let payload = frame::decode_in_place(&mut out[..used - 1]).unwrap();
+        assert_eq!(
+            Response::decode(&out[..payload]),
+            Ok((42, Response::Error(ErrorCode::Version)))
+        );
+    }
+}
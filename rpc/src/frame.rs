@@ -0,0 +1,217 @@
+//! COBS framing, with a CRC-16 checksum.
+//!
+//! On the wire, each frame is the payload followed by its CRC-16
+//! (CCITT-FALSE, big endian), COBS encoded so that it contains no zero
+//! bytes, and then terminated with a single zero byte. A receiver that
+//! joins mid-stream (or sees garbage) resyncs at the next zero.
+
+use heapless::Vec;
+
+/// The largest payload that fits in a frame
+pub const MAX_PAYLOAD: usize = 248;
+
+/// The largest encoded frame, including the checksum, COBS overhead and
+/// the terminating zero
+pub const MAX_FRAME: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// The output buffer is too small, or the payload is too big
+    BufferTooSmall,
+    /// More than `MAX_FRAME` bytes arrived without a terminator
+    Overflow,
+    /// The frame is not valid COBS
+    Encoding,
+    /// The frame is too short to hold a checksum
+    TooShort,
+    /// The checksum did not match
+    Checksum,
+}
+
+/// CRC-16/CCITT-FALSE
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xFFFF, |crc, &byte| {
+        (0..8).fold(crc ^ ((byte as u16) << 8), |crc, _| {
+            if (crc & 0x8000) != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// Encode a payload into a frame, returning the number of bytes used,
+/// including the terminating zero.
+pub fn encode(payload: &[u8], out: &mut [u8]) -> Result<usize, FrameError> {
+    if payload.len() > MAX_PAYLOAD {
+        return Err(FrameError::BufferTooSmall);
+    }
+    let crc = crc16(payload).to_be_bytes();
+
+    let mut code_idx = 0;
+    let mut wr = 1;
+    let mut code = 1u8;
+    let mut put = |idx: usize, val: u8| {
+        out.get_mut(idx)
+            .map(|b| *b = val)
+            .ok_or(FrameError::BufferTooSmall)
+    };
+
+    for &byte in payload.iter().chain(crc.iter()) {
+        if byte == 0 {
+            put(code_idx, code)?;
+            code_idx = wr;
+            wr += 1;
+            code = 1;
+        } else {
+            put(wr, byte)?;
+            wr += 1;
+            code += 1;
+            if code == 0xFF {
+                put(code_idx, code)?;
+                code_idx = wr;
+                wr += 1;
+                code = 1;
+            }
+        }
+    }
+    put(code_idx, code)?;
+    put(wr, 0)?;
+    Ok(wr + 1)
+}
+
+/// Decode a frame (without its terminating zero) in place, returning the
+/// length of the payload at the start of the buffer.
+pub fn decode_in_place(buf: &mut [u8]) -> Result<usize, FrameError> {
+    let len = buf.len();
+    let mut rd = 0;
+    let mut wr = 0;
+
+    while rd < len {
+        let code = buf[rd];
+        if code == 0 {
+            return Err(FrameError::Encoding);
+        }
+        rd += 1;
+        let run = (code - 1) as usize;
+        if (rd + run) > len {
+            return Err(FrameError::Encoding);
+        }
+        // NOTE: the write position is always behind the read position
+        buf.copy_within(rd..(rd + run), wr);
+        wr += run;
+        rd += run;
+        if code != 0xFF && rd < len {
+            buf[wr] = 0;
+            wr += 1;
+        }
+    }
+
+    let Some(body_len) = wr.checked_sub(2) else {
+        return Err(FrameError::TooShort);
+    };
+    let crc = u16::from_be_bytes([buf[body_len], buf[body_len + 1]]);
+    if crc != crc16(&buf[..body_len]) {
+        return Err(FrameError::Checksum);
+    }
+    Ok(body_len)
+}
+
+/// Collects bytes from a stream, one at a time, into frames.
+#[derive(Debug, Default)]
+pub struct Decoder {
+    buf: Vec<u8, MAX_FRAME>,
+    overflow: bool,
+    done: bool,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Push a byte. Once a frame is complete, this returns its payload
+    /// (or why it was bad).
+    pub fn push(&mut self, byte: u8) -> Option<Result<&[u8], FrameError>> {
+        if self.done {
+            self.buf.clear();
+            self.done = false;
+        }
+
+        if byte != 0 {
+            if self.buf.push(byte).is_err() {
+                self.overflow = true;
+            }
+            return None;
+        }
+
+        if self.overflow {
+            self.buf.clear();
+            self.overflow = false;
+            return Some(Err(FrameError::Overflow));
+        }
+        if self.buf.is_empty() {
+            // Back to back terminators are harmless
+            return None;
+        }
+
+        self.done = true;
+        Some(decode_in_place(&mut self.buf).map(|len| &self.buf[..len]))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn round_trip(payload: &[u8]) {
+        let mut frame = [0u8; MAX_FRAME];
+        let used = encode(payload, &mut frame).unwrap();
+        assert!(frame[..used - 1].iter().all(|b| *b != 0));
+        assert_eq!(frame[used - 1], 0);
+
+        let mut dec = Decoder::new();
+        let (last, rest) = frame[..used].split_last().unwrap();
+        rest.iter().for_each(|b| assert!(dec.push(*b).is_none()));
+        assert_eq!(dec.push(*last), Some(Ok(payload)));
+    }
+
+    #[test]
+    fn check_crc() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    fn round_trips() {
+        round_trip(&[]);
+        round_trip(&[0]);
+        round_trip(&[0, 0, 1, 0]);
+        round_trip(&[1, 2, 3]);
+        let long = (0..MAX_PAYLOAD).map(|i| (i % 255) as u8 + 1).collect::<std::vec::Vec<_>>();
+        round_trip(&long);
+        let zeros = [0u8; MAX_PAYLOAD];
+        round_trip(&zeros);
+    }
+
+    #[test]
+    fn rejects_bad_frames() {
+        let mut frame = [0u8; 16];
+        let used = encode(&[1, 2, 3], &mut frame).unwrap();
+        frame[2] ^= 0x40;
+
+        let mut dec = Decoder::new();
+        let res = frame[..used].iter().find_map(|b| dec.push(*b).map(|r| r.map(|p| p.len())));
+        assert_eq!(res, Some(Err(FrameError::Checksum)));
+
+        // Garbage that never ends
+        let mut dec = Decoder::new();
+        (0..1000).for_each(|_| assert!(dec.push(0x55).is_none()));
+        assert_eq!(dec.push(0), Some(Err(FrameError::Overflow)));
+
+        // Then recovers
+        let used = encode(&[9], &mut frame).unwrap();
+        let res = frame[..used].iter().find_map(|b| dec.push(*b).map(|r| r.map(|p| p.to_owned())));
+        assert_eq!(res, Some(Ok(vec![9])));
+    }
+}
@@ -0,0 +1,22 @@
+//! A binary RPC protocol for driving a minijam sequencer over a byte
+//! stream, like a serial port.
+//!
+//! * [`frame`] handles splitting the stream into checksummed frames
+//! * [`proto`] defines the versioned request and response messages
+//! * [`device`] carries out requests on a set of tracks
+//! * [`client`] is the host side, for sending requests
+//! * [`loopback`] serves a device over a local stream, for testing
+//!
+//! The frames, messages and device work anywhere minijam does. The client
+//! and loopback use `std::io`, so are behind the `std` feature (on by
+//! default).
+
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+#[cfg(feature = "std")]
+pub mod client;
+pub mod device;
+pub mod frame;
+#[cfg(feature = "std")]
+pub mod loopback;
+pub mod proto;
@@ -0,0 +1,132 @@
+//! A stand-in for a real device, serving requests over any byte stream.
+//!
+//! This makes it possible to test the host side without hardware: run
+//! [`serve`] on one end of a pipe, socket pair or pseudo-terminal, and
+//! point a [`Client`](crate::client::Client) at the other end. No audio is
+//! rendered, so the position only moves if the caller renders it.
+
+use std::{
+    io::{self, Read, Write},
+    os::unix::net::UnixStream,
+    thread::{self, JoinHandle},
+};
+
+use crate::{device::Device, frame::Decoder};
+
+/// Handle requests from `stream` until it is closed.
+pub fn serve<S, const TRACKS: usize, const DEPTH: usize>(
+    device: &mut Device<TRACKS, DEPTH>,
+    mut stream: S,
+) -> io::Result<()>
+where
+    S: Read + Write,
+{
+    let mut decoder = Decoder::new();
+    let mut buf = [0u8; 256];
+    loop {
+        let used = match stream.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(used) => used,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+
+        let mut res = Ok(());
+        device.feed(&mut decoder, &buf[..used], |frame| {
+            if res.is_ok() {
+                res = stream.write_all(frame);
+            }
+        });
+        res?;
+        stream.flush()?;
+    }
+}
+
+/// Serve a device on a background thread, returning the host's end of the
+/// connection. Once the host end is dropped, the thread hands back the
+/// device.
+pub fn spawn<const TRACKS: usize, const DEPTH: usize>(
+    mut device: Device<TRACKS, DEPTH>,
+) -> io::Result<(UnixStream, JoinHandle<io::Result<Device<TRACKS, DEPTH>>>)> {
+    let (host, dev) = UnixStream::pair()?;
+    let handle = thread::spawn(move || {
+        serve(&mut device, dev)?;
+        Ok(device)
+    });
+    Ok((host, handle))
+}
+
+#[cfg(test)]
+mod test {
+    use minijam::{scale::Pitch, tones::ToneKind, NoteError};
+    use thursday::{EncNote, Length, PPQN_16TH};
+
+    use super::*;
+    use crate::{
+        client::{Client, ClientError, Position, Queued},
+        proto::ErrorCode,
+    };
+
+    #[test]
+    fn over_a_socket() {
+        let device: Device<2, 128> = Device::new(44100, 120).unwrap();
+        let (stream, handle) = spawn(device).unwrap();
+        let mut client = Client::new(stream);
+
+        let lead = client.create_track(ToneKind::Saw).unwrap();
+        let bass = client.create_track(ToneKind::Sine).unwrap();
+        assert_eq!((lead, bass), (0, 1));
+        assert!(matches!(
+            client.create_track(ToneKind::Sine),
+            Err(ClientError::Device(ErrorCode::TooManyTracks))
+        ));
+
+        // More notes than fit in a single frame
+        let note = |i| EncNote::new_simple(Pitch::E, 3, i * PPQN_16TH, Length::Sixteenth).unwrap();
+        let notes = (0..100).map(note).collect::<Vec<_>>();
+        assert_eq!(
+            client.queue_notes(lead, 0, &notes).unwrap(),
+            Queued { accepted: 100, rejected: None }
+        );
+        assert_eq!(
+            client.queue_notes(bass, 0, &notes[..10]).unwrap(),
+            Queued { accepted: 10, rejected: None }
+        );
+        // A note that overlaps the one before it, in the second request
+        let overlaps = (0..100)
+            .map(|i| note(if i == 95 { 94 } else { i }))
+            .collect::<Vec<_>>();
+        let next_bar = 100 * PPQN_16TH as u32;
+        assert_eq!(
+            client.queue_notes(bass, next_bar, &overlaps).unwrap(),
+            Queued { accepted: 99, rejected: Some((95, NoteError::OutOfOrder)) }
+        );
+
+        client.set_tempo(0, 90).unwrap();
+        client.play().unwrap();
+        assert_eq!(
+            client.position().unwrap(),
+            Position { sample: 0, tick: 0, playing: true }
+        );
+        client.pause().unwrap();
+        assert!(!client.position().unwrap().playing);
+
+        drop(client);
+        let device = handle.join().unwrap().unwrap();
+        assert_eq!(device.tempo().bpm_at(0), 90.0);
+    }
+
+    #[test]
+    fn skips_garbage() {
+        let device: Device<1, 1> = Device::new(44100, 120).unwrap();
+        let (mut stream, handle) = spawn(device).unwrap();
+
+        // Line noise before the host connects
+        stream.write_all(&[0x12, 0x34, 0x00, 0xFF, 0x00]).unwrap();
+        let mut client = Client::new(stream);
+        assert_eq!(client.create_track(ToneKind::Square).unwrap(), 0);
+
+        drop(client);
+        handle.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,471 @@
+//! Request and response messages.
+//!
+//! Every message starts with a three byte header: the protocol
+//! [`VERSION`], a sequence number (echoed back in the response, so the
+//! host can match them up), and an opcode. Multi-byte numbers are little
+//! endian.
+
+use minijam::{tones::ToneKind, NoteError};
+use thursday::{EncError, EncNote};
+
+pub const VERSION: u8 = 1;
+
+const HEADER_LEN: usize = 3;
+
+mod op {
+    pub const CREATE_TRACK: u8 = 0x01;
+    pub const QUEUE_NOTES: u8 = 0x02;
+    pub const SET_TEMPO: u8 = 0x03;
+    pub const TRANSPORT: u8 = 0x04;
+    pub const QUERY_POSITION: u8 = 0x05;
+
+    pub const OK: u8 = 0x80;
+    pub const TRACK_CREATED: u8 = 0x81;
+    pub const QUEUED: u8 = 0x82;
+    pub const POSITION: u8 = 0x83;
+    pub const ERROR: u8 = 0xFF;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtoError {
+    /// The message was sent with a different protocol version
+    Version(u8),
+    /// The message was cut short, or had an invalid field
+    Malformed,
+    UnknownOpcode(u8),
+    /// The output buffer is too small
+    BufferTooSmall,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Play,
+    /// Stop playing, but keep the current position and notes
+    Pause,
+    /// Stop playing, clear all notes, and go back to the start
+    Stop,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request<'a> {
+    CreateTrack {
+        kind: ToneKind,
+    },
+    /// Queue notes on a track. `bar_tick` is the position of the bar the
+    /// notes belong to, and `notes` holds back to back encoded `EncNote`s.
+    QueueNotes {
+        track: u8,
+        bar_tick: u32,
+        notes: &'a [u8],
+    },
+    /// Change the tempo, starting at the given tick
+    SetTempo {
+        tick: u32,
+        bpm: u16,
+    },
+    Transport(Transport),
+    QueryPosition,
+}
+
+/// Why the device could not carry out a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    Version = 1,
+    Malformed = 2,
+    UnknownOpcode = 3,
+    NoSuchTrack = 4,
+    TooManyTracks = 5,
+    BadTempo = 6,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Response {
+    Ok,
+    TrackCreated {
+        track: u8,
+    },
+    /// The number of notes that were accepted. Notes are rejected if the
+    /// track's queue is full, or if they overlap a note already queued.
+    Queued {
+        count: u16,
+        rejected: Option<Rejected>,
+    },
+    Position {
+        sample: u32,
+        tick: u32,
+        playing: bool,
+    },
+    Error(ErrorCode),
+}
+
+/// The first note of a `QueueNotes` request that the track rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rejected {
+    /// Its position in the request's notes, from zero
+    pub index: u16,
+    pub reason: NoteError,
+}
+
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn new(buf: &'a mut [u8], seq: u8, opcode: u8) -> Result<Self, ProtoError> {
+        let mut me = Self { buf, pos: 0 };
+        me.put(&[VERSION, seq, opcode])?;
+        Ok(me)
+    }
+
+    fn put(&mut self, data: &[u8]) -> Result<(), ProtoError> {
+        let end = self.pos + data.len();
+        self.buf
+            .get_mut(self.pos..end)
+            .ok_or(ProtoError::BufferTooSmall)?
+            .copy_from_slice(data);
+        self.pos = end;
+        Ok(())
+    }
+
+    fn finish(self) -> usize {
+        self.pos
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    /// Check the header, returning the sequence number, opcode and a
+    /// reader for the body
+    fn new(buf: &'a [u8]) -> Result<(u8, u8, Self), ProtoError> {
+        if buf.len() < HEADER_LEN {
+            return Err(ProtoError::Malformed);
+        }
+        if buf[0] != VERSION {
+            return Err(ProtoError::Version(buf[0]));
+        }
+        Ok((buf[1], buf[2], Self { buf: &buf[HEADER_LEN..] }))
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], ProtoError> {
+        if self.buf.len() < N {
+            return Err(ProtoError::Malformed);
+        }
+        let (now, later) = self.buf.split_at(N);
+        self.buf = later;
+        let mut out = [0u8; N];
+        out.copy_from_slice(now);
+        Ok(out)
+    }
+
+    fn u8(&mut self) -> Result<u8, ProtoError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ProtoError> {
+        Ok(u16::from_le_bytes(self.take()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, ProtoError> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    fn rest(self) -> &'a [u8] {
+        self.buf
+    }
+
+    fn end(self) -> Result<(), ProtoError> {
+        if self.buf.is_empty() {
+            Ok(())
+        } else {
+            Err(ProtoError::Malformed)
+        }
+    }
+}
+
+fn kind_to_u8(kind: ToneKind) -> u8 {
+    match kind {
+        ToneKind::Sine => 0,
+        ToneKind::Square => 1,
+        ToneKind::Saw => 2,
+    }
+}
+
+fn kind_from_u8(val: u8) -> Result<ToneKind, ProtoError> {
+    match val {
+        0 => Ok(ToneKind::Sine),
+        1 => Ok(ToneKind::Square),
+        2 => Ok(ToneKind::Saw),
+        _ => Err(ProtoError::Malformed),
+    }
+}
+
+impl<'a> Request<'a> {
+    /// Write the request into `out`, returning the number of bytes used
+    pub fn encode(&self, seq: u8, out: &mut [u8]) -> Result<usize, ProtoError> {
+        let wr = match self {
+            Request::CreateTrack { kind } => {
+                let mut wr = Writer::new(out, seq, op::CREATE_TRACK)?;
+                wr.put(&[kind_to_u8(*kind)])?;
+                wr
+            }
+            Request::QueueNotes { track, bar_tick, notes } => {
+                let mut wr = Writer::new(out, seq, op::QUEUE_NOTES)?;
+                wr.put(&[*track])?;
+                wr.put(&bar_tick.to_le_bytes())?;
+                wr.put(notes)?;
+                wr
+            }
+            Request::SetTempo { tick, bpm } => {
+                let mut wr = Writer::new(out, seq, op::SET_TEMPO)?;
+                wr.put(&tick.to_le_bytes())?;
+                wr.put(&bpm.to_le_bytes())?;
+                wr
+            }
+            Request::Transport(tp) => {
+                let mut wr = Writer::new(out, seq, op::TRANSPORT)?;
+                let val = match tp {
+                    Transport::Play => 0,
+                    Transport::Pause => 1,
+                    Transport::Stop => 2,
+                };
+                wr.put(&[val])?;
+                wr
+            }
+            Request::QueryPosition => Writer::new(out, seq, op::QUERY_POSITION)?,
+        };
+        Ok(wr.finish())
+    }
+
+    /// Decode a request, returning it along with its sequence number
+    pub fn decode(buf: &'a [u8]) -> Result<(u8, Self), ProtoError> {
+        let (seq, opcode, mut rd) = Reader::new(buf)?;
+        let req = match opcode {
+            op::CREATE_TRACK => {
+                let kind = kind_from_u8(rd.u8()?)?;
+                rd.end()?;
+                Request::CreateTrack { kind }
+            }
+            op::QUEUE_NOTES => {
+                let track = rd.u8()?;
+                let bar_tick = rd.u32()?;
+                Request::QueueNotes {
+                    track,
+                    bar_tick,
+                    notes: rd.rest(),
+                }
+            }
+            op::SET_TEMPO => {
+                let tick = rd.u32()?;
+                let bpm = rd.u16()?;
+                rd.end()?;
+                Request::SetTempo { tick, bpm }
+            }
+            op::TRANSPORT => {
+                let tp = match rd.u8()? {
+                    0 => Transport::Play,
+                    1 => Transport::Pause,
+                    2 => Transport::Stop,
+                    _ => return Err(ProtoError::Malformed),
+                };
+                rd.end()?;
+                Request::Transport(tp)
+            }
+            op::QUERY_POSITION => {
+                rd.end()?;
+                Request::QueryPosition
+            }
+            other => return Err(ProtoError::UnknownOpcode(other)),
+        };
+        Ok((seq, req))
+    }
+}
+
+/// Iterate over the notes of a `QueueNotes` request
+pub fn notes(mut sli: &[u8]) -> impl Iterator<Item = Result<EncNote, EncError>> + '_ {
+    core::iter::from_fn(move || {
+        if sli.is_empty() {
+            return None;
+        }
+        match EncNote::take_from_slice(sli) {
+            Ok((note, rest)) => {
+                sli = rest;
+                Some(Ok(note))
+            }
+            Err(e) => {
+                sli = &[];
+                Some(Err(e))
+            }
+        }
+    })
+}
+
+impl ErrorCode {
+    fn from_u8(val: u8) -> Result<Self, ProtoError> {
+        Ok(match val {
+            1 => ErrorCode::Version,
+            2 => ErrorCode::Malformed,
+            3 => ErrorCode::UnknownOpcode,
+            4 => ErrorCode::NoSuchTrack,
+            5 => ErrorCode::TooManyTracks,
+            6 => ErrorCode::BadTempo,
+            _ => return Err(ProtoError::Malformed),
+        })
+    }
+}
+
+impl From<ProtoError> for ErrorCode {
+    fn from(err: ProtoError) -> Self {
+        match err {
+            ProtoError::Version(_) => ErrorCode::Version,
+            ProtoError::UnknownOpcode(_) => ErrorCode::UnknownOpcode,
+            ProtoError::Malformed | ProtoError::BufferTooSmall => ErrorCode::Malformed,
+        }
+    }
+}
+
+impl Response {
+    /// Write the response into `out`, returning the number of bytes used
+    pub fn encode(&self, seq: u8, out: &mut [u8]) -> Result<usize, ProtoError> {
+        let wr = match self {
+            Response::Ok => Writer::new(out, seq, op::OK)?,
+            Response::TrackCreated { track } => {
+                let mut wr = Writer::new(out, seq, op::TRACK_CREATED)?;
+                wr.put(&[*track])?;
+                wr
+            }
+            Response::Queued { count, rejected } => {
+                let mut wr = Writer::new(out, seq, op::QUEUED)?;
+                wr.put(&count.to_le_bytes())?;
+                // Zero if every note was accepted
+                match rejected {
+                    None => wr.put(&[0])?,
+                    Some(Rejected { index, reason }) => {
+                        let reason = match reason {
+                            NoteError::Empty => 1,
+                            NoteError::QueueFull => 2,
+                            NoteError::OutOfOrder => 3,
+                        };
+                        wr.put(&[reason])?;
+                        wr.put(&index.to_le_bytes())?;
+                    }
+                }
+                wr
+            }
+            Response::Position { sample, tick, playing } => {
+                let mut wr = Writer::new(out, seq, op::POSITION)?;
+                wr.put(&sample.to_le_bytes())?;
+                wr.put(&tick.to_le_bytes())?;
+                wr.put(&[*playing as u8])?;
+                wr
+            }
+            Response::Error(code) => {
+                let mut wr = Writer::new(out, seq, op::ERROR)?;
+                wr.put(&[*code as u8])?;
+                wr
+            }
+        };
+        Ok(wr.finish())
+    }
+
+    /// Decode a response, returning it along with its sequence number
+    pub fn decode(buf: &[u8]) -> Result<(u8, Self), ProtoError> {
+        let (seq, opcode, mut rd) = Reader::new(buf)?;
+        let resp = match opcode {
+            op::OK => Response::Ok,
+            op::TRACK_CREATED => Response::TrackCreated { track: rd.u8()? },
+            op::QUEUED => {
+                let count = rd.u16()?;
+                let reason = match rd.u8()? {
+                    0 => None,
+                    1 => Some(NoteError::Empty),
+                    2 => Some(NoteError::QueueFull),
+                    3 => Some(NoteError::OutOfOrder),
+                    _ => return Err(ProtoError::Malformed),
+                };
+                let rejected = match reason {
+                    Some(reason) => Some(Rejected { index: rd.u16()?, reason }),
+                    None => None,
+                };
+                Response::Queued { count, rejected }
+            }
+            op::POSITION => Response::Position {
+                sample: rd.u32()?,
+                tick: rd.u32()?,
+                playing: rd.u8()? != 0,
+            },
+            op::ERROR => Response::Error(ErrorCode::from_u8(rd.u8()?)?),
+            other => return Err(ProtoError::UnknownOpcode(other)),
+        };
+        rd.end()?;
+        Ok((seq, resp))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use minijam::scale::Pitch;
+    use thursday::Length;
+
+    #[test]
+    fn requests_round_trip() {
+        let mut notes = [0u8; 32];
+        let note = EncNote::new_simple(Pitch::C, 4, 96, Length::Eighth).unwrap();
+        let remain = note.write_to_slice(&mut notes).unwrap().len();
+        let notes = &notes[..(32 - remain)];
+
+        let reqs = [
+            Request::CreateTrack { kind: ToneKind::Saw },
+            Request::QueueNotes { track: 3, bar_tick: 0x1234_5678, notes },
+            Request::SetTempo { tick: 768, bpm: 140 },
+            Request::Transport(Transport::Pause),
+            Request::QueryPosition,
+        ];
+
+        let mut buf = [0u8; 64];
+        for (seq, req) in reqs.iter().enumerate() {
+            let used = req.encode(seq as u8, &mut buf).unwrap();
+            let (dseq, dreq) = Request::decode(&buf[..used]).unwrap();
+            assert_eq!(dseq, seq as u8);
+            assert_eq!(&dreq, req);
+        }
+
+        let Request::QueueNotes { notes, .. } = reqs[1] else { unreachable!() };
+        let dec = super::notes(notes).collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(dec.len(), 1);
+        assert_eq!(dec[0].ppqn_start(), 96);
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        let resps = [
+            Response::Ok,
+            Response::TrackCreated { track: 2 },
+            Response::Queued { count: 300, rejected: None },
+            Response::Queued {
+                count: 1,
+                rejected: Some(Rejected { index: 1, reason: NoteError::QueueFull }),
+            },
+            Response::Position { sample: 44100, tick: 192, playing: true },
+            Response::Error(ErrorCode::BadTempo),
+        ];
+        let mut buf = [0u8; 64];
+        for resp in resps.iter() {
+            let used = resp.encode(7, &mut buf).unwrap();
+            assert_eq!(Response::decode(&buf[..used]), Ok((7, *resp)));
+        }
+    }
+
+    #[test]
+    fn rejects_bad_messages() {
+        assert_eq!(Request::decode(&[2, 0, 0x05]), Err(ProtoError::Version(2)));
+        assert_eq!(Request::decode(&[VERSION, 0, 0x42]), Err(ProtoError::UnknownOpcode(0x42)));
+        assert_eq!(Request::decode(&[VERSION, 0, 0x03, 1, 2]), Err(ProtoError::Malformed));
+        assert_eq!(Request::decode(&[VERSION, 0, 0x01, 7]), Err(ProtoError::Malformed));
+        assert_eq!(Request::decode(&[VERSION]), Err(ProtoError::Malformed));
+    }
+}
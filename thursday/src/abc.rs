@@ -0,0 +1,966 @@
+// Importing tunes written in ABC notation.
+//
+// See https://abcnotation.com/wiki/abc:standard:v2.1 for the full
+// standard. This handles the parts that matter for a single line melody
+// (or a few of them, as voices):
+//
+// * Header fields: `T:` (title), `M:` (meter), `L:` (default note length),
+//   `Q:` (tempo), `K:` (key, which ends the header) and `V:` (voices).
+//   Fields can also be changed inline, like `[K:Dm]` or `[L:1/16]`.
+// * Notes with accidentals (`^`, `^^`, `_`, `__`, `=`), octave marks
+//   (`,` and `'`), lengths (`A2`, `A/`, `A3/2`), broken rhythms (`A>B`)
+//   and ties (`A-A`).
+// * Rests (`z`, `x`) and multi-measure rests (`Z4`).
+// * Tuplets (`(3abc`, `(3:2:3abc`).
+// * Repeats (`|:`, `:|`, `::`) with first and second endings (`[1`, `:|2`).
+//
+// Chords (`[CEG]`) are played as their first note, as `BarBuf`s hold a
+// single line. Chord symbols, decorations, grace notes and lyrics are
+// skipped.
+//
+// A `BarBuf` can only hold `PPQN_MAX` ticks, so each voice is split over as
+// many `BarBuf`s as it needs, at bar lines where possible.
+
+use alloc::{string::String, vec, vec::Vec};
+
+use minijam::scale::Pitch;
+
+use crate::{
+    bars::{BarBuf, BarError},
+    Length, PPQN_MAX, PPQN_QUARTER, PPQN_WHOLE,
+};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AbcErrorKind {
+    /// There was no `K:` field to end the header
+    MissingKey,
+    BadMeter,
+    /// A length that can't be used, like `L:1/0` or a broken rhythm
+    /// longer than `>>>`
+    BadLength,
+    BadTempo,
+    BadKey,
+    BadTuplet,
+    /// A first ending before the start of its repeat
+    BadEnding,
+    /// A note, or a whole voice, longer than `MAX_TICKS`
+    TooLong,
+    /// A character that doesn't belong in a tune
+    UnexpectedChar(char),
+    /// Something was opened (like a chord or chord symbol) but not closed
+    Unterminated,
+    Bar(BarError),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct AbcError {
+    /// The line the error was found on, starting at 1
+    pub line: usize,
+    pub kind: AbcErrorKind,
+}
+
+pub struct AbcVoice {
+    pub id: String,
+    /// The voice, split into `BarBuf`s to be played one after another
+    pub bars: Vec<BarBuf>,
+}
+
+pub struct Tune {
+    pub title: Option<String>,
+    /// Meter, as (beats, beat unit), like `(6, 8)`
+    pub meter: (u8, u8),
+    /// Quarter notes per minute
+    pub tempo: u32,
+    pub voices: Vec<AbcVoice>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Event {
+    Note(u32, Option<(Pitch, u8)>),
+    Bar,
+}
+
+#[derive(Default)]
+struct VoiceState {
+    id: String,
+    events: Vec<Event>,
+    // Accidentals set in this bar, by (letter, octave)
+    bar_accidentals: Vec<((u8, i16), i16)>,
+    repeat_start: usize,
+    first_ending: Option<usize>,
+    // The length of all the events so far
+    ticks: u32,
+    tie: bool,
+    // Notes remaining, and the (p, q) ratio of the tuplet
+    tuplet: Option<(u8, u32, u32)>,
+    // Multiplier for the next note's length, from a broken rhythm
+    broken: Option<(u32, u32)>,
+}
+
+struct Parser {
+    line: usize,
+    meter: (u8, u8),
+    unit: u32,
+    tempo: u32,
+    // Alteration of each letter (C, D, E, F, G, A, B) from the key
+    key: [i16; 7],
+    title: Option<String>,
+    voices: Vec<VoiceState>,
+    cur: usize,
+}
+
+/// The longest a voice can be, in ticks: 1024 `BarBuf`s' worth, or a bit
+/// over an hour and a half at 120bpm
+pub const MAX_TICKS: u32 = PPQN_MAX as u32 * 1024;
+
+const LETTERS: [char; 7] = ['C', 'D', 'E', 'F', 'G', 'A', 'B'];
+const SEMITONES: [i16; 7] = [0, 2, 4, 5, 7, 9, 11];
+
+fn parse_fraction(s: &str) -> Option<(u32, u32)> {
+    let (num, den) = s.trim().split_once('/')?;
+    let num = num.trim().parse().ok()?;
+    let den = den.trim().parse().ok()?;
+    if num == 0 || den == 0 {
+        return None;
+    }
+    Some((num, den))
+}
+
+// The alterations for each letter in a key, like `G`, `Bbm`, `F#mix` or `D dorian`
+fn parse_key(s: &str) -> Option<[i16; 7]> {
+    let s = s.split_whitespace().collect::<Vec<_>>().join("");
+    let s = s.to_ascii_lowercase();
+    if s.is_empty() || s.starts_with("none") || s.starts_with("hp") {
+        return Some([0; 7]);
+    }
+
+    let mut chars = s.chars();
+    let root = chars.next()?.to_ascii_uppercase();
+    let root = LETTERS.iter().position(|l| *l == root)?;
+    let rest = chars.as_str();
+    let (acc, rest) = match rest.chars().next() {
+        Some('#') => (1, &rest[1..]),
+        Some('b') => (-1, &rest[1..]),
+        _ => (0, rest),
+    };
+
+    // Sharps in the major key of each natural root
+    const MAJOR_SHARPS: [i16; 7] = [0, 2, 4, -1, 1, 3, 5];
+    let mode = rest.get(..3).unwrap_or(rest);
+    let mode_shift = match mode {
+        "" | "maj" | "ion" => 0,
+        "m" | "min" | "aeo" => -3,
+        "mix" => -1,
+        "dor" => -2,
+        "phr" => -4,
+        "lyd" => 1,
+        "loc" => -5,
+        // Anything else (like `clef=bass`) is ignored
+        _ if mode.starts_with('m') => -3,
+        _ => 0,
+    };
+    let sharps = MAJOR_SHARPS[root] + (acc * 7) + mode_shift;
+
+    let mut key = [0; 7];
+    if sharps > 0 {
+        // F C G D A E B
+        [3, 0, 4, 1, 5, 2, 6].iter().take(sharps as usize).for_each(|i| key[*i] = 1);
+    } else {
+        // B E A D G C F
+        [6, 2, 5, 1, 4, 0, 3].iter().take((-sharps) as usize).for_each(|i| key[*i] = -1);
+    }
+    Some(key)
+}
+
+impl Parser {
+    fn err(&self, kind: AbcErrorKind) -> AbcError {
+        AbcError { line: self.line, kind }
+    }
+
+    fn voice(&mut self) -> &mut VoiceState {
+        if self.voices.is_empty() {
+            self.voices.push(VoiceState {
+                id: "1".into(),
+                ..Default::default()
+            });
+        }
+        &mut self.voices[self.cur]
+    }
+
+    fn set_voice(&mut self, id: &str) {
+        let id = id.split_whitespace().next().unwrap_or("1");
+        self.cur = match self.voices.iter().position(|v| v.id == id) {
+            Some(idx) => idx,
+            None => {
+                self.voices.push(VoiceState {
+                    id: id.into(),
+                    ..Default::default()
+                });
+                self.voices.len() - 1
+            }
+        };
+    }
+
+    fn meter_ticks(&self) -> u32 {
+        (self.meter.0 as u32 * PPQN_WHOLE as u32) / self.meter.1 as u32
+    }
+
+    fn field(&mut self, name: char, value: &str) -> Result<(), AbcError> {
+        let value = value.split('%').next().unwrap_or("").trim();
+        match name {
+            'T' if self.title.is_none() => self.title = Some(value.into()),
+            'M' => {
+                self.meter = match value {
+                    "C" => (4, 4),
+                    "C|" => (2, 2),
+                    "none" | "" => (4, 4),
+                    _ => {
+                        let (n, d) = parse_fraction(value).ok_or_else(|| self.err(AbcErrorKind::BadMeter))?;
+                        let n = u8::try_from(n).map_err(|_| self.err(AbcErrorKind::BadMeter))?;
+                        let d = u8::try_from(d).map_err(|_| self.err(AbcErrorKind::BadMeter))?;
+                        (n, d)
+                    }
+                };
+            }
+            'L' => {
+                let (n, d) = parse_fraction(value).ok_or_else(|| self.err(AbcErrorKind::BadLength))?;
+                self.unit = n
+                    .checked_mul(PPQN_WHOLE as u32)
+                    .map(|t| t / d)
+                    .filter(|t| *t <= MAX_TICKS)
+                    .ok_or_else(|| self.err(AbcErrorKind::BadLength))?;
+            }
+            'Q' => {
+                // Skip any quoted text, like `"Allegro" 1/4=120`
+                let value = value.rsplit('"').next().unwrap_or(value).trim();
+                let (beat, bpm) = match value.split_once('=') {
+                    Some((beat, bpm)) => {
+                        // Beats may be the sum of several notes, like `1/8 3/8`
+                        let beat = beat.split_whitespace().try_fold(0u32, |acc, b| {
+                            let (n, d) = parse_fraction(b)?;
+                            acc.checked_add(n.checked_mul(PPQN_WHOLE as u32)? / d)
+                        });
+                        (beat, bpm)
+                    }
+                    None => (Some(PPQN_QUARTER as u32), value),
+                };
+                let beat = beat.ok_or_else(|| self.err(AbcErrorKind::BadTempo))?;
+                let bpm: u32 = bpm.trim().parse().map_err(|_| self.err(AbcErrorKind::BadTempo))?;
+                self.tempo = bpm
+                    .checked_mul(beat)
+                    .map(|t| t / PPQN_QUARTER as u32)
+                    .filter(|t| *t != 0)
+                    .ok_or_else(|| self.err(AbcErrorKind::BadTempo))?;
+            }
+            'K' => {
+                self.key = parse_key(value).ok_or_else(|| self.err(AbcErrorKind::BadKey))?;
+            }
+            'V' => self.set_voice(value),
+            // Everything else (like lyrics, or the reference number) doesn't
+            // change the notes
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn push_note(&mut self, ticks: u32, note: Option<(Pitch, u8)>) -> Result<(), AbcError> {
+        let line = self.line;
+        let too_long = || AbcError { line, kind: AbcErrorKind::TooLong };
+        let voice = self.voice();
+        let mut ticks = ticks;
+        if let Some((p, q, left)) = voice.tuplet {
+            ticks = ticks.checked_mul(q).ok_or_else(too_long)? / p as u32;
+            voice.tuplet = match left {
+                0 | 1 => None,
+                _ => Some((p, q, left - 1)),
+            };
+        }
+        if let Some((n, d)) = voice.broken.take() {
+            ticks = ticks.checked_mul(n).ok_or_else(too_long)? / d;
+        }
+        voice.ticks = voice
+            .ticks
+            .checked_add(ticks)
+            .filter(|t| *t <= MAX_TICKS)
+            .ok_or_else(too_long)?;
+
+        let tied = core::mem::take(&mut voice.tie);
+        if tied && note.is_some() {
+            if let Some(Event::Note(len, prev)) = voice.events.last_mut() {
+                if *prev == note {
+                    // Can't overflow, as it's no longer than the voice
+                    *len += ticks;
+                    return Ok(());
+                }
+            }
+        }
+        voice.events.push(Event::Note(ticks, note));
+        Ok(())
+    }
+
+    fn bar_line(&mut self) {
+        let voice = self.voice();
+        voice.bar_accidentals.clear();
+        // A bar line in the middle of a tied note can't be used to split
+        if !voice.tie {
+            voice.events.push(Event::Bar);
+        }
+    }
+
+    fn end_repeat(&mut self) -> Result<(), AbcError> {
+        let bad_ending = self.err(AbcErrorKind::BadEnding);
+        let too_long = self.err(AbcErrorKind::TooLong);
+        let voice = self.voice();
+        let end = voice.first_ending.take().unwrap_or(voice.events.len());
+        let again = voice.events.get(voice.repeat_start..end).ok_or(bad_ending)?.to_vec();
+        let ticks = again
+            .iter()
+            .map(|e| match e {
+                Event::Note(t, _) => *t,
+                Event::Bar => 0,
+            })
+            .try_fold(voice.ticks, |acc, t| acc.checked_add(t))
+            .filter(|t| *t <= MAX_TICKS)
+            .ok_or(too_long)?;
+        voice.ticks = ticks;
+        voice.events.extend(again);
+        voice.repeat_start = voice.events.len();
+        Ok(())
+    }
+
+    // Parse an optional length multiplier, like `2`, `/`, `//`, `3/2` or `/4`
+    fn length(chars: &mut core::iter::Peekable<core::str::Chars<'_>>) -> (u32, u32) {
+        let mut num = 0u32;
+        while let Some(d) = chars.peek().and_then(|c| c.to_digit(10)) {
+            num = num.saturating_mul(10).saturating_add(d);
+            chars.next();
+        }
+        let num = num.max(1);
+
+        let mut den = 1u32;
+        while chars.peek() == Some(&'/') {
+            chars.next();
+            let mut explicit = 0u32;
+            while let Some(d) = chars.peek().and_then(|c| c.to_digit(10)) {
+                explicit = explicit.saturating_mul(10).saturating_add(d);
+                chars.next();
+            }
+            den = den.saturating_mul(if explicit == 0 { 2 } else { explicit });
+        }
+        (num, den)
+    }
+
+    // Parse a note (after any accidentals), returning its length and pitch
+    fn note(
+        &mut self,
+        letter: char,
+        accidental: Option<i16>,
+        chars: &mut core::iter::Peekable<core::str::Chars<'_>>,
+    ) -> Result<(u32, (Pitch, u8)), AbcError> {
+        let idx = LETTERS
+            .iter()
+            .position(|l| *l == letter.to_ascii_uppercase())
+            .unwrap_or(0);
+        let mut octave: i16 = if letter.is_ascii_lowercase() { 5 } else { 4 };
+        loop {
+            match chars.peek() {
+                Some('\'') => octave += 1,
+                Some(',') => octave -= 1,
+                _ => break,
+            }
+            chars.next();
+        }
+
+        let key_alter = self.key[idx];
+        let voice = self.voice();
+        let alter = match accidental {
+            Some(acc) => {
+                voice.bar_accidentals.retain(|(k, _)| *k != (idx as u8, octave));
+                voice.bar_accidentals.push(((idx as u8, octave), acc));
+                acc
+            }
+            None => voice
+                .bar_accidentals
+                .iter()
+                .find(|(k, _)| *k == (idx as u8, octave))
+                .map(|(_, acc)| *acc)
+                .unwrap_or(key_alter),
+        };
+
+        let abs = (octave * 12) + SEMITONES[idx] + alter;
+        let pitch = Pitch::from(abs.rem_euclid(12) as u8);
+        let octave = abs.div_euclid(12).clamp(0, u8::MAX as i16) as u8;
+
+        let ticks = self.ticks(Self::length(chars))?;
+        Ok((ticks, (pitch, octave)))
+    }
+
+    // The length of a note with the given multiplier of the default length
+    fn ticks(&self, (n, d): (u32, u32)) -> Result<u32, AbcError> {
+        self.unit
+            .checked_mul(n)
+            .map(|t| t / d)
+            .filter(|t| *t <= MAX_TICKS)
+            .ok_or_else(|| self.err(AbcErrorKind::TooLong))
+    }
+
+    fn body_line(&mut self, line: &str) -> Result<(), AbcError> {
+        let line = line.split('%').next().unwrap_or("");
+        let mut chars = line.chars().peekable();
+
+        while let Some(ch) = chars.next() {
+            match ch {
+                ' ' | '\t' | '`' | '\\' | 'y' | ')' => {}
+                // Decorations
+                '~' | '.' | 'H' | 'L' | 'M' | 'O' | 'P' | 'S' | 'T' | 'u' | 'v' => {}
+                '!' | '+' => {
+                    if !chars.by_ref().any(|c| c == ch) {
+                        return Err(self.err(AbcErrorKind::Unterminated));
+                    }
+                }
+                // Chord symbols and annotations
+                '"' => {
+                    if !chars.by_ref().any(|c| c == '"') {
+                        return Err(self.err(AbcErrorKind::Unterminated));
+                    }
+                }
+                // Grace notes
+                '{' => {
+                    if !chars.by_ref().any(|c| c == '}') {
+                        return Err(self.err(AbcErrorKind::Unterminated));
+                    }
+                }
+                '^' | '_' | '=' | 'A'..='G' | 'a'..='g' => {
+                    let (acc, letter) = self.accidental(ch, &mut chars)?;
+                    let (ticks, note) = self.note(letter, acc, &mut chars)?;
+                    self.push_note(ticks, Some(note))?;
+                }
+                'z' | 'x' => {
+                    let ticks = self.ticks(Self::length(&mut chars))?;
+                    self.push_note(ticks, None)?;
+                }
+                'Z' => {
+                    let (n, _) = Self::length(&mut chars);
+                    // Each bar is at least one tick, so `push_note` stops a
+                    // huge count at `MAX_TICKS`
+                    for _ in 0..n {
+                        let ticks = self.meter_ticks();
+                        self.push_note(ticks, None)?;
+                        self.bar_line();
+                    }
+                }
+                '-' => self.voice().tie = true,
+                '>' | '<' => {
+                    let mut count = 1;
+                    while chars.peek() == Some(&ch) {
+                        chars.next();
+                        count += 1;
+                    }
+                    if count > 3 {
+                        return Err(self.err(AbcErrorKind::BadLength));
+                    }
+                    // `>` is dotted then halved, `>>` double dotted then quartered
+                    let den = 1u32 << count;
+                    let (long, short) = ((2 * den) - 1, 1);
+                    let (prev, next) = if ch == '>' { (long, short) } else { (short, long) };
+                    let too_long = self.err(AbcErrorKind::TooLong);
+                    let voice = self.voice();
+                    if let Some(Event::Note(len, _)) = voice.events.last_mut() {
+                        *len = len.checked_mul(prev).ok_or(too_long)? / den;
+                    }
+                    voice.broken = Some((next, den));
+                }
+                '(' => {
+                    // Otherwise, it's the start of a slur
+                    if chars.peek().map(|c| c.is_ascii_digit()) == Some(true) {
+                        let tuplet = self.tuplet(&mut chars)?;
+                        self.voice().tuplet = Some(tuplet);
+                    }
+                }
+                '[' => match chars.peek().copied() {
+                    Some(c) if c.is_ascii_digit() => {
+                        chars.next();
+                        let voice = self.voice();
+                        if c == '1' {
+                            voice.first_ending = Some(voice.events.len());
+                        }
+                    }
+                    Some('|') => {
+                        chars.next();
+                        self.bar_line();
+                    }
+                    Some(c) if c.is_ascii_alphabetic() && line_has_field(&mut chars.clone()) => {
+                        let field = chars.by_ref().take_while(|c| *c != ']').collect::<String>();
+                        let (name, value) = field.split_once(':').unwrap_or(("", ""));
+                        self.field(name.chars().next().unwrap_or(' '), value)?;
+                    }
+                    _ => self.chord(&mut chars)?,
+                },
+                '|' | ':' => self.bar_token(ch, &mut chars)?,
+                ']' => {}
+                other => return Err(self.err(AbcErrorKind::UnexpectedChar(other))),
+            }
+        }
+        Ok(())
+    }
+
+    fn accidental(
+        &self,
+        first: char,
+        chars: &mut core::iter::Peekable<core::str::Chars<'_>>,
+    ) -> Result<(Option<i16>, char), AbcError> {
+        let mut acc = None;
+        let mut ch = first;
+        loop {
+            acc = match (ch, acc) {
+                ('^', None) => Some(1),
+                ('^', Some(1)) => Some(2),
+                ('_', None) => Some(-1),
+                ('_', Some(-1)) => Some(-2),
+                ('=', None) => Some(0),
+                (c, acc) if c.is_ascii_alphabetic() => return Ok((acc, c)),
+                (c, _) => return Err(self.err(AbcErrorKind::UnexpectedChar(c))),
+            };
+            ch = chars.next().ok_or_else(|| self.err(AbcErrorKind::Unterminated))?;
+        }
+    }
+
+    // Play a chord as its first note, with the chord's length
+    fn chord(&mut self, chars: &mut core::iter::Peekable<core::str::Chars<'_>>) -> Result<(), AbcError> {
+        let mut first = None;
+        loop {
+            let ch = chars.next().ok_or_else(|| self.err(AbcErrorKind::Unterminated))?;
+            match ch {
+                ']' => break,
+                '^' | '_' | '=' | 'A'..='G' | 'a'..='g' => {
+                    let (acc, letter) = self.accidental(ch, chars)?;
+                    let note = self.note(letter, acc, chars)?;
+                    first.get_or_insert(note);
+                }
+                '-' => self.voice().tie = true,
+                ' ' => {}
+                other => return Err(self.err(AbcErrorKind::UnexpectedChar(other))),
+            }
+        }
+        let (n, d) = Self::length(chars);
+        if let Some((ticks, note)) = first {
+            let ticks = ticks.checked_mul(n).ok_or_else(|| self.err(AbcErrorKind::TooLong))? / d;
+            self.push_note(ticks, Some(note))?;
+        }
+        Ok(())
+    }
+
+    fn tuplet(&self, chars: &mut core::iter::Peekable<core::str::Chars<'_>>) -> Result<(u8, u32, u32), AbcError> {
+        let mut nums = [None; 3];
+        for (i, num) in nums.iter_mut().enumerate() {
+            if i != 0 {
+                if chars.peek() != Some(&':') {
+                    break;
+                }
+                chars.next();
+            }
+            let mut val = None;
+            while let Some(d) = chars.peek().and_then(|c| c.to_digit(10)) {
+                let next = val.unwrap_or(0u32).checked_mul(10).and_then(|v| v.checked_add(d));
+                val = Some(next.ok_or_else(|| self.err(AbcErrorKind::BadTuplet))?);
+                chars.next();
+            }
+            *num = val;
+        }
+
+        let p = nums[0].ok_or_else(|| self.err(AbcErrorKind::BadTuplet))?;
+        let compound = self.meter.0.is_multiple_of(3) && self.meter.0 > 3;
+        let q = match (nums[1], p) {
+            (Some(q), _) => q,
+            (None, 2 | 4 | 8) => 3,
+            (None, 3 | 6) => 2,
+            (None, 5 | 7 | 9) if compound => 3,
+            (None, 5 | 7 | 9) => 2,
+            _ => return Err(self.err(AbcErrorKind::BadTuplet)),
+        };
+        let r = nums[2].unwrap_or(p);
+        if p == 0 || q == 0 || p > u8::MAX as u32 {
+            return Err(self.err(AbcErrorKind::BadTuplet));
+        }
+        Ok((p as u8, q, r))
+    }
+
+    // Bar lines, repeats and endings, like `|`, `||`, `|]`, `|:`, `:|`,
+    // `::` and `:|2`
+    fn bar_token(
+        &mut self,
+        first: char,
+        chars: &mut core::iter::Peekable<core::str::Chars<'_>>,
+    ) -> Result<(), AbcError> {
+        let mut token = String::from(first);
+        while let Some(c) = chars.peek().copied() {
+            if matches!(c, '|' | ':' | ']') {
+                token.push(c);
+                chars.next();
+            } else {
+                break;
+            }
+        }
+
+        self.bar_line();
+        if token.starts_with(':') {
+            self.end_repeat()?;
+        }
+        if token.ends_with(':') {
+            let voice = self.voice();
+            voice.repeat_start = voice.events.len();
+        }
+        if token.ends_with(']') || token == "||" {
+            // A section end is also the start of any following repeat
+            let voice = self.voice();
+            voice.repeat_start = voice.events.len();
+        }
+
+        // An ending number right after the bar, like `|1` or `:|2`
+        if let Some(c) = chars.peek().copied().filter(|c| c.is_ascii_digit()) {
+            chars.next();
+            if c == '1' {
+                let voice = self.voice();
+                voice.first_ending = Some(voice.events.len());
+            }
+        }
+        Ok(())
+    }
+}
+
+// Is the rest of an inline `[` a field like `K:G]`?
+fn line_has_field(chars: &mut core::iter::Peekable<core::str::Chars<'_>>) -> bool {
+    chars.next();
+    chars.next() == Some(':')
+}
+
+// Split a voice's events into `BarBuf`s, at bar lines where possible
+fn pack(events: &[Event]) -> Result<Vec<BarBuf>, BarError> {
+    let mut out = vec![];
+    let mut cur = BarBuf::new();
+    let mut used = 0u32;
+
+    for measure in events.split(|e| *e == Event::Bar) {
+        let ticks = measure
+            .iter()
+            .map(|e| match e {
+                Event::Note(t, _) => *t,
+                Event::Bar => 0,
+            })
+            .sum::<u32>();
+        if used != 0 && (used + ticks) > PPQN_MAX as u32 {
+            out.push(core::mem::replace(&mut cur, BarBuf::new()));
+            used = 0;
+        }
+
+        for event in measure {
+            let Event::Note(ticks, note) = *event else {
+                continue;
+            };
+            if ticks == 0 {
+                continue;
+            }
+            // A single measure too long for a `BarBuf`
+            if used != 0 && (used + ticks) > PPQN_MAX as u32 {
+                out.push(core::mem::replace(&mut cur, BarBuf::new()));
+                used = 0;
+            }
+            let len = Length::PPQNCount(u16::try_from(ticks).map_err(|_| BarError::ExceededBarLength)?);
+            match note {
+                Some((pitch, octave)) => cur.push_note_simple(len, pitch, octave)?,
+                None => cur.push_rest_simple(len)?,
+            }
+            used += ticks;
+        }
+    }
+
+    if used != 0 || out.is_empty() {
+        out.push(cur);
+    }
+    Ok(out)
+}
+
+impl Tune {
+    /// Parse the first tune in an ABC file
+    pub fn parse(src: &str) -> Result<Self, AbcError> {
+        let mut parser = Parser {
+            line: 0,
+            meter: (4, 4),
+            unit: (PPQN_WHOLE / 8) as u32,
+            tempo: 120,
+            key: [0; 7],
+            title: None,
+            voices: vec![],
+            cur: 0,
+        };
+        let mut unit_set = false;
+        let mut in_body = false;
+
+        for (idx, line) in src.lines().enumerate() {
+            parser.line = idx + 1;
+            let trimmed = line.trim();
+            if trimmed.starts_with('%') {
+                continue;
+            }
+
+            let mut chars = trimmed.chars();
+            let is_field = matches!(
+                (chars.next(), chars.next()),
+                (Some(c), Some(':')) if c.is_ascii_alphabetic()
+            );
+
+            if !in_body {
+                if trimmed.is_empty() || !is_field {
+                    continue;
+                }
+                let (name, value) = trimmed.split_at(2);
+                let name = name.chars().next().unwrap_or(' ');
+                // Only the first tune in the file
+                if name == 'X' && parser.title.is_some() {
+                    break;
+                }
+                if name == 'L' {
+                    unit_set = true;
+                }
+                if name == 'M' && !unit_set {
+                    parser.field(name, value)?;
+                    // The default length depends on the meter
+                    let (n, d) = parser.meter;
+                    let eighth = (n as u32 * 4) >= (d as u32 * 3);
+                    parser.unit = (PPQN_WHOLE / if eighth { 8 } else { 16 }) as u32;
+                    continue;
+                }
+                parser.field(name, value)?;
+                if name == 'K' {
+                    in_body = true;
+                }
+                continue;
+            }
+
+            if trimmed.is_empty() {
+                // A blank line ends the tune
+                break;
+            }
+            if is_field {
+                let (name, value) = trimmed.split_at(2);
+                parser.field(name.chars().next().unwrap_or(' '), value)?;
+            } else {
+                parser.body_line(trimmed)?;
+            }
+        }
+
+        if !in_body {
+            return Err(AbcError {
+                line: parser.line,
+                kind: AbcErrorKind::MissingKey,
+            });
+        }
+
+        let line = parser.line;
+        let voices = parser
+            .voices
+            .iter()
+            .map(|v| {
+                Ok(AbcVoice {
+                    id: v.id.clone(),
+                    bars: pack(&v.events).map_err(|e| AbcError {
+                        line,
+                        kind: AbcErrorKind::Bar(e),
+                    })?,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Tune {
+            title: parser.title,
+            meter: parser.meter,
+            tempo: parser.tempo,
+            voices,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{PlayNote, PPQN_EIGHTH};
+
+    // (start, length, tone)
+    fn notes(bbuf: &BarBuf) -> Vec<(u16, u16, u8)> {
+        bbuf.notes()
+            .map(|n| {
+                let p = PlayNote::from_enc(&n, 0);
+                (p.start, p.length, p.tone)
+            })
+            .collect()
+    }
+
+    fn tones(bbuf: &BarBuf) -> Vec<u8> {
+        notes(bbuf).iter().map(|n| n.2).collect()
+    }
+
+    #[test]
+    fn header_fields() {
+        let tune = Tune::parse(
+            "X:1
+            T:Speed the Plough
+            T:(alternate title)
+            M:6/8
+            Q:3/8=40
+            K:Ador
+            ABc|
+            ",
+        )
+        .unwrap();
+        assert_eq!(tune.title.as_deref(), Some("Speed the Plough"));
+        assert_eq!(tune.meter, (6, 8));
+        assert_eq!(tune.tempo, 60);
+        assert_eq!(tune.voices.len(), 1);
+        // No `L:`, so 6/8 defaults to eighth notes
+        assert_eq!(notes(&tune.voices[0].bars[0])[0], (0, PPQN_EIGHTH, 57));
+    }
+
+    #[test]
+    fn pitches_and_keys() {
+        let tune = Tune::parse(
+            "L:1/4
+            K:D
+            C c C, c' f | ^G _B =F F | f ^f f | [K:Bb] B e",
+        )
+        .unwrap();
+        let bar = &tune.voices[0].bars[0];
+        assert_eq!(
+            tones(bar),
+            vec![
+                // D major sharpens C and F
+                49, 61, 37, 73, 66,
+                // Explicit accidentals, which last until the bar line
+                56, 58, 53, 53,
+                // Then it's back to the key signature
+                66, 66, 66,
+                // New key
+                58, 63,
+            ]
+        );
+    }
+
+    #[test]
+    fn lengths() {
+        let q = PPQN_EIGHTH * 2;
+        let tune = Tune::parse("L:1/8\nK:C\nA2 A/ A// A3/2 A> A A<A z4 | (3ABc A-A | A-|A").unwrap();
+        let bar = &tune.voices[0].bars[0];
+        let lens = notes(bar).iter().map(|n| n.1).collect::<Vec<_>>();
+        let e = PPQN_EIGHTH;
+        assert_eq!(
+            lens,
+            vec![
+                q,
+                e / 2,
+                e / 4,
+                e + (e / 2),
+                // Broken rhythms
+                e + (e / 2),
+                e / 2,
+                e / 2,
+                e + (e / 2),
+                // Triplet
+                (e * 2) / 3,
+                (e * 2) / 3,
+                (e * 2) / 3,
+                // Ties, even over a bar line
+                q,
+                q,
+            ]
+        );
+        // The rest is in there too
+        assert_eq!(notes(bar)[8].0, q + (e / 2) + (e / 4) + (3 * (e + (e / 2))) + (2 * (e / 2)) + (e * 4));
+    }
+
+    #[test]
+    fn repeats() {
+        let tune = Tune::parse("K:C\nL:1/4\n|: C D |1 E :|2 F || G A :: B c :|").unwrap();
+        let bar = &tune.voices[0].bars[0];
+        assert_eq!(
+            tones(bar),
+            vec![48, 50, 52, 48, 50, 53, 55, 57, 55, 57, 59, 60, 59, 60]
+        );
+    }
+
+    #[test]
+    fn chords_and_decorations() {
+        let tune = Tune::parse("K:C\nL:1/4\n\"Am\"!trill!~A {g}B [CEG]2 .c uD").unwrap();
+        let bar = &tune.voices[0].bars[0];
+        assert_eq!(
+            notes(bar).iter().map(|n| (n.1, n.2)).collect::<Vec<_>>(),
+            vec![(192, 57), (192, 59), (384, 48), (192, 60), (192, 50)]
+        );
+    }
+
+    #[test]
+    fn voices() {
+        let tune = Tune::parse(
+            "K:C
+            L:1/4
+            V:T1
+            V:B1
+            [V:T1] c d e f |
+            [V:B1] C, D, |
+            V:T1
+            g a |",
+        )
+        .unwrap();
+        assert_eq!(tune.voices.len(), 2);
+        assert_eq!(tune.voices[0].id, "T1");
+        assert_eq!(tones(&tune.voices[0].bars[0]), vec![60, 62, 64, 65, 67, 69]);
+        assert_eq!(tones(&tune.voices[1].bars[0]), vec![36, 38]);
+    }
+
+    #[test]
+    fn long_tunes_are_split() {
+        // 20 bars of 4/4, more than fits in one `BarBuf`
+        let mut src = String::from("M:4/4\nL:1/4\nK:G\n");
+        for _ in 0..20 {
+            src.push_str("G A B c | ");
+        }
+        let tune = Tune::parse(&src).unwrap();
+        let bars = &tune.voices[0].bars;
+        assert_eq!(bars.len(), 2);
+        assert_eq!(bars[0].notes().count(), 64);
+        assert_eq!(bars[1].notes().count(), 16);
+        assert_eq!(notes(&bars[1])[0], (0, PPQN_QUARTER, 55));
+    }
+
+    #[test]
+    fn errors() {
+        let err = |src| Tune::parse(src).err().unwrap();
+        assert_eq!(err("X:1\nT:hi\n").kind, AbcErrorKind::MissingKey);
+        assert_eq!(err("K:C\nA B\nC ?").line, 3);
+        assert_eq!(err("K:C\nA ?").kind, AbcErrorKind::UnexpectedChar('?'));
+        assert_eq!(err("K:C\n\"Am A").kind, AbcErrorKind::Unterminated);
+        assert_eq!(err("M:x/4\nK:C\n").kind, AbcErrorKind::BadMeter);
+        assert_eq!(err("K:H\n").kind, AbcErrorKind::BadKey);
+        assert_eq!(err("K:C\n(0A").kind, AbcErrorKind::BadTuplet);
+    }
+
+    #[test]
+    fn malformed_lengths() {
+        let err = |src| Tune::parse(src).err().unwrap().kind;
+        assert_eq!(err("K:C\n[1 C |: D :|"), AbcErrorKind::BadEnding);
+        assert_eq!(err("K:C\nA>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>B"), AbcErrorKind::BadLength);
+        assert_eq!(err("K:C\nA99999999999"), AbcErrorKind::TooLong);
+        assert_eq!(err("K:C\nz99999999999"), AbcErrorKind::TooLong);
+        assert_eq!(err("K:C\n[CE]99999999999"), AbcErrorKind::TooLong);
+        assert_eq!(err("L:99999999/1\nK:C\n"), AbcErrorKind::BadLength);
+        assert_eq!(err("Q:1/4=99999999999\nK:C\n"), AbcErrorKind::BadTempo);
+        assert_eq!(err("Q:99999999/4=120\nK:C\n"), AbcErrorKind::BadTempo);
+        assert_eq!(err("K:C\n(2:99999999999A"), AbcErrorKind::BadTuplet);
+        assert_eq!(err("K:C\n(2:99999999AB"), AbcErrorKind::TooLong);
+        assert_eq!(err("K:C\nZ4294967295"), AbcErrorKind::TooLong);
+        // Repeats can't make a voice too long either
+        let mut src = String::from("K:C\n");
+        for _ in 0..16 {
+            src.push_str("|: Z1000 :|");
+        }
+        assert_eq!(err(&src), AbcErrorKind::TooLong);
+    }
+}
@@ -1,12 +1,28 @@
-use minijam::scale::Pitch;
+use core::ops::Range;
 
-use crate::{EncError, EncNote, Length, MAX_ENCODING_SIZE, PPQN_MAX};
+use minijam::{clock::Timebase, scale::Pitch, tones::ToneKind, NoteError, Track};
 
+use crate::{EncError, EncNote, EncStart, EncLength, Length, PlayNote, MAX_ENCODING_SIZE, EIGHTH_BEATS_MAX, PPQN_64TH, PPQN_MAX};
+
+/// The default capacity of a `BarBuf` in bytes: exactly `PPQN_MAX` ticks
+/// (64 quarter notes) of back to back sixty-fourth notes. Each of those takes
+/// four bytes (a start, tone and length), except that a start on an eighth
+/// note takes one byte rather than two. Notes with a pitch offset take a
+/// byte more, and chords add notes without adding ticks, so either can run
+/// out of room sooner.
+///
+/// The bytes are stored inline, so a default `BarBuf` is nearly 4KiB
+/// wherever it lives: on the stack, in a `Vec`, or in a clone. Use a smaller
+/// `N` where that's too much, like short bars on a small target.
+pub const BAR_BYTES: usize = (PPQN_MAX / PPQN_64TH) as usize * 4 - EIGHTH_BEATS_MAX as usize;
+
+/// A bar of encoded notes, in a fixed `N` bytes
 #[derive(Clone)]
-pub struct BarBuf {
+pub struct BarBuf<const N: usize = BAR_BYTES> {
     ppqn_idx: u16,
     notes: usize,
-    buf: Vec<u8>,
+    len: usize,
+    buf: [u8; N],
 }
 
 impl From<EncError> for BarError {
@@ -35,22 +51,100 @@ impl<'a> Iterator for Notes<'a> {
     }
 }
 
+/// Notes grouped by their start, from `BarBuf::chords`. Each group is
+/// iterated over like the notes of the whole bar.
+pub struct Chords<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Iterator for Chords<'a> {
+    type Item = Notes<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (first, mut rem) = EncNote::take_from_slice(self.buf).ok()?;
+        let mut notes = 1;
+        while let Ok((note, next)) = EncNote::take_from_slice(rem) {
+            if note.ppqn_start() != first.ppqn_start() {
+                break;
+            }
+            rem = next;
+            notes += 1;
+        }
+
+        let (chord, rest) = self.buf.split_at(self.buf.len() - rem.len());
+        self.buf = rest;
+        Some(Notes { notes, buf: chord })
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum BarError {
     BarFull,
     ExceededBarLength,
     EncodingErr(EncError),
+    /// There's no note with the given index
+    NoSuchNote,
+    /// There's no room left for the note's encoding
+    OutOfSpace,
+}
+
+impl<const N: usize> Default for BarBuf<N> {
+    fn default() -> Self {
+        Self::empty()
+    }
 }
 
 impl BarBuf {
-    pub fn new() -> Self {
+    /// An empty bar with the default capacity. Use `BarBuf::<N>::empty`
+    /// for other sizes.
+    pub const fn new() -> Self {
+        Self::empty()
+    }
+
+    pub fn from_notes_simple<'a, I>(into_iter: I) -> Result<Self, BarError>
+    where
+        I: IntoIterator<Item = &'a (Length, Pitch, u8)>,
+    {
+        let mut bbuf = Self::new();
+        into_iter
+            .into_iter()
+            .try_for_each(|(l, p, o)| bbuf.push_note_simple(*l, *p, *o))?;
+        Ok(bbuf)
+    }
+}
+
+impl<const N: usize> BarBuf<N> {
+    pub const fn empty() -> Self {
         Self {
             ppqn_idx: 0,
             notes: 0,
-            buf: Vec::new(),
+            len: 0,
+            buf: [0; N],
         }
     }
 
+    /// The most bytes of notes this can hold
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    // Put `bytes` at offset `at`, moving everything after it along
+    fn splice_in(&mut self, at: usize, bytes: &[u8]) -> Result<(), BarError> {
+        let new_len = self.len + bytes.len();
+        if new_len > N {
+            return Err(BarError::OutOfSpace);
+        }
+        self.buf.copy_within(at..self.len, at + bytes.len());
+        self.buf[at..at + bytes.len()].copy_from_slice(bytes);
+        self.len = new_len;
+        Ok(())
+    }
+
+    fn splice_out(&mut self, bytes: Range<usize>) {
+        self.buf.copy_within(bytes.end..self.len, bytes.start);
+        self.len -= bytes.len();
+    }
+
     // Check if the current buffer is totally full, in terms of
     // the 64-beat/16-bar 4:4 maximum
     fn check_full(&self) -> Result<(), BarError> {
@@ -62,7 +156,12 @@ impl BarBuf {
     }
 
     pub fn bytes(&self) -> &[u8] {
-        &self.buf
+        &self.buf[..self.len]
+    }
+
+    /// The length of everything pushed so far (notes and rests), in ticks
+    pub fn ppqn_len(&self) -> u16 {
+        self.ppqn_idx
     }
 
     // Increment the "start" ppqn index.
@@ -90,17 +189,6 @@ impl BarBuf {
         self.increment_ppqn(length)
     }
 
-    pub fn from_notes_simple<'a, I>(into_iter: I) -> Result<Self, BarError>
-    where
-        I: IntoIterator<Item = &'a (Length, Pitch, u8)>,
-    {
-        let mut bbuf = BarBuf::new();
-        into_iter
-            .into_iter()
-            .try_for_each(|(l, p, o)| bbuf.push_note_simple(*l, *p, *o))?;
-        Ok(bbuf)
-    }
-
     pub fn push_note_simple(
         &mut self,
         length: Length,
@@ -108,18 +196,201 @@ impl BarBuf {
         octave: u8,
     ) -> Result<(), BarError> {
         self.check_full()?;
-
-        // Encode the note to a temp buffer, returning if the encoding failed
-        let mut buf = [0x00; MAX_ENCODING_SIZE];
         let note = EncNote::new_simple(pitch, octave, self.ppqn_idx, length)?;
+        self.push_enc(note, length)
+    }
+
+    /// Push a note by MIDI-style note number, with a fraction of the way to
+    /// the next semitone in 1/256ths
+    pub fn push_note_tone(&mut self, length: Length, tone: u8, offset: u8) -> Result<(), BarError> {
+        self.check_full()?;
+        let note = EncNote::new_tone(tone, offset, self.ppqn_idx, length)?;
+        self.push_enc(note, length)
+    }
+
+    /// Push a note starting at `start`, rather than where the last note or
+    /// rest ended. It may overlap notes already pushed, to make a chord, and
+    /// notes are kept in order of their start.
+    ///
+    /// If the note ends after everything pushed so far, the bar is
+    /// lengthened to its end, so the next `push_note_simple` follows it.
+    pub fn push_note_at(
+        &mut self,
+        start: u16,
+        length: Length,
+        pitch: Pitch,
+        octave: u8,
+    ) -> Result<(), BarError> {
+        let note = EncNote::new_simple(pitch, octave, start, length)?;
+        self.insert(note)
+    }
+
+    /// Like `push_note_at`, by MIDI-style note number and offset
+    pub fn push_tone_at(&mut self, start: u16, length: Length, tone: u8, offset: u8) -> Result<(), BarError> {
+        let note = EncNote::new_tone(tone, offset, start, length)?;
+        self.insert(note)
+    }
+
+    /// Insert a note in order of its start, after any notes starting at the
+    /// same tick. Like `push_note_at`, this may lengthen the bar.
+    pub fn insert(&mut self, note: EncNote) -> Result<(), BarError> {
+        let end = Self::note_end(&note)?;
+        let (buf, used) = Self::encode(&note)?;
+
+        // After any notes with the same start, so they stay in the order
+        // they were pushed
+        let at = self.offset_after(note.ppqn_start());
+        self.splice_in(at, &buf[..used])?;
 
-        // Extend our internal buffer with the encoded contents
+        self.ppqn_idx = self.ppqn_idx.max(end);
+        self.notes += 1;
+        Ok(())
+    }
+
+    fn encode(note: &EncNote) -> Result<([u8; MAX_ENCODING_SIZE], usize), BarError> {
+        let mut buf = [0x00; MAX_ENCODING_SIZE];
         let rem_len = note.write_to_slice(&mut buf)?.len();
-        let used = buf.len() - rem_len;
-        self.buf.extend_from_slice(&buf[..used]);
+        Ok((buf, MAX_ENCODING_SIZE - rem_len))
+    }
 
-        // Update our tracking variables
+    fn note_end(note: &EncNote) -> Result<u16, BarError> {
+        let end = note.ppqn_start() + note.ppqn_len();
+        if end > PPQN_MAX {
+            return Err(BarError::ExceededBarLength);
+        }
+        Ok(end)
+    }
+
+    // The byte offset just past the last note starting at or before `start`
+    fn offset_after(&self, start: u16) -> usize {
+        self.offset_where(|note| note.ppqn_start() > start)
+    }
+
+    // The byte offset of the first note matching `stop`, or the end
+    fn offset_where<F: Fn(&EncNote) -> bool>(&self, stop: F) -> usize {
+        let mut rem = self.bytes();
+        while let Ok((note, next)) = EncNote::take_from_slice(rem) {
+            if stop(&note) {
+                break;
+            }
+            rem = next;
+        }
+        self.len - rem.len()
+    }
+
+    // The note at `index`, and where its bytes are
+    fn find(&self, index: usize) -> Result<(EncNote, Range<usize>), BarError> {
+        let mut rem = self.bytes();
+        for i in 0..self.notes {
+            let (note, next) = EncNote::take_from_slice(rem)?;
+            if i == index {
+                let start = self.len - rem.len();
+                return Ok((note, start..self.len - next.len()));
+            }
+            rem = next;
+        }
+        Err(BarError::NoSuchNote)
+    }
+
+    /// The note at `index`, counting in order of start
+    pub fn get(&self, index: usize) -> Option<EncNote> {
+        self.find(index).ok().map(|(note, _)| note)
+    }
+
+    /// Remove the note at `index`. The bar keeps its length, as if the note
+    /// were a rest.
+    pub fn remove(&mut self, index: usize) -> Result<EncNote, BarError> {
+        let (note, bytes) = self.find(index)?;
+        self.splice_out(bytes);
+        self.notes -= 1;
+        Ok(note)
+    }
+
+    /// Remove every note starting in `ticks`, returning how many there were.
+    /// Like `remove`, the bar keeps its length.
+    pub fn remove_range(&mut self, ticks: Range<u16>) -> usize {
+        // Notes are in order of start, so these are all together
+        let from = self.offset_where(|n| n.ppqn_start() >= ticks.start);
+        let to = self.offset_where(|n| n.ppqn_start() >= ticks.end).max(from);
+        let removed = Notes {
+            notes: 0,
+            buf: &self.bytes()[from..to],
+        }
+        .count();
+        self.splice_out(from..to);
+        self.notes -= removed;
+        removed
+    }
+
+    /// Replace the note at `index`, returning the old one. The new note is
+    /// put in order of its start, so may end up at a different index.
+    ///
+    /// Nothing is changed if the new note doesn't fit.
+    pub fn replace(&mut self, index: usize, note: EncNote) -> Result<EncNote, BarError> {
+        Self::note_end(&note)?;
+        let (_, used) = Self::encode(&note)?;
+        let (_, old_bytes) = self.find(index)?;
+        if self.len - old_bytes.len() + used > N {
+            return Err(BarError::OutOfSpace);
+        }
+
+        let old = self.remove(index)?;
+        // Can't fail, having been checked above
+        self.insert(note)?;
+        Ok(old)
+    }
+
+    /// Move the note at `index` to start at `start`
+    pub fn move_note(&mut self, index: usize, start: u16) -> Result<(), BarError> {
+        self.edit(index, |note| {
+            note.start = EncStart::try_from(start)?;
+            Ok(())
+        })
+    }
+
+    /// Move the note at `index` up or down by `semitones`
+    pub fn transpose(&mut self, index: usize, semitones: i8) -> Result<(), BarError> {
+        self.edit(index, |note| {
+            let tone = note
+                .pitch
+                .tone
+                .checked_add_signed(semitones)
+                .filter(|t| *t <= 0x7F)
+                .ok_or(EncError::ValueOutOfBounds)?;
+            note.pitch.tone = tone;
+            Ok(())
+        })
+    }
+
+    /// Change the length of the note at `index`
+    pub fn set_length(&mut self, index: usize, length: Length) -> Result<(), BarError> {
+        self.edit(index, |note| {
+            note.length = EncLength::try_from(length.to_ppqn())?;
+            Ok(())
+        })
+    }
+
+    fn edit<F>(&mut self, index: usize, change: F) -> Result<(), BarError>
+    where
+        F: FnOnce(&mut EncNote) -> Result<(), EncError>,
+    {
+        let (mut note, _) = self.find(index)?;
+        change(&mut note)?;
+        self.replace(index, note).map(drop)
+    }
+
+    fn push_enc(&mut self, note: EncNote, length: Length) -> Result<(), BarError> {
+        // Encode the note to a temp buffer, returning if the encoding failed
+        let (buf, used) = Self::encode(&note)?;
+
+        // Update our tracking variables, checking the length first so a
+        // note that doesn't fit leaves the buffer as it was
+        let old_idx = self.ppqn_idx;
         self.increment_ppqn(length)?;
+        if let Err(e) = self.splice_in(self.len, &buf[..used]) {
+            self.ppqn_idx = old_idx;
+            return Err(e);
+        }
         self.notes += 1;
 
         Ok(())
@@ -128,9 +399,43 @@ impl BarBuf {
     pub fn notes<'a>(&'a self) -> Notes<'a> {
         Notes {
             notes: self.notes,
-            buf: &self.buf,
+            buf: self.bytes(),
         }
     }
+
+    /// Notes grouped by their start, in order
+    pub fn chords(&self) -> Chords<'_> {
+        Chords { buf: self.bytes() }
+    }
+
+    /// The notes that are playing at `tick`
+    pub fn sounding_at(&self, tick: u16) -> impl Iterator<Item = EncNote> + '_ {
+        self.notes()
+            .filter(move |n| n.ppqn_start() <= tick && tick < n.ppqn_start() + n.ppqn_len())
+    }
+
+    /// Queue every note on a track, starting at `start_tick`.
+    ///
+    /// Returns an error if the track could not take every note. A track
+    /// plays one note at a time, so this fails for bars with chords.
+    pub fn queue_on<const DEPTH: usize, T: Timebase>(
+        &self,
+        track: &mut Track<DEPTH>,
+        clock: &T,
+        kind: ToneKind,
+        start_tick: u32,
+    ) -> Result<(), NoteError> {
+        self.notes().try_for_each(|n| {
+            let note = PlayNote::from_enc(&n, 0);
+            track.add_note_freq_ticks(
+                clock,
+                kind,
+                note.frequency(),
+                start_tick + note.start as u32,
+                note.length.into(),
+            )
+        })
+    }
 }
 
 #[cfg(test)]
@@ -235,6 +540,21 @@ mod test {
         assert_eq!(bbuf.bytes().len(), 3);
     }
 
+    #[test]
+    fn buf_tone() {
+        let mut bbuf = BarBuf::new();
+        bbuf.push_note_tone(Length::Quarter, 60, 0).unwrap();
+        bbuf.push_note_tone(Length::Quarter, 61, 128).unwrap();
+        assert_eq!(
+            bbuf.push_note_tone(Length::Quarter, 0x80, 0),
+            Err(BarError::EncodingErr(EncError::ValueOutOfBounds))
+        );
+
+        let out = bbuf.notes().map(|n| n.pitch_tone_offset()).collect::<Vec<_>>();
+        assert_eq!(out, vec![(60, 0), (61, 128)]);
+        assert_eq!(bbuf.ppqn_idx, 2 * PPQN_QUARTER);
+    }
+
     #[test]
     fn buf_fill() {
         let cases = [
@@ -270,6 +590,145 @@ mod test {
         }
     }
 
+    #[test]
+    fn chords() {
+        // A C major chord for a half note, with a melody over it
+        let mut bbuf = BarBuf::new();
+        bbuf.push_note_simple(Length::Quarter, Pitch::G, 4).unwrap();
+        bbuf.push_note_simple(Length::Quarter, Pitch::A, 4).unwrap();
+        bbuf.push_note_at(0, Length::Half, Pitch::C, 3).unwrap();
+        bbuf.push_note_at(0, Length::Half, Pitch::E, 3).unwrap();
+        bbuf.push_tone_at(PPQN_QUARTER, Length::Eighth, 53, 0).unwrap();
+        assert_eq!(bbuf.notes, 5);
+        assert_eq!(bbuf.ppqn_len(), 2 * PPQN_QUARTER);
+
+        let starts = bbuf.notes().map(|n| n.ppqn_start()).collect::<Vec<_>>();
+        assert_eq!(starts, [0, 0, 0, PPQN_QUARTER, PPQN_QUARTER]);
+
+        let chords = bbuf
+            .chords()
+            .map(|c| c.map(|n| n.pitch_tone_offset().0).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(chords, [vec![55, 36, 40], vec![57, 53]]);
+
+        let sounding = |tick| bbuf.sounding_at(tick).count();
+        assert_eq!(sounding(0), 3);
+        assert_eq!(sounding(PPQN_QUARTER + 1), 4);
+        assert_eq!(sounding(PPQN_QUARTER * 3 / 2), 3);
+        assert_eq!(sounding(PPQN_QUARTER * 2), 0);
+
+        // A note past the end lengthens the bar, and the next one follows it
+        bbuf.push_note_at(PPQN_QUARTER * 3, Length::Quarter, Pitch::C, 4).unwrap();
+        bbuf.push_note_simple(Length::Quarter, Pitch::D, 4).unwrap();
+        let last = bbuf.notes().last().unwrap();
+        assert_eq!(last.ppqn_start(), PPQN_QUARTER * 4);
+
+        assert_eq!(
+            bbuf.push_note_at(PPQN_MAX - 1, Length::Quarter, Pitch::C, 4),
+            Err(BarError::ExceededBarLength)
+        );
+        assert_eq!(
+            bbuf.push_note_at(PPQN_MAX, Length::Quarter, Pitch::C, 4),
+            Err(BarError::EncodingErr(EncError::ValueOutOfBounds))
+        );
+    }
+
+    #[test]
+    fn edit() {
+        let tones = |bbuf: &BarBuf| {
+            bbuf.notes()
+                .map(|n| (n.ppqn_start() / PPQN_QUARTER, n.pitch_tone_offset().0))
+                .collect::<Vec<_>>()
+        };
+
+        let notes = [
+            (Length::Quarter, Pitch::C, 4),
+            (Length::Quarter, Pitch::D, 4),
+            (Length::Quarter, Pitch::E, 4),
+            (Length::Quarter, Pitch::F, 4),
+        ];
+        let mut bbuf = BarBuf::from_notes_simple(&notes).unwrap();
+        assert_eq!(tones(&bbuf), [(0, 48), (1, 50), (2, 52), (3, 53)]);
+
+        // Removing keeps the length, leaving a rest
+        let removed = bbuf.remove(1).unwrap();
+        assert_eq!(removed.pitch_tone_offset(), (50, 0));
+        assert_eq!(tones(&bbuf), [(0, 48), (2, 52), (3, 53)]);
+        assert_eq!(bbuf.ppqn_len(), 4 * PPQN_QUARTER);
+        assert_eq!(bbuf.remove(3), Err(BarError::NoSuchNote));
+
+        // Moving re-sorts, and past the end lengthens the bar
+        bbuf.move_note(0, 5 * PPQN_QUARTER).unwrap();
+        assert_eq!(tones(&bbuf), [(2, 52), (3, 53), (5, 48)]);
+        assert_eq!(bbuf.ppqn_len(), 6 * PPQN_QUARTER);
+
+        bbuf.transpose(0, -12).unwrap();
+        bbuf.transpose(1, 2).unwrap();
+        assert_eq!(tones(&bbuf), [(2, 40), (3, 55), (5, 48)]);
+        assert_eq!(
+            bbuf.transpose(0, 100),
+            Err(BarError::EncodingErr(EncError::ValueOutOfBounds))
+        );
+
+        bbuf.set_length(2, Length::Half).unwrap();
+        assert_eq!(bbuf.get(2).unwrap().ppqn_len(), 2 * PPQN_QUARTER);
+        assert_eq!(bbuf.ppqn_len(), 7 * PPQN_QUARTER);
+
+        // A failed edit changes nothing
+        let before = bbuf.bytes().to_vec();
+        assert_eq!(
+            bbuf.set_length(2, Length::QuarterCount(64)),
+            Err(BarError::ExceededBarLength)
+        );
+        assert_eq!(bbuf.bytes(), before);
+
+        let new = EncNote::new_simple(Pitch::G, 4, 0, Length::Eighth).unwrap();
+        let old = bbuf.replace(1, new).unwrap();
+        assert_eq!(old.pitch_tone_offset(), (55, 0));
+        assert_eq!(tones(&bbuf), [(0, 55), (2, 40), (5, 48)]);
+
+        assert_eq!(bbuf.remove_range(PPQN_QUARTER..5 * PPQN_QUARTER), 1);
+        assert_eq!(tones(&bbuf), [(0, 55), (5, 48)]);
+        assert_eq!(bbuf.remove_range(0..PPQN_MAX), 2);
+        assert_eq!(bbuf.notes().count(), 0);
+        assert!(bbuf.bytes().is_empty());
+        assert_eq!(bbuf.ppqn_len(), 7 * PPQN_QUARTER);
+    }
+
+    #[test]
+    fn small_buf() {
+        // Quarter notes on the beat take three bytes each
+        let mut bbuf = BarBuf::<9>::empty();
+        assert_eq!(bbuf.capacity(), 9);
+        for _ in 0..3 {
+            bbuf.push_note_simple(Length::Quarter, Pitch::C, 4).unwrap();
+        }
+
+        // Nothing changes when there's no room
+        assert_eq!(
+            bbuf.push_note_simple(Length::Quarter, Pitch::D, 4),
+            Err(BarError::OutOfSpace)
+        );
+        assert_eq!(bbuf.push_note_at(0, Length::Quarter, Pitch::E, 4), Err(BarError::OutOfSpace));
+        assert_eq!(bbuf.notes().count(), 3);
+        assert_eq!(bbuf.ppqn_len(), 3 * PPQN_QUARTER);
+
+        // Until a note is removed
+        bbuf.remove(1).unwrap();
+        bbuf.push_note_at(0, Length::Quarter, Pitch::E, 4).unwrap();
+        assert_eq!(bbuf.bytes().len(), 9);
+        assert_eq!(bbuf.chords().count(), 2);
+    }
+
+    #[test]
+    fn default_capacity() {
+        // Sixty-fourths fill a default `BarBuf` to the last byte
+        let mut bbuf = BarBuf::new();
+        pushn_notes(&mut bbuf, Length::SixtyFourth, 64 * 16);
+        assert_eq!(bbuf.bytes().len(), BAR_BYTES);
+        assert_eq!(BAR_BYTES, 3968);
+    }
+
     fn pushn_notes(bbuf: &mut BarBuf, len: Length, ct: usize) {
         for _ in 0..ct {
             bbuf.push_note_simple(len, Pitch::C, 4).unwrap();
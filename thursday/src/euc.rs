@@ -1,3 +1,5 @@
+use core::fmt;
+
 #[derive(Clone)]
 pub struct Euc32 {
     interval: u32,
@@ -29,6 +31,17 @@ impl Iterator for EucCycler {
     }
 }
 
+// Like `[x..x.x.]`, with an `x` for each hit
+impl fmt::Display for Euc32 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[")?;
+        for i in 0..self.interval {
+            f.write_str(if (self.data & (1 << i)) != 0 { "x" } else { "." })?;
+        }
+        f.write_str("]")
+    }
+}
+
 impl Euc32 {
     pub fn new(hits: u32, interval: u32) -> Option<Euc32> {
         if (interval == 0) || (hits > 32) || (interval > 32) || (hits > interval) {
@@ -63,14 +76,9 @@ impl Euc32 {
         Some(Euc32 { interval, data })
     }
 
-    pub fn stringify(&self) -> String {
-        let mut out = String::new();
-        out += "[";
-        for i in 0..self.interval {
-            out += if (self.data & (1 << i)) != 0 { "x" } else { "." };
-        }
-        out += "]";
-        out
+    #[cfg(feature = "alloc")]
+    pub fn stringify(&self) -> alloc::string::String {
+        alloc::format!("{self}")
     }
 
     pub fn cycler(&self) -> EucCycler {
@@ -91,7 +99,7 @@ mod test {
         println!();
         for i in 0..=7 {
             let x = Euc32::new(i, 7).unwrap();
-            println!("{}: {}", i, x.stringify());
+            println!("{}: {}", i, x);
         }
     }
 
@@ -100,7 +108,7 @@ mod test {
         println!();
         for i in 0..=13 {
             let x = Euc32::new(i, 13).unwrap();
-            println!("{:02}: {}", i, x.stringify());
+            println!("{:02}: {}", i, x);
         }
     }
 
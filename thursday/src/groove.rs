@@ -0,0 +1,262 @@
+// Swing and groove templates.
+//
+// A groove nudges the timing (and velocity) of each 16th note step in a
+// measure of 4/4. Notes that fall between the steps (like triplets) are
+// moved by an amount interpolated between the steps on either side, so the
+// groove acts like a smooth warp of time, and notes are never reordered.
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+use crate::{PlayNote, PPQN_16TH, PPQN_EIGHTH, PPQN_MAX};
+#[cfg(feature = "alloc")]
+use crate::{bars::BarBuf, phrdat::EncRhythm};
+
+/// The number of 16th note steps in a groove template (one measure of 4/4)
+pub const GROOVE_STEPS: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Groove {
+    // Offset in ticks for each 16th note step
+    timing: [i8; GROOVE_STEPS],
+    // Offset added to the velocity of notes on each 16th note step
+    velocity: [i8; GROOVE_STEPS],
+}
+
+impl Default for Groove {
+    fn default() -> Self {
+        Self::straight()
+    }
+}
+
+impl Groove {
+    /// Create a groove template.
+    ///
+    /// Returns `None` if neighbouring timing offsets differ by more than a
+    /// 16th note, as this could swap the order of notes.
+    pub fn new(timing: [i8; GROOVE_STEPS], velocity: [i8; GROOVE_STEPS]) -> Option<Self> {
+        let ok = (0..GROOVE_STEPS).all(|i| {
+            let cur = timing[i] as i16;
+            let nxt = timing[(i + 1) % GROOVE_STEPS] as i16;
+            (nxt - cur).unsigned_abs() <= PPQN_16TH
+        });
+        if ok {
+            Some(Self { timing, velocity })
+        } else {
+            None
+        }
+    }
+
+    /// No timing or velocity changes at all
+    pub fn straight() -> Self {
+        Self {
+            timing: [0; GROOVE_STEPS],
+            velocity: [0; GROOVE_STEPS],
+        }
+    }
+
+    /// Swing the off-beat 8th notes.
+    ///
+    /// `percent` is the share of each pair of 8ths taken by the first one:
+    /// 50 is straight, ~66 is a triplet feel, and 75 is a dotted 8th and a
+    /// 16th. Values outside of `50..=75` are clamped.
+    pub fn swing_eighths(percent: u8) -> Self {
+        Self::swing(percent, PPQN_EIGHTH)
+    }
+
+    /// Swing the off-beat 16th notes. See [`Groove::swing_eighths`].
+    pub fn swing_sixteenths(percent: u8) -> Self {
+        Self::swing(percent, PPQN_16TH)
+    }
+
+    fn swing(percent: u8, unit: u16) -> Self {
+        let percent = percent.clamp(50, 75) as u16;
+        let delay = ((percent - 50) * unit * 2) / 100;
+        let period = ((unit * 2) / PPQN_16TH) as usize;
+
+        let mut groove = Self::straight();
+        groove
+            .timing
+            .iter_mut()
+            .skip(period / 2)
+            .step_by(period)
+            .for_each(|t| *t = delay as i8);
+        groove
+    }
+
+    pub fn timing(&self) -> &[i8; GROOVE_STEPS] {
+        &self.timing
+    }
+
+    pub fn velocity(&self) -> &[i8; GROOVE_STEPS] {
+        &self.velocity
+    }
+
+    /// Move a tick position according to the groove, staying below `limit`
+    fn warp(&self, tick: u16, limit: u16) -> u16 {
+        let step_len = PPQN_16TH as i32;
+        let tick = tick as i32;
+        let step = (tick / step_len) as usize;
+        let rem = tick % step_len;
+
+        let cur = self.timing[step % GROOVE_STEPS] as i32;
+        let nxt = self.timing[(step + 1) % GROOVE_STEPS] as i32;
+        let offset = cur + ((nxt - cur) * rem) / step_len;
+
+        (tick + offset).clamp(0, limit as i32) as u16
+    }
+
+    /// The velocity offset of the step nearest to the given tick
+    fn velocity_at(&self, tick: u16) -> i8 {
+        let step = ((tick + (PPQN_16TH / 2)) / PPQN_16TH) as usize;
+        self.velocity[step % GROOVE_STEPS]
+    }
+
+    /// Apply the groove to a single note.
+    ///
+    /// The end of the note is moved along with its start, so legato lines
+    /// stay legato.
+    pub fn apply(&self, note: &PlayNote) -> PlayNote {
+        let end = note.start.saturating_add(note.length).min(PPQN_MAX);
+        let start = self.warp(note.start, PPQN_MAX - 1);
+        let end = self.warp(end, PPQN_MAX).max(start + 1);
+        let velocity = (note.velocity as i16) + (self.velocity_at(note.start) as i16);
+
+        PlayNote {
+            start,
+            length: end - start,
+            velocity: velocity.clamp(1, 127) as u8,
+            ..note.clone()
+        }
+    }
+
+    /// Apply the groove to every note in a bar buffer, starting each note
+    /// with the given velocity.
+    #[cfg(feature = "alloc")]
+    pub fn apply_bar<const N: usize>(&self, bbuf: &BarBuf<N>, velocity: u8) -> Vec<PlayNote> {
+        bbuf.notes()
+            .map(|n| self.apply(&PlayNote::from_enc(&n, velocity)))
+            .collect()
+    }
+
+    /// Apply the groove to a generated rhythm, playing every hit with the
+    /// same pitch and velocity.
+    #[cfg(feature = "alloc")]
+    pub fn apply_rhythm(&self, rhythm: &[EncRhythm], tone: u8, velocity: u8) -> Vec<PlayNote> {
+        rhythm
+            .iter()
+            .map(|r| {
+                self.apply(&PlayNote {
+                    start: r.ppqn_start(),
+                    length: r.ppqn_len(),
+                    tone,
+                    offset: 0,
+                    velocity,
+                })
+            })
+            .collect()
+    }
+}
+
+// Most tests build their notes with a `BarBuf` and `apply_bar`, except for
+// `stays_in_bounds`, which applies single notes right at the edges
+#[cfg(all(test, feature = "alloc"))]
+mod test {
+    use minijam::scale::Pitch;
+
+    use super::*;
+    use crate::{Length, PPQN_QUARTER};
+
+    fn eighths() -> BarBuf {
+        let notes = [(Length::Eighth, Pitch::C, 4); 16];
+        BarBuf::from_notes_simple(&notes).unwrap()
+    }
+
+    #[test]
+    fn straight_is_unchanged() {
+        let bbuf = eighths();
+        let out = Groove::straight().apply_bar(&bbuf, 100);
+        for (played, orig) in out.iter().zip(bbuf.notes()) {
+            assert_eq!(played.start, orig.ppqn_start());
+            assert_eq!(played.length, orig.ppqn_len());
+            assert_eq!(played.velocity, 100);
+        }
+    }
+
+    #[test]
+    fn triplet_swing() {
+        let out = Groove::swing_eighths(66).apply_bar(&eighths(), 100);
+
+        for (i, note) in out.iter().enumerate() {
+            let beat = (i as u16 / 2) * PPQN_QUARTER;
+            if i % 2 == 0 {
+                // On-beats start on time, but are stretched to meet the
+                // (late) off-beat
+                assert_eq!(note.start, beat);
+                assert_eq!(note.length, PPQN_EIGHTH + 30);
+            } else {
+                // Off-beats are pushed (nearly) to the last triplet
+                assert_eq!(note.start, beat + PPQN_EIGHTH + 30);
+                assert_eq!(note.length, PPQN_EIGHTH - 30);
+            }
+        }
+    }
+
+    #[test]
+    fn sixteenth_swing() {
+        let groove = Groove::swing_sixteenths(75);
+        assert_eq!(groove.timing()[0], 0);
+        assert_eq!(groove.timing()[1], 24);
+        assert_eq!(groove.timing()[2], 0);
+        assert_eq!(groove.timing()[3], 24);
+    }
+
+    #[test]
+    fn never_reorders() {
+        let notes = [(Length::TripletSixteenth, Pitch::C, 4); 96];
+        let bbuf = BarBuf::from_notes_simple(&notes).unwrap();
+        let timing = [0, 40, -8, 20, 0, 48, 0, -20, 10, 20, 30, 40, 48, 0, -48, 0];
+        let groove = Groove::new(timing, [0; GROOVE_STEPS]).unwrap();
+
+        let out = groove.apply_bar(&bbuf, 64);
+        assert_eq!(out.len(), 96);
+        out.windows(2).for_each(|w| assert!(w[0].start <= w[1].start));
+        out.iter().for_each(|n| assert!(n.length >= 1));
+    }
+
+    #[test]
+    fn velocity_accents() {
+        let mut velocity = [0; GROOVE_STEPS];
+        velocity[0] = 20;
+        velocity[2] = -100;
+        let groove = Groove::new([0; GROOVE_STEPS], velocity).unwrap();
+
+        let out = groove.apply_bar(&eighths(), 100);
+        assert_eq!(out[0].velocity, 120);
+        assert_eq!(out[1].velocity, 1);
+        assert_eq!(out[2].velocity, 100);
+    }
+
+    #[test]
+    fn rejects_reordering() {
+        let mut timing = [0; GROOVE_STEPS];
+        timing[3] = 49;
+        assert!(Groove::new(timing, [0; GROOVE_STEPS]).is_none());
+    }
+
+    #[test]
+    fn stays_in_bounds() {
+        let mut timing = [0; GROOVE_STEPS];
+        timing[0] = -10;
+        timing[15] = 20;
+        let groove = Groove::new(timing, [0; GROOVE_STEPS]).unwrap();
+
+        let first = PlayNote { start: 0, length: 10, tone: 60, offset: 0, velocity: 64 };
+        assert_eq!(groove.apply(&first).start, 0);
+
+        let last = PlayNote { start: PPQN_MAX - 10, length: 10, tone: 60, offset: 0, velocity: 64 };
+        let out = groove.apply(&last);
+        assert!(out.start < PPQN_MAX);
+        assert!(out.start + out.length <= PPQN_MAX);
+    }
+}
@@ -0,0 +1,233 @@
+// Humanization of note timing, length, and velocity.
+//
+// Each of the three is nudged by a random amount within a configured bound.
+// With `correlation` at zero, every note gets a fresh random nudge (white
+// noise). Raising it towards one makes each nudge mostly follow the last one,
+// so the performance drifts smoothly ahead of and behind the beat, like a
+// player would.
+//
+// The randomness all comes from the caller's `Rng`, so using a seeded rng
+// gives the same performance every time.
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+use rand::Rng;
+
+use crate::{PlayNote, PPQN_MAX};
+#[cfg(feature = "alloc")]
+use crate::bars::BarBuf;
+
+#[derive(Debug, Clone)]
+pub struct HumanizeParameters {
+    /// The most a note start may move early or late, in ticks
+    pub timing: u16,
+    /// The most a note may be shortened or lengthened, in ticks
+    pub length: u16,
+    /// The most a note's velocity may change
+    pub velocity: u8,
+    /// How much each nudge follows the previous one, from `0.0` (not at all)
+    /// to `1.0` (never changes)
+    pub correlation: f32,
+}
+
+impl Default for HumanizeParameters {
+    fn default() -> Self {
+        Self {
+            timing: 4,
+            length: 8,
+            velocity: 10,
+            correlation: 0.5,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Humanizer {
+    params: HumanizeParameters,
+    // Current drift of each value, in the range -1.0..=1.0
+    timing: f32,
+    length: f32,
+    velocity: f32,
+}
+
+impl Humanizer {
+    pub fn new(params: HumanizeParameters) -> Self {
+        Self {
+            params,
+            timing: 0.0,
+            length: 0.0,
+            velocity: 0.0,
+        }
+    }
+
+    pub fn parameters(&self) -> &HumanizeParameters {
+        &self.params
+    }
+
+    /// Forget any drift from previous notes
+    pub fn reset(&mut self) {
+        self.timing = 0.0;
+        self.length = 0.0;
+        self.velocity = 0.0;
+    }
+
+    fn drift<R: Rng>(rng: &mut R, state: &mut f32, correlation: f32, bound: u16) -> i32 {
+        let noise: f32 = rng.gen_range(-1.0..=1.0);
+        // NOTE: this is a weighted average of two values in -1.0..=1.0, so
+        // it can never leave that range.
+        *state = (correlation * *state) + ((1.0 - correlation) * noise);
+        libm::roundf(*state * (bound as f32)) as i32
+    }
+
+    /// Humanize a list of notes, which must be sorted by start time.
+    ///
+    /// Notes are kept in the same order (a note may be moved to start at the
+    /// same time as the one before it, but never before it), and stay
+    /// within `0..PPQN_MAX`.
+    #[cfg(feature = "alloc")]
+    pub fn apply<R: Rng>(&mut self, rng: &mut R, notes: &[PlayNote]) -> Vec<PlayNote> {
+        let mut notes = notes.to_vec();
+        self.apply_in_place(rng, &mut notes);
+        notes
+    }
+
+    /// Like `apply`, changing the notes where they are
+    pub fn apply_in_place<R: Rng>(&mut self, rng: &mut R, notes: &mut [PlayNote]) {
+        let corr = self.params.correlation.clamp(0.0, 1.0);
+        let mut last_start = 0i32;
+
+        for note in notes.iter_mut() {
+            *note = {
+                let dt = Self::drift(rng, &mut self.timing, corr, self.params.timing);
+                let dl = Self::drift(rng, &mut self.length, corr, self.params.length);
+                let dv = Self::drift(rng, &mut self.velocity, corr, self.params.velocity.into());
+
+                let start = (note.start as i32 + dt)
+                    .max(last_start)
+                    .min(PPQN_MAX as i32 - 1);
+                let length = (note.length as i32 + dl)
+                    .max(1)
+                    .min(PPQN_MAX as i32 - start);
+                let velocity = (note.velocity as i32 + dv).clamp(1, 127);
+                last_start = start;
+
+                PlayNote {
+                    start: start as u16,
+                    length: length as u16,
+                    velocity: velocity as u8,
+                    ..note.clone()
+                }
+            };
+        }
+    }
+
+    /// Humanize every note in a bar buffer, starting each note with the
+    /// given velocity.
+    #[cfg(feature = "alloc")]
+    pub fn apply_bar<R: Rng, const N: usize>(
+        &mut self,
+        rng: &mut R,
+        bbuf: &BarBuf<N>,
+        velocity: u8,
+    ) -> Vec<PlayNote> {
+        let mut notes = bbuf
+            .notes()
+            .map(|n| PlayNote::from_enc(&n, velocity))
+            .collect::<Vec<_>>();
+        self.apply_in_place(rng, &mut notes);
+        notes
+    }
+}
+
+// Needs `alloc` for `apply_bar`
+#[cfg(all(test, feature = "alloc"))]
+mod test {
+    use minijam::scale::Pitch;
+    use rand::{rngs::SmallRng, SeedableRng};
+
+    use super::*;
+    use crate::{Length, PPQN_16TH};
+
+    fn sixteenths() -> BarBuf {
+        let notes = [(Length::Sixteenth, Pitch::C, 4); 256];
+        BarBuf::from_notes_simple(&notes).unwrap()
+    }
+
+    #[test]
+    fn within_bounds() {
+        let bbuf = sixteenths();
+        let params = HumanizeParameters {
+            timing: 6,
+            length: 10,
+            velocity: 20,
+            correlation: 0.0,
+        };
+        let mut hum = Humanizer::new(params);
+        let mut rng = SmallRng::seed_from_u64(1234);
+        let out = hum.apply_bar(&mut rng, &bbuf, 100);
+
+        assert_eq!(out.len(), 256);
+        for (played, orig) in out.iter().zip(bbuf.notes()) {
+            let dt = (played.start as i32 - orig.ppqn_start() as i32).abs();
+            let dl = (played.length as i32 - orig.ppqn_len() as i32).abs();
+            let dv = (played.velocity as i32 - 100).abs();
+            assert!(dt <= 6, "{dt}");
+            assert!(dl <= 10, "{dl}");
+            assert!(dv <= 20, "{dv}");
+            assert!(played.start + played.length <= PPQN_MAX);
+        }
+
+        // Something should have actually changed!
+        assert!(out.iter().zip(bbuf.notes()).any(|(p, o)| p.start != o.ppqn_start()));
+    }
+
+    #[test]
+    fn never_reorders() {
+        // Wild timing, with notes closer together than the jitter
+        let params = HumanizeParameters {
+            timing: PPQN_16TH * 2,
+            ..Default::default()
+        };
+        let mut hum = Humanizer::new(params);
+        let mut rng = SmallRng::seed_from_u64(5678);
+        let out = hum.apply_bar(&mut rng, &sixteenths(), 64);
+        out.windows(2).for_each(|w| assert!(w[0].start <= w[1].start));
+        assert!(out.iter().all(|n| n.start < PPQN_MAX));
+    }
+
+    #[test]
+    fn seeded_is_repeatable() {
+        let bbuf = sixteenths();
+        let run = |seed| {
+            let mut hum = Humanizer::new(HumanizeParameters::default());
+            let mut rng = SmallRng::seed_from_u64(seed);
+            hum.apply_bar(&mut rng, &bbuf, 90)
+        };
+        assert_eq!(run(42), run(42));
+        assert_ne!(run(42), run(43));
+    }
+
+    #[test]
+    fn correlation_smooths() {
+        let bbuf = sixteenths();
+        let jumpiness = |correlation| {
+            let params = HumanizeParameters {
+                timing: 20,
+                correlation,
+                ..Default::default()
+            };
+            let mut hum = Humanizer::new(params);
+            let mut rng = SmallRng::seed_from_u64(99);
+            let out = hum.apply_bar(&mut rng, &bbuf, 90);
+            let offsets = out
+                .iter()
+                .zip(bbuf.notes())
+                .map(|(p, o)| p.start as i32 - o.ppqn_start() as i32)
+                .collect::<Vec<_>>();
+            offsets.windows(2).map(|w| (w[1] - w[0]).abs()).sum::<i32>()
+        };
+
+        assert!(jumpiness(0.9) < jumpiness(0.0));
+    }
+}
@@ -1,13 +1,37 @@
 // I want to be able to store (and later generate)
 // musical notes in a bar (or multiple bars).
+//
+// Bars and the note encoding work anywhere minijam does. Phrase generation,
+// rendering and the text formats need a heap, so are behind the `alloc`
+// feature, which `std` (on by default) turns on.
+
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
 
 use minijam::scale::Pitch;
 
+#[cfg(feature = "alloc")]
+pub mod abc;
 pub mod bars;
 pub mod euc;
+pub mod groove;
+pub mod humanize;
+#[cfg(feature = "alloc")]
+pub mod mml;
+#[cfg(feature = "alloc")]
 pub mod phrdat;
-
-pub const PPQN: u16 = 192;
+#[cfg(feature = "alloc")]
+pub mod phrid;
+#[cfg(feature = "alloc")]
+pub mod render;
+#[cfg(feature = "alloc")]
+pub mod script;
+#[cfg(feature = "alloc")]
+pub mod song;
+
+pub const PPQN: u16 = minijam::clock::PPQN as u16;
 pub const PPQN_WHOLE: u16 = PPQN * 4;
 pub const PPQN_HALF: u16 = PPQN_WHOLE / 2;
 pub const PPQN_QUARTER: u16 = PPQN_WHOLE / 4;
@@ -28,6 +52,7 @@ pub const MIN_ENCODING_SIZE: usize = 3;
 pub const MAX_ENCODING_SIZE: usize = 6;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Length {
     TripletThirtySeconds,
     TripletSixteenth,
@@ -98,7 +123,6 @@ impl EncPitch {
         })
     }
 
-    #[allow(dead_code)]
     pub fn frequency(&self) -> f32 {
         let base = tone_to_freq(self.tone);
         if self.offset == 0 {
@@ -112,7 +136,6 @@ impl EncPitch {
     }
 }
 
-#[allow(dead_code)]
 fn tone_to_freq(tone: u8) -> f32 {
     let oct = tone / 12;
     let pitch: Pitch = (tone % 12).into();
@@ -152,6 +175,7 @@ impl From<EncPitch> for KCInt {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize), serde(transparent))]
 struct EncStart {
     // NOTE: Valid Range 0..PPQN_MAX
     ppqn_idx: u16,
@@ -217,6 +241,7 @@ impl From<EncStart> for KCInt {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize), serde(transparent))]
 struct EncLength {
     // NOTE: Valid Range 0..=PPQN_MAX
     ppqn_ct: u16,
@@ -315,6 +340,7 @@ impl From<EncLength> for KCInt {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct EncNote {
     pitch: EncPitch,
     start: EncStart,
@@ -345,6 +371,18 @@ impl EncNote {
         })
     }
 
+    /// Like `new_simple`, but from a MIDI-style note number and a fraction
+    /// of the way to the next semitone, in 1/256ths
+    pub fn new_tone(tone: u8, offset: u8, start_ppqn: u16, length: Length) -> Result<Self, EncError> {
+        if tone > 0x7F {
+            return Err(EncError::ValueOutOfBounds);
+        }
+
+        let mut note = Self::new_simple(Pitch::C, 0, start_ppqn, length)?;
+        note.pitch = EncPitch { tone, offset };
+        Ok(note)
+    }
+
     pub fn take_from_slice(sli: &[u8]) -> Result<(Self, &[u8]), EncError> {
         let (pitch, sli) = KCInt::take_from_slice(sli).ok_or(EncError::EndOfStream)?;
         let (start, sli) = KCInt::take_from_slice(sli).ok_or(EncError::EndOfStream)?;
@@ -382,6 +420,43 @@ impl EncNote {
     }
 }
 
+/// A note as it should be performed, after timing and dynamics have been
+/// applied. Unlike an [`EncNote`], this is not tied to the encoding, and
+/// carries a velocity.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlayNote {
+    /// Start position, in the range `0..PPQN_MAX`
+    pub start: u16,
+    pub length: u16,
+    /// MIDI-style note number, `0x00..=0x7F`
+    pub tone: u8,
+    /// Fraction of the way to the next semitone, in 1/256ths
+    pub offset: u8,
+    /// MIDI-style velocity, `1..=127`, or 0 if unspecified (as when playing
+    /// without dynamics). Applying a groove clamps it into `1..=127`.
+    pub velocity: u8,
+}
+
+impl PlayNote {
+    pub fn from_enc(note: &EncNote, velocity: u8) -> Self {
+        PlayNote {
+            start: note.ppqn_start(),
+            length: note.ppqn_len(),
+            tone: note.pitch.tone,
+            offset: note.pitch.offset,
+            velocity,
+        }
+    }
+
+    pub fn frequency(&self) -> f32 {
+        EncPitch {
+            tone: self.tone,
+            offset: self.offset,
+        }
+        .frequency()
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 enum KCInt {
     Long {
@@ -0,0 +1,628 @@
+// Music Macro Language (MML).
+//
+// MML is the classic compact notation for chiptune music, like
+// `t120 l8 o4 cdefgab>c`. Channels are separated by commas, and each one
+// becomes its own list of `BarBuf`s with its own `ToneKind`.
+//
+// | Command    | Meaning                                                    |
+// |------------|------------------------------------------------------------|
+// | `c`-`b`    | Note, with `+`/`#` (sharp) or `-` (flat), length and dots   |
+// | `r`, `p`   | Rest, with optional length and dots                         |
+// | `n<num>`   | Note by number (`octave * 12 + semitone`), like `n48`       |
+// | `l<len>`   | Default length, like `l8` or `l4.` (starts at `l4`)          |
+// | `o<num>`   | Octave (starts at `o4`)                                     |
+// | `>`, `<`   | Octave up, octave down                                      |
+// | `v<num>`   | Volume, `v0`-`v15` (starts at `v8`)                          |
+// | `t<num>`   | Tempo, in quarter notes per minute                         |
+// | `&`        | Tie to the next note, if it has the same pitch              |
+// | `@<num>`   | Tone: `@0` sine, `@1` square (the default) or `@2` saw       |
+// | `[...]<n>` | Loop `n` times (default 2). A `\|` inside is skipped to on    |
+// |            | the last time through, like `[cd\|e]3` is `cdecdecd`.       |
+//
+// Lengths are a fraction of a whole note, so `c8` is an eighth note, and
+// `c6` is a quarter note triplet. Whitespace is ignored.
+
+use alloc::{vec, vec::Vec};
+
+use minijam::{
+    clock::Timebase,
+    scale::Pitch,
+    tempo::{TempoEvent, TempoKind},
+    tones::ToneKind,
+    NoteError, Track,
+};
+
+use crate::{
+    bars::{BarBuf, BarError},
+    Length, PlayNote, PPQN_MAX, PPQN_QUARTER, PPQN_WHOLE,
+};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MmlErrorKind {
+    UnexpectedChar(char),
+    /// A command that needs a number didn't have one, or it was too big
+    BadNumber,
+    /// A length that isn't a whole number of ticks
+    BadLength,
+    BadOctave,
+    BadTone,
+    /// A `[` without a `]`, or the other way around
+    UnmatchedLoop,
+    /// A channel that runs more than `MAX_COMMANDS` commands, once its
+    /// loops are played out
+    TooLong,
+    Bar(BarError),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct MmlError {
+    /// The channel, starting at 0
+    pub channel: usize,
+    /// The position of the error in the channel's text, in bytes
+    pub pos: usize,
+    pub kind: MmlErrorKind,
+}
+
+pub struct MmlChannel {
+    pub kind: ToneKind,
+    /// The channel, split into `BarBuf`s to be played one after another
+    pub bars: Vec<BarBuf>,
+    /// The velocity (`1..=127`) of each note, in order
+    pub velocities: Vec<u8>,
+}
+
+/// The most commands a channel can run, counting each time through a loop
+pub const MAX_COMMANDS: u32 = 1 << 20;
+
+pub struct Mml {
+    /// Tempo changes, in ticks from the start. There is always at least one.
+    pub tempo: Vec<TempoEvent>,
+    pub channels: Vec<MmlChannel>,
+}
+
+#[derive(Debug, Clone)]
+enum Cmd {
+    // Semitones above C (may be -1 or 12), length and dots
+    Note(i16, Option<u16>, u8),
+    NoteNum(u8, Option<u16>, u8),
+    Rest(Option<u16>, u8),
+    Length(u16, u8),
+    Octave(u8),
+    OctaveUp,
+    OctaveDown,
+    Volume(u8),
+    Tempo(u32),
+    Tie,
+    Tone(ToneKind),
+    // Body, times, and where to stop on the last time through
+    Loop(Vec<Cmd>, u16, Option<usize>),
+}
+
+struct Lexer<'a> {
+    src: &'a str,
+    pos: usize,
+    channel: usize,
+}
+
+impl<'a> Lexer<'a> {
+    fn err(&self, kind: MmlErrorKind) -> MmlError {
+        MmlError {
+            channel: self.channel,
+            pos: self.pos.saturating_sub(1),
+            kind,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.src[self.pos..].chars().find(|c| !c.is_whitespace())
+    }
+
+    fn next(&mut self) -> Option<char> {
+        for ch in self.src[self.pos..].chars() {
+            self.pos += ch.len_utf8();
+            if !ch.is_whitespace() {
+                return Some(ch);
+            }
+        }
+        None
+    }
+
+    fn number(&mut self) -> Option<u32> {
+        let mut val: Option<u32> = None;
+        while let Some(d) = self.peek().and_then(|c| c.to_digit(10)) {
+            self.next();
+            val = Some(val.unwrap_or(0).saturating_mul(10).saturating_add(d));
+        }
+        val
+    }
+
+    fn required<T: TryFrom<u32>>(&mut self) -> Result<T, MmlError> {
+        self.number()
+            .and_then(|n| T::try_from(n).ok())
+            .ok_or_else(|| self.err(MmlErrorKind::BadNumber))
+    }
+
+    // An optional length (as a divisor of a whole note), then dots
+    fn length(&mut self) -> Result<(Option<u16>, u8), MmlError> {
+        let len = match self.number() {
+            Some(n) => {
+                let n = u16::try_from(n).map_err(|_| self.err(MmlErrorKind::BadLength))?;
+                if n == 0 || !PPQN_WHOLE.is_multiple_of(n) {
+                    return Err(self.err(MmlErrorKind::BadLength));
+                }
+                Some(PPQN_WHOLE / n)
+            }
+            None => None,
+        };
+        let mut dots = 0;
+        while self.peek() == Some('.') {
+            self.next();
+            dots += 1;
+        }
+        Ok((len, dots))
+    }
+
+    // Parse commands until the end of the channel, or the end of a loop
+    fn commands(&mut self, in_loop: bool) -> Result<(Vec<Cmd>, Option<usize>), MmlError> {
+        let mut cmds = vec![];
+        let mut brk = None;
+        loop {
+            let Some(ch) = self.next() else {
+                if in_loop {
+                    return Err(self.err(MmlErrorKind::UnmatchedLoop));
+                }
+                return Ok((cmds, brk));
+            };
+            let cmd = match ch.to_ascii_lowercase() {
+                letter @ 'a'..='g' => {
+                    let semi = [9, 11, 0, 2, 4, 5, 7][(letter as u8 - b'a') as usize];
+                    let acc = match self.peek() {
+                        Some('+' | '#') => 1,
+                        Some('-') => -1,
+                        _ => 0,
+                    };
+                    if acc != 0 {
+                        self.next();
+                    }
+                    let (len, dots) = self.length()?;
+                    Cmd::Note(semi + acc, len, dots)
+                }
+                'n' => {
+                    let num = self.required()?;
+                    if num > 127 {
+                        return Err(self.err(MmlErrorKind::BadNumber));
+                    }
+                    let (len, dots) = self.length()?;
+                    Cmd::NoteNum(num, len, dots)
+                }
+                'r' | 'p' => {
+                    let (len, dots) = self.length()?;
+                    Cmd::Rest(len, dots)
+                }
+                'l' => match self.length()? {
+                    (Some(len), dots) => Cmd::Length(len, dots),
+                    (None, _) => return Err(self.err(MmlErrorKind::BadLength)),
+                },
+                'o' => {
+                    let oct = self.required()?;
+                    if oct > 9 {
+                        return Err(self.err(MmlErrorKind::BadOctave));
+                    }
+                    Cmd::Octave(oct)
+                }
+                '>' => Cmd::OctaveUp,
+                '<' => Cmd::OctaveDown,
+                'v' => Cmd::Volume(self.required::<u8>()?.min(15)),
+                't' => {
+                    let bpm = self.required()?;
+                    if bpm == 0 {
+                        return Err(self.err(MmlErrorKind::BadNumber));
+                    }
+                    Cmd::Tempo(bpm)
+                }
+                '&' => Cmd::Tie,
+                '@' => Cmd::Tone(match self.required::<u8>()? {
+                    0 => ToneKind::Sine,
+                    1 => ToneKind::Square,
+                    2 => ToneKind::Saw,
+                    _ => return Err(self.err(MmlErrorKind::BadTone)),
+                }),
+                '[' => {
+                    let (body, brk) = self.commands(true)?;
+                    let times = match self.number() {
+                        Some(n) => {
+                            u16::try_from(n).map_err(|_| self.err(MmlErrorKind::BadNumber))?
+                        }
+                        None => 2,
+                    };
+                    Cmd::Loop(body, times, brk)
+                }
+                ']' if in_loop => return Ok((cmds, brk)),
+                ']' => return Err(self.err(MmlErrorKind::UnmatchedLoop)),
+                '|' if in_loop => {
+                    brk = Some(cmds.len());
+                    continue;
+                }
+                _ => return Err(self.err(MmlErrorKind::UnexpectedChar(ch))),
+            };
+            cmds.push(cmd);
+        }
+    }
+}
+
+struct Player {
+    length: u16,
+    octave: u8,
+    volume: u8,
+    tie: bool,
+    tick: u32,
+    // Commands left before the channel is too long
+    budget: u32,
+    kind: ToneKind,
+    // (ticks, tone, velocity), with `None` for rests
+    events: Vec<(u32, Option<u8>, u8)>,
+    tempo: Vec<TempoEvent>,
+}
+
+fn dotted(len: u16, dots: u8) -> u32 {
+    let mut total = len as u32;
+    let mut add = len as u32;
+    for _ in 0..dots {
+        add /= 2;
+        total += add;
+    }
+    total
+}
+
+impl Player {
+    fn note(&mut self, tone: Option<u8>, len: Option<u16>, dots: u8) -> Result<(), MmlErrorKind> {
+        let ticks = match len {
+            Some(len) => dotted(len, dots),
+            // A dot on its own lengthens the default length
+            None => dotted(self.length, dots),
+        };
+        self.tick = self.tick.checked_add(ticks).ok_or(MmlErrorKind::TooLong)?;
+
+        let tied = core::mem::take(&mut self.tie);
+        if let (true, Some(_), Some(last)) = (tied, tone, self.events.last_mut()) {
+            if last.1 == tone {
+                // Can't overflow, as it's no longer than the channel
+                last.0 += ticks;
+                return Ok(());
+            }
+        }
+        let velocity = ((self.volume as u16 * 127) / 15).max(1) as u8;
+        self.events.push((ticks, tone, velocity));
+        Ok(())
+    }
+
+    fn run(&mut self, cmds: &[Cmd]) -> Result<(), MmlErrorKind> {
+        for cmd in cmds {
+            self.budget = self.budget.checked_sub(1).ok_or(MmlErrorKind::TooLong)?;
+            match cmd {
+                Cmd::Note(semi, len, dots) => {
+                    let tone = (self.octave as i16 * 12) + semi;
+                    self.note(Some(tone.clamp(0, 127) as u8), *len, *dots)?;
+                }
+                Cmd::NoteNum(num, len, dots) => self.note(Some(*num), *len, *dots)?,
+                Cmd::Rest(len, dots) => self.note(None, *len, *dots)?,
+                Cmd::Length(len, dots) => self.length = dotted(*len, *dots) as u16,
+                Cmd::Octave(oct) => self.octave = *oct,
+                Cmd::OctaveUp => self.octave = (self.octave + 1).min(9),
+                Cmd::OctaveDown => self.octave = self.octave.saturating_sub(1),
+                Cmd::Volume(vol) => self.volume = *vol,
+                Cmd::Tempo(bpm) => {
+                    let event = TempoEvent {
+                        tick: self.tick,
+                        bpm: *bpm,
+                        kind: TempoKind::Step,
+                    };
+                    match self.tempo.last_mut() {
+                        Some(last) if last.tick == self.tick => *last = event,
+                        _ => self.tempo.push(event),
+                    }
+                }
+                Cmd::Tie => self.tie = true,
+                Cmd::Tone(kind) => self.kind = *kind,
+                Cmd::Loop(body, times, brk) => {
+                    for i in 0..*times {
+                        let last = (i + 1) == *times;
+                        match brk {
+                            Some(brk) if last => self.run(&body[..*brk])?,
+                            _ => self.run(body)?,
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Split a channel into `BarBuf`s, as it may be longer than one can hold
+fn pack(events: &[(u32, Option<u8>, u8)]) -> Result<(Vec<BarBuf>, Vec<u8>), BarError> {
+    let mut bars = vec![];
+    let mut velocities = vec![];
+    let mut cur = BarBuf::new();
+
+    for (ticks, tone, velocity) in events.iter().copied() {
+        if ticks == 0 {
+            continue;
+        }
+        if (cur.ppqn_len() as u32 + ticks) > PPQN_MAX as u32 && cur.ppqn_len() != 0 {
+            bars.push(core::mem::replace(&mut cur, BarBuf::new()));
+        }
+        let len = Length::PPQNCount(u16::try_from(ticks).map_err(|_| BarError::ExceededBarLength)?);
+        match tone {
+            Some(tone) => {
+                cur.push_note_simple(len, Pitch::from(tone % 12), tone / 12)?;
+                velocities.push(velocity);
+            }
+            None => cur.push_rest_simple(len)?,
+        }
+    }
+    if cur.ppqn_len() != 0 || bars.is_empty() {
+        bars.push(cur);
+    }
+    Ok((bars, velocities))
+}
+
+impl Mml {
+    pub fn parse(src: &str) -> Result<Self, MmlError> {
+        let mut tempo = vec![];
+        let mut channels = vec![];
+
+        for (idx, text) in src.split(',').enumerate() {
+            let mut lexer = Lexer {
+                src: text,
+                pos: 0,
+                channel: idx,
+            };
+            let (cmds, _) = lexer.commands(false)?;
+
+            let mut player = Player {
+                length: PPQN_QUARTER,
+                octave: 4,
+                volume: 8,
+                tie: false,
+                tick: 0,
+                budget: MAX_COMMANDS,
+                kind: ToneKind::Square,
+                events: vec![],
+                tempo: vec![],
+            };
+            player.run(&cmds).map_err(|kind| MmlError {
+                channel: idx,
+                pos: text.len(),
+                kind,
+            })?;
+            tempo.extend(player.tempo);
+
+            let (bars, velocities) = pack(&player.events).map_err(|e| MmlError {
+                channel: idx,
+                pos: text.len(),
+                kind: MmlErrorKind::Bar(e),
+            })?;
+            channels.push(MmlChannel {
+                kind: player.kind,
+                bars,
+                velocities,
+            });
+        }
+
+        // Merge the tempo changes from all channels. Later channels win
+        // if two land on the same tick.
+        tempo.sort_by_key(|e| e.tick);
+        tempo.reverse();
+        tempo.dedup_by_key(|e| e.tick);
+        tempo.reverse();
+        if tempo.first().map(|e| e.tick) != Some(0) {
+            tempo.insert(
+                0,
+                TempoEvent {
+                    tick: 0,
+                    bpm: 120,
+                    kind: TempoKind::Step,
+                },
+            );
+        }
+
+        Ok(Mml { tempo, channels })
+    }
+}
+
+impl MmlChannel {
+    /// Every note, with its start in ticks from the start of the channel
+    pub fn notes(&self) -> Vec<(u32, PlayNote)> {
+        let mut start = 0u32;
+        let mut out = vec![];
+        for bar in self.bars.iter() {
+            out.extend(
+                bar.notes()
+                    .map(|n| (start + n.ppqn_start() as u32, PlayNote::from_enc(&n, 0))),
+            );
+            start += bar.ppqn_len() as u32;
+        }
+        out.iter_mut()
+            .zip(self.velocities.iter())
+            .for_each(|((_, n), v)| n.velocity = *v);
+        out
+    }
+
+    /// Queue the whole channel on a track, starting at `start_tick`.
+    ///
+    /// Returns an error if the track could not take every note.
+    pub fn queue_on<const DEPTH: usize, T: Timebase>(
+        &self,
+        track: &mut Track<DEPTH>,
+        clock: &T,
+        start_tick: u32,
+    ) -> Result<(), NoteError> {
+        let mut start = start_tick;
+        for bar in self.bars.iter() {
+            bar.queue_on(track, clock, self.kind, start)?;
+            start += bar.ppqn_len() as u32;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use minijam::tempo::TempoMap;
+
+    use super::*;
+    use crate::PPQN_EIGHTH;
+
+    // (start, length, tone, velocity)
+    fn notes(chan: &MmlChannel) -> Vec<(u32, u16, u8, u8)> {
+        chan.notes()
+            .iter()
+            .map(|(s, n)| (*s, n.length, n.tone, n.velocity))
+            .collect()
+    }
+
+    #[test]
+    fn scale() {
+        let mml = Mml::parse("t150 l8 o4 cdefgab>c").unwrap();
+        assert_eq!(
+            mml.tempo,
+            vec![TempoEvent {
+                tick: 0,
+                bpm: 150,
+                kind: TempoKind::Step
+            }]
+        );
+        let chan = &mml.channels[0];
+        assert_eq!(chan.kind, ToneKind::Square);
+        let tones = notes(chan).iter().map(|n| n.2).collect::<Vec<_>>();
+        assert_eq!(tones, vec![48, 50, 52, 53, 55, 57, 59, 60]);
+        assert!(notes(chan).iter().all(|n| n.1 == PPQN_EIGHTH));
+    }
+
+    #[test]
+    fn lengths_and_accidentals() {
+        let mml = Mml::parse("c+ d-8 e4. l16 f r g.. l8. a n60 b-&b-4 c&d").unwrap();
+        assert_eq!(
+            notes(&mml.channels[0])
+                .iter()
+                .map(|n| (n.0, n.1, n.2))
+                .collect::<Vec<_>>(),
+            vec![
+                (0, 192, 49),
+                (192, 96, 49),
+                (288, 288, 52),
+                (576, 48, 53),
+                // After a 16th rest
+                (672, 84, 55),
+                (756, 144, 57),
+                (900, 144, 60),
+                // Tied
+                (1044, 336, 58),
+                // Can't tie different pitches
+                (1380, 144, 48),
+                (1524, 144, 50),
+            ]
+        );
+    }
+
+    #[test]
+    fn octaves_volume_and_tone() {
+        let mml = Mml::parse("@2 o2 c > c >> c < c v15 o9 c v0 o0 c").unwrap();
+        let chan = &mml.channels[0];
+        assert_eq!(chan.kind, ToneKind::Saw);
+        let found = notes(chan);
+        assert_eq!(
+            found.iter().map(|n| n.2).collect::<Vec<_>>(),
+            vec![24, 36, 60, 48, 108, 0]
+        );
+        assert_eq!(found[0].3, 67);
+        assert_eq!(found[4].3, 127);
+        assert_eq!(found[5].3, 1);
+    }
+
+    #[test]
+    fn loops() {
+        let mml = Mml::parse("[c [d]3 ] [e|f]3 g").unwrap();
+        let tones = notes(&mml.channels[0])
+            .iter()
+            .map(|n| n.2)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tones,
+            vec![48, 50, 50, 50, 48, 50, 50, 50, 52, 53, 52, 53, 52, 55]
+        );
+    }
+
+    #[test]
+    fn channels_and_tempo() {
+        let mml = Mml::parse("t100 @0 l2 c d t140 e, @2 o3 l1 r t90 c").unwrap();
+        assert_eq!(mml.channels.len(), 2);
+        assert_eq!(mml.channels[0].kind, ToneKind::Sine);
+        assert_eq!(mml.channels[1].kind, ToneKind::Saw);
+        let ticks = mml
+            .tempo
+            .iter()
+            .map(|e| (e.tick, e.bpm))
+            .collect::<Vec<_>>();
+        assert_eq!(ticks, vec![(0, 100), (768, 90)]);
+
+        // Play it
+        let map: TempoMap<4> = TempoMap::from_events(48000, &mml.tempo).unwrap();
+        let mut track: Track<4> = Track::new(48000);
+        mml.channels[1].queue_on(&mut track, &map, 0).unwrap();
+        assert_eq!(track.note_q.len(), 1);
+        assert_eq!(
+            track.note_q.front().unwrap().samp_start,
+            map.tick_to_sample(768)
+        );
+    }
+
+    #[test]
+    fn long_channels_are_split() {
+        let mml = Mml::parse("l1 [c]20").unwrap();
+        let chan = &mml.channels[0];
+        assert_eq!(chan.bars.len(), 2);
+        assert_eq!(chan.bars[0].notes().count(), 16);
+        assert_eq!(chan.bars[1].notes().count(), 4);
+        assert_eq!(chan.velocities.len(), 20);
+        assert_eq!(notes(chan)[19].0, 19 * PPQN_WHOLE as u32);
+    }
+
+    #[test]
+    fn errors() {
+        let err = |src| Mml::parse(src).err().unwrap();
+        assert_eq!(
+            err("c d x"),
+            MmlError {
+                channel: 0,
+                pos: 4,
+                kind: MmlErrorKind::UnexpectedChar('x')
+            }
+        );
+        assert_eq!(err("c, c5").channel, 1);
+        assert_eq!(err("c7").kind, MmlErrorKind::BadLength);
+        assert_eq!(err("o10").kind, MmlErrorKind::BadOctave);
+        assert_eq!(err("@7").kind, MmlErrorKind::BadTone);
+        assert_eq!(err("[cd").kind, MmlErrorKind::UnmatchedLoop);
+        assert_eq!(err("cd]").kind, MmlErrorKind::UnmatchedLoop);
+        assert_eq!(err("v").kind, MmlErrorKind::BadNumber);
+        assert_eq!(err("l").kind, MmlErrorKind::BadLength);
+        assert_eq!(err("n200").kind, MmlErrorKind::BadNumber);
+        assert!(Mml::parse("n127").is_ok());
+    }
+
+    #[test]
+    fn runaway_loops() {
+        let err = |src| Mml::parse(src).err().unwrap();
+        // Reported at the end of the channel
+        let too_long = MmlError {
+            channel: 1,
+            pos: 23,
+            kind: MmlErrorKind::TooLong,
+        };
+        assert_eq!(err("c, [[[c]65535]65535]65535"), too_long);
+        // Even if nothing is played
+        assert_eq!(err("[[[v1]65535]65535]65535").kind, MmlErrorKind::TooLong);
+    }
+}
@@ -1,10 +1,12 @@
 #![allow(dead_code)]
 
-use std::fmt::Debug;
+use alloc::{vec, vec::Vec};
+use core::fmt::Debug;
 
-use crate::{EncLength, EncStart, Length, PPQN_QUARTER, PPQN_EIGHTH, PPQN_16TH, euc::Euc32};
+use crate::{EncError, EncLength, EncStart, Length, PPQN_QUARTER, PPQN_EIGHTH, PPQN_16TH, PPQN_MAX, euc::Euc32};
 use minijam::{
     scale::{Pitch, Semitones, MAJOR_SCALES, MINOR_SCALES, PITCHES_PER_OCTAVE},
+    tempo::{TempoError, TempoEvent, TempoKind, TempoMap},
     tones::ToneKind,
 };
 use rand::Rng;
@@ -19,6 +21,7 @@ pub struct PhraseDataBuilder {
 #[derive(Debug, Default)]
 pub struct PhraseDataHeaderBuilder {
     bpm: Option<u16>,
+    tempo: Option<Vec<TempoEvent>>,
     key_kind: Option<KeyKind>,
     time_signature: Option<TimeSignature>,
     scale: Option<Scale>,
@@ -29,8 +32,11 @@ pub struct PhraseDataHeaderBuilder {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct PhraseDataHeader {
+    /// The tempo at the start of the phrase
     pub bpm: u16,
+    tempo: Vec<TempoEvent>,
     key_kind: KeyKind,
     time_signature: TimeSignature,
     scale: Scale,
@@ -42,42 +48,140 @@ pub struct PhraseDataHeader {
 
 impl PhraseDataHeader {
     fn max_beats_of_kind(&self, len: Length) -> u16 {
-        let denom_ppqn = match self.time_signature.denominator {
-            SignatureDenominator::Quarter => PPQN_QUARTER,
-            SignatureDenominator::Eighth => PPQN_EIGHTH,
-            SignatureDenominator::Sixteenth => PPQN_16TH,
-        };
-        let per_meas_ppqn = denom_ppqn * (self.time_signature.numerator as u16);
-        let ttl_ppqn = per_meas_ppqn * self.num_measures as u16;
+        let ttl_ppqn = self.time_signature.measure_ppqn() * self.num_measures as u16;
         let divisor = len.to_ppqn();
         ttl_ppqn / divisor
     }
+
+    /// The tempo changes over the course of the phrase. The first event is
+    /// always a step to `bpm` at tick zero.
+    pub fn tempo_events(&self) -> &[TempoEvent] {
+        &self.tempo
+    }
+
+    /// The tempo at the very end of the phrase
+    pub fn end_bpm(&self) -> u16 {
+        self.tempo.last().map(|ev| ev.bpm as u16).unwrap_or(self.bpm)
+    }
+
+    pub fn time_signature(&self) -> &TimeSignature {
+        &self.time_signature
+    }
+
+    pub fn key(&self) -> Pitch {
+        self.key
+    }
+
+    pub fn key_kind(&self) -> &KeyKind {
+        &self.key_kind
+    }
+
+    pub fn num_measures(&self) -> u8 {
+        self.num_measures
+    }
+
+    /// The length of the whole phrase
+    pub fn phrase_ppqn(&self) -> u32 {
+        u32::from(self.time_signature.measure_ppqn()) * u32::from(self.num_measures)
+    }
+
+    pub fn tempo_map<const N: usize>(&self, sample_rate: u32) -> Result<TempoMap<N>, TempoError> {
+        TempoMap::from_events(sample_rate, &self.tempo)
+    }
 }
 
-impl PhraseDataBuilder {
-    pub fn build_header(&self) -> PhraseDataHeader {
-        PhraseDataHeader {
-            bpm: *self.header.bpm.as_ref().unwrap(),
-            key_kind: self.header.key_kind.as_ref().unwrap().clone(),
-            time_signature: self.header.time_signature.as_ref().unwrap().clone(),
-            scale: self.header.scale.as_ref().unwrap().clone(),
-            num_measures: *self.header.num_measures.as_ref().unwrap(),
-            chord_progression: self.header.chord_progression.as_ref().unwrap().clone(),
-            key: *self.header.key.as_ref().unwrap(),
-            voices_ct: *self.header.voices_ct.as_ref().unwrap(),
+/// Something that stopped a phrase from being generated
+#[derive(Debug, PartialEq, Eq)]
+pub enum PhraseError {
+    /// The parameters aren't usable
+    Parameters(ParameterError),
+    /// `build_header` was called before the builder was filled
+    NotFilled,
+    /// Chord progressions need at least three measures
+    TooFewMeasures(u8),
+    /// With this many chorus voices, there's no room for the minimum number
+    /// of lead voices
+    NoRoomForLeads { chorus: u8 },
+    /// No euclidean rhythm has this many beats in this length. A length of
+    /// zero means the voice's resolution is longer than the whole phrase.
+    Rhythm { beats: u8, length: u8 },
+    /// A note of a rhythm is past the end of what a `BarBuf` can hold
+    Encoding(EncError),
+}
+
+impl From<ParameterError> for PhraseError {
+    fn from(e: ParameterError) -> Self {
+        PhraseError::Parameters(e)
+    }
+}
+
+impl From<EncError> for PhraseError {
+    fn from(e: EncError) -> Self {
+        PhraseError::Encoding(e)
+    }
+}
+
+impl core::fmt::Display for PhraseError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            PhraseError::Parameters(e) => write!(f, "bad parameters: {e}"),
+            PhraseError::NotFilled => write!(f, "no phrase has been generated yet"),
+            PhraseError::TooFewMeasures(n) => {
+                write!(f, "{n} measures is too few for a chord progression")
+            }
+            PhraseError::NoRoomForLeads { chorus } => {
+                write!(f, "no room for the minimum lead voices alongside {chorus} chorus voices")
+            }
+            PhraseError::Rhythm { beats, length } => {
+                write!(f, "no rhythm has {beats} beats in {length} notes")
+            }
+            PhraseError::Encoding(e) => write!(f, "a note doesn't fit in a bar: {e:?}"),
         }
     }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for PhraseError {}
+
+impl PhraseDataBuilder {
+    /// Returns `PhraseError::NotFilled` until `fill` has succeeded once.
+    pub fn build_header(&self) -> Result<PhraseDataHeader, PhraseError> {
+        let h = &self.header;
+        let missing = || PhraseError::NotFilled;
+        Ok(PhraseDataHeader {
+            bpm: h.bpm.ok_or_else(missing)?,
+            tempo: h.tempo.clone().ok_or_else(missing)?,
+            key_kind: h.key_kind.clone().ok_or_else(missing)?,
+            time_signature: h.time_signature.clone().ok_or_else(missing)?,
+            scale: h.scale.clone().ok_or_else(missing)?,
+            num_measures: h.num_measures.ok_or_else(missing)?,
+            chord_progression: h.chord_progression.clone().ok_or_else(missing)?,
+            key: h.key.ok_or_else(missing)?,
+            voices_ct: h.voices_ct.ok_or_else(missing)?,
+        })
+    }
 
-    pub fn fill<R: Rng>(&mut self, rng: &mut R, parameters: &PhraseDataParameters) {
+    /// Generate the next phrase, carrying on from the last one.
+    ///
+    /// If this fails the builder may be left part way through a phrase, so
+    /// it should be filled again (or replaced) before it's used.
+    pub fn fill<R: Rng>(&mut self, rng: &mut R, parameters: &ValidatedParameters) -> Result<(), PhraseError> {
+        let parameters = &parameters.0;
         let key_kind;
         let time_signature;
         let num_measures;
         let lead_voices;
         let chorus_voices;
 
-        self.header.bpm = Some(match self.header.bpm.take() {
-            Some(bpm) => parameters.bpm.step(rng, bpm),
-            None => parameters.bpm.generate(rng),
+        let bpm;
+        self.header.bpm = Some({
+            // Carry on from wherever the last phrase's tempo ended up
+            let old_end = self.header.tempo.take().and_then(|t| t.last().map(|ev| ev.bpm as u16));
+            bpm = match self.header.bpm.take() {
+                Some(old) => parameters.bpm.step(rng, old_end.unwrap_or(old)),
+                None => parameters.bpm.generate(rng),
+            };
+            bpm
         });
         self.header.key_kind = Some({
             key_kind = match self.header.key_kind.take() {
@@ -93,6 +197,7 @@ impl PhraseDataBuilder {
             };
             time_signature.clone()
         });
+        let measure_ppqn = time_signature.measure_ppqn();
         self.header.scale = Some(match self.header.scale.take() {
             Some(old) => parameters.scale.step(rng, old, key_kind),
             None => parameters.scale.generate(rng, key_kind),
@@ -104,9 +209,15 @@ impl PhraseDataBuilder {
             };
             num_measures
         });
+        self.header.tempo = Some(parameters.bpm.generate_changes(
+            rng,
+            bpm,
+            measure_ppqn,
+            num_measures,
+        ));
         self.header.chord_progression = Some(match self.header.chord_progression.take() {
-            Some(old) => parameters.chord_progression.step(rng, old, num_measures),
-            None => parameters.chord_progression.generate(rng, num_measures),
+            Some(old) => parameters.chord_progression.step(rng, old, num_measures)?,
+            None => parameters.chord_progression.generate(rng, num_measures)?,
         });
         self.header.key = Some(match self.header.key.take() {
             Some(old) => parameters.key.step(rng, old),
@@ -114,8 +225,8 @@ impl PhraseDataBuilder {
         });
         self.header.voices_ct = Some({
             let (ld, ch) = match self.header.voices_ct.take() {
-                Some((old_lead, old_chorus)) => parameters.voices.step(rng, old_lead, old_chorus),
-                None => parameters.voices.generate(rng),
+                Some((old_lead, old_chorus)) => parameters.voices.step(rng, old_lead, old_chorus)?,
+                None => parameters.voices.generate(rng)?,
             };
             lead_voices = ld;
             chorus_voices = ch;
@@ -130,18 +241,24 @@ impl PhraseDataBuilder {
         self.lead_voices.resize_with(lead_voices.into(), LeadVoiceDataBuilder::default);
         self.chorus_voices.resize_with(chorus_voices.into(), ChorusVoiceDataBuilder::default);
 
-        let header = self.build_header();
+        let header = self.build_header()?;
 
-        self.lead_voices.iter_mut().for_each(|lvdb| {
-            lvdb.fill(rng, &header, &parameters.lead_voices);
-        });
-        self.chorus_voices.iter_mut().for_each(|lvdb| {
-            lvdb.fill(rng, &header, &parameters.chorus_voices);
-        });
+        for lvdb in self.lead_voices.iter_mut() {
+            lvdb.fill(rng, &header, &parameters.lead_voices)?;
+        }
+        for cvdb in self.chorus_voices.iter_mut() {
+            cvdb.fill(rng, &header, &parameters.chorus_voices)?;
+        }
+        Ok(())
     }
 }
 
 #[derive(Debug, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
 pub struct PhraseDataParameters {
     pub bpm: BpmParameters,
     pub key_kind: KeyKindParameters,
@@ -155,12 +272,253 @@ pub struct PhraseDataParameters {
     pub chorus_voices: ChorusVoiceDataParameters,
 }
 
+/// A parameter that the generator can't work with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParameterError {
+    /// The field of `PhraseDataParameters` it's in, like `lead_voices`
+    pub section: &'static str,
+    /// The parameter within that section, like `euc.min_beats`
+    pub name: &'static str,
+    pub kind: ParameterErrorKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParameterErrorKind {
+    /// A minimum is more than its maximum, or equal to it for the voice
+    /// counts, where the maximum is exclusive
+    MinAboveMax,
+    /// A probability isn't in `0.0..=1.0`
+    NotAProbability,
+    /// Outside of what the generator can use
+    OutOfRange,
+    /// A list of choices is empty
+    Empty,
+}
+
+impl core::fmt::Display for ParameterError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let problem = match self.kind {
+            ParameterErrorKind::MinAboveMax => "is more than the maximum",
+            ParameterErrorKind::NotAProbability => "is not between 0.0 and 1.0",
+            ParameterErrorKind::OutOfRange => "is out of range",
+            ParameterErrorKind::Empty => "is empty",
+        };
+        write!(f, "`{}.{}` {}", self.section, self.name, problem)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ParameterError {}
+
+fn param_err(name: &'static str, kind: ParameterErrorKind) -> ParameterError {
+    ParameterError {
+        section: "",
+        name,
+        kind,
+    }
+}
+
+fn check_probability(name: &'static str, p: f32) -> Result<(), ParameterError> {
+    if (0.0..=1.0).contains(&p) {
+        Ok(())
+    } else {
+        Err(param_err(name, ParameterErrorKind::NotAProbability))
+    }
+}
+
+fn check_min_max<T: PartialOrd>(name: &'static str, min: T, max: T) -> Result<(), ParameterError> {
+    if min <= max {
+        Ok(())
+    } else {
+        Err(param_err(name, ParameterErrorKind::MinAboveMax))
+    }
+}
+
+impl PhraseDataParameters {
+    /// Check that phrases can be generated with these parameters.
+    pub fn validate(&self) -> Result<(), ParameterError> {
+        let section = |section| move |e: ParameterError| ParameterError { section, ..e };
+
+        self.bpm.validate().map_err(section("bpm"))?;
+        check_probability("mutation_probability", self.key_kind.mutation_probablity)
+            .map_err(section("key_kind"))?;
+        self.time_signature.validate().map_err(section("time_signature"))?;
+        check_probability("mutation_probability", self.scale.mutation_probability)
+            .map_err(section("scale"))?;
+        self.num_measures
+            .validate(&self.time_signature)
+            .map_err(section("num_measures"))?;
+        check_probability("mutation_probability", self.chord_progression.mutation_probability)
+            .map_err(section("chord_progression"))?;
+        check_probability("mutation_probability", self.key.mutation_probability)
+            .map_err(section("key"))?;
+        self.voices.validate().map_err(section("voices"))?;
+        self.lead_voices.resolution.0.validate().map_err(section("lead_voices"))?;
+        self.lead_voices.euc.validate().map_err(section("lead_voices"))?;
+        self.chorus_voices.resolution.0.validate().map_err(section("chorus_voices"))?;
+        self.chorus_voices.euc.validate().map_err(section("chorus_voices"))?;
+        Ok(())
+    }
+}
+
+impl PhraseDataParameters {
+    /// Feed every parameter to `hash`, for `phrid::params_hash`. Each value
+    /// is written as little endian bytes, and floats by their bits, so the
+    /// hash is the same on every platform and toolchain.
+    ///
+    /// The patterns name every field, so a new parameter can't be added
+    /// without deciding how it's hashed.
+    pub(crate) fn write_hash(&self, hash: &mut dyn FnMut(&[u8])) {
+        fn u16s(hash: &mut dyn FnMut(&[u8]), vals: &[u16]) {
+            vals.iter().for_each(|v| hash(&v.to_le_bytes()));
+        }
+        fn f32s(hash: &mut dyn FnMut(&[u8]), vals: &[f32]) {
+            vals.iter().for_each(|v| hash(&v.to_bits().to_le_bytes()));
+        }
+        fn euc(hash: &mut dyn FnMut(&[u8]), euc: &EuclideanGenerationParameters) {
+            let EuclideanGenerationParameters {
+                min_beats,
+                max_beats,
+                beats_mutation_probability,
+                min_length,
+                max_length,
+                length_mutation_probability,
+            } = euc;
+            hash(&[*min_beats, *max_beats, *min_length, *max_length]);
+            f32s(hash, &[*beats_mutation_probability, *length_mutation_probability]);
+        }
+        fn resolution(hash: &mut dyn FnMut(&[u8]), res: &VoiceResolutionParameters) {
+            let VoiceResolutionParameters {
+                resolution_choices,
+                mutation_percentage,
+            } = res;
+            hash(&(resolution_choices.len() as u32).to_le_bytes());
+            resolution_choices.iter().for_each(|l| u16s(hash, &[l.to_ppqn()]));
+            f32s(hash, &[*mutation_percentage]);
+        }
+
+        let Self {
+            bpm,
+            key_kind,
+            time_signature,
+            scale,
+            num_measures,
+            chord_progression,
+            key,
+            voices,
+            lead_voices,
+            chorus_voices,
+        } = self;
+
+        let BpmParameters {
+            min,
+            max,
+            max_delta_per_phrase,
+            mutation_probability,
+            change_probability,
+            ramp_probability,
+        } = bpm;
+        u16s(hash, &[*min, *max, *max_delta_per_phrase]);
+        f32s(hash, &[*mutation_probability, *change_probability, *ramp_probability]);
+
+        let KeyKindParameters { mutation_probablity } = key_kind;
+        f32s(hash, &[*mutation_probablity]);
+
+        let TimeSignatureParameters {
+            num_min,
+            num_max,
+            num_mutation_probability,
+        } = time_signature;
+        hash(&[*num_min, *num_max]);
+        f32s(hash, &[*num_mutation_probability]);
+
+        let ScaleParameters { mutation_probability } = scale;
+        f32s(hash, &[*mutation_probability]);
+
+        let NumMeasuresParameters {
+            min_measures,
+            max_measures,
+            mutation_probability,
+        } = num_measures;
+        hash(&[*min_measures, *max_measures]);
+        f32s(hash, &[*mutation_probability]);
+
+        let ChordProgressionParameters { mutation_probability } = chord_progression;
+        f32s(hash, &[*mutation_probability]);
+
+        let KeyParameters { mutation_probability } = key;
+        f32s(hash, &[*mutation_probability]);
+
+        let VoicesParameters {
+            lead_voices_min,
+            lead_voices_max,
+            chorus_voices_min,
+            chorus_voices_max,
+            total_voices_max,
+            lead_voices_mutation_probability,
+            chorus_voices_mutation_probability,
+        } = voices;
+        hash(&[*lead_voices_min, *lead_voices_max, *chorus_voices_min, *chorus_voices_max, *total_voices_max]);
+        f32s(hash, &[*lead_voices_mutation_probability, *chorus_voices_mutation_probability]);
+
+        let LeadVoiceDataParameters { resolution: lead_res, euc: lead_euc } = lead_voices;
+        resolution(hash, &lead_res.0);
+        euc(hash, lead_euc);
+
+        let ChorusVoiceDataParameters { resolution: chorus_res, euc: chorus_euc } = chorus_voices;
+        resolution(hash, &chorus_res.0);
+        euc(hash, chorus_euc);
+    }
+}
+
+/// `PhraseDataParameters` that have passed `validate`, ready for
+/// `PhraseDataBuilder::fill`
+#[derive(Debug, Default)]
+pub struct ValidatedParameters(PhraseDataParameters);
+
+impl ValidatedParameters {
+    pub fn new(params: PhraseDataParameters) -> Result<Self, ParameterError> {
+        params.validate()?;
+        Ok(Self(params))
+    }
+
+    pub fn into_inner(self) -> PhraseDataParameters {
+        self.0
+    }
+}
+
+impl TryFrom<PhraseDataParameters> for ValidatedParameters {
+    type Error = ParameterError;
+
+    fn try_from(params: PhraseDataParameters) -> Result<Self, Self::Error> {
+        Self::new(params)
+    }
+}
+
+impl core::ops::Deref for ValidatedParameters {
+    type Target = PhraseDataParameters;
+
+    fn deref(&self) -> &PhraseDataParameters {
+        &self.0
+    }
+}
+
 #[derive(Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
 pub struct BpmParameters {
     pub min: u16,
     pub max: u16,
     pub max_delta_per_phrase: u16,
     pub mutation_probability: f32,
+    /// Chance of the tempo changing part way through a phrase
+    pub change_probability: f32,
+    /// When the tempo changes, the chance that it is a ramp across the whole
+    /// phrase, rather than a step at a measure boundary
+    pub ramp_probability: f32,
 }
 
 impl Default for BpmParameters {
@@ -170,20 +528,35 @@ impl Default for BpmParameters {
             max: 150,
             max_delta_per_phrase: 10,
             mutation_probability: 0.1,
+            change_probability: 0.05,
+            ramp_probability: 0.5,
         }
     }
 }
 
 impl BpmParameters {
+    fn validate(&self) -> Result<(), ParameterError> {
+        if self.min == 0 {
+            return Err(param_err("min", ParameterErrorKind::OutOfRange));
+        }
+        check_min_max("min", self.min, self.max)?;
+        check_probability("mutation_probability", self.mutation_probability)?;
+        check_probability("change_probability", self.change_probability)?;
+        check_probability("ramp_probability", self.ramp_probability)
+    }
+
     fn generate<R: Rng>(&self, rng: &mut R) -> u16 {
-        rng.gen_range(self.min..=self.max + 1)
+        rng.gen_range(self.min..=self.max)
     }
 
     fn step<R: Rng>(&self, rng: &mut R, old: u16) -> u16 {
         if !rng.gen_bool(self.mutation_probability.into()) {
             return old;
         }
+        self.nudge(rng, old)
+    }
 
+    fn nudge<R: Rng>(&self, rng: &mut R, old: u16) -> u16 {
         let delta = rng.gen_range(0..=(self.max_delta_per_phrase));
         if rng.gen() {
             let mut new = old;
@@ -197,10 +570,57 @@ impl BpmParameters {
             new
         }
     }
+
+    fn generate_changes<R: Rng>(
+        &self,
+        rng: &mut R,
+        bpm: u16,
+        measure_ppqn: u16,
+        num_measures: u8,
+    ) -> Vec<TempoEvent> {
+        let mut events = vec![TempoEvent {
+            tick: 0,
+            bpm: bpm.into(),
+            kind: TempoKind::Step,
+        }];
+
+        if num_measures < 2 || !rng.gen_bool(self.change_probability.into()) {
+            return events;
+        }
+
+        let new_bpm = self.nudge(rng, bpm);
+        if new_bpm == bpm {
+            return events;
+        }
+
+        let event = if rng.gen_bool(self.ramp_probability.into()) {
+            // Arrive at the new tempo at the start of the last measure
+            TempoEvent {
+                tick: u32::from(measure_ppqn) * u32::from(num_measures - 1),
+                bpm: new_bpm.into(),
+                kind: TempoKind::Ramp,
+            }
+        } else {
+            let meas = rng.gen_range(1..num_measures);
+            TempoEvent {
+                tick: u32::from(measure_ppqn) * u32::from(meas),
+                bpm: new_bpm.into(),
+                kind: TempoKind::Step,
+            }
+        };
+        events.push(event);
+        events
+    }
 }
 
 #[derive(Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
 pub struct KeyKindParameters {
+    #[cfg_attr(feature = "serde", serde(rename = "mutation_probability"))]
     pub mutation_probablity: f32,
 }
 
@@ -230,6 +650,11 @@ impl KeyKindParameters {
 }
 
 #[derive(Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
 pub struct TimeSignatureParameters {
     // TODO: This is probably a place where I want
     // a distribution instead of a mutation?
@@ -251,6 +676,21 @@ impl Default for TimeSignatureParameters {
 }
 
 impl TimeSignatureParameters {
+    // Enough for any reasonable time signature, while leaving room for
+    // several measures in a `BarBuf`
+    const MAX_NUMERATOR: u8 = 16;
+
+    fn validate(&self) -> Result<(), ParameterError> {
+        if self.num_min == 0 {
+            return Err(param_err("num_min", ParameterErrorKind::OutOfRange));
+        }
+        if self.num_max > Self::MAX_NUMERATOR {
+            return Err(param_err("num_max", ParameterErrorKind::OutOfRange));
+        }
+        check_min_max("num_min", self.num_min, self.num_max)?;
+        check_probability("num_mutation_probability", self.num_mutation_probability)
+    }
+
     fn generate<R: Rng>(&self, rng: &mut R) -> TimeSignature {
         TimeSignature {
             numerator: rng.gen_range(self.num_min..=self.num_max),
@@ -259,7 +699,9 @@ impl TimeSignatureParameters {
     }
 
     fn step<R: Rng>(&self, rng: &mut R, old: TimeSignature) -> TimeSignature {
-        if rng.gen_bool(self.num_mutation_probability.into()) {
+        // The parameters may have changed since the last phrase
+        let valid = (self.num_min..=self.num_max).contains(&old.numerator);
+        if !valid || rng.gen_bool(self.num_mutation_probability.into()) {
             self.generate(rng)
         } else {
             old
@@ -268,6 +710,11 @@ impl TimeSignatureParameters {
 }
 
 #[derive(Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
 pub struct ScaleParameters {
     mutation_probability: f32,
 }
@@ -316,6 +763,11 @@ impl ScaleParameters {
 }
 
 #[derive(Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
 pub struct NumMeasuresParameters {
     min_measures: u8,
     max_measures: u8,
@@ -333,6 +785,23 @@ impl Default for NumMeasuresParameters {
 }
 
 impl NumMeasuresParameters {
+    fn validate(&self, time_sig: &TimeSignatureParameters) -> Result<(), ParameterError> {
+        // Chord progressions need at least three measures
+        if self.min_measures < 3 {
+            return Err(param_err("min_measures", ParameterErrorKind::OutOfRange));
+        }
+        check_min_max("min_measures", self.min_measures, self.max_measures)?;
+        // The longest measures must still fit `min_measures` in a `BarBuf`
+        let longest = TimeSignature {
+            numerator: time_sig.num_max,
+            denominator: SignatureDenominator::Quarter,
+        };
+        if self.min_measures > self.max_meas(longest) {
+            return Err(param_err("min_measures", ParameterErrorKind::OutOfRange));
+        }
+        check_probability("mutation_probability", self.mutation_probability)
+    }
+
     fn max_meas(&self, time_sig: TimeSignature) -> u8 {
         let lim: u16 = match time_sig.denominator {
             SignatureDenominator::Quarter => 16 * 4,
@@ -361,6 +830,11 @@ impl NumMeasuresParameters {
 }
 
 #[derive(Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
 pub struct ChordProgressionParameters {
     mutation_probability: f32,
 }
@@ -374,8 +848,10 @@ impl Default for ChordProgressionParameters {
 }
 
 impl ChordProgressionParameters {
-    fn generate<R: Rng>(&self, rng: &mut R, num_meas: u8) -> ChordProgression {
-        assert!(num_meas >= 3);
+    fn generate<R: Rng>(&self, rng: &mut R, num_meas: u8) -> Result<ChordProgression, PhraseError> {
+        if num_meas < 3 {
+            return Err(PhraseError::TooFewMeasures(num_meas));
+        }
         let mut new = Vec::with_capacity(num_meas as usize);
         new.push(Chord::I);
         for _ in 0..(num_meas - 3) {
@@ -383,11 +859,18 @@ impl ChordProgressionParameters {
         }
         new.push(if rng.gen() { Chord::IV } else { Chord::V });
         new.push(Chord::I);
-        ChordProgression { chords: new }
+        Ok(ChordProgression { chords: new })
     }
 
-    fn step<R: Rng>(&self, rng: &mut R, mut old: ChordProgression, num_meas: u8) -> ChordProgression {
-        assert!(num_meas >= 3);
+    fn step<R: Rng>(
+        &self,
+        rng: &mut R,
+        mut old: ChordProgression,
+        num_meas: u8,
+    ) -> Result<ChordProgression, PhraseError> {
+        if num_meas < 3 {
+            return Err(PhraseError::TooFewMeasures(num_meas));
+        }
         let num_meas = num_meas as usize;
         old.chords.resize_with(num_meas, || Chord::I);
         for ch in &mut old.chords[1..(num_meas - 2)] {
@@ -401,11 +884,16 @@ impl ChordProgressionParameters {
             *sec = if rng.gen() { Chord::IV } else { Chord::V };
         }
         old.chords[num_meas - 1] = Chord::I;
-        old
+        Ok(old)
     }
 }
 
 #[derive(Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
 pub struct KeyParameters {
     mutation_probability: f32,
 }
@@ -432,6 +920,11 @@ impl KeyParameters {
 }
 
 #[derive(Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
 pub struct VoicesParameters {
     lead_voices_min: u8,
     lead_voices_max: u8,
@@ -457,37 +950,61 @@ impl Default for VoicesParameters {
 }
 
 impl VoicesParameters {
+    fn validate(&self) -> Result<(), ParameterError> {
+        // The maximums are exclusive
+        if self.chorus_voices_min >= self.chorus_voices_max {
+            return Err(param_err("chorus_voices_min", ParameterErrorKind::MinAboveMax));
+        }
+        if self.lead_voices_min >= self.lead_voices_max {
+            return Err(param_err("lead_voices_min", ParameterErrorKind::MinAboveMax));
+        }
+        // There must be room for the minimum leads alongside the most chorus
+        // voices
+        let most_chorus = self.chorus_voices_max - 1;
+        if self.total_voices_max <= most_chorus.saturating_add(self.lead_voices_min) {
+            return Err(param_err("total_voices_max", ParameterErrorKind::OutOfRange));
+        }
+        check_probability("lead_voices_mutation_probability", self.lead_voices_mutation_probability)?;
+        check_probability("chorus_voices_mutation_probability", self.chorus_voices_mutation_probability)
+    }
+
     fn gen_chorus<R: Rng>(&self, rng: &mut R) -> u8 {
         rng.gen_range(self.chorus_voices_min..self.chorus_voices_max)
     }
 
-    fn gen_leads<R: Rng>(&self, rng: &mut R, chorus: u8) -> u8 {
-        let leads_max = (self.total_voices_max - chorus).min(self.lead_voices_max);
-        rng.gen_range(self.lead_voices_min..leads_max)
+    fn gen_leads<R: Rng>(&self, rng: &mut R, chorus: u8) -> Result<u8, PhraseError> {
+        let leads_max = self.total_voices_max.saturating_sub(chorus).min(self.lead_voices_max);
+        if leads_max <= self.lead_voices_min {
+            return Err(PhraseError::NoRoomForLeads { chorus });
+        }
+        Ok(rng.gen_range(self.lead_voices_min..leads_max))
     }
 
-    fn generate<R: Rng>(&self, rng: &mut R) -> (u8, u8) {
+    fn generate<R: Rng>(&self, rng: &mut R) -> Result<(u8, u8), PhraseError> {
         let chorus = self.gen_chorus(rng);
-        let leads = self.gen_leads(rng, chorus);
-        (leads, chorus)
+        let leads = self.gen_leads(rng, chorus)?;
+        Ok((leads, chorus))
     }
 
-    fn step<R: Rng>(&self, rng: &mut R, old_lead: u8, old_chorus: u8) -> (u8, u8) {
-        let chorus = if rng.gen_bool(self.chorus_voices_mutation_probability.into()) {
+    fn step<R: Rng>(&self, rng: &mut R, old_lead: u8, old_chorus: u8) -> Result<(u8, u8), PhraseError> {
+        // The parameters may have changed since the last phrase
+        let valid = (self.chorus_voices_min..self.chorus_voices_max).contains(&old_chorus);
+        let chorus = if !valid || rng.gen_bool(self.chorus_voices_mutation_probability.into()) {
             self.gen_chorus(rng)
         } else {
             old_chorus
         };
         let leads = if rng.gen_bool(self.lead_voices_mutation_probability.into()) {
-            self.gen_leads(rng, chorus)
+            self.gen_leads(rng, chorus)?
         } else {
-            old_lead.min(self.total_voices_max - chorus)
+            old_lead.min(self.total_voices_max.saturating_sub(chorus))
         };
-        (leads, chorus)
+        Ok((leads, chorus))
     }
 }
 
 #[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct LeadVoiceDataBuilder {
     resolution: Option<Length>,
     euc: Option<(u8, u8)>,
@@ -497,7 +1014,12 @@ pub struct LeadVoiceDataBuilder {
 }
 
 impl LeadVoiceDataBuilder {
-    pub fn fill<R: Rng>(&mut self, rng: &mut R, header: &PhraseDataHeader, parameters: &LeadVoiceDataParameters) {
+    pub fn fill<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        header: &PhraseDataHeader,
+        parameters: &LeadVoiceDataParameters,
+    ) -> Result<(), PhraseError> {
         let mut needs_regen = false;
         let resolution;
         let beats;
@@ -548,25 +1070,14 @@ impl LeadVoiceDataBuilder {
         });
 
         if needs_regen {
-            let euc = Euc32::new(beats as u32, length as u32).unwrap();
-            let euc_iter = euc.cycler();
-            let res_len = resolution.to_ppqn();
-
-            self.rhythm = (0..max_notes)
-                .map(|i| i * res_len)
-                .zip(euc_iter)
-                .filter_map(|(start, hit)| {
-                    if hit {
-                        Some(EncRhythm { start: start.try_into().unwrap(), length: res_len.try_into().unwrap() })
-                    } else {
-                        None
-                    }
-                }).collect();
+            self.rhythm = EncRhythm::euclidean(beats, length, resolution, max_notes)?;
         }
+        Ok(())
     }
 }
 
 #[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct ChorusVoiceDataBuilder {
     resolution: Option<Length>,
     euc: Option<(u8, u8)>,
@@ -576,7 +1087,12 @@ pub struct ChorusVoiceDataBuilder {
 }
 
 impl ChorusVoiceDataBuilder {
-    pub fn fill<R: Rng>(&mut self, rng: &mut R, header: &PhraseDataHeader, parameters: &ChorusVoiceDataParameters) {
+    pub fn fill<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        header: &PhraseDataHeader,
+        parameters: &ChorusVoiceDataParameters,
+    ) -> Result<(), PhraseError> {
         let mut needs_regen = false;
         let resolution;
         let beats;
@@ -627,25 +1143,18 @@ impl ChorusVoiceDataBuilder {
         });
 
         if needs_regen {
-            let euc = Euc32::new(beats as u32, length as u32).unwrap();
-            let euc_iter = euc.cycler();
-            let res_len = resolution.to_ppqn();
-
-            self.rhythm = (0..max_notes)
-                .map(|i| i * res_len)
-                .zip(euc_iter)
-                .filter_map(|(start, hit)| {
-                    if hit {
-                        Some(EncRhythm { start: start.try_into().unwrap(), length: res_len.try_into().unwrap() })
-                    } else {
-                        None
-                    }
-                }).collect();
+            self.rhythm = EncRhythm::euclidean(beats, length, resolution, max_notes)?;
         }
+        Ok(())
     }
 }
 
 #[derive(Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
 pub struct LeadVoiceDataParameters {
     resolution: LeadVoiceResolutionParameters,
     euc: EuclideanGenerationParameters,
@@ -668,6 +1177,11 @@ impl Default for LeadVoiceDataParameters {
 }
 
 #[derive(Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
 pub struct ChorusVoiceDataParameters {
     resolution: ChorusVoiceResolutionParameters,
     euc: EuclideanGenerationParameters,
@@ -689,7 +1203,14 @@ impl Default for ChorusVoiceDataParameters {
     }
 }
 
+// The lead and chorus voices have different defaults, so when loading, a
+// `euc` or `resolution` table has to give every field
 #[derive(Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(deny_unknown_fields)
+)]
 pub struct EuclideanGenerationParameters {
     min_beats: u8,
     max_beats: u8,
@@ -713,6 +1234,23 @@ impl Default for EuclideanGenerationParameters {
 }
 
 impl EuclideanGenerationParameters {
+    fn validate(&self) -> Result<(), ParameterError> {
+        if self.min_length == 0 {
+            return Err(param_err("euc.min_length", ParameterErrorKind::OutOfRange));
+        }
+        if self.max_length > 32 {
+            return Err(param_err("euc.max_length", ParameterErrorKind::OutOfRange));
+        }
+        check_min_max("euc.min_length", self.min_length, self.max_length)?;
+        check_min_max("euc.min_beats", self.min_beats, self.max_beats)?;
+        // Every pattern must have room for the minimum beats
+        if self.min_beats > self.min_length {
+            return Err(param_err("euc.min_beats", ParameterErrorKind::OutOfRange));
+        }
+        check_probability("euc.beats_mutation_probability", self.beats_mutation_probability)?;
+        check_probability("euc.length_mutation_probability", self.length_mutation_probability)
+    }
+
     #[inline]
     fn generate<R: Rng>(&self, rng: &mut R, max_len: u8) -> (u8, u8) {
         let max_length = self.max_length.min(max_len);
@@ -723,7 +1261,8 @@ impl EuclideanGenerationParameters {
 
         let length = rng.gen_range(min_length..=max_length);
         let max_beats = self.max_beats.min(length);
-        let beats = rng.gen_range(self.min_beats..=max_beats);
+        let min_beats = self.min_beats.min(max_beats);
+        let beats = rng.gen_range(min_beats..=max_beats);
         (beats, length)
     }
 
@@ -743,12 +1282,18 @@ impl EuclideanGenerationParameters {
 }
 
 #[derive(Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(deny_unknown_fields)
+)]
 pub struct VoiceResolutionParameters {
     resolution_choices: Vec<Length>,
     mutation_percentage: f32,
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct LeadVoiceResolutionParameters(VoiceResolutionParameters);
 
 impl Default for LeadVoiceResolutionParameters {
@@ -766,6 +1311,7 @@ impl Default for LeadVoiceResolutionParameters {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ChorusVoiceResolutionParameters(VoiceResolutionParameters);
 
 impl Default for ChorusVoiceResolutionParameters {
@@ -806,6 +1352,17 @@ impl LeadVoiceResolutionParameters {
 }
 
 impl VoiceResolutionParameters {
+    fn validate(&self) -> Result<(), ParameterError> {
+        if self.resolution_choices.is_empty() {
+            return Err(param_err("resolution.resolution_choices", ParameterErrorKind::Empty));
+        }
+        // Beats are counted by dividing by the resolution
+        if self.resolution_choices.iter().any(|l| !(1..=PPQN_MAX).contains(&l.to_ppqn())) {
+            return Err(param_err("resolution.resolution_choices", ParameterErrorKind::OutOfRange));
+        }
+        check_probability("resolution.mutation_percentage", self.mutation_percentage)
+    }
+
     fn generate<R: Rng>(&self, rng: &mut R) -> Length {
         let idx = rng.gen_range(0..self.resolution_choices.len());
         self.resolution_choices[idx]
@@ -822,12 +1379,39 @@ impl VoiceResolutionParameters {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct TimeSignature {
     numerator: u8,
     denominator: SignatureDenominator,
 }
 
+impl TimeSignature {
+    /// The length of one measure
+    pub fn measure_ppqn(&self) -> u16 {
+        let denom_ppqn = match self.denominator {
+            SignatureDenominator::Quarter => PPQN_QUARTER,
+            SignatureDenominator::Eighth => PPQN_EIGHTH,
+            SignatureDenominator::Sixteenth => PPQN_16TH,
+        };
+        denom_ppqn * (self.numerator as u16)
+    }
+
+    pub fn numerator(&self) -> u8 {
+        self.numerator
+    }
+
+    /// The note value of one beat, as in the bottom of `3/4` or `6/8`
+    pub fn denominator(&self) -> u8 {
+        match self.denominator {
+            SignatureDenominator::Quarter => 4,
+            SignatureDenominator::Eighth => 8,
+            SignatureDenominator::Sixteenth => 16,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum SignatureDenominator {
     Quarter,
     Eighth,
@@ -835,12 +1419,14 @@ pub enum SignatureDenominator {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum KeyKind {
     Major,
     Minor,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[repr(u8)]
 pub enum Chord {
     I,
@@ -866,12 +1452,13 @@ impl From<u8> for Chord {
 }
 
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize), serde(transparent))]
 pub struct ChordProgression {
     chords: Vec<Chord>,
 }
 
 impl Debug for ChordProgression {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.write_str("[")?;
         for (i, st) in self.chords.iter().enumerate() {
             st.fmt(f)?;
@@ -884,12 +1471,13 @@ impl Debug for ChordProgression {
 }
 
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize), serde(transparent))]
 pub struct Scale {
     scale: &'static [Semitones],
 }
 
 impl Debug for Scale {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.write_str("[")?;
         for (i, st) in self.scale.iter().enumerate() {
             st.0.fmt(f)?;
@@ -902,12 +1490,32 @@ impl Debug for Scale {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct EncRhythm {
     start: EncStart,
     length: EncLength,
 }
 
 impl EncRhythm {
+    // `max_notes` notes of `resolution`, with the hits of a euclidean rhythm
+    // repeated across them
+    fn euclidean(beats: u8, length: u8, resolution: Length, max_notes: u16) -> Result<Vec<Self>, PhraseError> {
+        let euc = Euc32::new(beats.into(), length.into()).ok_or(PhraseError::Rhythm { beats, length })?;
+        let res_len = resolution.to_ppqn();
+
+        (0..max_notes)
+            .map(|i| i * res_len)
+            .zip(euc.cycler())
+            .filter(|(_, hit)| *hit)
+            .map(|(start, _)| {
+                Ok(EncRhythm {
+                    start: start.try_into()?,
+                    length: res_len.try_into()?,
+                })
+            })
+            .collect()
+    }
+
     pub fn ppqn_start(&self) -> u16 {
         self.start.ppqn_idx
     }
@@ -916,3 +1524,159 @@ impl EncRhythm {
         self.length.ppqn_ct
     }
 }
+
+#[cfg(test)]
+mod test {
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    use super::*;
+
+    #[track_caller]
+    fn err(params: &PhraseDataParameters) -> (&'static str, &'static str, ParameterErrorKind) {
+        let e = params.validate().unwrap_err();
+        (e.section, e.name, e.kind)
+    }
+
+    #[test]
+    fn validate() {
+        assert_eq!(PhraseDataParameters::default().validate(), Ok(()));
+
+        let mut params = PhraseDataParameters::default();
+        params.bpm.min = 200;
+        assert_eq!(err(&params), ("bpm", "min", ParameterErrorKind::MinAboveMax));
+
+        let mut params = PhraseDataParameters::default();
+        params.key.mutation_probability = 1.5;
+        assert_eq!(err(&params), ("key", "mutation_probability", ParameterErrorKind::NotAProbability));
+
+        // Sixteen measures of 16/4 doesn't fit in a `BarBuf`
+        let mut params = PhraseDataParameters::default();
+        params.time_signature.num_max = 16;
+        params.num_measures.min_measures = 16;
+        assert_eq!(err(&params), ("num_measures", "min_measures", ParameterErrorKind::OutOfRange));
+
+        let mut params = PhraseDataParameters::default();
+        params.voices.total_voices_max = 4;
+        assert_eq!(err(&params), ("voices", "total_voices_max", ParameterErrorKind::OutOfRange));
+
+        let mut params = PhraseDataParameters::default();
+        params.chorus_voices.resolution.0.resolution_choices.clear();
+        assert_eq!(
+            err(&params),
+            ("chorus_voices", "resolution.resolution_choices", ParameterErrorKind::Empty)
+        );
+
+        let mut params = PhraseDataParameters::default();
+        params.lead_voices.resolution.0.resolution_choices.push(Length::PPQNCount(0));
+        assert_eq!(
+            err(&params),
+            ("lead_voices", "resolution.resolution_choices", ParameterErrorKind::OutOfRange)
+        );
+        params.lead_voices.resolution.0.resolution_choices = vec![Length::PPQNCount(PPQN_MAX + 1)];
+        assert_eq!(
+            err(&params),
+            ("lead_voices", "resolution.resolution_choices", ParameterErrorKind::OutOfRange)
+        );
+
+        // The whole range of tempos can be used
+        let mut params = PhraseDataParameters::default();
+        params.bpm.max = u16::MAX;
+        assert_eq!(params.validate(), Ok(()));
+
+        let mut params = PhraseDataParameters::default();
+        params.lead_voices.euc.min_beats = 12;
+        params.lead_voices.euc.max_beats = 16;
+        assert_eq!(err(&params), ("lead_voices", "euc.min_beats", ParameterErrorKind::OutOfRange));
+        assert_eq!(params.validate().unwrap_err().to_string(), "`lead_voices.euc.min_beats` is out of range");
+    }
+
+    #[test]
+    fn fill_many() {
+        // Chorus rhythms are often cut shorter than their minimum number of
+        // beats, which must not stop generation
+        let params = ValidatedParameters::default();
+        for seed in 0..32 {
+            let mut rng = ChaCha8Rng::seed_from_u64(seed);
+            let mut builder = PhraseDataBuilder::default();
+            for _ in 0..20 {
+                builder.fill(&mut rng, &params).unwrap();
+                let header = builder.build_header().unwrap();
+                assert!(header.num_measures() >= 3);
+            }
+        }
+    }
+
+    #[test]
+    fn fill_errors() {
+        let builder = PhraseDataBuilder::default();
+        assert_eq!(builder.build_header().unwrap_err(), PhraseError::NotFilled);
+
+        let mut params = PhraseDataParameters::default();
+        params.voices.total_voices_max = 2;
+        assert!(matches!(
+            ValidatedParameters::new(params),
+            Err(ParameterError { section: "voices", .. })
+        ));
+
+        // Three measures of 1/4 is too short for a single whole note
+        let mut params = PhraseDataParameters::default();
+        params.time_signature.num_min = 1;
+        params.time_signature.num_max = 1;
+        params.num_measures.max_measures = 3;
+        params.chorus_voices.resolution.0.resolution_choices = vec![Length::Whole];
+        let params = ValidatedParameters::new(params).unwrap();
+
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let mut builder = PhraseDataBuilder::default();
+        assert_eq!(
+            builder.fill(&mut rng, &params),
+            Err(PhraseError::Rhythm { beats: 0, length: 0 })
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn load() {
+        // Anything left out keeps its default
+        let params: PhraseDataParameters = toml::from_str(
+            "[bpm]\nmin = 90\nmax = 140\n\n[key_kind]\nmutation_probability = 0.5\n\n[voices]\nlead_voices_max = 3\n",
+        )
+        .unwrap();
+        assert_eq!((params.bpm.min, params.bpm.max), (90, 140));
+        assert_eq!(params.key_kind.mutation_probablity, 0.5);
+        assert_eq!(params.voices.lead_voices_max, 3);
+        assert_eq!(params.voices.total_voices_max, 8);
+        assert_eq!(params.lead_voices.euc.min_length, 8);
+        assert_eq!(params.validate(), Ok(()));
+
+        let params: PhraseDataParameters =
+            serde_json::from_str(r#"{ "voices": { "total_voices_max": 6 } }"#).unwrap();
+        assert_eq!(params.voices.total_voices_max, 6);
+        assert_eq!(params.bpm.min, BpmParameters::default().min);
+
+        // Misspelled parameters aren't silently ignored
+        assert!(toml::from_str::<PhraseDataParameters>("[bpm]\nminimum = 90\n").is_err());
+        assert!(serde_json::from_str::<PhraseDataParameters>(r#"{ "tempo": {} }"#).is_err());
+
+        // The lead and chorus voices' defaults differ, so these can't be
+        // given in part
+        assert!(toml::from_str::<PhraseDataParameters>("[lead_voices.euc]\nmax_length = 24\n").is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn round_trip() {
+        let mut params = PhraseDataParameters::default();
+        params.scale.mutation_probability = 0.25;
+        params.chorus_voices.resolution.0.resolution_choices = vec![Length::Half, Length::Whole];
+
+        let toml_src = toml::to_string(&params).unwrap();
+        let from_toml: PhraseDataParameters = toml::from_str(&toml_src).unwrap();
+        assert_eq!(format!("{from_toml:?}"), format!("{params:?}"));
+
+        let json_src = serde_json::to_string(&params).unwrap();
+        let from_json: PhraseDataParameters = serde_json::from_str(&json_src).unwrap();
+        assert_eq!(format!("{from_json:?}"), format!("{params:?}"));
+    }
+}
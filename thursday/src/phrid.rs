@@ -0,0 +1,228 @@
+// Reproducible phrase generation.
+//
+// A `PhraseGenerator` runs `PhraseDataBuilder::fill` off a ChaCha8 rng
+// seeded from a `u64`, which gives the same numbers on every platform.
+// Its `PhraseId` (the seed, a hash of the parameters, and how many phrases
+// have been generated) is enough to get back to the exact same phrase,
+// and prints as something short enough to paste around, like
+// `00000000000004d2-9f1a33c0-12`.
+//
+// The parameter hash is taken from every field of the parameters in turn,
+// with floats hashed by their bits, so it doesn't depend on how any
+// toolchain formats them. Adding a parameter changes it, even if the new
+// parameter has no effect. That's on purpose: old IDs might not give the
+// same phrases.
+
+use core::{fmt, str::FromStr};
+
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+
+use crate::phrdat::{PhraseDataBuilder, PhraseDataParameters, PhraseError, ValidatedParameters};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PhraseId {
+    pub seed: u64,
+    /// Hash of the parameters, from `params_hash`
+    pub params: u32,
+    /// How many phrases have been generated
+    pub step: u32,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PhraseIdError {
+    /// Not in the `seed-params-step` form
+    BadFormat,
+    /// The ID was made with different parameters
+    ParamsMismatch { expected: u32, found: u32 },
+    /// One of the phrases up to the ID couldn't be generated
+    Phrase(PhraseError),
+}
+
+impl fmt::Display for PhraseId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}-{:08x}-{}", self.seed, self.params, self.step)
+    }
+}
+
+impl FromStr for PhraseId {
+    type Err = PhraseIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().split('-');
+        let mut next = || parts.next().ok_or(PhraseIdError::BadFormat);
+        let seed = u64::from_str_radix(next()?, 16).map_err(|_| PhraseIdError::BadFormat)?;
+        let params = u32::from_str_radix(next()?, 16).map_err(|_| PhraseIdError::BadFormat)?;
+        let step = next()?.parse().map_err(|_| PhraseIdError::BadFormat)?;
+        if parts.next().is_some() {
+            return Err(PhraseIdError::BadFormat);
+        }
+        Ok(PhraseId { seed, params, step })
+    }
+}
+
+/// A 32-bit FNV-1a hash of the parameters
+pub fn params_hash(params: &PhraseDataParameters) -> u32 {
+    let mut hash = 0x811C_9DC5u32;
+    params.write_hash(&mut |bytes: &[u8]| {
+        hash = bytes
+            .iter()
+            .fold(hash, |hash, b| (hash ^ u32::from(*b)).wrapping_mul(0x0100_0193));
+    });
+    hash
+}
+
+pub struct PhraseGenerator {
+    id: PhraseId,
+    params: ValidatedParameters,
+    rng: ChaCha8Rng,
+    builder: PhraseDataBuilder,
+}
+
+impl PhraseGenerator {
+    /// Start from nothing. Call `next_phrase` to generate the first phrase.
+    pub fn new(seed: u64, params: ValidatedParameters) -> Self {
+        Self {
+            id: PhraseId {
+                seed,
+                params: params_hash(&params),
+                step: 0,
+            },
+            params,
+            rng: ChaCha8Rng::seed_from_u64(seed),
+            builder: PhraseDataBuilder::default(),
+        }
+    }
+
+    /// Generate the phrases up to `id` again. The parameters must be the
+    /// same ones the ID was made with.
+    pub fn from_id(id: PhraseId, params: ValidatedParameters) -> Result<Self, PhraseIdError> {
+        let found = params_hash(&params);
+        if found != id.params {
+            return Err(PhraseIdError::ParamsMismatch {
+                expected: id.params,
+                found,
+            });
+        }
+        let mut gen = Self::new(id.seed, params);
+        for _ in 0..id.step {
+            gen.next_phrase().map_err(PhraseIdError::Phrase)?;
+        }
+        Ok(gen)
+    }
+
+    /// Generate the next phrase, carrying on from the last one. After an
+    /// error, the ID is left at the last phrase that was generated.
+    pub fn next_phrase(&mut self) -> Result<&PhraseDataBuilder, PhraseError> {
+        self.builder.fill(&mut self.rng, &self.params)?;
+        self.id.step += 1;
+        Ok(&self.builder)
+    }
+
+    /// The ID of the current phrase
+    pub fn id(&self) -> PhraseId {
+        self.id
+    }
+
+    pub fn builder(&self) -> &PhraseDataBuilder {
+        &self.builder
+    }
+
+    pub fn params(&self) -> &PhraseDataParameters {
+        &self.params
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use minijam::scale::Pitch;
+
+    use super::*;
+
+    fn summary(builder: &PhraseDataBuilder) -> String {
+        let header = builder.build_header().unwrap();
+        let rhythms = builder
+            .lead_voices
+            .iter()
+            .map(|v| &v.rhythm)
+            .chain(builder.chorus_voices.iter().map(|v| &v.rhythm))
+            .map(|r| r.iter().map(|n| n.ppqn_start().to_string()).collect::<Vec<_>>().join(","))
+            .collect::<Vec<_>>();
+        format!("{header:?} {rhythms:?}")
+    }
+
+    #[test]
+    fn id_strings() {
+        let id = PhraseId {
+            seed: 1234,
+            params: 0x9F1A_33C0,
+            step: 12,
+        };
+        assert_eq!(id.to_string(), "00000000000004d2-9f1a33c0-12");
+        assert_eq!("00000000000004d2-9f1a33c0-12".parse(), Ok(id));
+        assert_eq!("4d2-9f1a33c0-12".parse(), Ok(id));
+
+        for bad in ["", "4d2", "4d2-9f1a33c0", "4d2-9f1a33c0-x", "4d2-9f1a33c0-12-1"] {
+            assert_eq!(bad.parse::<PhraseId>(), Err(PhraseIdError::BadFormat), "{bad}");
+        }
+    }
+
+    #[test]
+    fn regenerate() {
+        let mut gen = PhraseGenerator::new(4, ValidatedParameters::default());
+        let mut phrases = vec![];
+        for _ in 0..4 {
+            phrases.push((gen.id(), summary(gen.next_phrase().unwrap())));
+        }
+
+        // Any phrase can be made again from its ID, even out of order
+        for (id, phrase) in phrases.iter().rev() {
+            let mut again = PhraseGenerator::from_id(*id, ValidatedParameters::default()).unwrap();
+            assert_eq!(again.id(), *id);
+            assert_eq!(&summary(again.next_phrase().unwrap()), phrase);
+        }
+
+        let mut params = PhraseDataParameters::default();
+        params.bpm.max += 1;
+        assert!(matches!(
+            PhraseGenerator::from_id(gen.id(), params.try_into().unwrap()),
+            Err(PhraseIdError::ParamsMismatch { .. })
+        ));
+
+        // Even the smallest change to a probability counts
+        let mut params = PhraseDataParameters::default();
+        params.bpm.ramp_probability = f32::from_bits(params.bpm.ramp_probability.to_bits() + 1);
+        assert_ne!(params_hash(&params), params_hash(&PhraseDataParameters::default()));
+    }
+
+    // The same seed must give the same phrases from one build to the next,
+    // or saved IDs stop working. If this fails after changing the generator
+    // on purpose, update the expected values.
+    #[test]
+    fn stable_output() {
+        let params = ValidatedParameters::default();
+        assert_eq!(params_hash(&params), 0x7BF7_E8D6);
+
+        let mut gen = PhraseGenerator::new(4, params);
+        gen.next_phrase().unwrap();
+        gen.next_phrase().unwrap();
+        assert_eq!(gen.id().to_string(), "0000000000000004-7bf7e8d6-2");
+
+        let builder = gen.builder();
+        let header = builder.build_header().unwrap();
+        assert_eq!(header.bpm, 149);
+        assert_eq!(header.time_signature().numerator(), 6);
+        assert_eq!(header.key(), Pitch::ASharp);
+        assert_eq!(header.num_measures(), 10);
+        assert_eq!(builder.lead_voices.len(), 3);
+        assert_eq!(builder.chorus_voices.len(), 2);
+
+        let starts = builder.lead_voices[0]
+            .rhythm
+            .iter()
+            .take(8)
+            .map(|r| r.ppqn_start())
+            .collect::<Vec<_>>();
+        assert_eq!(starts, [0, 384, 768, 1152, 1536, 1920, 2304, 2880]);
+    }
+}
@@ -0,0 +1,357 @@
+// Rendering `BarBuf`s to audio with minijam's synth.
+//
+// Each voice is a list of `BarBuf`s played one after the other (each
+// starting where the last one's notes and rests end, like
+// `MmlChannel::queue_on`), with a `ToneKind` and `Operator` to play them
+// with. Notes are fed into small minijam `Track`s as they drain, so any
+// number of notes can be rendered with a `DEPTH` note queue. A `Track` plays
+// one note at a time, so each voice gets as many as it has notes sounding
+// at once, for chords and other overlapping notes.
+//
+// Ticks are turned into samples with any `Timebase`: a `Clock` for a steady
+// tempo (`Renderer::new`), or a `TempoMap` for one that changes
+// (`Renderer::with_timebase`). A voice can also have `Automation`, which is
+// evaluated once per rendered block.
+//
+// Pitch offsets are kept, as a frequency between the two semitones.
+// Velocity isn't, as a `Track` has no per-note volume.
+
+use alloc::{vec, vec::Vec};
+
+use minijam::{
+    automation::Automation,
+    clock::{Clock, Timebase},
+    tones::{Mix, Operator, OperatorKind, ToneKind},
+    wav::{WavError, WavSink, WavWriter},
+    Sample, StereoSample, Track,
+};
+
+use crate::{bars::BarBuf, PlayNote};
+
+// Samples rendered at a time by `Renderer::write_wav`
+const BLOCK_LEN: usize = 512;
+
+/// Automation for a `RenderVoice`, with room for a lane for every `Target`
+pub type RenderAutomation = Automation<RENDER_LANES, RENDER_POINTS>;
+
+/// The number of lanes in a `RenderAutomation`, one for each `Target`
+pub const RENDER_LANES: usize = 5;
+
+/// The most breakpoints in each lane of a `RenderAutomation`
+pub const RENDER_POINTS: usize = 32;
+
+pub struct RenderVoice<'a> {
+    pub bars: &'a [BarBuf],
+    pub kind: ToneKind,
+    pub operator: Operator,
+    pub mix: Mix,
+    /// Applied at the start of each block, by sample position from the
+    /// start of the render
+    pub automation: Option<&'a RenderAutomation>,
+}
+
+impl<'a> RenderVoice<'a> {
+    /// A voice with no modulation, mixed at a quarter of full volume
+    pub fn new(bars: &'a [BarBuf], kind: ToneKind) -> Self {
+        Self {
+            bars,
+            kind,
+            operator: Operator::new(OperatorKind::None),
+            mix: Mix::Div4,
+            automation: None,
+        }
+    }
+}
+
+struct VoiceState<'a, const DEPTH: usize> {
+    voice: RenderVoice<'a>,
+    parts: Vec<Part<DEPTH>>,
+}
+
+// One note at a time of a voice
+struct Part<const DEPTH: usize> {
+    track: Track<DEPTH>,
+    // Each part runs its own copy of the voice's operator
+    operator: Operator,
+    // Start tick, length in ticks, and frequency of its notes
+    notes: Vec<(u32, u32, f32)>,
+    next: usize,
+}
+
+pub struct Renderer<'a, const DEPTH: usize = 8, T: Timebase = Clock> {
+    sample_rate: u32,
+    timebase: T,
+    voices: Vec<VoiceState<'a, DEPTH>>,
+}
+
+impl<'a, const DEPTH: usize> Renderer<'a, DEPTH> {
+    /// Render at a steady tempo. Returns `None` if the sample rate or bpm
+    /// are zero.
+    pub fn new(sample_rate: u32, bpm: u32) -> Option<Self> {
+        Self::with_timebase(sample_rate, Clock::new(sample_rate, bpm)?)
+    }
+}
+
+impl<'a, const DEPTH: usize, T: Timebase> Renderer<'a, DEPTH, T> {
+    /// Render with any timebase, like a `TempoMap`. It should use the same
+    /// sample rate. Returns `None` if the sample rate is zero.
+    pub fn with_timebase(sample_rate: u32, timebase: T) -> Option<Self> {
+        if sample_rate == 0 {
+            return None;
+        }
+        Some(Self {
+            sample_rate,
+            timebase,
+            voices: Vec::new(),
+        })
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn add_voice(&mut self, voice: RenderVoice<'a>) {
+        let mut parts: Vec<Part<DEPTH>> = vec![];
+        let mut start = 0u32;
+        for bar in voice.bars.iter() {
+            for note in bar.notes() {
+                let note = PlayNote::from_enc(&note, 0);
+                let note = (start + u32::from(note.start), u32::from(note.length), note.frequency());
+
+                // Notes are in order of their start, so the first part that
+                // has finished its last note is free
+                let free = parts
+                    .iter()
+                    .position(|p| p.notes.last().is_none_or(|(s, l, _)| s + l <= note.0));
+                let idx = free.unwrap_or_else(|| {
+                    parts.push(Part {
+                        track: Track::new(self.sample_rate),
+                        operator: voice.operator.clone(),
+                        notes: vec![],
+                        next: 0,
+                    });
+                    parts.len() - 1
+                });
+                parts[idx].notes.push(note);
+            }
+            start += u32::from(bar.ppqn_len());
+        }
+
+        self.voices.push(VoiceState { voice, parts });
+    }
+
+    /// True once every note of every voice has been played
+    pub fn is_done(&self) -> bool {
+        self.voices
+            .iter()
+            .flat_map(|v| v.parts.iter())
+            .all(|p| p.next == p.notes.len() && p.track.is_done())
+    }
+
+    /// Render the next block. Anything already in `samples` is replaced.
+    pub fn render(&mut self, samples: &mut [StereoSample]) {
+        let empty = Sample { word: 0 };
+        samples.fill(StereoSample { left: empty, right: empty });
+
+        for state in self.voices.iter_mut() {
+            let voice = &state.voice;
+            for part in state.parts.iter_mut() {
+                while !part.track.note_q.is_full() {
+                    let Some(&(start, len, freq)) = part.notes.get(part.next) else {
+                        break;
+                    };
+                    // Notes too short to last a sample are skipped. Parts
+                    // never overlap, so nothing else can go wrong.
+                    part.track
+                        .add_note_freq_ticks(&self.timebase, voice.kind, freq, start, len)
+                        .ok();
+                    part.next += 1;
+                }
+
+                match voice.automation {
+                    Some(auto) => part
+                        .track
+                        .fill_stereo_samples_automated(samples, voice.mix, &mut part.operator, auto),
+                    None => part.track.fill_stereo_samples(samples, voice.mix, &mut part.operator),
+                }
+            }
+        }
+    }
+
+    /// Render everything to a WAV file. The file should be stereo, with the
+    /// same sample rate as the renderer.
+    pub fn write_wav<S: WavSink>(
+        &mut self,
+        wav: &mut WavWriter<S>,
+    ) -> Result<(), WavError<S::Error>> {
+        let empty = Sample { word: 0 };
+        let mut block = [StereoSample { left: empty, right: empty }; BLOCK_LEN];
+        while !self.is_done() {
+            self.render(&mut block);
+            wav.write_stereo(&block)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use minijam::{
+        automation::{Breakpoint, Shape, Target},
+        scale::Pitch,
+        tempo::TempoMap,
+        wav::SliceSink,
+    };
+
+    use super::*;
+    use crate::Length;
+
+    fn render_all<const DEPTH: usize, T: Timebase>(renderer: &mut Renderer<'_, DEPTH, T>) -> Vec<i16> {
+        let empty = Sample { word: 0 };
+        let mut out = vec![];
+        let mut block = [StereoSample { left: empty, right: empty }; 64];
+        while !renderer.is_done() {
+            renderer.render(&mut block);
+            out.extend(block.iter().map(|s| s.left.to_i16()));
+        }
+        out
+    }
+
+    // Count the times the wave crosses from negative to positive
+    fn cycles(samples: &[i16]) -> usize {
+        samples.windows(2).filter(|w| w[0] < 0 && w[1] >= 0).count()
+    }
+
+    #[test]
+    fn one_voice() {
+        // A quarter note at 60bpm lasts a second
+        let mut bar = BarBuf::new();
+        bar.push_rest_simple(Length::Quarter).unwrap();
+        bar.push_note_simple(Length::Quarter, Pitch::A, 4).unwrap();
+        let bars = [bar];
+
+        let mut renderer: Renderer = Renderer::new(8000, 60).unwrap();
+        renderer.add_voice(RenderVoice::new(&bars, ToneKind::Sine));
+        let out = render_all(&mut renderer);
+
+        // Silent for the rest, then about 440 cycles
+        assert!((16000..16200).contains(&out.len()));
+        assert!(out[..7900].iter().all(|s| *s == 0));
+        let n = cycles(&out);
+        assert!((435..=445).contains(&n), "{n}");
+    }
+
+    #[test]
+    fn offsets_and_many_notes() {
+        // More notes than the track can queue at once
+        let mut bar = BarBuf::new();
+        for _ in 0..32 {
+            bar.push_note_tone(Length::Eighth, 57, 0).unwrap();
+        }
+        let mut bent = BarBuf::new();
+        for _ in 0..32 {
+            bent.push_note_tone(Length::Eighth, 57, 128).unwrap();
+        }
+        let plain = [bar];
+        let bent = [bent];
+
+        let mut a: Renderer<'_, 4> = Renderer::new(8000, 60).unwrap();
+        a.add_voice(RenderVoice::new(&plain, ToneKind::Sine));
+        let a = render_all(&mut a);
+        let mut b: Renderer<'_, 4> = Renderer::new(8000, 60).unwrap();
+        b.add_voice(RenderVoice::new(&bent, ToneKind::Sine));
+        let b = render_all(&mut b);
+
+        // 16 seconds of notes, a quarter of a tone apart
+        assert_eq!(a.len(), b.len());
+        assert!(a.len() >= 16 * 8000);
+        let (a, b) = (cycles(&a), cycles(&b));
+        assert!(b > a + (a / 40), "{a} {b}");
+        assert!(b < a + (a / 20), "{a} {b}");
+    }
+
+    #[test]
+    fn bars_in_sequence_to_wav() {
+        let first = BarBuf::from_notes_simple(&[(Length::Eighth, Pitch::C, 4)]).unwrap();
+        let second = BarBuf::from_notes_simple(&[(Length::Eighth, Pitch::E, 4)]).unwrap();
+        let bars = [first, second];
+        let bass = [BarBuf::from_notes_simple(&[(Length::Quarter, Pitch::C, 2)]).unwrap()];
+
+        let mut renderer: Renderer = Renderer::new(8000, 120).unwrap();
+        renderer.add_voice(RenderVoice::new(&bars, ToneKind::Square));
+        renderer.add_voice(RenderVoice::new(&bass, ToneKind::Saw));
+
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut wav = WavWriter::new(SliceSink::new(&mut buf), 8000, 2).unwrap();
+        renderer.write_wav(&mut wav).unwrap();
+        assert!(renderer.is_done());
+
+        // Two eighth notes at 120bpm is 4000 samples, plus the last block
+        let frames = wav.frames();
+        assert!((4000..=4000 + BLOCK_LEN as u32 * 2).contains(&frames), "{frames}");
+        let sink = wav.finish().unwrap();
+        assert_eq!(sink.written().len(), 44 + frames as usize * 4);
+    }
+
+    #[test]
+    fn chords() {
+        // A fifth, then the root on its own, each a second long
+        let mut bar = BarBuf::new();
+        bar.push_note_simple(Length::Quarter, Pitch::A, 4).unwrap();
+        bar.push_note_at(0, Length::Quarter, Pitch::E, 5).unwrap();
+        bar.push_note_simple(Length::Quarter, Pitch::A, 4).unwrap();
+        let bars = [bar];
+
+        let mut renderer: Renderer = Renderer::new(8000, 60).unwrap();
+        renderer.add_voice(RenderVoice::new(&bars, ToneKind::Sine));
+        assert_eq!(renderer.voices[0].parts.len(), 2);
+        let out = render_all(&mut renderer);
+        assert!((16000..16200).contains(&out.len()));
+
+        // Both notes of the chord are heard, so it's different from, and
+        // louder than, the note on its own
+        let peak = |s: &[i16]| s.iter().map(|s| s.unsigned_abs()).max().unwrap();
+        let (chord, root) = (&out[..8000], &out[8000..16000]);
+        assert!(peak(chord) > peak(root) + peak(root) / 4, "{} {}", peak(chord), peak(root));
+        assert_ne!(cycles(chord), cycles(root));
+    }
+
+    #[test]
+    fn tempo_changes() {
+        // Two quarter notes, the second after the tempo halves
+        let bars = [BarBuf::from_notes_simple(&[(Length::Quarter, Pitch::A, 4); 2]).unwrap()];
+        let mut map: TempoMap<2> = TempoMap::new(8000, 120).unwrap();
+        map.set_tempo(u32::from(crate::PPQN), 60).unwrap();
+
+        let mut renderer: Renderer<'_, 8, _> = Renderer::with_timebase(8000, map).unwrap();
+        renderer.add_voice(RenderVoice::new(&bars, ToneKind::Sine));
+        let out = render_all(&mut renderer);
+
+        // Half a second, then a second
+        assert!((12000..12100).contains(&out.len()), "{}", out.len());
+    }
+
+    #[test]
+    fn gain_ramp() {
+        // Fade a one second note out over its first half, then hold it silent
+        let bars = [BarBuf::from_notes_simple(&[(Length::Whole, Pitch::A, 4)]).unwrap()];
+        let mut auto = RenderAutomation::new();
+        let gain = auto.lane_mut(Target::Gain).unwrap();
+        gain.push(Breakpoint { sample: 0, value: 1.0, shape: Shape::Linear }).unwrap();
+        gain.push(Breakpoint { sample: 4000, value: 0.0, shape: Shape::Linear }).unwrap();
+
+        let mut renderer: Renderer = Renderer::new(8000, 240).unwrap();
+        let mut voice = RenderVoice::new(&bars, ToneKind::Sine);
+        voice.automation = Some(&auto);
+        renderer.add_voice(voice);
+        let out = render_all(&mut renderer);
+
+        let peak = |s: &[i16]| s.iter().map(|s| s.unsigned_abs()).max().unwrap();
+        let start = peak(&out[..1000]);
+        let late = peak(&out[3000..3500]);
+        assert!(start > 1000, "{start}");
+        assert!(late < start / 3, "{start} {late}");
+        // From the first block after the end of the ramp
+        assert_eq!(peak(&out[4096..8000]), 0);
+    }
+}
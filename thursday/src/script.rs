@@ -0,0 +1,622 @@
+// A small text language for describing tracks.
+//
+// ```text
+// # Comments run to the end of the line
+// tempo 150
+//
+// # A voice has a name, a waveform (sine, square or saw), and optionally an
+// # amplitude LFO (`amp`, with a rate in Hz), LFO `depth` (0-255), and
+// # MIDI `program`. Frequency LFOs (`freq`) can't be played yet, so they
+// # are an error.
+// voice lead square amp 4.5 depth 96 program 1
+// voice bass sine
+//
+// # Variables hold a block of notes, to be pasted in with `$name`
+// let lamb = { e4 d c d | e e e r }
+//
+// lead {
+//     $lamb
+//     /8 repeat 2 { d4 d } r/4      # `/8` sets the length of the notes after it
+//     e4/2 g4/2
+// }
+// bass { /1 r c3 }
+// ```
+//
+// Notes are a pitch (`a`-`g`), an optional sharp (`#`) or flat (`b`), and an
+// octave. If the octave is left off, the last one used in the block is
+// kept (starting from 4, or as set with `octave 3`). `r` is a rest. Lengths are `/1`, `/2`, `/4`, `/8`, `/16`, `/32` or
+// `/64`, with a `t` for triplets (`/8t`) or a `.` for dotted (`/4.`). A
+// length on its own sets the length for the rest of the block (starting
+// from quarter notes), and a length stuck on to a note (`c4/8`) is just for
+// that note. `|` can be used to mark bars, but is ignored.
+//
+// Every block added to a voice is appended to its `BarBuf`. A script can
+// expand to at most `MAX_ITEMS` items, counting each time through a repeat
+// and every item pasted in from a variable.
+
+use alloc::{string::String, vec::Vec};
+
+use minijam::{
+    clock::Timebase,
+    scale::Pitch,
+    tones::{Operator, OperatorKind, Tone, ToneKind},
+    NoteError, Track,
+};
+
+use crate::{
+    bars::{BarBuf, BarError},
+    Length,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Modulation {
+    None,
+    /// Amplitude LFO, at the given rate in Hz
+    Amplitude(f32),
+    /// Frequency LFO, at the given rate in Hz. minijam can't play these
+    /// yet, so scripts can't ask for one.
+    Frequency(f32),
+}
+
+#[derive(Clone)]
+pub struct Voice {
+    pub name: String,
+    pub kind: ToneKind,
+    pub modulation: Modulation,
+    pub depth: u8,
+    pub program: Option<u8>,
+    pub bar: BarBuf,
+}
+
+#[derive(Clone)]
+pub struct Script {
+    pub tempo: u32,
+    pub voices: Vec<Voice>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ScriptErrorKind {
+    /// Found something other than what was expected
+    UnexpectedToken,
+    /// The script ended in the middle of a statement
+    UnexpectedEnd,
+    UnknownVoice,
+    DuplicateVoice,
+    UnknownVariable,
+    BadNumber,
+    BadNote,
+    BadLength,
+    /// Something that can't be played yet, like a `freq` LFO
+    Unsupported,
+    /// The script expands to more than `MAX_ITEMS` items, once its repeats
+    /// are played out and its variables pasted in
+    TooLong,
+    Bar(BarError),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ScriptError {
+    /// The line the error was found on, starting at 1
+    pub line: usize,
+    pub kind: ScriptErrorKind,
+}
+
+/// The most items a script can expand to, counting each time through a
+/// repeat and each item pasted in from a variable
+pub const MAX_ITEMS: u32 = 1 << 20;
+
+#[derive(Debug, Clone)]
+enum Item {
+    Length(Length),
+    Octave(u8),
+    // Semitones above C, which may be -1 or 12 for `cb` or `b#`
+    Note(Option<Length>, i16, Option<u8>),
+    Rest(Option<Length>),
+    Repeat(u16, Vec<Item>),
+}
+
+// The number of items in a block, including those inside its repeats
+fn item_count(items: &[Item]) -> u32 {
+    items.iter().fold(0u32, |count, item| {
+        let inner = match item {
+            Item::Repeat(_, body) => item_count(body),
+            _ => 0,
+        };
+        count.saturating_add(1).saturating_add(inner)
+    })
+}
+
+struct Token<'a> {
+    line: usize,
+    text: &'a str,
+}
+
+fn tokenize(src: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    for (idx, line) in src.lines().enumerate() {
+        let line_no = idx + 1;
+        // `#` also means sharp, so is only a comment at the start of a word
+        let line = strip_comment(line);
+
+        let mut start = None;
+        for (i, ch) in line.char_indices() {
+            let single = matches!(ch, '{' | '}' | '=' | '|');
+            if ch.is_whitespace() || single {
+                if let Some(s) = start.take() {
+                    tokens.push(Token { line: line_no, text: &line[s..i] });
+                }
+                if single {
+                    tokens.push(Token { line: line_no, text: &line[i..(i + 1)] });
+                }
+            } else if start.is_none() {
+                start = Some(i);
+            }
+        }
+        if let Some(s) = start {
+            tokens.push(Token { line: line_no, text: &line[s..] });
+        }
+    }
+    tokens
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut prev_ws = true;
+    for (i, ch) in line.char_indices() {
+        if ch == '#' && prev_ws {
+            return &line[..i];
+        }
+        prev_ws = ch.is_whitespace() || matches!(ch, '{' | '}' | '=' | '|');
+    }
+    line
+}
+
+fn parse_length(text: &str) -> Option<Length> {
+    let (base, suffix) = match text.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => text.split_at(i),
+        None => (text, ""),
+    };
+    let triplet = suffix == "t";
+    let len = match (base, triplet) {
+        ("1", false) => Length::Whole,
+        ("2", false) => Length::Half,
+        ("4", false) => Length::Quarter,
+        ("8", false) => Length::Eighth,
+        ("16", false) => Length::Sixteenth,
+        ("32", false) => Length::ThirtySecond,
+        ("64", false) => Length::SixtyFourth,
+        ("2", true) => Length::TripletHalf,
+        ("4", true) => Length::TripletQuarter,
+        ("8", true) => Length::TripletEighth,
+        ("16", true) => Length::TripletSixteenth,
+        ("32", true) => Length::TripletThirtySeconds,
+        _ => return None,
+    };
+    match suffix {
+        "" | "t" => Some(len),
+        "." => Some(Length::PPQNCount(len.to_ppqn() + (len.to_ppqn() / 2))),
+        _ => None,
+    }
+}
+
+// Parse a note or rest, like `c#4`, `eb`, `a3/8` or `r/2`
+fn parse_note(text: &str) -> Option<Item> {
+    let (note, len) = match text.split_once('/') {
+        Some((note, len)) => (note, Some(parse_length(len)?)),
+        None => (text, None),
+    };
+    if note == "r" {
+        return Some(Item::Rest(len));
+    }
+
+    let mut chars = note.chars();
+    let base: i16 = match chars.next()? {
+        'c' => 0,
+        'd' => 2,
+        'e' => 4,
+        'f' => 5,
+        'g' => 7,
+        'a' => 9,
+        'b' => 11,
+        _ => return None,
+    };
+    let rest = chars.as_str();
+    let (acc, octave) = if let Some(oct) = rest.strip_prefix('#') {
+        (1, oct)
+    } else if let Some(oct) = rest.strip_prefix('b') {
+        (-1, oct)
+    } else {
+        (0, rest)
+    };
+
+    let octave = if octave.is_empty() {
+        None
+    } else {
+        Some(octave.parse::<u8>().ok()?)
+    };
+    Some(Item::Note(len, base + acc, octave))
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token<'a>>,
+    pos: usize,
+    vars: Vec<(&'a str, Vec<Item>)>,
+    // Items left before the script is too long
+    budget: u32,
+    script: Script,
+}
+
+impl<'a> Parser<'a> {
+    fn err(&self, kind: ScriptErrorKind) -> ScriptError {
+        let line = self
+            .tokens
+            .get(self.pos.saturating_sub(1))
+            .map(|t| t.line)
+            .unwrap_or(0);
+        ScriptError { line, kind }
+    }
+
+    fn next(&mut self) -> Result<&'a str, ScriptError> {
+        let tok = self
+            .tokens
+            .get(self.pos)
+            .map(|t| t.text)
+            .ok_or_else(|| self.err(ScriptErrorKind::UnexpectedEnd))?;
+        self.pos += 1;
+        Ok(tok)
+    }
+
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.pos).map(|t| t.text)
+    }
+
+    fn expect(&mut self, text: &str) -> Result<(), ScriptError> {
+        if self.next()? == text {
+            Ok(())
+        } else {
+            Err(self.err(ScriptErrorKind::UnexpectedToken))
+        }
+    }
+
+    fn charge(&mut self, items: u32) -> Result<(), ScriptError> {
+        self.budget = self
+            .budget
+            .checked_sub(items)
+            .ok_or_else(|| self.err(ScriptErrorKind::TooLong))?;
+        Ok(())
+    }
+
+    fn number<T: core::str::FromStr>(&mut self) -> Result<T, ScriptError> {
+        self.next()?
+            .parse()
+            .map_err(|_| self.err(ScriptErrorKind::BadNumber))
+    }
+
+    fn statement(&mut self) -> Result<(), ScriptError> {
+        match self.next()? {
+            "tempo" => {
+                self.script.tempo = self.number()?;
+                if self.script.tempo == 0 {
+                    return Err(self.err(ScriptErrorKind::BadNumber));
+                }
+            }
+            "voice" => self.voice()?,
+            "let" => {
+                let name = self.next()?;
+                self.expect("=")?;
+                let items = self.block()?;
+                self.vars.retain(|(n, _)| *n != name);
+                self.vars.push((name, items));
+            }
+            name => {
+                let items = self.block()?;
+                let idx = self
+                    .script
+                    .voices
+                    .iter()
+                    .position(|v| v.name == name)
+                    .ok_or_else(|| self.err(ScriptErrorKind::UnknownVoice))?;
+                let mut state = BlockState::default();
+                state
+                    .emit(&items, &mut self.script.voices[idx].bar, &mut self.budget)
+                    .map_err(|kind| self.err(kind))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn voice(&mut self) -> Result<(), ScriptError> {
+        let name = self.next()?;
+        if self.script.voices.iter().any(|v| v.name == name) {
+            return Err(self.err(ScriptErrorKind::DuplicateVoice));
+        }
+        let kind = match self.next()? {
+            "sine" => ToneKind::Sine,
+            "square" => ToneKind::Square,
+            "saw" => ToneKind::Saw,
+            _ => return Err(self.err(ScriptErrorKind::UnexpectedToken)),
+        };
+        let mut voice = Voice {
+            name: name.into(),
+            kind,
+            modulation: Modulation::None,
+            depth: Operator::DEFAULT_DEPTH,
+            program: None,
+            bar: BarBuf::new(),
+        };
+
+        loop {
+            match self.peek() {
+                Some("amp") => {
+                    self.pos += 1;
+                    voice.modulation = Modulation::Amplitude(self.number()?);
+                }
+                Some("freq") => return Err(self.err(ScriptErrorKind::Unsupported)),
+                Some("depth") => {
+                    self.pos += 1;
+                    voice.depth = self.number()?;
+                }
+                Some("program") => {
+                    self.pos += 1;
+                    voice.program = Some(self.number()?);
+                }
+                _ => break,
+            }
+        }
+
+        self.script.voices.push(voice);
+        Ok(())
+    }
+
+    fn block(&mut self) -> Result<Vec<Item>, ScriptError> {
+        self.expect("{")?;
+        let mut items = Vec::new();
+        loop {
+            let tok = self.next()?;
+            match tok {
+                "}" => return Ok(items),
+                "|" => {}
+                "repeat" => {
+                    let count = self.number()?;
+                    items.push(Item::Repeat(count, self.block()?));
+                }
+                "octave" => items.push(Item::Octave(self.number()?)),
+                _ => {
+                    if let Some(len) = tok.strip_prefix('/') {
+                        let len = parse_length(len).ok_or_else(|| self.err(ScriptErrorKind::BadLength))?;
+                        items.push(Item::Length(len));
+                    } else if let Some(var) = tok.strip_prefix('$') {
+                        let idx = self
+                            .vars
+                            .iter()
+                            .position(|(n, _)| *n == var)
+                            .ok_or_else(|| self.err(ScriptErrorKind::UnknownVariable))?;
+                        self.charge(item_count(&self.vars[idx].1))?;
+                        items.extend(self.vars[idx].1.iter().cloned());
+                    } else {
+                        let item = parse_note(tok).ok_or_else(|| self.err(ScriptErrorKind::BadNote))?;
+                        items.push(item);
+                    }
+                }
+            }
+        }
+    }
+}
+
+struct BlockState {
+    length: Length,
+    octave: u8,
+}
+
+impl Default for BlockState {
+    fn default() -> Self {
+        Self {
+            length: Length::Quarter,
+            octave: 4,
+        }
+    }
+}
+
+impl BlockState {
+    // Push the items on to `bar`, taking one from `budget` for each
+    fn emit(&mut self, items: &[Item], bar: &mut BarBuf, budget: &mut u32) -> Result<(), ScriptErrorKind> {
+        for item in items {
+            *budget = budget.checked_sub(1).ok_or(ScriptErrorKind::TooLong)?;
+            match item {
+                Item::Length(len) => self.length = *len,
+                Item::Octave(oct) => self.octave = *oct,
+                Item::Note(len, semi, octave) => {
+                    if let Some(oct) = octave {
+                        self.octave = *oct;
+                    }
+                    let abs = (self.octave as i16 * 12) + semi;
+                    let pitch = Pitch::from(abs.rem_euclid(12) as u8);
+                    let octave = abs.div_euclid(12).clamp(0, u8::MAX.into()) as u8;
+                    bar.push_note_simple(len.unwrap_or(self.length), pitch, octave)
+                        .map_err(ScriptErrorKind::Bar)?;
+                }
+                Item::Rest(len) => bar
+                    .push_rest_simple(len.unwrap_or(self.length))
+                    .map_err(ScriptErrorKind::Bar)?,
+                Item::Repeat(count, body) => {
+                    for _ in 0..*count {
+                        self.emit(body, bar, budget)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Script {
+    pub fn parse(src: &str) -> Result<Self, ScriptError> {
+        let mut parser = Parser {
+            tokens: tokenize(src),
+            pos: 0,
+            vars: Vec::new(),
+            budget: MAX_ITEMS,
+            script: Script {
+                tempo: 120,
+                voices: Vec::new(),
+            },
+        };
+        while parser.peek().is_some() {
+            parser.statement()?;
+        }
+        Ok(parser.script)
+    }
+
+    pub fn voice(&self, name: &str) -> Option<&Voice> {
+        self.voices.iter().find(|v| v.name == name)
+    }
+}
+
+impl Voice {
+    /// Make an operator for this voice's modulation
+    pub fn operator(&self, sample_rate: u32) -> Operator {
+        let kind = match self.modulation {
+            Modulation::None => OperatorKind::None,
+            Modulation::Amplitude(rate) => OperatorKind::AmplitudeLfo(Tone::new_sine(rate, sample_rate)),
+            Modulation::Frequency(rate) => OperatorKind::FrequencyLfo(Tone::new_sine(rate, sample_rate)),
+        };
+        let mut op = Operator::new(kind);
+        op.depth = self.depth;
+        op
+    }
+
+    /// Queue this voice's notes on a track, starting at `start_tick`.
+    ///
+    /// Returns an error if the track could not take every note.
+    pub fn queue_on<const DEPTH: usize, T: Timebase>(
+        &self,
+        track: &mut Track<DEPTH>,
+        clock: &T,
+        start_tick: u32,
+    ) -> Result<(), NoteError> {
+        self.bar.queue_on(track, clock, self.kind, start_tick)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use minijam::clock::Clock;
+
+    use super::*;
+    use crate::{PlayNote, PPQN_EIGHTH, PPQN_QUARTER};
+
+    fn notes(voice: &Voice) -> Vec<(u16, u16, u8)> {
+        voice
+            .bar
+            .notes()
+            .map(|n| {
+                let p = PlayNote::from_enc(&n, 0);
+                (p.start, p.length, p.tone)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn voices_and_notes() {
+        let script = Script::parse(
+            "
+            tempo 90 # slow
+            voice lead saw amp 4.5 depth 200 program 33
+            lead { c4 d#/8 eb /8 r c#5 | cb4 }
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(script.tempo, 90);
+        let lead = script.voice("lead").unwrap();
+        assert_eq!(lead.kind, ToneKind::Saw);
+        assert_eq!(lead.modulation, Modulation::Amplitude(4.5));
+        assert_eq!(lead.program, Some(33));
+        assert_eq!(lead.operator(44100).depth, 200);
+
+        let q = PPQN_QUARTER;
+        let e = PPQN_EIGHTH;
+        assert_eq!(
+            notes(lead),
+            vec![
+                (0, q, 48),
+                (q, e, 51),
+                (q + e, q, 51),
+                // rest
+                (q + e + q + e, e, 61),
+                // `cb4` is really `b3`
+                (q + e + q + e + e, e, 47),
+            ]
+        );
+    }
+
+    #[test]
+    fn variables_and_repeats() {
+        let script = Script::parse(
+            "
+            voice v sine
+            let riff = { /8 c e g }
+            let twice = { repeat 2 { $riff } }
+            v { octave 3 $twice c5/4. }
+            v { r/2t }
+            ",
+        )
+        .unwrap();
+
+        let v = script.voice("v").unwrap();
+        let found = notes(v);
+        assert_eq!(found.len(), 7);
+        assert_eq!(found[3], (3 * PPQN_EIGHTH, PPQN_EIGHTH, 36));
+        assert_eq!(found[6], (6 * PPQN_EIGHTH, PPQN_QUARTER + PPQN_EIGHTH, 60));
+    }
+
+    #[test]
+    fn errors() {
+        let err = |src| Script::parse(src).err().unwrap();
+        assert_eq!(err("voice a sine\nb { c4 }").kind, ScriptErrorKind::UnknownVoice);
+        assert_eq!(err("voice a sine\n\na { h4 }"), ScriptError { line: 3, kind: ScriptErrorKind::BadNote });
+        assert_eq!(err("voice a sine a { $x }").kind, ScriptErrorKind::UnknownVariable);
+        assert_eq!(err("voice a sine a { c4/3 }").kind, ScriptErrorKind::BadNote);
+        assert_eq!(err("voice a sine a { /3 }").kind, ScriptErrorKind::BadLength);
+        assert_eq!(err("voice a sine a { c4").kind, ScriptErrorKind::UnexpectedEnd);
+        assert_eq!(err("voice a sine voice a saw").kind, ScriptErrorKind::DuplicateVoice);
+        assert_eq!(err("tempo fast").kind, ScriptErrorKind::BadNumber);
+        assert_eq!(err("voice a sine freq 4.5").kind, ScriptErrorKind::Unsupported);
+        assert_eq!(
+            err("voice a sine a { repeat 100 { /1 c4 } }").kind,
+            ScriptErrorKind::Bar(BarError::BarFull)
+        );
+    }
+
+    #[test]
+    fn runaway_loops() {
+        // Lengths never fill the bar, so only the budget stops this
+        let err = Script::parse("voice v sine\nv { repeat 65535 { repeat 65535 { /8 } } }").err();
+        assert_eq!(err, Some(ScriptError { line: 2, kind: ScriptErrorKind::TooLong }));
+
+        // Each variable holds twice the last, and is never played
+        let mut src = String::from("let v0 = { c c }\n");
+        for i in 1..32 {
+            src += &format!("let v{i} = {{ $v{} $v{} }}\n", i - 1, i - 1);
+        }
+        assert_eq!(Script::parse(&src).err().map(|e| e.kind), Some(ScriptErrorKind::TooLong));
+
+        // Cloning a variable counts what's inside its repeats too
+        let src = "let a = { repeat 2 { /8 /8 /8 /8 /8 /8 /8 /8 } }\n";
+        let src = src.to_string() + &"let a = { $a $a }\n".repeat(32);
+        assert_eq!(Script::parse(&src).err().map(|e| e.kind), Some(ScriptErrorKind::TooLong));
+    }
+
+    #[test]
+    fn into_track() {
+        let script = Script::parse("voice v square\nv { c4 d e f }").unwrap();
+        let clock = Clock::new(48000, 120).unwrap();
+        let mut track: Track<8> = Track::new(48000);
+        script.voice("v").unwrap().queue_on(&mut track, &clock, 0).unwrap();
+        assert_eq!(track.note_q.len(), 4);
+        assert_eq!(track.note_q.back().unwrap().samp_start, 72000);
+
+        // Too many for a small track
+        let mut track: Track<2> = Track::new(48000);
+        assert!(script.voice("v").unwrap().queue_on(&mut track, &clock, 0).is_err());
+    }
+}
@@ -0,0 +1,395 @@
+// Songs: chaining `BarBuf` segments past the `PPQN_MAX` limit.
+//
+// A single `BarBuf` holds at most 64 quarter notes. A `Song` holds any
+// number of them as segments, and a form: a list of steps that says which
+// segment plays when. Steps can repeat a block, jump (for a D.C., a coda or
+// first and second endings) or loop forever.
+//
+// Every control step counts how many times playback has reached it:
+//
+// * `Repeat { to, times }` goes back to step `to` the first `times` times
+//   it is reached. After that it falls through, and starts counting again,
+//   so a repeat inside another repeat plays in full every time.
+// * `Jump { to, on_pass }` goes to step `to` only the `on_pass`th time it
+//   is reached, and falls through otherwise.
+// * `Loop { to }` always goes back to step `to`, and resets the count of
+//   every step from there on, so each time around plays the same way.
+//
+// If a `Loop` goes around without playing anything (say, because a `Jump`
+// inside it always skips the only `Play`), the song ends there rather than
+// spinning forever.
+//
+// A jump to `form.len()` ends the song, like a Fine.
+
+use alloc::{vec, vec::Vec};
+
+use crate::{
+    bars::{BarBuf, Notes, BAR_BYTES},
+    EncNote,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// Play a segment, by its index
+    Play(usize),
+    /// Go back to an earlier step `times` times, then carry on
+    Repeat { to: usize, times: u16 },
+    /// Go to any step, the `on_pass`th time this one is reached
+    Jump { to: usize, on_pass: u16 },
+    /// Go back to an earlier step, forever
+    Loop { to: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SongErrorKind {
+    NoSuchSegment,
+    /// A target past the end of the form
+    NoSuchStep,
+    /// A `Repeat` or `Loop` that goes forwards
+    NotBackwards,
+    /// A `times` or `on_pass` of zero
+    ZeroCount,
+    /// A `Loop` with nothing (of any length) to play inside it, which
+    /// would never give another note
+    EmptyLoop,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SongError {
+    /// The step in the form, starting at 0
+    pub step: usize,
+    pub kind: SongErrorKind,
+}
+
+pub struct Song<const N: usize = BAR_BYTES> {
+    segments: Vec<BarBuf<N>>,
+    form: Vec<Step>,
+}
+
+impl<const N: usize> Song<N> {
+    pub fn new(segments: Vec<BarBuf<N>>, form: Vec<Step>) -> Result<Self, SongError> {
+        for (i, step) in form.iter().enumerate() {
+            let err = |kind| Err(SongError { step: i, kind });
+            match *step {
+                Step::Play(seg) if seg >= segments.len() => return err(SongErrorKind::NoSuchSegment),
+                Step::Play(_) => {}
+                Step::Repeat { to, .. } | Step::Loop { to } if to > i => {
+                    return err(SongErrorKind::NotBackwards)
+                }
+                Step::Jump { to, .. } if to > form.len() => return err(SongErrorKind::NoSuchStep),
+                Step::Repeat { times: 0, .. } | Step::Jump { on_pass: 0, .. } => {
+                    return err(SongErrorKind::ZeroCount)
+                }
+                Step::Loop { to } => {
+                    let plays = form[to..i].iter().any(|s| match *s {
+                        Step::Play(seg) => segments[seg].ppqn_len() != 0,
+                        _ => false,
+                    });
+                    if !plays {
+                        return err(SongErrorKind::EmptyLoop);
+                    }
+                }
+                Step::Repeat { .. } | Step::Jump { .. } => {}
+            }
+        }
+        Ok(Self { segments, form })
+    }
+
+    /// A song that plays each segment once, in order
+    pub fn from_segments(segments: Vec<BarBuf<N>>) -> Self {
+        let form = (0..segments.len()).map(Step::Play).collect();
+        Self { segments, form }
+    }
+
+    pub fn segments(&self) -> &[BarBuf<N>] {
+        &self.segments
+    }
+
+    pub fn form(&self) -> &[Step] {
+        &self.form
+    }
+
+    /// The segments in the order they are played, with the tick each one
+    /// starts at. Each segment lasts for its `ppqn_len`, including any
+    /// trailing rest.
+    ///
+    /// If the form has a `Loop`, this only ends when it runs out of ticks
+    /// at `u32::MAX`, or when the loop goes around without playing anything.
+    pub fn arrangement(&self) -> Arrangement<'_, N> {
+        Arrangement {
+            song: self,
+            step: 0,
+            passes: vec![0; self.form.len()],
+            tick: 0,
+            played: true,
+        }
+    }
+
+    /// Every note of the song, in order, with the tick it starts at
+    pub fn notes(&self) -> SongNotes<'_, N> {
+        SongNotes {
+            arrangement: self.arrangement(),
+            current: None,
+        }
+    }
+
+    /// The length of the whole song in ticks, or `None` if it loops forever
+    /// (or is longer than `u32::MAX` ticks)
+    pub fn ppqn_len(&self) -> Option<u32> {
+        // Playback only depends on the step and the pass counts, so if they
+        // ever come back around, so will everything after. Check for that
+        // with Brent's cycle detection, rather than playing out a loop all
+        // the way to `u32::MAX` ticks.
+        let mut arr = self.arrangement();
+        let mut seen = (arr.step, arr.passes.clone(), arr.played);
+        let (mut limit, mut count) = (1, 0);
+        while arr.next().is_some() {
+            if (arr.step, &arr.passes, arr.played) == (seen.0, &seen.1, seen.2) {
+                return None;
+            }
+            count += 1;
+            if count == limit {
+                seen = (arr.step, arr.passes.clone(), arr.played);
+                (limit, count) = (limit * 2, 0);
+            }
+        }
+        (arr.step >= self.form.len()).then_some(arr.tick)
+    }
+}
+
+pub struct Arrangement<'a, const N: usize = BAR_BYTES> {
+    song: &'a Song<N>,
+    step: usize,
+    // How many times each step has been reached
+    passes: Vec<u16>,
+    tick: u32,
+    // Whether anything (of any length) has played since the last `Loop`
+    played: bool,
+}
+
+impl<'a, const N: usize> Iterator for Arrangement<'a, N> {
+    type Item = (u32, &'a BarBuf<N>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let step = *self.song.form.get(self.step)?;
+            let pass = &mut self.passes[self.step];
+            *pass = pass.saturating_add(1);
+            let pass = *pass;
+
+            match step {
+                Step::Play(seg) => {
+                    let bar = &self.song.segments[seg];
+                    let start = self.tick;
+                    self.tick = start.checked_add(bar.ppqn_len().into())?;
+                    self.played |= bar.ppqn_len() != 0;
+                    self.step += 1;
+                    return Some((start, bar));
+                }
+                Step::Repeat { to, times } if pass <= times => self.step = to,
+                Step::Repeat { .. } => {
+                    self.passes[self.step] = 0;
+                    self.step += 1;
+                }
+                Step::Jump { to, on_pass } if pass == on_pass => self.step = to,
+                Step::Jump { .. } => self.step += 1,
+                Step::Loop { to } => {
+                    if !core::mem::take(&mut self.played) {
+                        // Going around again would give nothing either
+                        self.step = self.song.form.len();
+                        return None;
+                    }
+                    self.passes[to..].iter_mut().for_each(|p| *p = 0);
+                    self.step = to;
+                }
+            }
+        }
+    }
+}
+
+pub struct SongNotes<'a, const N: usize = BAR_BYTES> {
+    arrangement: Arrangement<'a, N>,
+    // The segment being played, and the tick it started at
+    current: Option<(u32, Notes<'a>)>,
+}
+
+impl<'a, const N: usize> Iterator for SongNotes<'a, N> {
+    type Item = (u32, EncNote);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((start, notes)) = self.current.as_mut() {
+                if let Some(note) = notes.next() {
+                    return Some((*start + u32::from(note.ppqn_start()), note));
+                }
+            }
+            let (start, bar) = self.arrangement.next()?;
+            self.current = Some((start, bar.notes()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use minijam::scale::Pitch;
+
+    use super::*;
+    use crate::{Length, PPQN_MAX, PPQN_QUARTER};
+
+    // One quarter note of each pitch
+    fn segment(pitches: &[Pitch]) -> BarBuf {
+        let notes: Vec<_> = pitches.iter().map(|p| (Length::Quarter, *p, 4)).collect();
+        BarBuf::from_notes_simple(&notes).unwrap()
+    }
+
+    fn segments() -> Vec<BarBuf> {
+        vec![
+            segment(&[Pitch::C]),
+            segment(&[Pitch::D]),
+            segment(&[Pitch::E]),
+            segment(&[Pitch::F]),
+        ]
+    }
+
+    // The pitches of the first `n` notes, as indexes into `segments()`
+    fn played(song: &Song, n: usize) -> Vec<usize> {
+        let first: Vec<u8> = segments().iter().map(|b| b.notes().next().unwrap().pitch_tone_offset().0).collect();
+        song.notes()
+            .take(n)
+            .map(|(_, n)| first.iter().position(|t| *t == n.pitch_tone_offset().0).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn continuous_ticks() {
+        let long = BarBuf::from_notes_simple(&[(Length::Whole, Pitch::C, 4); 16]).unwrap();
+        assert_eq!(long.ppqn_len(), PPQN_MAX);
+        let song = Song::from_segments(vec![long.clone(), long.clone(), segment(&[Pitch::D])]);
+
+        let ticks: Vec<u32> = song.notes().map(|(t, _)| t).collect();
+        assert_eq!(ticks.len(), 33);
+        ticks.windows(2).for_each(|w| assert_eq!(w[1] - w[0], u32::from(PPQN_QUARTER * 4)));
+        assert_eq!(ticks[32], 2 * u32::from(PPQN_MAX));
+        assert_eq!(song.ppqn_len(), Some(2 * u32::from(PPQN_MAX) + u32::from(PPQN_QUARTER)));
+    }
+
+    #[test]
+    fn repeats() {
+        // |: A |: B :| C :| D
+        let form = vec![
+            Step::Play(0),
+            Step::Play(1),
+            Step::Repeat { to: 1, times: 2 },
+            Step::Play(2),
+            Step::Repeat { to: 0, times: 1 },
+            Step::Play(3),
+        ];
+        let song = Song::new(segments(), form).unwrap();
+        assert_eq!(played(&song, 100), [0, 1, 1, 1, 2, 0, 1, 1, 1, 2, 3]);
+        assert_eq!(song.ppqn_len(), Some(11 * u32::from(PPQN_QUARTER)));
+    }
+
+    #[test]
+    fn jumps() {
+        // A, B (to coda), C, D.C. al coda, coda: D
+        let form = vec![
+            Step::Play(0),
+            Step::Play(1),
+            Step::Jump { to: 5, on_pass: 2 },
+            Step::Play(2),
+            Step::Jump { to: 0, on_pass: 1 },
+            Step::Play(3),
+        ];
+        let song = Song::new(segments(), form).unwrap();
+        assert_eq!(played(&song, 100), [0, 1, 2, 0, 1, 3]);
+
+        // |: A [1. B :| [2. C, then Fine
+        let form = vec![
+            Step::Play(0),
+            Step::Jump { to: 4, on_pass: 2 },
+            Step::Play(1),
+            Step::Repeat { to: 0, times: 1 },
+            Step::Play(2),
+            Step::Jump { to: 6, on_pass: 1 },
+        ];
+        let song = Song::new(segments(), form).unwrap();
+        assert_eq!(played(&song, 100), [0, 1, 0, 2]);
+    }
+
+    #[test]
+    fn loops() {
+        // A, then loop B [1. C [2. D forever
+        let form = vec![
+            Step::Play(0),
+            Step::Play(1),
+            Step::Jump { to: 5, on_pass: 2 },
+            Step::Play(2),
+            Step::Repeat { to: 1, times: 1 },
+            Step::Play(3),
+            Step::Loop { to: 1 },
+        ];
+        let song = Song::new(segments(), form).unwrap();
+        assert_eq!(played(&song, 9), [0, 1, 2, 1, 3, 1, 2, 1, 3]);
+        assert_eq!(song.ppqn_len(), None);
+
+        let ticks: Vec<u32> = song.notes().take(9).map(|(t, _)| t).collect();
+        assert!(ticks.iter().enumerate().all(|(i, t)| *t == i as u32 * u32::from(PPQN_QUARTER)));
+
+        // The jump skips the only `Play` in the loop every time around, so
+        // the song ends instead
+        let form = vec![
+            Step::Play(0),
+            Step::Jump { to: 3, on_pass: 1 },
+            Step::Play(1),
+            Step::Loop { to: 1 },
+        ];
+        let song = Song::new(segments(), form).unwrap();
+        assert_eq!(played(&song, 100), [0]);
+        assert_eq!(song.arrangement().count(), 1);
+        assert_eq!(song.ppqn_len(), Some(u32::from(PPQN_QUARTER)));
+
+        // A jump out of the loop before it goes around
+        let form = vec![
+            Step::Play(0),
+            Step::Jump { to: 5, on_pass: 2 },
+            Step::Play(1),
+            Step::Jump { to: 1, on_pass: 1 },
+            Step::Loop { to: 2 },
+            Step::Play(2),
+        ];
+        let song = Song::new(segments(), form).unwrap();
+        assert_eq!(played(&song, 100), [0, 1, 2]);
+        assert_eq!(song.ppqn_len(), Some(3 * u32::from(PPQN_QUARTER)));
+    }
+
+    #[test]
+    fn errors() {
+        let check = |form: Vec<Step>| Song::new(segments(), form).err();
+        let err = |step, kind| Some(SongError { step, kind });
+
+        assert_eq!(check(vec![Step::Play(4)]), err(0, SongErrorKind::NoSuchSegment));
+        assert_eq!(
+            check(vec![Step::Play(0), Step::Jump { to: 3, on_pass: 1 }]),
+            err(1, SongErrorKind::NoSuchStep)
+        );
+        assert_eq!(
+            check(vec![Step::Repeat { to: 1, times: 1 }, Step::Play(0)]),
+            err(0, SongErrorKind::NotBackwards)
+        );
+        assert_eq!(
+            check(vec![Step::Play(0), Step::Repeat { to: 0, times: 0 }]),
+            err(1, SongErrorKind::ZeroCount)
+        );
+        assert_eq!(
+            check(vec![Step::Play(0), Step::Loop { to: 1 }]),
+            err(1, SongErrorKind::EmptyLoop)
+        );
+
+        let mut segs = segments();
+        segs.push(BarBuf::new());
+        let empty = Song::new(segs, vec![Step::Play(4), Step::Loop { to: 0 }]);
+        assert_eq!(empty.err(), err(1, SongErrorKind::EmptyLoop));
+
+        assert!(check(vec![Step::Play(0), Step::Jump { to: 2, on_pass: 1 }]).is_none());
+    }
+}